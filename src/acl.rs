@@ -0,0 +1,223 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn new(network: IpAddr, prefix_len: u8) -> Result<Self, String> {
+        let max_len = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(format!(
+                "Prefix length {} is too long for {}",
+                prefix_len, network
+            ));
+        }
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.network, addr) {
+            (IpAddr::V4(network), IpAddr::V4(addr)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(addr)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let network: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("Invalid IP address in '{}'", s))?;
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .map_err(|_| format!("Invalid prefix length in '{}'", s))?,
+            None if network.is_ipv4() => 32,
+            None => 128,
+        };
+
+        Self::new(network, prefix_len)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+// Dual-stack listeners see IPv4 clients as ::ffff:a.b.c.d; this turns them
+// back into a.b.c.d so IPv4 CIDRs and per-IP limits match them
+pub fn unmap_ipv4(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+// Decides whether an accepted client socket is served at all. Runs before the
+// handshake, rejected sockets are closed without a reply.
+pub trait AcceptFilter: Send + Sync {
+    fn accept(&self, client_addr: SocketAddr) -> bool;
+}
+
+impl fmt::Debug for dyn AcceptFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AcceptFilter")
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AllowAll;
+
+impl AcceptFilter for AllowAll {
+    fn accept(&self, _client_addr: SocketAddr) -> bool {
+        true
+    }
+}
+
+// Source address filter applied before the handshake.
+// Deny entries win; a non-empty allow list rejects everything it does not match.
+#[derive(Debug, Clone, Default)]
+pub struct ClientAcl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl ClientAcl {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(addr)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(addr))
+    }
+}
+
+impl AcceptFilter for ClientAcl {
+    fn accept(&self, client_addr: SocketAddr) -> bool {
+        self.permits(client_addr.ip())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_parse() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(cidr.to_string(), "10.0.0.0/8");
+
+        let host: Cidr = "192.168.1.1".parse().unwrap();
+        assert_eq!(host.to_string(), "192.168.1.1/32");
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert_eq!(v6.to_string(), "2001:db8::/32");
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("::/129".parse::<Cidr>().is_err());
+        assert!("not-an-ip/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(cidr.contains(ip("10.1.2.3")));
+        assert!(!cidr.contains(ip("10.2.0.1")));
+        assert!(!cidr.contains(ip("::1")));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains(ip("8.8.8.8")));
+
+        let v6: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(v6.contains(ip("2001:db8::1")));
+        assert!(!v6.contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn test_client_acl() {
+        assert!(ClientAcl::default().permits(ip("1.2.3.4")));
+
+        let acl = ClientAcl::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.0.0.66".parse().unwrap()],
+        );
+        assert!(acl.permits(ip("10.1.1.1")));
+        assert!(!acl.permits(ip("10.0.0.66")));
+        assert!(!acl.permits(ip("192.168.0.1")));
+
+        let deny_only = ClientAcl::new(vec![], vec!["192.168.0.0/16".parse().unwrap()]);
+        assert!(deny_only.permits(ip("10.1.1.1")));
+        assert!(!deny_only.permits(ip("192.168.5.5")));
+    }
+
+    #[test]
+    fn test_unmap_ipv4_lets_ipv4_acls_match() {
+        let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        let mapped = addr("[::ffff:10.0.0.66]:1080");
+        let acl = ClientAcl::new(vec![], vec!["10.0.0.66".parse().unwrap()]);
+        assert!(acl.accept(mapped));
+
+        let unmapped = unmap_ipv4(mapped);
+        assert_eq!(unmapped, addr("10.0.0.66:1080"));
+        assert!(!acl.accept(unmapped));
+
+        assert_eq!(unmap_ipv4(addr("[::1]:1")), addr("[::1]:1"));
+        assert_eq!(unmap_ipv4(addr("10.0.0.1:1")), addr("10.0.0.1:1"));
+    }
+
+    #[test]
+    fn test_accept_filters() {
+        let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+        assert!(AllowAll.accept(addr("10.0.0.66:1")));
+
+        let acl = ClientAcl::new(vec![], vec!["10.0.0.66".parse().unwrap()]);
+        let filter: &dyn AcceptFilter = &acl;
+        assert!(!filter.accept(addr("10.0.0.66:1")));
+        assert!(filter.accept(addr("10.0.0.67:1")));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+};
+use tokio::sync::Notify;
+
+// Freelist of relay buffers shared by all connections, so connection churn
+// reuses a handful of allocations instead of two fresh ones per relay.
+// Buffers are handed out as-is: the relay only ever forwards the bytes it
+// just read into them, never stale contents.
+#[derive(Debug)]
+pub struct BufferPool {
+    buffer_size: usize,
+    max_idle: usize,
+    free: Mutex<Vec<Box<[u8]>>>,
+    allocated: AtomicUsize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize, max_idle: usize) -> Self {
+        Self {
+            buffer_size: buffer_size.max(1),
+            max_idle,
+            free: Mutex::new(Vec::new()),
+            allocated: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    /// Takes an idle buffer, allocating one only if none is left.
+    /// The buffer goes back to the pool when the returned guard is dropped.
+    pub fn checkout(self: &Arc<Self>) -> PooledBuffer {
+        let buf = self.free.lock().unwrap().pop().unwrap_or_else(|| {
+            self.allocated.fetch_add(1, Ordering::Relaxed);
+            vec![0u8; self.buffer_size].into_boxed_slice()
+        });
+        PooledBuffer {
+            buf,
+            pool: Some(self.clone()),
+        }
+    }
+
+    // Buffers allocated over the pool's lifetime, idle or not
+    pub fn allocated(&self) -> usize {
+        self.allocated.load(Ordering::Relaxed)
+    }
+
+    pub fn idle(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+}
+
+pub struct PooledBuffer {
+    buf: Box<[u8]>,
+    pool: Option<Arc<BufferPool>>,
+}
+
+impl PooledBuffer {
+    // Plain allocation that is freed on drop, for relays without a pool
+    pub fn unpooled(size: usize) -> Self {
+        Self {
+            buf: vec![0u8; size.max(1)].into_boxed_slice(),
+            pool: None,
+        }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.pool {
+            let mut free = pool.free.lock().unwrap();
+            if free.len() < pool.max_idle {
+                free.push(std::mem::take(&mut self.buf));
+            }
+        }
+    }
+}
+
+// Cap on the bytes of relay buffers in use across all connections. Relays
+// charge their buffers against it up front and are credited back when done.
+#[derive(Debug)]
+pub struct BufferBudget {
+    limit: usize,
+    in_use: AtomicUsize,
+    released: Notify,
+}
+
+impl BufferBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            in_use: AtomicUsize::new(0),
+            released: Notify::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Acquire)
+    }
+
+    /// Charges up to `wanted` bytes, settling for whatever is left as long as
+    /// that is at least `floor`, or None when even `floor` does not fit.
+    pub fn try_charge(self: &Arc<Self>, wanted: usize, floor: usize) -> Option<BudgetCharge> {
+        let floor = floor.min(wanted);
+        let mut bytes = 0;
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                bytes = wanted.min(self.limit.saturating_sub(in_use));
+                (bytes >= floor).then_some(in_use + bytes)
+            })
+            .ok()?;
+        Some(BudgetCharge {
+            budget: self.clone(),
+            bytes,
+        })
+    }
+
+    /// Like [`try_charge`](Self::try_charge), but waits for other charges to
+    /// be released instead of giving up. Never returns if `floor` exceeds the
+    /// limit.
+    pub async fn charge(self: &Arc<Self>, wanted: usize, floor: usize) -> BudgetCharge {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Register before checking so a release in between is not missed
+            released.as_mut().enable();
+            if let Some(charge) = self.try_charge(wanted, floor) {
+                return charge;
+            }
+            released.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BudgetCharge {
+    budget: Arc<BufferBudget>,
+    bytes: usize,
+}
+
+impl BudgetCharge {
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+impl Drop for BudgetCharge {
+    fn drop(&mut self) {
+        self.budget.in_use.fetch_sub(self.bytes, Ordering::AcqRel);
+        self.budget.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let pool = Arc::new(BufferPool::new(16, 4));
+        let first = pool.checkout();
+        let second = pool.checkout();
+        assert_eq!(first.len(), 16);
+        assert_eq!(pool.allocated(), 2);
+
+        drop(first);
+        drop(second);
+        assert_eq!(pool.idle(), 2);
+
+        let _again = pool.checkout();
+        assert_eq!(pool.allocated(), 2);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_idle_buffers_are_capped() {
+        let pool = Arc::new(BufferPool::new(16, 1));
+        let buffers: Vec<_> = (0..3).map(|_| pool.checkout()).collect();
+        drop(buffers);
+        assert_eq!(pool.idle(), 1);
+    }
+
+    #[test]
+    fn test_budget_shrinks_charges_to_what_is_left() {
+        let budget = Arc::new(BufferBudget::new(10));
+        let full = budget.try_charge(8, 2).unwrap();
+        assert_eq!(full.bytes(), 8);
+        let shrunk = budget.try_charge(8, 2).unwrap();
+        assert_eq!(shrunk.bytes(), 2);
+        assert!(budget.try_charge(8, 1).is_none());
+        assert_eq!(budget.in_use(), 10);
+
+        drop(full);
+        assert_eq!(budget.in_use(), 2);
+        drop(shrunk);
+        assert_eq!(budget.in_use(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_budget_charge_waits_for_release() {
+        let budget = Arc::new(BufferBudget::new(4));
+        let held = budget.try_charge(4, 4).unwrap();
+
+        let waiter = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.charge(4, 4).await.bytes() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(held);
+        let bytes = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes, 4);
+    }
+}
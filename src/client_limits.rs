@@ -0,0 +1,100 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Default)]
+struct ClientCounts {
+    // Connections from this IP that have not finished their request yet
+    handshakes: usize,
+}
+
+// Per-source-IP limits, shared by the whole accept loop
+#[derive(Debug)]
+pub struct ClientLimiter {
+    max_handshakes: usize,
+    clients: Mutex<HashMap<IpAddr, ClientCounts>>,
+}
+
+impl ClientLimiter {
+    pub fn new(max_handshakes: usize) -> Self {
+        Self {
+            max_handshakes,
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claims a handshake slot for `ip`, or None if it already has the maximum
+    /// number of connections in the handshake phase. The slot is given back
+    /// when the returned guard is dropped.
+    pub fn try_start_handshake(self: &Arc<Self>, ip: IpAddr) -> Option<HandshakeSlot> {
+        let mut clients = self.clients.lock().unwrap();
+        let counts = clients.entry(ip).or_default();
+        if counts.handshakes >= self.max_handshakes {
+            return None;
+        }
+        counts.handshakes += 1;
+        Some(HandshakeSlot {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    pub fn handshakes(&self, ip: IpAddr) -> usize {
+        self.clients
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .map_or(0, |counts| counts.handshakes)
+    }
+}
+
+#[derive(Debug)]
+pub struct HandshakeSlot {
+    limiter: Arc<ClientLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        let mut clients = self.limiter.clients.lock().unwrap();
+        if let Some(counts) = clients.get_mut(&self.ip) {
+            counts.handshakes -= 1;
+            if counts.handshakes == 0 {
+                clients.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(127, 0, 0, last))
+    }
+
+    #[test]
+    fn test_handshake_limit_is_per_ip() {
+        let limiter = Arc::new(ClientLimiter::new(2));
+        let first = limiter.try_start_handshake(ip(1)).unwrap();
+        let _second = limiter.try_start_handshake(ip(1)).unwrap();
+        assert!(limiter.try_start_handshake(ip(1)).is_none());
+        assert!(limiter.try_start_handshake(ip(2)).is_some());
+
+        drop(first);
+        assert_eq!(limiter.handshakes(ip(1)), 1);
+        assert!(limiter.try_start_handshake(ip(1)).is_some());
+    }
+
+    #[test]
+    fn test_finished_clients_are_forgotten() {
+        let limiter = Arc::new(ClientLimiter::new(1));
+        drop(limiter.try_start_handshake(ip(1)).unwrap());
+        assert_eq!(limiter.handshakes(ip(1)), 0);
+        assert!(limiter.clients.lock().unwrap().is_empty());
+    }
+}
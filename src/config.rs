@@ -1,11 +1,37 @@
 use std::{
+    fmt,
     net::{SocketAddr, ToSocketAddrs},
+    str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 
 use clap::Parser;
+use serde::Serialize;
+use tokio::sync::Semaphore;
 
-use crate::connection::method::method::Method;
+use crate::{
+    acl::{Cidr, ClientAcl},
+    buffer_pool::{BufferBudget, BufferPool},
+    connection::DnsRetry,
+    connection::address_type::{MAX_DOMAIN_LEN, ResolveFamily},
+    connection::command::{
+        Command,
+        bind::PortRange,
+        connect::{Ipv6TargetPolicy, NodelaySwitch},
+        relay::MIN_RELAY_BUFFER,
+    },
+    connection::method::{
+        client_greeting::GreetingPolicy, method::Method, method_handler::DEFAULT_METHOD_PRIORITY,
+    },
+    events::EventSink,
+    host_map::{HostMap, HostMapping},
+    interceptor::ConnectionInterceptor,
+    json_log::LogFormat,
+    metrics::Metrics,
+    syslog::LogTarget,
+    target_limits::TargetLimiter,
+};
 
 #[derive(Parser, Debug, Clone)]
 #[command(version, about = "SOCKS5 proxy", long_about = None)]
@@ -19,15 +45,64 @@ pub struct ProxyConfig {
     #[arg(long, help = "Enable debug logging")]
     pub verbose: bool,
 
+    #[arg(long, value_enum, default_value_t = LogTarget::Stdout, help = "Where to write logs")]
+    pub log_target: LogTarget,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, help = "Log line format")]
+    pub log_format: LogFormat,
+
+    #[arg(
+        long,
+        help = "Remote syslog host:port (UDP) for --log-target syslog, instead of the local socket"
+    )]
+    pub syslog_addr: Option<SocketAddr>,
+
     #[arg(long, default_value = "1000", help = "Maximum concurrent connections")]
     pub max_connections: usize,
 
+    #[arg(
+        long,
+        default_value = "90",
+        help = "Warn when active connections reach this percentage of --max-connections"
+    )]
+    pub connection_watermark: u8,
+
+    #[arg(
+        long,
+        help = "Maximum connections still in the handshake phase (default: no separate limit)"
+    )]
+    pub max_pending_handshakes: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Maximum connections accepted but not yet relaying, further accepts wait"
+    )]
+    pub max_accepts_in_flight: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Maximum connections from one client IP still in the handshake phase"
+    )]
+    pub max_handshakes_per_ip: Option<usize>,
+
     #[arg(long, default_value = "30", help = "Handshake timeout in seconds")]
     pub handshake_timeout: u64,
 
+    #[arg(
+        long,
+        help = "Drop a client that does not take a handshake reply within this many seconds"
+    )]
+    pub handshake_write_timeout: Option<u64>,
+
     #[arg(long, default_value = "60", help = "Connection timeout in seconds")]
     pub connection_timeout: u64,
 
+    #[arg(
+        long,
+        help = "Force-close any connection after this many seconds, regardless of activity"
+    )]
+    pub max_connection_lifetime: Option<u64>,
+
     #[arg(long, default_value = "10", help = "Shutdown timeout in seconds")]
     pub shutdown_timeout: u64,
 
@@ -38,6 +113,12 @@ pub struct ProxyConfig {
     )]
     pub buffer_size: usize,
 
+    #[arg(
+        long,
+        help = "Cap --buffer-size at the maximum with a warning instead of refusing to start"
+    )]
+    pub clamp_buffer_size: bool,
+
     #[arg(
         long,
         default_value = "true",
@@ -45,14 +126,352 @@ pub struct ProxyConfig {
     )]
     pub tcp_nodelay: bool,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value = "off",
+        help = "Flip TCP_NODELAY on the target socket at its first response byte"
+    )]
+    pub nodelay_switch: NodelaySwitch,
+
     #[arg(
         long,
         default_value = "none",
         help = "Comma-separated list of auth methods: none,userpass,gssapi"
     )]
     pub auth_methods: String,
+
+    #[arg(
+        long,
+        default_value = "none,userpass,gssapi",
+        help = "Comma-separated method preference order used during negotiation"
+    )]
+    pub method_priority: String,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "warn",
+        help = "How to treat duplicate, unknown or extra methods in the client greeting"
+    )]
+    pub greeting_policy: GreetingPolicy,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Maximum random delay in milliseconds before auth failure replies"
+    )]
+    pub auth_failure_jitter_ms: u64,
+
+    #[arg(
+        long,
+        help = "User to switch to after binding the listener (Linux only)"
+    )]
+    pub user: Option<String>,
+
+    #[arg(
+        long,
+        help = "Group to switch to after binding the listener (Linux only)"
+    )]
+    pub group: Option<String>,
+
+    #[arg(
+        long,
+        help = "Also start a built-in echo server on this address for benchmarking"
+    )]
+    pub test_echo_target: Option<SocketAddr>,
+
+    #[arg(
+        long,
+        help = "Register with this http:// discovery URL on startup and deregister on shutdown"
+    )]
+    pub register_url: Option<HttpUrl>,
+
+    #[arg(
+        long,
+        default_value = "30",
+        help = "Seconds between discovery heartbeats when --register-url is set"
+    )]
+    pub register_interval: u64,
+
+    #[arg(
+        long,
+        help = "Push metrics to this http:// OTLP collector URL, e.g. http://collector:4318/v1/metrics (needs the otlp feature)"
+    )]
+    pub otlp_endpoint: Option<HttpUrl>,
+
+    #[arg(
+        long,
+        default_value = "30",
+        help = "Seconds between OTLP metric pushes when --otlp-endpoint is set"
+    )]
+    pub otlp_interval: u64,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "POST each established CONNECT as JSON to this http:// URL"
+    )]
+    pub connect_webhook: Option<HttpUrl>,
+
+    #[arg(
+        long,
+        default_value = "8",
+        help = "Webhook requests open at once, further events queue"
+    )]
+    pub max_webhooks_in_flight: usize,
+
+    #[arg(
+        long,
+        help = "Answer the non-standard diagnostics command 0xF0 (needs the diagnostics feature)"
+    )]
+    pub diagnostics_command: bool,
+
+    #[arg(
+        long,
+        help = "Reset the client connection when the target resets the relay"
+    )]
+    pub abort_on_target_reset: bool,
+
+    #[arg(
+        long,
+        help = "Reset the client connection instead of closing it after a malformed greeting or request"
+    )]
+    pub reset_on_protocol_violation: bool,
+
+    #[arg(
+        long,
+        help = "Send data the client pipelines behind a CONNECT in the SYN to the target (TCP Fast Open, Linux only)"
+    )]
+    pub tfo: bool,
+
+    #[arg(
+        long,
+        help = "Abort client and target connections whose sent data stays unacknowledged this many milliseconds (Linux only)"
+    )]
+    pub tcp_user_timeout_ms: Option<u64>,
+
+    #[arg(
+        long,
+        default_value = "250",
+        help = "Milliseconds to wait on one address family before racing the other"
+    )]
+    pub fallback_delay_ms: u64,
+
+    #[arg(
+        long,
+        default_value = "10000",
+        help = "Overall deadline in milliseconds for connecting to a target"
+    )]
+    pub connect_deadline_ms: u64,
+
+    #[arg(
+        long,
+        help = "Close relays that move no data in either direction for this many seconds"
+    )]
+    pub idle_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Close relays where neither side sends a first byte within this many seconds"
+    )]
+    pub first_byte_timeout: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Enable TCP keepalive on the target socket once a relay has been idle for this many seconds"
+    )]
+    pub idle_keepalive: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Keep relaying the other direction after one side half-closes"
+    )]
+    pub half_close: bool,
+
+    #[arg(
+        long,
+        help = "Wait up to 100 ms for data the target sends on connect and forward it with the CONNECT reply"
+    )]
+    pub prefetch_target: bool,
+
+    #[arg(
+        long,
+        help = "Reuse relay buffers across connections instead of allocating per relay"
+    )]
+    pub buffer_pool: bool,
+
+    #[arg(
+        long,
+        help = "Total KB of relay buffers across all connections; relays shrink their buffers or wait beyond it"
+    )]
+    pub max_buffer_memory: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Close a relay once this many bytes have been transferred in total"
+    )]
+    pub max_bytes_per_connection: Option<u64>,
+
+    #[arg(
+        long,
+        help = "Refuse CONNECTs to a target address that already has this many relays"
+    )]
+    pub max_connections_per_target: Option<usize>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "PORTS",
+        help = "Comma-separated destination ports and ranges CONNECT may reach, e.g. 80,443,1024-65535 (default: all)"
+    )]
+    pub allow_ports: Vec<PortRange>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma-separated client CIDRs allowed to connect (default: all)"
+    )]
+    pub client_allow: Vec<Cidr>,
+
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Comma-separated client CIDRs refused before the handshake"
+    )]
+    pub client_deny: Vec<Cidr>,
+
+    #[arg(
+        long,
+        help = "Treat IPv4-mapped IPv6 client addresses as plain IPv4 for ACLs, limits and logs"
+    )]
+    pub unmap_ipv4_clients: bool,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Log 1 in N completed connections at info level (0 = only errors)"
+    )]
+    pub access_log_sample_rate: u64,
+
+    #[arg(
+        long,
+        help = "Log one info line per accepted command with its method and target"
+    )]
+    pub log_accepted: bool,
+
+    #[arg(
+        long,
+        help = "Accept requests with a non-zero reserved byte instead of rejecting them"
+    )]
+    pub lenient_reserved: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "auto",
+        help = "Whether CONNECTs to IPv6 targets are attempted when the host may lack IPv6"
+    )]
+    pub ipv6_targets: Ipv6TargetPolicy,
+
+    #[arg(
+        long,
+        help = "Allocate BIND listeners from this port range, e.g. 40000-41000"
+    )]
+    pub bind_port_range: Option<PortRange>,
+
+    #[arg(
+        long,
+        help = "Maximum BIND listeners open at once, further BINDs are refused"
+    )]
+    pub max_bind_listeners: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "30",
+        help = "Seconds a BIND waits for its peer after the first reply, e.g. for an FTP client to send PORT"
+    )]
+    pub bind_accept_timeout: u64,
+
+    #[arg(
+        long,
+        default_value_t = true,
+        action = clap::ArgAction::Set,
+        help = "Accept BIND requests, false answers them as unsupported"
+    )]
+    pub enable_bind: bool,
+
+    #[arg(
+        long,
+        help = "Refuse domain name requests instead of resolving them (IP-only mode)"
+    )]
+    pub no_dns: bool,
+
+    #[arg(
+        long = "map",
+        value_name = "DOMAIN=ADDR:PORT",
+        help = "Connect requests for DOMAIN to ADDR:PORT without resolving it (repeatable)"
+    )]
+    pub host_map: Vec<HostMapping>,
+
+    // lookup_host runs getaddrinfo on tokio's blocking pool (512 threads by
+    // default), so slow DNS can otherwise crowd out file I/O and other blocking work
+    #[arg(
+        long,
+        help = "Maximum domain name lookups in flight at once, the rest wait their turn"
+    )]
+    pub max_concurrent_resolutions: Option<usize>,
+
+    #[arg(
+        long,
+        default_value = "255",
+        help = "Refuse domain names longer than this many bytes (DNS itself allows 253)"
+    )]
+    pub max_domain_len: u8,
+
+    #[arg(
+        long,
+        default_value = "0",
+        help = "Retry lookups that fail transiently (timeout, SERVFAIL) this many times"
+    )]
+    pub dns_retries: u32,
+
+    #[arg(
+        long,
+        default_value = "100",
+        help = "Delay before the first DNS retry in milliseconds, doubled for each further retry"
+    )]
+    pub dns_retry_backoff_ms: u64,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "any",
+        help = "Address family domain names are resolved to"
+    )]
+    pub resolve_family: ResolveFamily,
+
+    #[arg(long, help = "Kernel receive buffer size (SO_RCVBUF) in bytes")]
+    pub so_rcvbuf: Option<usize>,
+
+    #[arg(long, help = "Kernel send buffer size (SO_SNDBUF) in bytes")]
+    pub so_sndbuf: Option<usize>,
+
+    #[arg(
+        long,
+        help = "DSCP value (0-63) to mark outbound traffic to targets with"
+    )]
+    pub dscp: Option<u8>,
 }
 
+const MAX_BUFFER_SIZE_KB: usize = 1024;
+const SOCKET_BUFFER_MIN: usize = 1024;
+const SOCKET_BUFFER_MAX: usize = 64 * 1024 * 1024;
+const MAX_AUTH_FAILURE_JITTER_MS: u64 = 10_000;
+const MAX_TCP_USER_TIMEOUT_MS: u64 = 3_600_000;
+// DSCP is the upper six bits of the TOS / traffic class byte
+const MAX_DSCP: u8 = 63;
+
 impl ProxyConfig {
     pub fn from_args() -> Self {
         Self::parse()
@@ -80,7 +499,20 @@ impl ProxyConfig {
     }
 
     pub fn buffer_size_bytes(&self) -> usize {
-        self.buffer_size * 1024
+        self.effective_buffer_size_kb() * 1024
+    }
+
+    // --buffer-size after --clamp-buffer-size has been applied
+    pub fn effective_buffer_size_kb(&self) -> usize {
+        if self.clamp_buffer_size {
+            self.buffer_size.min(MAX_BUFFER_SIZE_KB)
+        } else {
+            self.buffer_size
+        }
+    }
+
+    pub fn buffer_size_clamped(&self) -> bool {
+        self.effective_buffer_size_kb() != self.buffer_size
     }
 
     pub fn supported_auth_methods(&self) -> Vec<u8> {
@@ -95,60 +527,818 @@ impl ProxyConfig {
             }
         }
 
-        if methods.is_empty() {
-            methods.push(0x00);
-        }
+        if methods.is_empty() {
+            methods.push(0x00);
+        }
+
+        methods
+    }
+
+    // Unknown names are refused by `validate`
+    pub fn method_priority(&self) -> Vec<u8> {
+        let mut priority = Vec::new();
+
+        for method in self.method_priority.split(',') {
+            let Some(code) = priority_method_code(method) else {
+                continue;
+            };
+            if !priority.contains(&code) {
+                priority.push(code);
+            }
+        }
+
+        if priority.is_empty() {
+            priority.extend_from_slice(&DEFAULT_METHOD_PRIORITY);
+        }
+
+        priority
+    }
+
+    pub fn tracing_level(&self) -> tracing::Level {
+        if self.verbose {
+            tracing::Level::DEBUG
+        } else {
+            tracing::Level::INFO
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.port == 0 {
+            return Err(ConfigError::InvalidPort);
+        }
+
+        if self.max_connections == 0 {
+            return Err(ConfigError::NoMaxConnections);
+        }
+
+        if !(1..=100).contains(&self.connection_watermark) {
+            return Err(ConfigError::WatermarkOutOfRange);
+        }
+
+        if self.max_pending_handshakes == Some(0) {
+            return Err(ConfigError::NoMaxPendingHandshakes);
+        }
+
+        if self.max_accepts_in_flight == Some(0) {
+            return Err(ConfigError::NoMaxAcceptsInFlight);
+        }
+
+        if self.max_handshakes_per_ip == Some(0) {
+            return Err(ConfigError::NoMaxHandshakesPerIp);
+        }
+
+        if self.buffer_size == 0 {
+            return Err(ConfigError::BufferSizeZero);
+        }
+
+        if self.buffer_size > MAX_BUFFER_SIZE_KB && !self.clamp_buffer_size {
+            return Err(ConfigError::BufferSizeTooLarge);
+        }
+
+        // A relay needs room for its two smallest buffers or it waits forever
+        if self
+            .max_buffer_memory
+            .is_some_and(|kb| kb.saturating_mul(1024) < 2 * MIN_RELAY_BUFFER)
+        {
+            return Err(ConfigError::BufferMemoryTooSmall);
+        }
+
+        if self.shutdown_timeout == 0 {
+            return Err(ConfigError::NoShutdownTimeout);
+        }
+
+        if self.max_connection_lifetime == Some(0) {
+            return Err(ConfigError::NoMaxConnectionLifetime);
+        }
+
+        let methods = self.supported_auth_methods();
+        if methods.is_empty() {
+            return Err(ConfigError::NoAuthMethods);
+        }
+
+        if self
+            .method_priority
+            .split(',')
+            .any(|method| priority_method_code(method).is_none())
+        {
+            return Err(ConfigError::UnknownPriorityMethod);
+        }
+
+        if self.auth_failure_jitter_ms > MAX_AUTH_FAILURE_JITTER_MS {
+            return Err(ConfigError::AuthFailureJitterTooLarge);
+        }
+
+        if self.max_bytes_per_connection == Some(0) {
+            return Err(ConfigError::NoMaxBytesPerConnection);
+        }
+
+        if self.max_connections_per_target == Some(0) {
+            return Err(ConfigError::NoMaxConnectionsPerTarget);
+        }
+
+        if self.max_concurrent_resolutions == Some(0) {
+            return Err(ConfigError::NoMaxConcurrentResolutions);
+        }
+
+        if self.max_domain_len == 0 {
+            return Err(ConfigError::NoMaxDomainLen);
+        }
+
+        if self.max_bind_listeners == Some(0) {
+            return Err(ConfigError::NoMaxBindListeners);
+        }
+
+        if self.bind_accept_timeout == 0 {
+            return Err(ConfigError::NoBindAcceptTimeout);
+        }
+
+        if self.register_interval == 0 {
+            return Err(ConfigError::NoRegisterInterval);
+        }
+
+        if self.otlp_interval == 0 {
+            return Err(ConfigError::NoOtlpInterval);
+        }
+
+        if self.max_webhooks_in_flight == 0 {
+            return Err(ConfigError::NoMaxWebhooksInFlight);
+        }
+
+        if let Some(size) = self.so_rcvbuf
+            && !(SOCKET_BUFFER_MIN..=SOCKET_BUFFER_MAX).contains(&size)
+        {
+            return Err(ConfigError::ReceiveBufferOutOfRange);
+        }
+
+        if let Some(size) = self.so_sndbuf
+            && !(SOCKET_BUFFER_MIN..=SOCKET_BUFFER_MAX).contains(&size)
+        {
+            return Err(ConfigError::SendBufferOutOfRange);
+        }
+
+        if self.dscp.is_some_and(|dscp| dscp > MAX_DSCP) {
+            return Err(ConfigError::DscpOutOfRange);
+        }
+
+        if self.group.is_some() && self.user.is_none() {
+            return Err(ConfigError::GroupWithoutUser);
+        }
+
+        if self.syslog_addr.is_some() && self.log_target != LogTarget::Syslog {
+            return Err(ConfigError::SyslogAddrWithoutSyslog);
+        }
+
+        if self.user.is_some() && !cfg!(target_os = "linux") {
+            return Err(ConfigError::PrivilegeDropUnsupported);
+        }
+
+        if self.diagnostics_command && !cfg!(feature = "diagnostics") {
+            return Err(ConfigError::DiagnosticsUnsupported);
+        }
+
+        if self.otlp_endpoint.is_some() && !cfg!(feature = "otlp") {
+            return Err(ConfigError::OtlpUnsupported);
+        }
+
+        if self.tfo && !cfg!(target_os = "linux") {
+            return Err(ConfigError::FastOpenUnsupported);
+        }
+
+        if let Some(ms) = self.tcp_user_timeout_ms {
+            if !(1..=MAX_TCP_USER_TIMEOUT_MS).contains(&ms) {
+                return Err(ConfigError::TcpUserTimeoutOutOfRange);
+            }
+            if !cfg!(target_os = "linux") {
+                return Err(ConfigError::TcpUserTimeoutUnsupported);
+            }
+        }
+
+        if self.handshake_write_timeout == Some(0) {
+            return Err(ConfigError::NoHandshakeWriteTimeout);
+        }
+
+        if self.idle_timeout == Some(0) {
+            return Err(ConfigError::NoIdleTimeout);
+        }
+
+        if self.first_byte_timeout == Some(0) {
+            return Err(ConfigError::NoFirstByteTimeout);
+        }
+
+        if self.idle_keepalive == Some(0) {
+            return Err(ConfigError::NoIdleKeepalive);
+        }
+
+        if self.connect_deadline_ms == 0 {
+            return Err(ConfigError::NoConnectDeadline);
+        }
+
+        Ok(())
+    }
+
+    pub fn client_acl(&self) -> ClientAcl {
+        ClientAcl::new(self.client_allow.clone(), self.client_deny.clone())
+    }
+
+    pub fn summary(&self) -> ConfigSummary {
+        ConfigSummary {
+            server_address: format!("{}:{}", self.host, self.port),
+            max_connections: self.max_connections,
+            connection_watermark: self.connection_watermark,
+            max_pending_handshakes: self.max_pending_handshakes,
+            max_accepts_in_flight: self.max_accepts_in_flight,
+            max_handshakes_per_ip: self.max_handshakes_per_ip,
+            handshake_timeout_secs: self.handshake_timeout,
+            handshake_write_timeout_secs: self.handshake_write_timeout,
+            connection_timeout_secs: self.connection_timeout,
+            shutdown_timeout_secs: self.shutdown_timeout,
+            max_connection_lifetime_secs: self.max_connection_lifetime,
+            buffer_size_kb: self.effective_buffer_size_kb(),
+            tcp_nodelay: self.tcp_nodelay,
+            nodelay_switch: self.nodelay_switch,
+            tcp_fast_open: self.tfo,
+            tcp_user_timeout_ms: self.tcp_user_timeout_ms,
+            fallback_delay_ms: self.fallback_delay_ms,
+            connect_deadline_ms: self.connect_deadline_ms,
+            abort_on_target_reset: self.abort_on_target_reset,
+            reset_on_protocol_violation: self.reset_on_protocol_violation,
+            idle_timeout_secs: self.idle_timeout,
+            first_byte_timeout_secs: self.first_byte_timeout,
+            idle_keepalive_secs: self.idle_keepalive,
+            half_close: self.half_close,
+            prefetch_target: self.prefetch_target,
+            buffer_pool: self.buffer_pool,
+            max_buffer_memory: self.max_buffer_memory,
+            max_bytes_per_connection: self.max_bytes_per_connection,
+            max_connections_per_target: self.max_connections_per_target,
+            allow_ports: self.allow_ports.iter().map(PortRange::to_string).collect(),
+            client_allow: self.client_allow.iter().map(Cidr::to_string).collect(),
+            client_deny: self.client_deny.iter().map(Cidr::to_string).collect(),
+            unmap_ipv4_clients: self.unmap_ipv4_clients,
+            access_log_sample_rate: self.access_log_sample_rate,
+            log_accepted: self.log_accepted,
+            so_rcvbuf: self.so_rcvbuf,
+            so_sndbuf: self.so_sndbuf,
+            dscp: self.dscp,
+            no_dns: self.no_dns,
+            host_map: self
+                .host_map
+                .iter()
+                .map(|mapping| format!("{}={}", mapping.domain, mapping.target))
+                .collect(),
+            max_concurrent_resolutions: self.max_concurrent_resolutions,
+            max_domain_len: self.max_domain_len,
+            dns_retries: self.dns_retries,
+            dns_retry_backoff_ms: self.dns_retry_backoff_ms,
+            resolve_family: self.resolve_family,
+            bind_port_range: self.bind_port_range.map(|range| range.to_string()),
+            max_bind_listeners: self.max_bind_listeners,
+            bind_accept_timeout_secs: self.bind_accept_timeout,
+            enable_bind: self.enable_bind,
+            lenient_reserved: self.lenient_reserved,
+            ipv6_targets: self.ipv6_targets,
+            auth_methods: self.auth_methods.clone(),
+            method_priority: self.method_priority.clone(),
+            greeting_policy: self.greeting_policy,
+            auth_failure_jitter_ms: self.auth_failure_jitter_ms,
+            user: self.user.clone(),
+            group: self.group.clone(),
+            test_echo_target: self.test_echo_target,
+            register_url: self.register_url.as_ref().map(HttpUrl::to_string),
+            otlp_endpoint: self.otlp_endpoint.as_ref().map(HttpUrl::to_string),
+            connect_webhook: self.connect_webhook.as_ref().map(HttpUrl::to_string),
+            diagnostics_command: self.diagnostics_command,
+            debug_logging: self.verbose,
+            log_target: self.log_target,
+            log_format: self.log_format,
+            syslog_addr: self.syslog_addr,
+        }
+    }
+
+    pub fn display_summary(&self) {
+        println!("{}", self.summary());
+    }
+}
+
+fn priority_method_code(method: &str) -> Option<u8> {
+    match method.trim().to_lowercase().as_str() {
+        "none" => Some(Method::NO_AUTHENTICATION_REQUIRED),
+        "userpass" => Some(Method::USERNAME_PASSWORD),
+        "gssapi" => Some(Method::GSSAPI),
+        _ => None,
+    }
+}
+
+// Plain http:// endpoint the proxy sends JSON to, for discovery, OTLP export
+// and the CONNECT webhook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpUrl {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: String,
+}
+
+impl FromStr for HttpUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .trim()
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("URL '{}' must start with http://", s))?;
+        let (authority, path) = match rest.find('/') {
+            Some(index) => rest.split_at(index),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("Invalid port in URL '{}'", s))?,
+            ),
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in URL '{}'", s));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    InvalidPort,
+    NoMaxConnections,
+    WatermarkOutOfRange,
+    NoMaxPendingHandshakes,
+    NoMaxAcceptsInFlight,
+    NoMaxHandshakesPerIp,
+    BufferSizeZero,
+    BufferSizeTooLarge,
+    BufferMemoryTooSmall,
+    NoShutdownTimeout,
+    NoMaxConnectionLifetime,
+    NoAuthMethods,
+    UnknownPriorityMethod,
+    AuthFailureJitterTooLarge,
+    NoMaxBytesPerConnection,
+    NoMaxConnectionsPerTarget,
+    NoMaxConcurrentResolutions,
+    NoMaxDomainLen,
+    NoMaxBindListeners,
+    NoBindAcceptTimeout,
+    NoRegisterInterval,
+    NoOtlpInterval,
+    NoMaxWebhooksInFlight,
+    ReceiveBufferOutOfRange,
+    SendBufferOutOfRange,
+    DscpOutOfRange,
+    GroupWithoutUser,
+    SyslogAddrWithoutSyslog,
+    PrivilegeDropUnsupported,
+    FastOpenUnsupported,
+    DiagnosticsUnsupported,
+    OtlpUnsupported,
+    TcpUserTimeoutOutOfRange,
+    TcpUserTimeoutUnsupported,
+    NoConnectDeadline,
+    NoHandshakeWriteTimeout,
+    NoIdleTimeout,
+    NoFirstByteTimeout,
+    NoIdleKeepalive,
+}
+
+impl ConfigError {
+    /// The `ProxyConfig` field that failed validation.
+    pub fn field(&self) -> &'static str {
+        match self {
+            ConfigError::InvalidPort => "port",
+            ConfigError::NoMaxConnections => "max_connections",
+            ConfigError::WatermarkOutOfRange => "connection_watermark",
+            ConfigError::NoMaxPendingHandshakes => "max_pending_handshakes",
+            ConfigError::NoMaxAcceptsInFlight => "max_accepts_in_flight",
+            ConfigError::NoMaxHandshakesPerIp => "max_handshakes_per_ip",
+            ConfigError::BufferSizeZero | ConfigError::BufferSizeTooLarge => "buffer_size",
+            ConfigError::BufferMemoryTooSmall => "max_buffer_memory",
+            ConfigError::NoShutdownTimeout => "shutdown_timeout",
+            ConfigError::NoMaxConnectionLifetime => "max_connection_lifetime",
+            ConfigError::NoAuthMethods => "auth_methods",
+            ConfigError::UnknownPriorityMethod => "method_priority",
+            ConfigError::AuthFailureJitterTooLarge => "auth_failure_jitter_ms",
+            ConfigError::NoMaxBytesPerConnection => "max_bytes_per_connection",
+            ConfigError::NoMaxConnectionsPerTarget => "max_connections_per_target",
+            ConfigError::NoMaxConcurrentResolutions => "max_concurrent_resolutions",
+            ConfigError::NoMaxDomainLen => "max_domain_len",
+            ConfigError::NoMaxBindListeners => "max_bind_listeners",
+            ConfigError::NoBindAcceptTimeout => "bind_accept_timeout",
+            ConfigError::NoRegisterInterval => "register_interval",
+            ConfigError::NoOtlpInterval => "otlp_interval",
+            ConfigError::NoMaxWebhooksInFlight => "max_webhooks_in_flight",
+            ConfigError::ReceiveBufferOutOfRange => "so_rcvbuf",
+            ConfigError::SendBufferOutOfRange => "so_sndbuf",
+            ConfigError::DscpOutOfRange => "dscp",
+            ConfigError::GroupWithoutUser => "group",
+            ConfigError::SyslogAddrWithoutSyslog => "syslog_addr",
+            ConfigError::PrivilegeDropUnsupported => "user",
+            ConfigError::FastOpenUnsupported => "tfo",
+            ConfigError::DiagnosticsUnsupported => "diagnostics_command",
+            ConfigError::OtlpUnsupported => "otlp_endpoint",
+            ConfigError::TcpUserTimeoutOutOfRange | ConfigError::TcpUserTimeoutUnsupported => {
+                "tcp_user_timeout_ms"
+            }
+            ConfigError::NoConnectDeadline => "connect_deadline_ms",
+            ConfigError::NoHandshakeWriteTimeout => "handshake_write_timeout",
+            ConfigError::NoIdleTimeout => "idle_timeout",
+            ConfigError::NoFirstByteTimeout => "first_byte_timeout",
+            ConfigError::NoIdleKeepalive => "idle_keepalive",
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidPort => write!(f, "Port cannot be 0"),
+            ConfigError::NoMaxConnections => write!(f, "Max connections must be greater than 0"),
+            ConfigError::WatermarkOutOfRange => {
+                write!(f, "Connection watermark must be between 1 and 100 percent")
+            }
+            ConfigError::NoMaxPendingHandshakes => {
+                write!(f, "Max pending handshakes must be greater than 0")
+            }
+            ConfigError::NoMaxAcceptsInFlight => {
+                write!(f, "Max accepts in flight must be greater than 0")
+            }
+            ConfigError::NoMaxHandshakesPerIp => {
+                write!(f, "Max handshakes per IP must be greater than 0")
+            }
+            ConfigError::BufferSizeZero => write!(f, "Buffer size must be greater than 0"),
+            ConfigError::BufferSizeTooLarge => {
+                write!(f, "Buffer size cannot exceed {} KB", MAX_BUFFER_SIZE_KB)
+            }
+            ConfigError::BufferMemoryTooSmall => write!(
+                f,
+                "Max buffer memory must be at least {} KB",
+                2 * MIN_RELAY_BUFFER / 1024
+            ),
+            ConfigError::NoShutdownTimeout => write!(f, "Shutdown timeout must be greater than 0"),
+            ConfigError::NoMaxConnectionLifetime => {
+                write!(f, "Max connection lifetime must be greater than 0")
+            }
+            ConfigError::NoAuthMethods => {
+                write!(f, "At least one authentication method must be supported")
+            }
+            ConfigError::UnknownPriorityMethod => {
+                write!(f, "Method priority may only list none, userpass and gssapi")
+            }
+            ConfigError::AuthFailureJitterTooLarge => write!(
+                f,
+                "Auth failure jitter cannot exceed {} ms",
+                MAX_AUTH_FAILURE_JITTER_MS
+            ),
+            ConfigError::NoMaxBytesPerConnection => {
+                write!(f, "Max bytes per connection must be greater than 0")
+            }
+            ConfigError::NoMaxConnectionsPerTarget => {
+                write!(f, "Max connections per target must be greater than 0")
+            }
+            ConfigError::NoMaxConcurrentResolutions => {
+                write!(f, "Max concurrent resolutions must be greater than 0")
+            }
+            ConfigError::NoMaxDomainLen => write!(f, "Max domain length must be greater than 0"),
+            ConfigError::NoMaxBindListeners => {
+                write!(f, "Max BIND listeners must be greater than 0")
+            }
+            ConfigError::NoBindAcceptTimeout => {
+                write!(f, "BIND accept timeout must be greater than 0")
+            }
+            ConfigError::NoRegisterInterval => {
+                write!(f, "Register interval must be greater than 0")
+            }
+            ConfigError::NoOtlpInterval => write!(f, "OTLP interval must be greater than 0"),
+            ConfigError::NoMaxWebhooksInFlight => {
+                write!(f, "Max webhooks in flight must be greater than 0")
+            }
+            ConfigError::ReceiveBufferOutOfRange => write!(
+                f,
+                "SO_RCVBUF must be between {} and {} bytes",
+                SOCKET_BUFFER_MIN, SOCKET_BUFFER_MAX
+            ),
+            ConfigError::SendBufferOutOfRange => write!(
+                f,
+                "SO_SNDBUF must be between {} and {} bytes",
+                SOCKET_BUFFER_MIN, SOCKET_BUFFER_MAX
+            ),
+            ConfigError::DscpOutOfRange => write!(f, "DSCP must be between 0 and {}", MAX_DSCP),
+            ConfigError::GroupWithoutUser => write!(f, "--group requires --user"),
+            ConfigError::SyslogAddrWithoutSyslog => {
+                write!(f, "--syslog-addr requires --log-target syslog")
+            }
+            ConfigError::PrivilegeDropUnsupported => {
+                write!(f, "Dropping privileges is only supported on Linux")
+            }
+            ConfigError::FastOpenUnsupported => {
+                write!(f, "TCP Fast Open is only supported on Linux")
+            }
+            ConfigError::DiagnosticsUnsupported => write!(
+                f,
+                "--diagnostics-command requires building with the diagnostics feature"
+            ),
+            ConfigError::OtlpUnsupported => {
+                write!(f, "--otlp-endpoint requires building with the otlp feature")
+            }
+            ConfigError::TcpUserTimeoutOutOfRange => write!(
+                f,
+                "TCP user timeout must be between 1 and {} ms",
+                MAX_TCP_USER_TIMEOUT_MS
+            ),
+            ConfigError::TcpUserTimeoutUnsupported => {
+                write!(f, "TCP user timeout is only supported on Linux")
+            }
+            ConfigError::NoConnectDeadline => {
+                write!(f, "Connect deadline must be greater than 0")
+            }
+            ConfigError::NoHandshakeWriteTimeout => {
+                write!(f, "Handshake write timeout must be greater than 0")
+            }
+            ConfigError::NoIdleTimeout => write!(f, "Idle timeout must be greater than 0"),
+            ConfigError::NoFirstByteTimeout => {
+                write!(f, "First byte timeout must be greater than 0")
+            }
+            ConfigError::NoIdleKeepalive => write!(f, "Idle keepalive must be greater than 0"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
 
-        methods
-    }
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConfigSummary {
+    pub server_address: String,
+    pub max_connections: usize,
+    pub connection_watermark: u8,
+    pub max_pending_handshakes: Option<usize>,
+    pub max_accepts_in_flight: Option<usize>,
+    pub max_handshakes_per_ip: Option<usize>,
+    pub handshake_timeout_secs: u64,
+    pub handshake_write_timeout_secs: Option<u64>,
+    pub connection_timeout_secs: u64,
+    pub shutdown_timeout_secs: u64,
+    pub max_connection_lifetime_secs: Option<u64>,
+    pub buffer_size_kb: usize,
+    pub tcp_nodelay: bool,
+    pub nodelay_switch: NodelaySwitch,
+    pub tcp_fast_open: bool,
+    pub tcp_user_timeout_ms: Option<u64>,
+    pub fallback_delay_ms: u64,
+    pub connect_deadline_ms: u64,
+    pub abort_on_target_reset: bool,
+    pub reset_on_protocol_violation: bool,
+    pub idle_timeout_secs: Option<u64>,
+    pub first_byte_timeout_secs: Option<u64>,
+    pub idle_keepalive_secs: Option<u64>,
+    pub half_close: bool,
+    pub prefetch_target: bool,
+    pub buffer_pool: bool,
+    pub max_buffer_memory: Option<usize>,
+    pub max_bytes_per_connection: Option<u64>,
+    pub max_connections_per_target: Option<usize>,
+    pub allow_ports: Vec<String>,
+    pub client_allow: Vec<String>,
+    pub client_deny: Vec<String>,
+    pub unmap_ipv4_clients: bool,
+    pub access_log_sample_rate: u64,
+    pub log_accepted: bool,
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    pub dscp: Option<u8>,
+    pub no_dns: bool,
+    pub host_map: Vec<String>,
+    pub max_concurrent_resolutions: Option<usize>,
+    pub max_domain_len: u8,
+    pub dns_retries: u32,
+    pub dns_retry_backoff_ms: u64,
+    pub resolve_family: ResolveFamily,
+    pub bind_port_range: Option<String>,
+    pub max_bind_listeners: Option<usize>,
+    pub bind_accept_timeout_secs: u64,
+    pub enable_bind: bool,
+    pub lenient_reserved: bool,
+    pub ipv6_targets: Ipv6TargetPolicy,
+    pub auth_methods: String,
+    pub method_priority: String,
+    pub greeting_policy: GreetingPolicy,
+    pub auth_failure_jitter_ms: u64,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub test_echo_target: Option<SocketAddr>,
+    pub register_url: Option<String>,
+    pub otlp_endpoint: Option<String>,
+    pub connect_webhook: Option<String>,
+    pub diagnostics_command: bool,
+    pub debug_logging: bool,
+    pub log_target: LogTarget,
+    pub log_format: LogFormat,
+    pub syslog_addr: Option<SocketAddr>,
+}
 
-    pub fn tracing_level(&self) -> tracing::Level {
-        if self.verbose {
-            tracing::Level::DEBUG
-        } else {
-            tracing::Level::INFO
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Rhoxy SOCKS5 Proxy Configuration:")?;
+        writeln!(f, "   Server Address:      {}", self.server_address)?;
+        writeln!(f, "   Max Connections:     {}", self.max_connections)?;
+        writeln!(f, "   Watermark:           {}%", self.connection_watermark)?;
+        if let Some(limit) = self.max_pending_handshakes {
+            writeln!(f, "   Max Handshakes:      {}", limit)?;
         }
-    }
-
-    pub fn validate(&self) -> Result<(), String> {
-        if self.port == 0 {
-            return Err("Port cannot be 0".to_string());
+        if let Some(limit) = self.max_accepts_in_flight {
+            writeln!(f, "   Accepts In Flight:   {}", limit)?;
         }
-
-        if self.max_connections == 0 {
-            return Err("Max connections must be greater than 0".to_string());
+        if let Some(limit) = self.max_handshakes_per_ip {
+            writeln!(f, "   Handshakes Per IP:   {}", limit)?;
         }
-
-        if self.buffer_size == 0 {
-            return Err("Buffer size must be greater than 0".to_string());
+        writeln!(
+            f,
+            "   Handshake Timeout:   {}s",
+            self.handshake_timeout_secs
+        )?;
+        if let Some(secs) = self.handshake_write_timeout_secs {
+            writeln!(f, "   Handshake Write:     {}s", secs)?;
         }
-
-        if self.buffer_size > 1024 {
-            return Err("Buffer size cannot exceed 1024 KB".to_string());
+        writeln!(
+            f,
+            "   Connection Timeout:  {}s",
+            self.connection_timeout_secs
+        )?;
+        writeln!(f, "   Shutdown Timeout:    {}s", self.shutdown_timeout_secs)?;
+        if let Some(secs) = self.max_connection_lifetime_secs {
+            writeln!(f, "   Max Lifetime:        {}s", secs)?;
         }
-
-        if self.shutdown_timeout <= 0 {
-            return Err("Shutdown timeout must be greater than 0".to_string());
+        writeln!(f, "   Buffer Size:         {}KB", self.buffer_size_kb)?;
+        writeln!(f, "   TCP_NODELAY:         {}", self.tcp_nodelay)?;
+        if self.nodelay_switch != NodelaySwitch::Off {
+            writeln!(f, "   Nodelay Switch:      {:?}", self.nodelay_switch)?;
         }
-
-        let methods = self.supported_auth_methods();
-        if methods.is_empty() {
-            return Err("At least one authentication method must be supported".to_string());
+        if self.tcp_fast_open {
+            writeln!(f, "   TCP Fast Open:       enabled")?;
         }
-
-        Ok(())
-    }
-
-    pub fn display_summary(&self) {
-        println!("Rhoxy SOCKS5 Proxy Configuration:");
-        println!("   Server Address:      {}:{}", self.host, self.port);
-        println!("   Max Connections:     {}", self.max_connections);
-        println!("   Handshake Timeout:  {}s", self.handshake_timeout);
-        println!("   Connection Timeout:  {}s", self.connection_timeout);
-        println!("   Buffer Size:         {}KB", self.buffer_size);
-        println!("   TCP_NODELAY:         {}", self.tcp_nodelay);
-        println!("   Auth Methods:        {}", self.auth_methods);
-        println!("   Debug Logging:       {}", self.verbose);
+        if let Some(ms) = self.tcp_user_timeout_ms {
+            writeln!(f, "   TCP User Timeout:    {}ms", ms)?;
+        }
+        writeln!(f, "   Fallback Delay:      {}ms", self.fallback_delay_ms)?;
+        writeln!(f, "   Connect Deadline:    {}ms", self.connect_deadline_ms)?;
+        if let Some(size) = self.so_rcvbuf {
+            writeln!(f, "   SO_RCVBUF:           {}", size)?;
+        }
+        if let Some(size) = self.so_sndbuf {
+            writeln!(f, "   SO_SNDBUF:           {}", size)?;
+        }
+        if let Some(dscp) = self.dscp {
+            writeln!(f, "   DSCP:                {}", dscp)?;
+        }
+        writeln!(f, "   Abort On Reset:      {}", self.abort_on_target_reset)?;
+        if self.reset_on_protocol_violation {
+            writeln!(f, "   Violation Reset:     enabled")?;
+        }
+        if let Some(secs) = self.idle_timeout_secs {
+            writeln!(f, "   Idle Timeout:        {}s", secs)?;
+        }
+        if let Some(secs) = self.first_byte_timeout_secs {
+            writeln!(f, "   First Byte Timeout:  {}s", secs)?;
+        }
+        if let Some(secs) = self.idle_keepalive_secs {
+            writeln!(f, "   Idle Keepalive:      {}s", secs)?;
+        }
+        writeln!(f, "   Half Close:          {}", self.half_close)?;
+        writeln!(f, "   Prefetch Target:     {}", self.prefetch_target)?;
+        if self.buffer_pool {
+            writeln!(f, "   Buffer Pool:         enabled")?;
+        }
+        if let Some(kb) = self.max_buffer_memory {
+            writeln!(f, "   Buffer Memory:       {}KB", kb)?;
+        }
+        if let Some(max_bytes) = self.max_bytes_per_connection {
+            writeln!(f, "   Byte Quota:          {}", max_bytes)?;
+        }
+        if let Some(limit) = self.max_connections_per_target {
+            writeln!(f, "   Per-Target Limit:    {}", limit)?;
+        }
+        if !self.allow_ports.is_empty() {
+            writeln!(f, "   Allowed Ports:       {}", self.allow_ports.join(","))?;
+        }
+        if !self.client_allow.is_empty() {
+            writeln!(f, "   Client Allow:        {}", self.client_allow.join(","))?;
+        }
+        if !self.client_deny.is_empty() {
+            writeln!(f, "   Client Deny:         {}", self.client_deny.join(","))?;
+        }
+        if self.unmap_ipv4_clients {
+            writeln!(f, "   Unmap IPv4 Clients:  enabled")?;
+        }
+        if self.access_log_sample_rate > 0 {
+            writeln!(
+                f,
+                "   Access Log Sampling: 1/{}",
+                self.access_log_sample_rate
+            )?;
+        }
+        if self.log_accepted {
+            writeln!(f, "   Log Accepted:        enabled")?;
+        }
+        if self.no_dns {
+            writeln!(f, "   DNS Resolution:      disabled")?;
+        } else if self.resolve_family != ResolveFamily::Any {
+            writeln!(f, "   Resolve Family:      {:?}", self.resolve_family)?;
+        }
+        if !self.host_map.is_empty() {
+            writeln!(f, "   Host Map:            {}", self.host_map.join(","))?;
+        }
+        if let Some(limit) = self.max_concurrent_resolutions {
+            writeln!(f, "   DNS Concurrency:     {}", limit)?;
+        }
+        if self.max_domain_len != MAX_DOMAIN_LEN {
+            writeln!(f, "   Max Domain Length:   {}", self.max_domain_len)?;
+        }
+        if self.dns_retries > 0 {
+            writeln!(
+                f,
+                "   DNS Retries:         {} (backoff {}ms)",
+                self.dns_retries, self.dns_retry_backoff_ms
+            )?;
+        }
+        if let Some(range) = &self.bind_port_range {
+            writeln!(f, "   BIND Port Range:     {}", range)?;
+        }
+        if let Some(limit) = self.max_bind_listeners {
+            writeln!(f, "   BIND Listeners:      {}", limit)?;
+        }
+        writeln!(
+            f,
+            "   BIND Accept Timeout: {}s",
+            self.bind_accept_timeout_secs
+        )?;
+        if !self.enable_bind {
+            writeln!(f, "   BIND Command:        disabled")?;
+        }
+        if self.lenient_reserved {
+            writeln!(f, "   Request Reserved:    lenient")?;
+        }
+        writeln!(f, "   IPv6 Targets:        {:?}", self.ipv6_targets)?;
+        writeln!(f, "   Auth Methods:        {}", self.auth_methods)?;
+        writeln!(f, "   Method Priority:     {}", self.method_priority)?;
+        writeln!(f, "   Greeting Policy:     {:?}", self.greeting_policy)?;
+        if self.auth_failure_jitter_ms > 0 {
+            writeln!(
+                f,
+                "   Auth Failure Jitter: {}ms",
+                self.auth_failure_jitter_ms
+            )?;
+        }
+        if let Some(user) = &self.user {
+            writeln!(
+                f,
+                "   Run As:              {}:{}",
+                user,
+                self.group.as_deref().unwrap_or("-")
+            )?;
+        }
+        if let Some(addr) = self.test_echo_target {
+            writeln!(f, "   Test Echo Target:    {}", addr)?;
+        }
+        if let Some(url) = &self.register_url {
+            writeln!(f, "   Register URL:        {}", url)?;
+        }
+        if let Some(url) = &self.otlp_endpoint {
+            writeln!(f, "   OTLP Endpoint:       {}", url)?;
+        }
+        if let Some(url) = &self.connect_webhook {
+            writeln!(f, "   CONNECT Webhook:     {}", url)?;
+        }
+        if self.diagnostics_command {
+            writeln!(f, "   Diagnostics Command: enabled")?;
+        }
+        match (self.log_target, self.syslog_addr) {
+            (LogTarget::Stdout, _) => {}
+            (LogTarget::Syslog, Some(addr)) => {
+                writeln!(f, "   Log Target:          syslog ({})", addr)?
+            }
+            (LogTarget::Syslog, None) => writeln!(f, "   Log Target:          syslog (local)")?,
+        }
+        if self.log_format == LogFormat::Json {
+            writeln!(f, "   Log Format:          json")?;
+        }
+        write!(f, "   Debug Logging:       {}", self.debug_logging)
     }
 }
 
@@ -156,10 +1346,97 @@ impl ProxyConfig {
 pub struct ConnectionConfig {
     pub buffer_size: usize,
     pub tcp_nodelay: bool,
+    pub nodelay_switch: NodelaySwitch,
+    pub tcp_fast_open: bool,
+    pub tcp_user_timeout: Option<Duration>,
+    pub fallback_delay: Duration,
+    pub connect_deadline: Duration,
     pub shutdown_timeout: Duration,
     pub handshake_timeout: Duration,
+    // Bounds each handshake reply write, within the overall handshake timeout
+    pub handshake_write_timeout: Option<Duration>,
     pub connection_timeout: Duration,
+    pub max_connection_lifetime: Option<Duration>,
     pub supported_auth_methods: Vec<u8>,
+    pub method_priority: Vec<u8>,
+    pub greeting_policy: GreetingPolicy,
+    pub auth_failure_jitter: Duration,
+    pub abort_on_target_reset: bool,
+    // Close with RST rather than FIN when the handshake or request is malformed
+    pub reset_on_protocol_violation: bool,
+    pub idle_timeout: Option<Duration>,
+    pub first_byte_timeout: Option<Duration>,
+    // Target keepalive is only switched on after this much relay idle time
+    pub idle_keepalive: Option<Duration>,
+    pub half_close: bool,
+    pub prefetch_target: bool,
+    // Shared by every relay when --buffer-pool is set
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    // Shared by every relay when --max-buffer-memory is set
+    pub buffer_budget: Option<Arc<BufferBudget>>,
+    pub max_bytes_per_connection: Option<u64>,
+    // Shared by every connection when --max-connections-per-target is set
+    pub target_limiter: Option<Arc<TargetLimiter>>,
+    // Destination ports CONNECT may reach, any port when empty
+    pub allow_ports: Vec<PortRange>,
+    pub so_rcvbuf: Option<usize>,
+    pub so_sndbuf: Option<usize>,
+    pub dscp: Option<u8>,
+    pub no_dns: bool,
+    // Domains dialed at a fixed address, set when --map is given
+    pub host_map: Option<Arc<HostMap>>,
+    // Permits for lookups in flight, when --max-concurrent-resolutions is set
+    pub dns_slots: Option<Arc<Semaphore>>,
+    pub max_domain_len: u8,
+    pub dns_retry: DnsRetry,
+    pub resolve_family: ResolveFamily,
+    pub bind_port_range: Option<PortRange>,
+    // Permits for open BIND listeners, when --max-bind-listeners is set
+    pub bind_slots: Option<Arc<Semaphore>>,
+    // How long a BIND waits for its peer once the first reply is out
+    pub bind_accept_timeout: Duration,
+    pub enable_bind: bool,
+    pub lenient_reserved: bool,
+    pub diagnostics_command: bool,
+    pub log_accepted: bool,
+    // Cleared when --ipv6-targets rejects them, or the startup probe finds no route
+    pub ipv6_available: bool,
+    pub metrics: Arc<Metrics>,
+    pub interceptor: Option<Arc<dyn ConnectionInterceptor>>,
+    pub event_sink: Option<Arc<dyn EventSink>>,
+}
+
+impl ConnectionConfig {
+    // Family to resolve domain names to, or None when they are refused
+    pub fn domain_resolution(&self) -> Option<ResolveFamily> {
+        (!self.no_dns).then_some(self.resolve_family)
+    }
+
+    /// Commands and auth methods clients can actually use with this config.
+    pub fn capabilities(&self) -> Capabilities {
+        let commands = Command::SERVED
+            .iter()
+            .copied()
+            .filter(|command| command.is_enabled(self))
+            .collect();
+        Capabilities {
+            commands,
+            auth_methods: self.supported_auth_methods.clone(),
+        }
+    }
+}
+
+// What the proxy offers, for health and admin endpoints of embedders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub commands: Vec<Command>,
+    pub auth_methods: Vec<u8>,
+}
+
+impl Capabilities {
+    pub fn supports(&self, command: Command) -> bool {
+        self.commands.contains(&command)
+    }
 }
 
 impl From<&ProxyConfig> for ConnectionConfig {
@@ -167,10 +1444,70 @@ impl From<&ProxyConfig> for ConnectionConfig {
         Self {
             buffer_size: config.buffer_size_bytes(),
             tcp_nodelay: config.tcp_nodelay,
+            nodelay_switch: config.nodelay_switch,
+            tcp_fast_open: config.tfo,
+            tcp_user_timeout: config.tcp_user_timeout_ms.map(Duration::from_millis),
+            fallback_delay: Duration::from_millis(config.fallback_delay_ms),
+            connect_deadline: Duration::from_millis(config.connect_deadline_ms),
             shutdown_timeout: Duration::from_secs(config.shutdown_timeout),
             handshake_timeout: Duration::from_secs(config.handshake_timeout),
+            handshake_write_timeout: config.handshake_write_timeout.map(Duration::from_secs),
             connection_timeout: Duration::from_secs(config.connection_timeout),
+            max_connection_lifetime: config.max_connection_lifetime.map(Duration::from_secs),
             supported_auth_methods: config.supported_auth_methods(),
+            method_priority: config.method_priority(),
+            greeting_policy: config.greeting_policy,
+            auth_failure_jitter: Duration::from_millis(config.auth_failure_jitter_ms),
+            abort_on_target_reset: config.abort_on_target_reset,
+            reset_on_protocol_violation: config.reset_on_protocol_violation,
+            idle_timeout: config.idle_timeout.map(Duration::from_secs),
+            first_byte_timeout: config.first_byte_timeout.map(Duration::from_secs),
+            idle_keepalive: config.idle_keepalive.map(Duration::from_secs),
+            half_close: config.half_close,
+            prefetch_target: config.prefetch_target,
+            // Two buffers per relay, so idle ones never exceed the peak in use
+            buffer_pool: config.buffer_pool.then(|| {
+                Arc::new(BufferPool::new(
+                    config.buffer_size_bytes(),
+                    config.max_connections.saturating_mul(2),
+                ))
+            }),
+            buffer_budget: config
+                .max_buffer_memory
+                .map(|kb| Arc::new(BufferBudget::new(kb.saturating_mul(1024)))),
+            max_bytes_per_connection: config.max_bytes_per_connection,
+            target_limiter: config
+                .max_connections_per_target
+                .map(|limit| Arc::new(TargetLimiter::new(limit))),
+            allow_ports: config.allow_ports.clone(),
+            so_rcvbuf: config.so_rcvbuf,
+            so_sndbuf: config.so_sndbuf,
+            dscp: config.dscp,
+            no_dns: config.no_dns,
+            host_map: (!config.host_map.is_empty())
+                .then(|| Arc::new(HostMap::new(&config.host_map))),
+            dns_slots: config
+                .max_concurrent_resolutions
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            max_domain_len: config.max_domain_len,
+            dns_retry: DnsRetry {
+                retries: config.dns_retries,
+                backoff: Duration::from_millis(config.dns_retry_backoff_ms),
+            },
+            resolve_family: config.resolve_family,
+            bind_port_range: config.bind_port_range,
+            bind_slots: config
+                .max_bind_listeners
+                .map(|limit| Arc::new(Semaphore::new(limit))),
+            bind_accept_timeout: Duration::from_secs(config.bind_accept_timeout),
+            enable_bind: config.enable_bind,
+            lenient_reserved: config.lenient_reserved,
+            diagnostics_command: config.diagnostics_command,
+            log_accepted: config.log_accepted,
+            ipv6_available: config.ipv6_targets != Ipv6TargetPolicy::Reject,
+            metrics: Arc::new(Metrics::default()),
+            interceptor: None,
+            event_sink: None,
         }
     }
 }
@@ -185,13 +1522,74 @@ mod tests {
             host: "localhost".to_string(),
             port: 1080,
             verbose: false,
+            log_target: LogTarget::Stdout,
+            log_format: LogFormat::Text,
+            syslog_addr: None,
             max_connections: 1000,
+            connection_watermark: 90,
+            max_pending_handshakes: None,
+            max_accepts_in_flight: None,
+            max_handshakes_per_ip: None,
             shutdown_timeout: 10,
+            max_connection_lifetime: None,
             handshake_timeout: 30,
+            handshake_write_timeout: None,
             connection_timeout: 30,
             buffer_size: 32,
+            clamp_buffer_size: false,
             tcp_nodelay: true,
+            nodelay_switch: NodelaySwitch::Off,
+            tfo: false,
+            tcp_user_timeout_ms: None,
+            fallback_delay_ms: 250,
+            connect_deadline_ms: 10_000,
             auth_methods: "none".to_string(),
+            method_priority: "none,userpass,gssapi".to_string(),
+            greeting_policy: GreetingPolicy::Warn,
+            auth_failure_jitter_ms: 0,
+            user: None,
+            group: None,
+            test_echo_target: None,
+            register_url: None,
+            register_interval: 30,
+            otlp_endpoint: None,
+            otlp_interval: 30,
+            connect_webhook: None,
+            max_webhooks_in_flight: 8,
+            diagnostics_command: false,
+            abort_on_target_reset: false,
+            reset_on_protocol_violation: false,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            idle_keepalive: None,
+            half_close: false,
+            prefetch_target: false,
+            buffer_pool: false,
+            max_buffer_memory: None,
+            max_bytes_per_connection: None,
+            max_connections_per_target: None,
+            allow_ports: vec![],
+            client_allow: vec![],
+            client_deny: vec![],
+            unmap_ipv4_clients: false,
+            access_log_sample_rate: 0,
+            log_accepted: false,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            no_dns: false,
+            host_map: vec![],
+            max_concurrent_resolutions: None,
+            max_domain_len: 255,
+            dns_retries: 0,
+            dns_retry_backoff_ms: 100,
+            resolve_family: ResolveFamily::Any,
+            bind_port_range: None,
+            max_bind_listeners: None,
+            bind_accept_timeout: 30,
+            enable_bind: true,
+            lenient_reserved: false,
+            ipv6_targets: Ipv6TargetPolicy::Auto,
         };
 
         assert!(config.validate().is_ok());
@@ -203,16 +1601,77 @@ mod tests {
             host: "localhost".to_string(),
             port: 0,
             verbose: false,
+            log_target: LogTarget::Stdout,
+            log_format: LogFormat::Text,
+            syslog_addr: None,
             max_connections: 1000,
+            connection_watermark: 90,
+            max_pending_handshakes: None,
+            max_accepts_in_flight: None,
+            max_handshakes_per_ip: None,
             shutdown_timeout: 10,
+            max_connection_lifetime: None,
             handshake_timeout: 30,
+            handshake_write_timeout: None,
             connection_timeout: 30,
             buffer_size: 32,
+            clamp_buffer_size: false,
             tcp_nodelay: true,
+            nodelay_switch: NodelaySwitch::Off,
+            tfo: false,
+            tcp_user_timeout_ms: None,
+            fallback_delay_ms: 250,
+            connect_deadline_ms: 10_000,
             auth_methods: "none".to_string(),
+            method_priority: "none,userpass,gssapi".to_string(),
+            greeting_policy: GreetingPolicy::Warn,
+            auth_failure_jitter_ms: 0,
+            user: None,
+            group: None,
+            test_echo_target: None,
+            register_url: None,
+            register_interval: 30,
+            otlp_endpoint: None,
+            otlp_interval: 30,
+            connect_webhook: None,
+            max_webhooks_in_flight: 8,
+            diagnostics_command: false,
+            abort_on_target_reset: false,
+            reset_on_protocol_violation: false,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            idle_keepalive: None,
+            half_close: false,
+            prefetch_target: false,
+            buffer_pool: false,
+            max_buffer_memory: None,
+            max_bytes_per_connection: None,
+            max_connections_per_target: None,
+            allow_ports: vec![],
+            client_allow: vec![],
+            client_deny: vec![],
+            unmap_ipv4_clients: false,
+            access_log_sample_rate: 0,
+            log_accepted: false,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            no_dns: false,
+            host_map: vec![],
+            max_concurrent_resolutions: None,
+            max_domain_len: 255,
+            dns_retries: 0,
+            dns_retry_backoff_ms: 100,
+            resolve_family: ResolveFamily::Any,
+            bind_port_range: None,
+            max_bind_listeners: None,
+            bind_accept_timeout: 30,
+            enable_bind: true,
+            lenient_reserved: false,
+            ipv6_targets: Ipv6TargetPolicy::Auto,
         };
 
-        assert!(config.validate().is_err());
+        assert_eq!(config.validate(), Err(ConfigError::InvalidPort));
     }
 
     #[test]
@@ -221,13 +1680,74 @@ mod tests {
             host: "localhost".to_string(),
             port: 1080,
             verbose: false,
+            log_target: LogTarget::Stdout,
+            log_format: LogFormat::Text,
+            syslog_addr: None,
             max_connections: 1000,
+            connection_watermark: 90,
+            max_pending_handshakes: None,
+            max_accepts_in_flight: None,
+            max_handshakes_per_ip: None,
             shutdown_timeout: 10,
+            max_connection_lifetime: None,
             handshake_timeout: 30,
+            handshake_write_timeout: None,
             connection_timeout: 30,
             buffer_size: 32,
+            clamp_buffer_size: false,
             tcp_nodelay: true,
+            nodelay_switch: NodelaySwitch::Off,
+            tfo: false,
+            tcp_user_timeout_ms: None,
+            fallback_delay_ms: 250,
+            connect_deadline_ms: 10_000,
             auth_methods: "none".to_string(),
+            method_priority: "none,userpass,gssapi".to_string(),
+            greeting_policy: GreetingPolicy::Warn,
+            auth_failure_jitter_ms: 0,
+            user: None,
+            group: None,
+            test_echo_target: None,
+            register_url: None,
+            register_interval: 30,
+            otlp_endpoint: None,
+            otlp_interval: 30,
+            connect_webhook: None,
+            max_webhooks_in_flight: 8,
+            diagnostics_command: false,
+            abort_on_target_reset: false,
+            reset_on_protocol_violation: false,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            idle_keepalive: None,
+            half_close: false,
+            prefetch_target: false,
+            buffer_pool: false,
+            max_buffer_memory: None,
+            max_bytes_per_connection: None,
+            max_connections_per_target: None,
+            allow_ports: vec![],
+            client_allow: vec![],
+            client_deny: vec![],
+            unmap_ipv4_clients: false,
+            access_log_sample_rate: 0,
+            log_accepted: false,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            no_dns: false,
+            host_map: vec![],
+            max_concurrent_resolutions: None,
+            max_domain_len: 255,
+            dns_retries: 0,
+            dns_retry_backoff_ms: 100,
+            resolve_family: ResolveFamily::Any,
+            bind_port_range: None,
+            max_bind_listeners: None,
+            bind_accept_timeout: 30,
+            enable_bind: true,
+            lenient_reserved: false,
+            ipv6_targets: Ipv6TargetPolicy::Auto,
         };
 
         let methods = config.supported_auth_methods();
@@ -240,13 +1760,74 @@ mod tests {
             host: "localhost".to_string(),
             port: 1080,
             verbose: false,
+            log_target: LogTarget::Stdout,
+            log_format: LogFormat::Text,
+            syslog_addr: None,
             max_connections: 1000,
+            connection_watermark: 90,
+            max_pending_handshakes: None,
+            max_accepts_in_flight: None,
+            max_handshakes_per_ip: None,
             shutdown_timeout: 10,
+            max_connection_lifetime: None,
             handshake_timeout: 30,
+            handshake_write_timeout: None,
             connection_timeout: 30,
             buffer_size: 32,
+            clamp_buffer_size: false,
             tcp_nodelay: true,
+            nodelay_switch: NodelaySwitch::Off,
+            tfo: false,
+            tcp_user_timeout_ms: None,
+            fallback_delay_ms: 250,
+            connect_deadline_ms: 10_000,
             auth_methods: "none".to_string(),
+            method_priority: "none,userpass,gssapi".to_string(),
+            greeting_policy: GreetingPolicy::Warn,
+            auth_failure_jitter_ms: 0,
+            user: None,
+            group: None,
+            test_echo_target: None,
+            register_url: None,
+            register_interval: 30,
+            otlp_endpoint: None,
+            otlp_interval: 30,
+            connect_webhook: None,
+            max_webhooks_in_flight: 8,
+            diagnostics_command: false,
+            abort_on_target_reset: false,
+            reset_on_protocol_violation: false,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            idle_keepalive: None,
+            half_close: false,
+            prefetch_target: false,
+            buffer_pool: false,
+            max_buffer_memory: None,
+            max_bytes_per_connection: None,
+            max_connections_per_target: None,
+            allow_ports: vec![],
+            client_allow: vec![],
+            client_deny: vec![],
+            unmap_ipv4_clients: false,
+            access_log_sample_rate: 0,
+            log_accepted: false,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            no_dns: false,
+            host_map: vec![],
+            max_concurrent_resolutions: None,
+            max_domain_len: 255,
+            dns_retries: 0,
+            dns_retry_backoff_ms: 100,
+            resolve_family: ResolveFamily::Any,
+            bind_port_range: None,
+            max_bind_listeners: None,
+            bind_accept_timeout: 30,
+            enable_bind: true,
+            lenient_reserved: false,
+            ipv6_targets: Ipv6TargetPolicy::Auto,
         };
 
         let conn_config = ConnectionConfig::from(&proxy_config);
@@ -261,16 +1842,881 @@ mod tests {
             host: "127.0.0.1".to_string(),
             port: 8080,
             verbose: false,
+            log_target: LogTarget::Stdout,
+            log_format: LogFormat::Text,
+            syslog_addr: None,
             max_connections: 1000,
+            connection_watermark: 90,
+            max_pending_handshakes: None,
+            max_accepts_in_flight: None,
+            max_handshakes_per_ip: None,
             shutdown_timeout: 10,
+            max_connection_lifetime: None,
             handshake_timeout: 30,
+            handshake_write_timeout: None,
             connection_timeout: 30,
             buffer_size: 32,
+            clamp_buffer_size: false,
             tcp_nodelay: true,
+            nodelay_switch: NodelaySwitch::Off,
+            tfo: false,
+            tcp_user_timeout_ms: None,
+            fallback_delay_ms: 250,
+            connect_deadline_ms: 10_000,
             auth_methods: "none".to_string(),
+            method_priority: "none,userpass,gssapi".to_string(),
+            greeting_policy: GreetingPolicy::Warn,
+            auth_failure_jitter_ms: 0,
+            user: None,
+            group: None,
+            test_echo_target: None,
+            register_url: None,
+            register_interval: 30,
+            otlp_endpoint: None,
+            otlp_interval: 30,
+            connect_webhook: None,
+            max_webhooks_in_flight: 8,
+            diagnostics_command: false,
+            abort_on_target_reset: false,
+            reset_on_protocol_violation: false,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            idle_keepalive: None,
+            half_close: false,
+            prefetch_target: false,
+            buffer_pool: false,
+            max_buffer_memory: None,
+            max_bytes_per_connection: None,
+            max_connections_per_target: None,
+            allow_ports: vec![],
+            client_allow: vec![],
+            client_deny: vec![],
+            unmap_ipv4_clients: false,
+            access_log_sample_rate: 0,
+            log_accepted: false,
+            so_rcvbuf: None,
+            so_sndbuf: None,
+            dscp: None,
+            no_dns: false,
+            host_map: vec![],
+            max_concurrent_resolutions: None,
+            max_domain_len: 255,
+            dns_retries: 0,
+            dns_retry_backoff_ms: 100,
+            resolve_family: ResolveFamily::Any,
+            bind_port_range: None,
+            max_bind_listeners: None,
+            bind_accept_timeout: 30,
+            enable_bind: true,
+            lenient_reserved: false,
+            ipv6_targets: Ipv6TargetPolicy::Auto,
         };
 
         let addr = config.server_addr().unwrap();
         assert_eq!(addr.port(), 8080);
     }
+
+    #[test]
+    fn test_group_requires_user() {
+        let mut config = ProxyConfig::parse_from(["rhoxy-socks", "--group", "nogroup"]);
+        assert_eq!(config.group.as_deref(), Some("nogroup"));
+        assert_eq!(config.validate(), Err(ConfigError::GroupWithoutUser));
+
+        config.user = Some("nobody".to_string());
+        assert_eq!(config.validate().is_ok(), cfg!(target_os = "linux"));
+    }
+
+    #[test]
+    fn test_summary_matches_config() {
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--host",
+            "127.0.0.1",
+            "--port",
+            "9050",
+            "--max-connections",
+            "42",
+            "--buffer-size",
+            "64",
+            "--user",
+            "nobody",
+        ]);
+
+        let summary = config.summary();
+        assert_eq!(summary.server_address, "127.0.0.1:9050");
+        assert_eq!(summary.max_connections, 42);
+        assert_eq!(summary.handshake_timeout_secs, config.handshake_timeout);
+        assert_eq!(summary.connection_timeout_secs, config.connection_timeout);
+        assert_eq!(summary.shutdown_timeout_secs, config.shutdown_timeout);
+        assert_eq!(summary.buffer_size_kb, 64);
+        assert_eq!(summary.tcp_nodelay, config.tcp_nodelay);
+        assert_eq!(summary.auth_methods, "none");
+        assert_eq!(summary.greeting_policy, GreetingPolicy::Warn);
+        assert_eq!(summary.user.as_deref(), Some("nobody"));
+        assert_eq!(summary.group, None);
+        assert!(!summary.debug_logging);
+
+        let text = summary.to_string();
+        assert!(text.starts_with("Rhoxy SOCKS5 Proxy Configuration:"));
+        assert!(text.contains("127.0.0.1:9050"));
+        assert!(text.contains("Run As:              nobody:-"));
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["server_address"], "127.0.0.1:9050");
+        assert_eq!(json["max_connections"], 42);
+        assert_eq!(json["greeting_policy"], "warn");
+    }
+
+    #[test]
+    fn test_auth_failure_jitter() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--auth-failure-jitter-ms", "250"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(
+            ConnectionConfig::from(&config).auth_failure_jitter,
+            Duration::from_millis(250)
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--auth-failure-jitter-ms", "60000"]);
+        assert_eq!(
+            config.validate(),
+            Err(ConfigError::AuthFailureJitterTooLarge)
+        );
+    }
+
+    #[test]
+    fn test_bind_port_range_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--bind-port-range", "40000-41000"]);
+        let range = ConnectionConfig::from(&config).bind_port_range.unwrap();
+        assert!(range.contains(40500));
+        assert!(!range.contains(39999));
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("BIND Port Range:     40000-41000")
+        );
+
+        assert!(
+            ProxyConfig::try_parse_from(["rhoxy-socks", "--bind-port-range", "41000-40000"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_max_pending_handshakes() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-pending-handshakes", "50"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.summary().max_pending_handshakes, Some(50));
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-pending-handshakes", "0"]);
+        assert_eq!(config.validate(), Err(ConfigError::NoMaxPendingHandshakes));
+    }
+
+    #[test]
+    fn test_max_accepts_in_flight() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-accepts-in-flight", "16"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.summary().max_accepts_in_flight, Some(16));
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Accepts In Flight:   16")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-accepts-in-flight", "0"]);
+        assert_eq!(config.validate(), Err(ConfigError::NoMaxAcceptsInFlight));
+    }
+
+    #[test]
+    fn test_max_handshakes_per_ip() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-handshakes-per-ip", "4"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(config.summary().max_handshakes_per_ip, Some(4));
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Handshakes Per IP:   4")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-handshakes-per-ip", "0"]);
+        assert_eq!(config.validate(), Err(ConfigError::NoMaxHandshakesPerIp));
+    }
+
+    #[test]
+    fn test_validation_errors_name_the_field() {
+        let cases: [(&[&str], ConfigError, &str); 29] = [
+            (&["--port", "0"], ConfigError::InvalidPort, "port"),
+            (
+                &["--max-connections", "0"],
+                ConfigError::NoMaxConnections,
+                "max_connections",
+            ),
+            (
+                &["--buffer-size", "0"],
+                ConfigError::BufferSizeZero,
+                "buffer_size",
+            ),
+            (
+                &["--buffer-size", "2048"],
+                ConfigError::BufferSizeTooLarge,
+                "buffer_size",
+            ),
+            (
+                &["--shutdown-timeout", "0"],
+                ConfigError::NoShutdownTimeout,
+                "shutdown_timeout",
+            ),
+            (
+                &["--max-connection-lifetime", "0"],
+                ConfigError::NoMaxConnectionLifetime,
+                "max_connection_lifetime",
+            ),
+            (
+                &["--method-priority", "none,bogus"],
+                ConfigError::UnknownPriorityMethod,
+                "method_priority",
+            ),
+            (
+                &["--auth-failure-jitter-ms", "10001"],
+                ConfigError::AuthFailureJitterTooLarge,
+                "auth_failure_jitter_ms",
+            ),
+            (
+                &["--max-bytes-per-connection", "0"],
+                ConfigError::NoMaxBytesPerConnection,
+                "max_bytes_per_connection",
+            ),
+            (
+                &["--so-rcvbuf", "1"],
+                ConfigError::ReceiveBufferOutOfRange,
+                "so_rcvbuf",
+            ),
+            (&["--group", "0"], ConfigError::GroupWithoutUser, "group"),
+            (
+                &["--syslog-addr", "127.0.0.1:514"],
+                ConfigError::SyslogAddrWithoutSyslog,
+                "syslog_addr",
+            ),
+            (
+                &["--connection-watermark", "101"],
+                ConfigError::WatermarkOutOfRange,
+                "connection_watermark",
+            ),
+            (
+                &["--handshake-write-timeout", "0"],
+                ConfigError::NoHandshakeWriteTimeout,
+                "handshake_write_timeout",
+            ),
+            (
+                &["--idle-timeout", "0"],
+                ConfigError::NoIdleTimeout,
+                "idle_timeout",
+            ),
+            (
+                &["--first-byte-timeout", "0"],
+                ConfigError::NoFirstByteTimeout,
+                "first_byte_timeout",
+            ),
+            (
+                &["--idle-keepalive", "0"],
+                ConfigError::NoIdleKeepalive,
+                "idle_keepalive",
+            ),
+            (&["--dscp", "64"], ConfigError::DscpOutOfRange, "dscp"),
+            (
+                &["--connect-deadline-ms", "0"],
+                ConfigError::NoConnectDeadline,
+                "connect_deadline_ms",
+            ),
+            (
+                &["--tcp-user-timeout-ms", "0"],
+                ConfigError::TcpUserTimeoutOutOfRange,
+                "tcp_user_timeout_ms",
+            ),
+            (
+                &["--max-connections-per-target", "0"],
+                ConfigError::NoMaxConnectionsPerTarget,
+                "max_connections_per_target",
+            ),
+            (
+                &["--register-interval", "0"],
+                ConfigError::NoRegisterInterval,
+                "register_interval",
+            ),
+            (
+                &["--max-webhooks-in-flight", "0"],
+                ConfigError::NoMaxWebhooksInFlight,
+                "max_webhooks_in_flight",
+            ),
+            (
+                &["--max-handshakes-per-ip", "0"],
+                ConfigError::NoMaxHandshakesPerIp,
+                "max_handshakes_per_ip",
+            ),
+            (
+                &["--max-concurrent-resolutions", "0"],
+                ConfigError::NoMaxConcurrentResolutions,
+                "max_concurrent_resolutions",
+            ),
+            (
+                &["--max-domain-len", "0"],
+                ConfigError::NoMaxDomainLen,
+                "max_domain_len",
+            ),
+            (
+                &["--max-bind-listeners", "0"],
+                ConfigError::NoMaxBindListeners,
+                "max_bind_listeners",
+            ),
+            (
+                &["--bind-accept-timeout", "0"],
+                ConfigError::NoBindAcceptTimeout,
+                "bind_accept_timeout",
+            ),
+            (
+                &["--max-buffer-memory", "1"],
+                ConfigError::BufferMemoryTooSmall,
+                "max_buffer_memory",
+            ),
+        ];
+
+        for (args, expected, field) in cases {
+            let config =
+                ProxyConfig::parse_from(std::iter::once("rhoxy-socks").chain(args.iter().copied()));
+            let err = config.validate().unwrap_err();
+            assert_eq!(err, expected, "{:?}", args);
+            assert_eq!(err.field(), field);
+        }
+    }
+
+    #[test]
+    fn test_validation_error_messages_unchanged() {
+        assert_eq!(ConfigError::InvalidPort.to_string(), "Port cannot be 0");
+        assert_eq!(
+            ConfigError::BufferSizeTooLarge.to_string(),
+            "Buffer size cannot exceed 1024 KB"
+        );
+        assert_eq!(
+            ConfigError::SendBufferOutOfRange.to_string(),
+            "SO_SNDBUF must be between 1024 and 67108864 bytes"
+        );
+        assert_eq!(
+            ConfigError::GroupWithoutUser.to_string(),
+            "--group requires --user"
+        );
+    }
+
+    #[test]
+    fn test_tfo_flag() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--tfo"]);
+        assert!(config.tfo);
+        assert!(ConnectionConfig::from(&config).tcp_fast_open);
+        assert_eq!(config.validate().is_ok(), cfg!(target_os = "linux"));
+        assert!(config.summary().tcp_fast_open);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(!ConnectionConfig::from(&config).tcp_fast_open);
+    }
+
+    #[test]
+    fn test_no_dns_flag() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--no-dns"]);
+        assert!(config.no_dns);
+        assert!(ConnectionConfig::from(&config).no_dns);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("DNS Resolution:      disabled")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(!ConnectionConfig::from(&config).no_dns);
+    }
+
+    #[test]
+    fn test_log_target_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(config.log_target, LogTarget::Stdout);
+        assert!(!config.summary().to_string().contains("Log Target"));
+
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--log-target",
+            "syslog",
+            "--syslog-addr",
+            "192.0.2.10:514",
+        ]);
+        assert_eq!(config.log_target, LogTarget::Syslog);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Log Target:          syslog (192.0.2.10:514)")
+        );
+        let json = serde_json::to_value(config.summary()).unwrap();
+        assert_eq!(json["log_target"], "syslog");
+    }
+
+    #[test]
+    fn test_log_format_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(config.log_format, LogFormat::Text);
+        assert!(!config.summary().to_string().contains("Log Format"));
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--log-format", "json"]);
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Log Format:          json")
+        );
+        assert!(ProxyConfig::try_parse_from(["rhoxy-socks", "--log-format", "xml"]).is_err());
+    }
+
+    #[test]
+    fn test_max_domain_len_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(ConnectionConfig::from(&config).max_domain_len, 255);
+        assert!(!config.summary().to_string().contains("Max Domain Length"));
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-domain-len", "253"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(ConnectionConfig::from(&config).max_domain_len, 253);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Max Domain Length:   253")
+        );
+        assert!(ProxyConfig::try_parse_from(["rhoxy-socks", "--max-domain-len", "256"]).is_err());
+    }
+
+    #[test]
+    fn test_dns_retry_options() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(ConnectionConfig::from(&config).dns_retry.retries, 0);
+        assert!(!config.summary().to_string().contains("DNS Retries"));
+
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--dns-retries",
+            "3",
+            "--dns-retry-backoff-ms",
+            "50",
+        ]);
+        assert_eq!(
+            ConnectionConfig::from(&config).dns_retry,
+            DnsRetry {
+                retries: 3,
+                backoff: Duration::from_millis(50),
+            }
+        );
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("DNS Retries:         3 (backoff 50ms)")
+        );
+    }
+
+    #[test]
+    fn test_max_concurrent_resolutions_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(ConnectionConfig::from(&config).dns_slots.is_none());
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-concurrent-resolutions", "8"]);
+        assert!(config.validate().is_ok());
+        let slots = ConnectionConfig::from(&config).dns_slots.unwrap();
+        assert_eq!(slots.available_permits(), 8);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("DNS Concurrency:     8")
+        );
+    }
+
+    #[test]
+    fn test_socket_buffer_options() {
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--so-rcvbuf",
+            "262144",
+            "--so-sndbuf",
+            "131072",
+        ]);
+        assert_eq!(config.so_rcvbuf, Some(262144));
+        assert_eq!(config.so_sndbuf, Some(131072));
+        assert!(config.validate().is_ok());
+
+        let conn_config = ConnectionConfig::from(&config);
+        assert_eq!(conn_config.so_rcvbuf, Some(262144));
+        assert_eq!(conn_config.so_sndbuf, Some(131072));
+
+        let too_small = ProxyConfig::parse_from(["rhoxy-socks", "--so-rcvbuf", "16"]);
+        assert_eq!(
+            too_small.validate(),
+            Err(ConfigError::ReceiveBufferOutOfRange)
+        );
+
+        let too_large = ProxyConfig::parse_from(["rhoxy-socks", "--so-sndbuf", "1073741824"]);
+        assert_eq!(too_large.validate(), Err(ConfigError::SendBufferOutOfRange));
+    }
+
+    #[test]
+    fn test_dscp_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(ConnectionConfig::from(&config).dscp, None);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--dscp", "46"]);
+        assert!(config.validate().is_ok());
+        assert_eq!(ConnectionConfig::from(&config).dscp, Some(46));
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("DSCP:                46")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--dscp", "63"]);
+        assert!(config.validate().is_ok());
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--dscp", "64"]);
+        assert_eq!(config.validate(), Err(ConfigError::DscpOutOfRange));
+        assert!(ProxyConfig::try_parse_from(["rhoxy-socks", "--dscp", "256"]).is_err());
+    }
+
+    #[test]
+    fn test_method_priority_parsing() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(config.method_priority(), vec![0x00, 0x02, 0x01]);
+
+        let config =
+            ProxyConfig::parse_from(["rhoxy-socks", "--method-priority", "userpass, none,none"]);
+        assert_eq!(config.validate(), Ok(()));
+        assert_eq!(config.method_priority(), vec![0x02, 0x00]);
+        assert_eq!(
+            ConnectionConfig::from(&config).method_priority,
+            vec![0x02, 0x00]
+        );
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Method Priority:     userpass, none,none")
+        );
+    }
+
+    #[test]
+    fn test_unknown_priority_method_is_rejected() {
+        for priority in ["bogus", "none,bogus", "none,"] {
+            let config = ProxyConfig::parse_from(["rhoxy-socks", "--method-priority", priority]);
+            assert_eq!(
+                config.validate(),
+                Err(ConfigError::UnknownPriorityMethod),
+                "{priority}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_dial_timing_options() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        let connection_config = ConnectionConfig::from(&config);
+        assert_eq!(connection_config.fallback_delay, Duration::from_millis(250));
+        assert_eq!(connection_config.connect_deadline, Duration::from_secs(10));
+
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--fallback-delay-ms",
+            "50",
+            "--connect-deadline-ms",
+            "2000",
+        ]);
+        let connection_config = ConnectionConfig::from(&config);
+        assert_eq!(connection_config.fallback_delay, Duration::from_millis(50));
+        assert_eq!(connection_config.connect_deadline, Duration::from_secs(2));
+        let summary = config.summary().to_string();
+        assert!(summary.contains("Fallback Delay:      50ms"));
+        assert!(summary.contains("Connect Deadline:    2000ms"));
+    }
+
+    #[test]
+    fn test_relay_options() {
+        let config =
+            ProxyConfig::parse_from(["rhoxy-socks", "--idle-timeout", "30", "--half-close"]);
+        let connection_config = ConnectionConfig::from(&config);
+        assert_eq!(connection_config.first_byte_timeout, None);
+        assert_eq!(
+            connection_config.idle_timeout,
+            Some(Duration::from_secs(30))
+        );
+        assert!(connection_config.half_close);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Idle Timeout:        30s")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        let connection_config = ConnectionConfig::from(&config);
+        assert_eq!(connection_config.idle_timeout, None);
+        assert_eq!(connection_config.first_byte_timeout, None);
+        assert!(!connection_config.half_close);
+    }
+
+    #[test]
+    fn test_ipv6_targets_policy() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(config.ipv6_targets, Ipv6TargetPolicy::Auto);
+        // The startup probe is the server's job, the config alone assumes IPv6 works
+        assert!(ConnectionConfig::from(&config).ipv6_available);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--ipv6-targets", "reject"]);
+        assert!(!ConnectionConfig::from(&config).ipv6_available);
+        let json = serde_json::to_value(config.summary()).unwrap();
+        assert_eq!(json["ipv6_targets"], "reject");
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--ipv6-targets", "attempt"]);
+        assert!(ConnectionConfig::from(&config).ipv6_available);
+    }
+
+    #[test]
+    fn test_max_connection_lifetime() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-connection-lifetime", "3600"]);
+        assert_eq!(
+            ConnectionConfig::from(&config).max_connection_lifetime,
+            Some(Duration::from_secs(3600))
+        );
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Max Lifetime:        3600s")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(
+            ConnectionConfig::from(&config).max_connection_lifetime,
+            None
+        );
+    }
+
+    #[test]
+    fn test_resolve_family_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(
+            ConnectionConfig::from(&config).domain_resolution(),
+            Some(ResolveFamily::Any)
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--resolve-family", "v6"]);
+        assert_eq!(
+            ConnectionConfig::from(&config).domain_resolution(),
+            Some(ResolveFamily::V6)
+        );
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Resolve Family:      V6")
+        );
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--resolve-family", "v4", "--no-dns"]);
+        assert_eq!(ConnectionConfig::from(&config).domain_resolution(), None);
+    }
+
+    #[test]
+    fn test_lenient_reserved_flag() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(!ConnectionConfig::from(&config).lenient_reserved);
+        assert!(!config.summary().to_string().contains("Request Reserved"));
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--lenient-reserved"]);
+        assert!(ConnectionConfig::from(&config).lenient_reserved);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Request Reserved:    lenient")
+        );
+    }
+
+    #[test]
+    fn test_tcp_user_timeout_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert_eq!(ConnectionConfig::from(&config).tcp_user_timeout, None);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--tcp-user-timeout-ms", "30000"]);
+        assert_eq!(
+            ConnectionConfig::from(&config).tcp_user_timeout,
+            Some(Duration::from_secs(30))
+        );
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("TCP User Timeout:    30000ms")
+        );
+        #[cfg(target_os = "linux")]
+        assert!(config.validate().is_ok());
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--tcp-user-timeout-ms", "3600001"]);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            ConfigError::TcpUserTimeoutOutOfRange
+        );
+    }
+
+    #[test]
+    fn test_max_buffer_memory_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(ConnectionConfig::from(&config).buffer_budget.is_none());
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-buffer-memory", "512"]);
+        assert!(config.validate().is_ok());
+        let budget = ConnectionConfig::from(&config).buffer_budget.unwrap();
+        assert_eq!(budget.limit(), 512 * 1024);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Buffer Memory:       512KB")
+        );
+    }
+
+    #[test]
+    fn test_buffer_pool_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(ConnectionConfig::from(&config).buffer_pool.is_none());
+
+        let config =
+            ProxyConfig::parse_from(["rhoxy-socks", "--buffer-pool", "--buffer-size", "16"]);
+        let pool = ConnectionConfig::from(&config).buffer_pool.unwrap();
+        assert_eq!(pool.buffer_size(), 16 * 1024);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Buffer Pool:         enabled")
+        );
+    }
+
+    #[test]
+    fn test_max_connections_per_target_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(ConnectionConfig::from(&config).target_limiter.is_none());
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--max-connections-per-target", "4"]);
+        assert!(config.validate().is_ok());
+        assert!(ConnectionConfig::from(&config).target_limiter.is_some());
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Per-Target Limit:    4")
+        );
+    }
+
+    #[test]
+    fn test_allow_ports_option() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--allow-ports", "80,443,1024-65535"]);
+        let allow_ports = ConnectionConfig::from(&config).allow_ports;
+        assert_eq!(allow_ports.len(), 3);
+        assert!(allow_ports.iter().any(|range| range.contains(443)));
+        assert!(allow_ports.iter().any(|range| range.contains(8080)));
+        assert!(!allow_ports.iter().any(|range| range.contains(22)));
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Allowed Ports:       80,443,1024-65535")
+        );
+
+        assert!(ProxyConfig::try_parse_from(["rhoxy-socks", "--allow-ports", "443,0"]).is_err());
+    }
+
+    #[test]
+    fn test_diagnostics_command_flag() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(!ConnectionConfig::from(&config).diagnostics_command);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--diagnostics-command"]);
+        assert!(ConnectionConfig::from(&config).diagnostics_command);
+        if cfg!(feature = "diagnostics") {
+            assert!(config.validate().is_ok());
+        } else {
+            assert_eq!(
+                config.validate().unwrap_err(),
+                ConfigError::DiagnosticsUnsupported
+            );
+        }
+    }
+
+    #[test]
+    fn test_log_accepted_flag() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks"]);
+        assert!(!ConnectionConfig::from(&config).log_accepted);
+
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--log-accepted"]);
+        assert!(ConnectionConfig::from(&config).log_accepted);
+        assert!(
+            config
+                .summary()
+                .to_string()
+                .contains("Log Accepted:        enabled")
+        );
+    }
+
+    #[test]
+    fn test_oversized_buffer_errors_by_default() {
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--buffer-size", "4096"]);
+        assert_eq!(
+            config.validate().unwrap_err(),
+            ConfigError::BufferSizeTooLarge
+        );
+    }
+
+    #[test]
+    fn test_clamp_buffer_size() {
+        let config = ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--buffer-size",
+            "4096",
+            "--clamp-buffer-size",
+        ]);
+        assert!(config.validate().is_ok());
+        assert!(config.buffer_size_clamped());
+        assert_eq!(config.effective_buffer_size_kb(), 1024);
+        assert_eq!(ConnectionConfig::from(&config).buffer_size, 1024 * 1024);
+        assert_eq!(config.summary().buffer_size_kb, 1024);
+
+        // Values in range are left alone
+        let config = ProxyConfig::parse_from(["rhoxy-socks", "--clamp-buffer-size"]);
+        assert!(!config.buffer_size_clamped());
+        assert_eq!(config.buffer_size_bytes(), 32 * 1024);
+        // Zero is still an error, there is nothing sensible to clamp it to
+        let config =
+            ProxyConfig::parse_from(["rhoxy-socks", "--buffer-size", "0", "--clamp-buffer-size"]);
+        assert_eq!(config.validate().unwrap_err(), ConfigError::BufferSizeZero);
+    }
+
+    #[test]
+    fn test_http_url_parse() {
+        let url: HttpUrl = "http://discovery.local:8500/v1/proxies".parse().unwrap();
+        assert_eq!(url.host, "discovery.local");
+        assert_eq!(url.port, 8500);
+        assert_eq!(url.path, "/v1/proxies");
+
+        let url: HttpUrl = "http://10.0.0.1".parse().unwrap();
+        assert_eq!(url.to_string(), "http://10.0.0.1:80/");
+
+        assert!("https://discovery.local/".parse::<HttpUrl>().is_err());
+        assert!("http://:80/".parse::<HttpUrl>().is_err());
+        assert!("http://host:port/".parse::<HttpUrl>().is_err());
+    }
 }
@@ -1,6 +1,46 @@
+use std::net::{IpAddr, SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 
+use crate::config::ConnectionConfig;
 use crate::connection::{error::SocksError, resolve_domain};
+use tracing::debug;
+
+// Longest name the one-byte length field can carry
+pub const MAX_DOMAIN_LEN: u8 = u8::MAX;
+
+/// Which address family domain names are resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolveFamily {
+    /// Use every address the resolver returns.
+    #[default]
+    Any,
+    /// Only connect to A records.
+    V4,
+    /// Only connect to AAAA records.
+    V6,
+}
+
+impl ResolveFamily {
+    pub fn matches(&self, addr: &IpAddr) -> bool {
+        match self {
+            ResolveFamily::Any => true,
+            ResolveFamily::V4 => addr.is_ipv4(),
+            ResolveFamily::V6 => addr.is_ipv6(),
+        }
+    }
+
+    // Resolved addresses of the allowed family, in resolver order
+    pub fn select(&self, addrs: &[SocketAddr]) -> Vec<IpAddr> {
+        let mut selected = Vec::new();
+        for addr in addrs.iter().map(SocketAddr::ip) {
+            if self.matches(&addr) && !selected.contains(&addr) {
+                selected.push(addr);
+            }
+        }
+        selected
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -24,17 +64,30 @@ impl AddressType {
         }
     }
 
+    // Reads the address for `atyp`, also returning the domain name it was
+    // resolved from when the client sent one. A domain name gives every
+    // address it resolved to, never none; a literal address is the only one.
+    // With resolution off in `config`, domain names are refused as an
+    // unsupported address type before anything is read, and names longer
+    // than `max_domain_len` before being read.
     pub async fn parse<R>(
         reader: &mut BufReader<R>,
         atyp: u8,
-    ) -> Result<std::net::IpAddr, SocksError>
+        config: &ConnectionConfig,
+    ) -> Result<(Vec<IpAddr>, Option<String>), SocksError>
     where
         R: AsyncRead + Unpin,
     {
         match AddressType::from_u8(atyp) {
-            Some(AddressType::IPv4) => Self::parse_ipv4(reader).await,
-            Some(AddressType::DomainName) => Self::parse_domain_name(reader).await,
-            Some(AddressType::IPv6) => Self::parse_ipv6(reader).await,
+            Some(AddressType::IPv4) => Ok((vec![Self::parse_ipv4(reader).await?], None)),
+            Some(AddressType::DomainName) => match config.domain_resolution() {
+                Some(family) => {
+                    let (addrs, domain) = Self::parse_domain_name(reader, family, config).await?;
+                    Ok((addrs, Some(domain)))
+                }
+                None => Err(SocksError::UnsupportedAddressType(atyp)),
+            },
+            Some(AddressType::IPv6) => Ok((vec![Self::parse_ipv6(reader).await?], None)),
             None => Err(SocksError::UnsupportedAddressType(atyp)),
         }
     }
@@ -63,17 +116,27 @@ impl AddressType {
         Ok(std::net::IpAddr::from(addr))
     }
 
-    async fn parse_domain_name<R>(reader: &mut BufReader<R>) -> Result<std::net::IpAddr, SocksError>
+    // Transient lookup failures are retried per `dns_retry`, and names in the
+    // host map skip the lookup and take the mapped address
+    async fn parse_domain_name<R>(
+        reader: &mut BufReader<R>,
+        family: ResolveFamily,
+        config: &ConnectionConfig,
+    ) -> Result<(Vec<IpAddr>, String), SocksError>
     where
         R: AsyncRead + Unpin,
     {
         let domain_len = reader
             .read_u8()
             .await
-            .map_err(|e| SocksError::IoError(e.kind()))? as usize;
+            .map_err(|e| SocksError::IoError(e.kind()))?;
         if domain_len == 0 {
             return Err(SocksError::EmptyDomainName);
         }
+        if domain_len > config.max_domain_len {
+            return Err(SocksError::DomainNameTooLong(domain_len));
+        }
+        let domain_len = domain_len as usize;
 
         let mut domain = vec![0u8; domain_len];
         reader
@@ -84,25 +147,56 @@ impl AddressType {
         let domain_str =
             String::from_utf8(domain).map_err(|_| SocksError::InvalidDomainNameEncoding)?;
 
-        let resolved_addrs = resolve_domain(&domain_str)
-            .await
-            .map_err(|_| SocksError::DnsResolutionFailed)?;
-
-        let addr = resolved_addrs
-            .first()
-            .ok_or(SocksError::NoAddressesResolved)?
-            .ip();
+        if let Some(target) = config
+            .host_map
+            .as_deref()
+            .and_then(|host_map| host_map.get(&domain_str))
+        {
+            debug!("Mapped {} to {}", domain_str, target);
+            return Ok((vec![target.ip()], domain_str));
+        }
 
-        Ok(addr)
+        let resolved_addrs =
+            resolve_domain(&domain_str, config.dns_slots.as_ref(), config.dns_retry)
+                .await
+                .map_err(|e| SocksError::DnsResolutionFailed {
+                    domain: domain_str.clone(),
+                    detail: e.to_string(),
+                })?;
+
+        let addrs = family.select(&resolved_addrs);
+        if addrs.is_empty() {
+            return Err(SocksError::NoAddressesResolved);
+        }
+        Ok((addrs, domain_str))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::ProxyConfig;
+    use clap::Parser;
     use std::net::Ipv4Addr;
     use tokio::io::BufReader;
 
+    fn test_config() -> ConnectionConfig {
+        ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+    }
+
+    async fn parse_with<R>(
+        reader: &mut BufReader<R>,
+        atyp: u8,
+        config: &ConnectionConfig,
+    ) -> Result<IpAddr, SocksError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        AddressType::parse(reader, atyp, config)
+            .await
+            .map(|(addrs, _)| addrs[0])
+    }
+
     #[test]
     fn test_address_type_from_u8() {
         assert_eq!(AddressType::from_u8(0x01), Some(AddressType::IPv4));
@@ -133,7 +227,7 @@ mod tests {
         let data = vec![127, 0, 0, 1];
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::IPV4).await;
+        let result = parse_with(&mut reader, AddressType::IPV4, &test_config()).await;
         assert!(result.is_ok());
 
         let addr = result.unwrap();
@@ -145,7 +239,7 @@ mod tests {
         let data = vec![127, 0, 0]; // Missing one byte
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::IPV4).await;
+        let result = parse_with(&mut reader, AddressType::IPV4, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::IoError(kind)) = result {
@@ -163,7 +257,7 @@ mod tests {
         ];
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::IPV6).await;
+        let result = parse_with(&mut reader, AddressType::IPV6, &test_config()).await;
         assert!(result.is_ok());
 
         let addr = result.unwrap();
@@ -184,7 +278,7 @@ mod tests {
         let data = vec![0x20, 0x01, 0x0d, 0xb8]; // Only 4 bytes instead of 16
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::IPV6).await;
+        let result = parse_with(&mut reader, AddressType::IPV6, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::IoError(kind)) = result {
@@ -199,7 +293,7 @@ mod tests {
         let data = vec![0]; // Domain length = 0
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME).await;
+        let result = parse_with(&mut reader, AddressType::DOMAIN_NAME, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::EmptyDomainName) = result {
@@ -214,7 +308,7 @@ mod tests {
         let data = vec![3, 0xFF, 0xFE, 0xFD]; // Invalid UTF-8 sequence
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME).await;
+        let result = parse_with(&mut reader, AddressType::DOMAIN_NAME, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::InvalidDomainNameEncoding) = result {
@@ -229,7 +323,7 @@ mod tests {
         let data = vec![]; // No domain length byte
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME).await;
+        let result = parse_with(&mut reader, AddressType::DOMAIN_NAME, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::IoError(kind)) = result {
@@ -244,7 +338,7 @@ mod tests {
         let data = vec![5, b'h', b'e']; // Claims 5 bytes but only provides 2
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME).await;
+        let result = parse_with(&mut reader, AddressType::DOMAIN_NAME, &test_config()).await;
         assert!(result.is_err());
 
         if let Err(SocksError::IoError(kind)) = result {
@@ -254,12 +348,41 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_parse_domain_name_with_dns_disabled() {
+        let data = vec![9, b'l', b'o', b'c', b'a', b'l', b'h', b'o', b's', b't'];
+        let mut reader = BufReader::new(data.as_slice());
+
+        let config = ConnectionConfig {
+            no_dns: true,
+            ..test_config()
+        };
+        let result = parse_with(&mut reader, AddressType::DOMAIN_NAME, &config).await;
+        assert_eq!(
+            result,
+            Err(SocksError::UnsupportedAddressType(AddressType::DOMAIN_NAME))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_ipv4_with_dns_disabled() {
+        let data = vec![127, 0, 0, 1];
+        let mut reader = BufReader::new(data.as_slice());
+
+        let config = ConnectionConfig {
+            no_dns: true,
+            ..test_config()
+        };
+        let result = parse_with(&mut reader, AddressType::IPV4, &config).await;
+        assert_eq!(result, Ok(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+
     #[tokio::test]
     async fn test_parse_unsupported_address_type() {
         let data = vec![127, 0, 0, 1];
         let mut reader = BufReader::new(data.as_slice());
 
-        let result = AddressType::parse(&mut reader, 0x99).await; // Invalid ATYP
+        let result = parse_with(&mut reader, 0x99, &test_config()).await; // Invalid ATYP
         assert!(result.is_err());
 
         if let Err(SocksError::UnsupportedAddressType(atyp)) = result {
@@ -277,6 +400,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_address_type_clone() {
         let original = AddressType::IPv6;
         let cloned = original.clone();
@@ -289,4 +413,101 @@ mod tests {
         let copied = original;
         assert_eq!(original, copied);
     }
+
+    fn mixed_resolution() -> Vec<SocketAddr> {
+        [
+            "[2001:db8::1]:0",
+            "192.0.2.1:0",
+            "[2001:db8::2]:0",
+            "192.0.2.2:0",
+        ]
+        .iter()
+        .map(|s| s.parse().unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn test_resolve_family_any_keeps_resolver_order() {
+        let all: Vec<IpAddr> = mixed_resolution().iter().map(SocketAddr::ip).collect();
+        assert_eq!(ResolveFamily::Any.select(&mixed_resolution()), all);
+    }
+
+    #[test]
+    fn test_resolve_family_v4() {
+        assert_eq!(
+            ResolveFamily::V4.select(&mixed_resolution()),
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "192.0.2.2".parse().unwrap()
+            ]
+        );
+        let v6_only = &mixed_resolution()[..1];
+        assert!(ResolveFamily::V4.select(v6_only).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_family_v6() {
+        assert_eq!(
+            ResolveFamily::V6.select(&mixed_resolution()),
+            [
+                "2001:db8::1".parse::<IpAddr>().unwrap(),
+                "2001:db8::2".parse().unwrap()
+            ]
+        );
+        let v4_only = &mixed_resolution()[1..2];
+        assert!(ResolveFamily::V6.select(v4_only).is_empty());
+        assert!(ResolveFamily::Any.select(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_family_drops_repeated_addresses() {
+        let repeated: Vec<SocketAddr> = ["192.0.2.1:0", "192.0.2.1:0"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(ResolveFamily::Any.select(&repeated).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_domain_resolution_honours_family() {
+        let mut data = vec![9];
+        data.extend_from_slice(b"localhost");
+        let mut reader = BufReader::new(&data[..]);
+
+        let config = ConnectionConfig {
+            resolve_family: ResolveFamily::V4,
+            ..test_config()
+        };
+        let addr = parse_with(&mut reader, AddressType::DOMAIN_NAME, &config)
+            .await
+            .unwrap();
+        assert_eq!(addr, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+
+    #[tokio::test]
+    async fn test_domain_length_limit() {
+        // At the limit the name is read and resolved as usual
+        let mut data = vec![9];
+        data.extend_from_slice(b"localhost");
+        let mut reader = BufReader::new(&data[..]);
+        let config = ConnectionConfig {
+            resolve_family: ResolveFamily::V4,
+            max_domain_len: 9,
+            ..test_config()
+        };
+        let (_, domain) = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME, &config)
+            .await
+            .unwrap();
+        assert_eq!(domain.as_deref(), Some("localhost"));
+
+        let mut reader = BufReader::new(&data[..]);
+        let config = ConnectionConfig {
+            max_domain_len: 8,
+            ..config
+        };
+        let err = AddressType::parse(&mut reader, AddressType::DOMAIN_NAME, &config)
+            .await
+            .unwrap_err();
+        assert_eq!(err, SocksError::DomainNameTooLong(9));
+    }
 }
@@ -0,0 +1,100 @@
+use std::{fmt, io};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // Client side of the relay reached EOF
+    ClientClosed,
+    // Target side of the relay reached EOF
+    TargetClosed,
+    // Target aborted the relay with a TCP RST
+    TargetReset,
+    // Relay hit --max-bytes-per-connection
+    QuotaExceeded,
+    // Relay moved no data for --idle-timeout
+    IdleTimeout,
+    // Neither side sent a byte before --first-byte-timeout
+    NoData,
+    // Success reply was sent but the relay could not be set up
+    RelaySetupFailed,
+    // Command finished without relaying data (e.g. BIND replies)
+    Completed,
+    // Command failed and the client was sent this reply code
+    RequestRejected(u8),
+    HandshakeTimeout,
+    ConnectionTimeout,
+    // Connection outlived --max-connection-lifetime, active or not
+    LifetimeExceeded,
+    Shutdown,
+    Error(io::ErrorKind),
+}
+
+impl CloseReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseReason::ClientClosed => "client_closed",
+            CloseReason::TargetClosed => "target_closed",
+            CloseReason::TargetReset => "target_reset",
+            CloseReason::QuotaExceeded => "quota_exceeded",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::NoData => "no_data",
+            CloseReason::RelaySetupFailed => "relay_setup_failed",
+            CloseReason::Completed => "completed",
+            CloseReason::RequestRejected(_) => "request_rejected",
+            CloseReason::HandshakeTimeout => "handshake_timeout",
+            CloseReason::ConnectionTimeout => "connection_timeout",
+            CloseReason::LifetimeExceeded => "lifetime_exceeded",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::Error(_) => "error",
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, CloseReason::Error(_))
+    }
+}
+
+impl From<&io::Error> for CloseReason {
+    fn from(error: &io::Error) -> Self {
+        CloseReason::Error(error.kind())
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::RequestRejected(code) => {
+                write!(f, "{} (reply 0x{:02X})", self.name(), code)
+            }
+            CloseReason::Error(kind) => write!(f, "{} ({})", self.name(), kind),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_close_reason_display() {
+        assert_eq!(CloseReason::ClientClosed.to_string(), "client_closed");
+        assert_eq!(
+            CloseReason::RequestRejected(0x07).to_string(),
+            "request_rejected (reply 0x07)"
+        );
+        assert!(
+            CloseReason::Error(io::ErrorKind::BrokenPipe)
+                .to_string()
+                .starts_with("error")
+        );
+    }
+
+    #[test]
+    fn test_close_reason_from_io_error() {
+        let error = io::Error::new(io::ErrorKind::ConnectionReset, "reset");
+        let reason = CloseReason::from(&error);
+        assert_eq!(reason, CloseReason::Error(io::ErrorKind::ConnectionReset));
+        assert!(reason.is_error());
+        assert!(!CloseReason::Shutdown.is_error());
+    }
+}
@@ -1,18 +1,134 @@
-use std::{io, net::SocketAddr, time::Duration};
+use std::{
+    fmt, io,
+    net::{Ipv4Addr, SocketAddr},
+    str::FromStr,
+};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, BufReader, BufWriter},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
     net::TcpListener,
+    sync::oneshot,
     time::timeout,
 };
 use tracing::{debug, warn};
 
-use crate::connection::{command::CommandResult, reply::Reply, request::SocksRequest};
+use crate::ConnectionContext;
+use crate::config::ConnectionConfig;
+use crate::connection::{
+    close_reason::CloseReason,
+    command::{
+        Command, CommandResult, log_accepted,
+        relay::{self, RelayEnd, RelayOptions},
+    },
+    reply::Reply,
+    request::SocksRequest,
+};
+
+// Inclusive range of ports BIND listeners may be allocated from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn new(start: u16, end: u16) -> Result<Self, String> {
+        if start == 0 {
+            return Err("Port range cannot include port 0".to_string());
+        }
+        if start > end {
+            return Err(format!("Port range start {} is after end {}", start, end));
+        }
+        Ok(Self { start, end })
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|_| format!("Invalid port in range '{}'", s))
+        };
+        Self::new(parse(start)?, parse(end)?)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
 
 pub async fn handle_command<R, W>(
     client_request: SocksRequest,
     client_addr: SocketAddr,
-    _client_reader: &mut BufReader<R>,
+    client_reader: &mut BufReader<R>,
+    client_writer: &mut BufWriter<W>,
+    config: &ConnectionConfig,
+    context: &ConnectionContext,
+) -> io::Result<CommandResult>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    handle_command_with_notify(
+        client_request,
+        client_addr,
+        client_reader,
+        client_writer,
+        config,
+        context,
+        None,
+    )
+    .await
+}
+
+async fn bind_listener(port_range: Option<PortRange>) -> io::Result<TcpListener> {
+    let Some(port_range) = port_range else {
+        return TcpListener::bind((Ipv4Addr::UNSPECIFIED, 0)).await;
+    };
+
+    for port in port_range.ports() {
+        match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+            Ok(listener) => return Ok(listener),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!("No free port in BIND range {}", port_range),
+    ))
+}
+
+// Same as `handle_command`, but sends the allocated listener address on
+// `bound_addr_tx` as soon as it is known, before waiting for the peer.
+// Lets library callers coordinate the peer without parsing the first reply.
+pub async fn handle_command_with_notify<R, W>(
+    client_request: SocksRequest,
+    client_addr: SocketAddr,
+    client_reader: &mut BufReader<R>,
     client_writer: &mut BufWriter<W>,
+    config: &ConnectionConfig,
+    context: &ConnectionContext,
+    bound_addr_tx: Option<oneshot::Sender<SocketAddr>>,
 ) -> io::Result<CommandResult>
 where
     R: AsyncRead + Unpin,
@@ -23,11 +139,27 @@ where
         client_request
     );
 
-    let listener = match TcpListener::bind("0.0.0.0:0").await {
+    // Held until the BIND finishes, which is when its listener is closed
+    let _bind_slot = match &config.bind_slots {
+        Some(slots) => match slots.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                debug!("[{client_addr}] BIND listener limit reached, refusing");
+                let error_result = CommandResult::error(Reply::GENERAL_FAILURE);
+                error_result.send_reply(client_writer).await?;
+                return Ok(error_result);
+            }
+        },
+        None => None,
+    };
+
+    let listener = match bind_listener(config.bind_port_range).await {
         Ok(listener) => listener,
         Err(e) => {
             debug!("[{client_addr}] Failed to create bind socket: {}", e);
-            return Ok(CommandResult::error(Reply::GENERAL_FAILURE));
+            let error_result = CommandResult::error(Reply::GENERAL_FAILURE);
+            error_result.send_reply(client_writer).await?;
+            return Ok(error_result);
         }
     };
 
@@ -41,19 +173,30 @@ where
         "[{client_addr}] Sent first BIND reply with bound address {}",
         bound_addr
     );
+    log_accepted(config, context, client_addr, Command::Bind, bound_addr);
+    // Waiting for the peer is not setup work
+    context.end_setup();
+
+    if let Some(bound_addr_tx) = bound_addr_tx {
+        // Receiver may have lost interest, the BIND still proceeds
+        let _ = bound_addr_tx.send(bound_addr);
+    }
 
-    let connection_result = timeout(Duration::from_secs(30), listener.accept()).await;
+    // The first reply is flushed by now, so the client can pass the address on
+    // (e.g. in an FTP PORT command) while the peer has this long to connect
+    let connection_result = timeout(config.bind_accept_timeout, listener.accept()).await;
 
     match connection_result {
-        Ok(Ok((stream, connecting_addr))) => {
+        Ok(Ok((mut peer_stream, connecting_addr))) => {
             debug!(
                 "[{client_addr}] BIND accepted connection from {}",
                 connecting_addr
             );
 
             // Verify the connecting address matches the requested destination
-            // According to RFC, the SOCKS server should use DST.ADDR and DST.PORT for evaluation
-            if connecting_addr.ip() != client_request.dest_addr {
+            // According to RFC, the SOCKS server should use DST.ADDR and DST.PORT for evaluation.
+            // A domain may connect back from any of its addresses
+            if !client_request.dest_addrs.contains(&connecting_addr.ip()) {
                 warn!(
                     "[{client_addr}] BIND connection from {} doesn't match expected destination {}",
                     connecting_addr.ip(),
@@ -73,7 +216,43 @@ where
                 connecting_addr
             );
 
-            Ok(second_reply)
+            let (mut peer_reader, mut peer_writer) = peer_stream.split();
+            let stats = relay::relay(
+                client_reader,
+                client_writer,
+                &mut peer_reader,
+                &mut peer_writer,
+                &RelayOptions::from(config),
+            )
+            .await;
+            debug!(
+                "[{client_addr}] BIND relay ended by {:?} after {} bytes up, {} bytes down",
+                stats.end, stats.client_to_target, stats.target_to_client
+            );
+            let close_reason = match (stats.end, stats.error) {
+                (RelayEnd::Idle, _) => CloseReason::IdleTimeout,
+                (RelayEnd::NoData, _) => CloseReason::NoData,
+                (RelayEnd::ClientToTarget, None) => CloseReason::ClientClosed,
+                (RelayEnd::TargetToClient, None) => CloseReason::TargetClosed,
+                (end, Some(e)) => {
+                    debug!("[{client_addr}] BIND {:?} transfer failed: {}", end, e);
+                    return Err(e);
+                }
+            };
+
+            // As after a CONNECT relay, push out what is buffered and send a FIN
+            let finished = async {
+                client_writer.flush().await?;
+                client_writer.shutdown().await
+            };
+            if let Err(e) = finished.await {
+                debug!(
+                    "[{client_addr}] Failed to close client side after relay: {}",
+                    e
+                );
+            }
+
+            Ok(second_reply.with_close_reason(close_reason))
         }
         Ok(Err(e)) => {
             debug!("[{client_addr}] BIND accept failed: {}", e);
@@ -93,18 +272,32 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::connection::{AddressType, command::Command};
-    use std::net::{IpAddr, Ipv4Addr};
-    use tokio::{io::BufReader, time::sleep};
+    use crate::config::ProxyConfig;
+    use crate::connection::AddressType;
+    use clap::Parser;
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, BufReader},
+        time::{Instant, sleep},
+    };
+
+    fn test_config() -> ConnectionConfig {
+        ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+    }
 
     fn create_test_request() -> SocksRequest {
         SocksRequest {
             version: 0x05,
-            command: Command::BIND as u8,
+            command: Command::BIND,
             reserved: 0x00,
             address_type: AddressType::IPV4,
             dest_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            dest_addrs: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
             dest_port: 8080,
+            dest_domain: None,
         }
     }
 
@@ -120,7 +313,14 @@ mod tests {
         // This should timeout since no connection will be made
         let result = timeout(
             Duration::from_millis(100),
-            handle_command(request, client_addr, &mut reader, &mut writer),
+            handle_command(
+                request,
+                client_addr,
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+            ),
         )
         .await;
 
@@ -128,6 +328,49 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[tokio::test]
+    async fn test_first_reply_arrives_before_accept_window() {
+        let config = ConnectionConfig {
+            bind_accept_timeout: Duration::from_millis(300),
+            ..test_config()
+        };
+        let (proxy_side, mut client) = tokio::io::duplex(1024);
+        let (proxy_read, proxy_write) = tokio::io::split(proxy_side);
+
+        let start = Instant::now();
+        let bind = tokio::spawn(async move {
+            let mut reader = BufReader::new(proxy_read);
+            let mut writer = tokio::io::BufWriter::new(proxy_write);
+            handle_command(
+                create_test_request(),
+                "127.0.0.1:12345".parse().unwrap(),
+                &mut reader,
+                &mut writer,
+                &config,
+                &ConnectionContext::new(),
+            )
+            .await
+        });
+
+        // No peer ever connects, the first reply must not wait for one
+        let mut first_reply = [0u8; 10];
+        timeout(
+            Duration::from_millis(200),
+            client.read_exact(&mut first_reply),
+        )
+        .await
+        .expect("first reply should be flushed before accepting")
+        .unwrap();
+        assert_eq!(first_reply[1], Reply::SUCCESS);
+        assert!(!bind.is_finished());
+
+        let result = bind.await.unwrap().unwrap();
+        assert_eq!(result.reply_code, Reply::TTL_EXPIRED);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+    }
+
     #[tokio::test]
     async fn test_bind_socket_creation_success() {
         let request = create_test_request();
@@ -139,7 +382,15 @@ mod tests {
 
         // Start the bind command in a task
         let handle = tokio::spawn(async move {
-            handle_command(request, client_addr, &mut reader, &mut writer).await
+            handle_command(
+                request,
+                client_addr,
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+            )
+            .await
         });
 
         // Give it a moment to create the socket and send first reply
@@ -161,11 +412,310 @@ mod tests {
         // Test that a bind socket can be created (will timeout waiting for connection)
         let result = timeout(
             Duration::from_millis(100),
-            handle_command(request, client_addr, &mut reader, &mut writer),
+            handle_command(
+                request,
+                client_addr,
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+            ),
         )
         .await;
 
         // Should timeout because we didn't send anyone to connect
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_bind_notifies_bound_addr_before_accept() {
+        let request = create_test_request();
+        let client_addr = "127.0.0.1:12345".parse().unwrap();
+        let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+        let (proxy_side, _client) = tokio::io::duplex(1024);
+
+        let handle = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(proxy_side);
+            let mut reader = BufReader::new(reader);
+            let mut writer = tokio::io::BufWriter::new(writer);
+            handle_command_with_notify(
+                request,
+                client_addr,
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+                Some(bound_addr_tx),
+            )
+            .await
+        });
+
+        // Observable while the BIND is still blocked waiting for a peer
+        let bound_addr = timeout(Duration::from_secs(1), bound_addr_rx)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_ne!(bound_addr.port(), 0);
+        assert!(!handle.is_finished());
+
+        let peer = tokio::net::TcpStream::connect(("127.0.0.1", bound_addr.port()))
+            .await
+            .unwrap();
+        let peer_port = peer.local_addr().unwrap().port();
+        // The relay that follows the second reply ends with the peer
+        drop(peer);
+        let result = handle.await.unwrap().unwrap();
+        assert!(result.is_success());
+        assert_eq!(result.bind_port, peer_port);
+    }
+
+    #[test]
+    fn test_port_range_parse() {
+        let range: PortRange = "40000-41000".parse().unwrap();
+        assert!(range.contains(40000));
+        assert!(range.contains(41000));
+        assert!(!range.contains(41001));
+        assert_eq!(range.to_string(), "40000-41000");
+
+        let single: PortRange = "5000".parse().unwrap();
+        assert_eq!(single.ports().collect::<Vec<_>>(), vec![5000]);
+        assert_eq!(single.to_string(), "5000");
+
+        assert!("41000-40000".parse::<PortRange>().is_err());
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("abc-10".parse::<PortRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_port_within_configured_range() {
+        // Occupy the first port so allocation has to skip past it
+        let occupied = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let first = occupied.local_addr().unwrap().port();
+        let range = PortRange::new(first, first.saturating_add(50)).unwrap();
+        let config = ConnectionConfig {
+            bind_port_range: Some(range),
+            ..test_config()
+        };
+
+        let (client_read, client_write) = tokio::io::duplex(1024);
+        let mut reader = BufReader::new(client_read);
+        let mut writer = tokio::io::BufWriter::new(client_write);
+        let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+        let request = create_test_request();
+        let client_addr = "127.0.0.1:12345".parse().unwrap();
+        let context = ConnectionContext::new();
+        let bind = handle_command_with_notify(
+            request,
+            client_addr,
+            &mut reader,
+            &mut writer,
+            &config,
+            &context,
+            Some(bound_addr_tx),
+        );
+        tokio::pin!(bind);
+
+        let bound_addr = tokio::select! {
+            addr = bound_addr_rx => addr.unwrap(),
+            _ = &mut bind => panic!("BIND finished before reporting its address"),
+        };
+        assert!(range.contains(bound_addr.port()));
+        assert_ne!(bound_addr.port(), first);
+    }
+
+    #[tokio::test]
+    async fn test_bind_port_range_exhausted() {
+        let occupied = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = occupied.local_addr().unwrap().port();
+        let config = ConnectionConfig {
+            bind_port_range: Some(PortRange::new(port, port).unwrap()),
+            ..test_config()
+        };
+
+        let (client_read, client_write) = tokio::io::duplex(1024);
+        let mut reader = BufReader::new(client_read);
+        let mut writer = tokio::io::BufWriter::new(client_write);
+        let request = create_test_request();
+        let client_addr = "127.0.0.1:12345".parse().unwrap();
+
+        let context = ConnectionContext::new();
+        let result = handle_command(
+            request,
+            client_addr,
+            &mut reader,
+            &mut writer,
+            &config,
+            &context,
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.reply_code, Reply::GENERAL_FAILURE);
+
+        // The client is told, rather than left waiting for a first reply
+        let mut reply = [0u8; 10];
+        timeout(Duration::from_secs(1), reader.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply[0], 0x05);
+        assert_eq!(reply[1], Reply::GENERAL_FAILURE);
+    }
+
+    #[tokio::test]
+    async fn test_max_bind_listeners_refuses_extra_bind() {
+        let config = ConnectionConfig::from(&ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--max-bind-listeners",
+            "2",
+        ]));
+        let client_addr: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+
+        let mut pending = Vec::new();
+        for _ in 0..2 {
+            let config = config.clone();
+            let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+            pending.push(tokio::spawn(async move {
+                let (client_read, client_write) = tokio::io::duplex(1024);
+                let mut reader = BufReader::new(client_read);
+                let mut writer = tokio::io::BufWriter::new(client_write);
+                handle_command_with_notify(
+                    create_test_request(),
+                    client_addr,
+                    &mut reader,
+                    &mut writer,
+                    &config,
+                    &ConnectionContext::new(),
+                    Some(bound_addr_tx),
+                )
+                .await
+            }));
+            timeout(Duration::from_secs(1), bound_addr_rx)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+
+        let (client_read, client_write) = tokio::io::duplex(1024);
+        let mut reader = BufReader::new(client_read);
+        let mut writer = tokio::io::BufWriter::new(client_write);
+        let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+        let result = handle_command_with_notify(
+            create_test_request(),
+            client_addr,
+            &mut reader,
+            &mut writer,
+            &config,
+            &ConnectionContext::new(),
+            Some(bound_addr_tx),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.reply_code, Reply::GENERAL_FAILURE);
+        // Refused before a listener was created, so no address was ever reported
+        assert!(bound_addr_rx.await.is_err());
+
+        // A finished BIND gives its slot back
+        pending.remove(0).abort();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(config.bind_slots.as_ref().unwrap().available_permits(), 1);
+        for bind in pending {
+            bind.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_relays_between_client_and_peer() {
+        let (proxy_side, mut client) = tokio::io::duplex(1024);
+        let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+        let bind = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(proxy_side);
+            let mut reader = BufReader::new(reader);
+            let mut writer = tokio::io::BufWriter::new(writer);
+            handle_command_with_notify(
+                create_test_request(),
+                "127.0.0.1:12345".parse().unwrap(),
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+                Some(bound_addr_tx),
+            )
+            .await
+        });
+
+        let bound_port = bound_addr_rx.await.unwrap().port();
+        let mut peer = tokio::net::TcpStream::connect(("127.0.0.1", bound_port))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 20];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[1], Reply::SUCCESS);
+        assert_eq!(replies[11], Reply::SUCCESS);
+
+        peer.write_all(b"from peer").await.unwrap();
+        let mut received = [0u8; 9];
+        client.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"from peer");
+
+        client.write_all(b"from client").await.unwrap();
+        let mut received = [0u8; 11];
+        peer.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"from client");
+
+        drop(peer);
+        let result = timeout(Duration::from_secs(1), bind)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.close_reason(), CloseReason::TargetClosed);
+    }
+
+    #[tokio::test]
+    async fn test_bind_accepts_peer_from_any_resolved_address() {
+        // A domain resolving to two hosts, the peer connects from the second
+        let request = SocksRequest {
+            address_type: AddressType::DOMAIN_NAME,
+            dest_addr: IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+            dest_addrs: vec![
+                IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)),
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            ],
+            dest_domain: Some("peer.example".to_string()),
+            ..create_test_request()
+        };
+        let (proxy_side, mut client) = tokio::io::duplex(1024);
+        let (bound_addr_tx, bound_addr_rx) = oneshot::channel();
+        let bind = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(proxy_side);
+            let mut reader = BufReader::new(reader);
+            let mut writer = tokio::io::BufWriter::new(writer);
+            handle_command_with_notify(
+                request,
+                "127.0.0.1:12345".parse().unwrap(),
+                &mut reader,
+                &mut writer,
+                &test_config(),
+                &ConnectionContext::new(),
+                Some(bound_addr_tx),
+            )
+            .await
+        });
+
+        let bound_port = bound_addr_rx.await.unwrap().port();
+        let peer = tokio::net::TcpStream::connect(("127.0.0.1", bound_port))
+            .await
+            .unwrap();
+        let mut replies = [0u8; 20];
+        client.read_exact(&mut replies).await.unwrap();
+        assert_eq!(replies[1], Reply::SUCCESS);
+        assert_eq!(replies[11], Reply::SUCCESS);
+
+        drop(peer);
+        timeout(Duration::from_secs(1), bind)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+    }
 }
@@ -1,19 +1,84 @@
-use std::{io, net::SocketAddr};
+use socket2::SockRef;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    task::{Context, Poll, ready},
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncRead, AsyncWrite, BufReader, BufWriter, copy},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter, ReadBuf},
     net::TcpStream,
+    time::timeout,
 };
 use tracing::debug;
 
+use crate::ConnectionContext;
+use crate::config::ConnectionConfig;
 use crate::connection::SocksError;
-use crate::connection::{command::CommandResult, request::SocksRequest};
+use crate::connection::{
+    close_reason::CloseReason,
+    command::{
+        Command, CommandResult, dialer, log_accepted,
+        relay::{RelayEnd, RelayOptions, relay_with_idle_hook},
+    },
+    reply::Reply,
+    request::SocksRequest,
+    socket_options,
+};
+use crate::target_limits::{TargetLimiter, TargetSlot};
+
+// How long --prefetch-target holds the CONNECT reply for the target's first bytes
+const PREFETCH_WAIT: Duration = Duration::from_millis(100);
+
+/// Whether CONNECTs to IPv6 targets are attempted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Ipv6TargetPolicy {
+    /// Probe for IPv6 connectivity at startup and reject targets without it.
+    #[default]
+    Auto,
+    /// Always try to connect, even if the host looks IPv6-less.
+    Attempt,
+    /// Reject IPv6 targets with NETWORK UNREACHABLE.
+    Reject,
+}
+
+/// How TCP_NODELAY on the target socket changes at the first response byte.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NodelaySwitch {
+    /// Keep --tcp-nodelay for the whole relay.
+    #[default]
+    Off,
+    /// Nodelay for the interactive opening, Nagle once the target responds.
+    UntilResponse,
+    /// Nagle until the target responds, nodelay afterwards.
+    AfterResponse,
+}
+
+impl NodelaySwitch {
+    // Nodelay before the first response byte and, if it changes, after it
+    fn states(self, tcp_nodelay: bool) -> (bool, Option<bool>) {
+        match self {
+            NodelaySwitch::Off => (tcp_nodelay, None),
+            NodelaySwitch::UntilResponse => (true, Some(false)),
+            NodelaySwitch::AfterResponse => (false, Some(true)),
+        }
+    }
+}
 
 pub async fn handle_command<R, W>(
     client_request: SocksRequest,
     client_addr: SocketAddr,
-    _client_reader: &mut BufReader<R>,
+    client_reader: &mut BufReader<R>,
     client_writer: &mut BufWriter<W>,
-    tcp_nodelay: bool,
+    config: &ConnectionConfig,
+    context: &ConnectionContext,
 ) -> io::Result<CommandResult>
 where
     R: AsyncRead + Unpin,
@@ -24,9 +89,46 @@ where
         client_request
     );
 
-    let target_stream =
-        match TcpStream::connect((client_request.dest_addr, client_request.dest_port)).await {
-            Ok(stream) => stream,
+    if !config.allow_ports.is_empty()
+        && !config
+            .allow_ports
+            .iter()
+            .any(|range| range.contains(client_request.dest_port))
+    {
+        debug!(
+            "[{client_addr}] Destination port {} not allowed",
+            client_request.dest_port
+        );
+        let error_result = CommandResult::error(Reply::CONNECTION_NOT_ALLOWED);
+        error_result.send_reply(client_writer).await?;
+        return Ok(error_result);
+    }
+
+    // Without IPv6 only the IPv4 addresses of a domain are dialed. A domain
+    // left with none goes to the dialer, which reports the host unreachable;
+    // only a literal IPv6 target is refused as an unreachable network
+    let dial_addrs: Vec<SocketAddr> = client_request
+        .dest_addrs
+        .iter()
+        .filter(|addr| config.ipv6_available || addr.is_ipv4())
+        .map(|&addr| SocketAddr::new(addr, client_request.dest_port))
+        .collect();
+    if dial_addrs.is_empty() && client_request.dest_domain.is_none() {
+        debug!(
+            "[{client_addr}] IPv6 unavailable, rejecting target {}",
+            client_request.dest_addr
+        );
+        let error_result = CommandResult::error(Reply::NETWORK_UNREACHABLE);
+        error_result.send_reply(client_writer).await?;
+        return Ok(error_result);
+    }
+
+    let target_addr = SocketAddr::new(client_request.dest_addr, client_request.dest_port);
+    let early_data = fast_open_data(client_reader.buffer(), &dial_addrs, config);
+    // The slot is held until the relay ends so it counts the whole connection
+    let (target_stream, _target_slot) =
+        match connect_target(&dial_addrs, early_data.clone(), config).await {
+            Ok(connected) => connected,
             Err(e) => {
                 debug!(
                     "[{client_addr}] Failed to connect to target {}:{}: {}",
@@ -43,62 +145,493 @@ where
         "[{client_addr}] Connected to target {}:{}",
         client_request.dest_addr, client_request.dest_port
     );
+    if let Some(early_data) = &early_data {
+        // Already delivered with the SYN
+        client_reader.consume(early_data.len());
+    }
+    config.metrics.record_target(client_request.dest_addr);
 
-    let destination_addr = target_stream.local_addr()?;
-    let destination_port = destination_addr.port();
+    if let Err(e) = socket_options::apply_user_timeout(&target_stream, config.tcp_user_timeout) {
+        debug!(
+            "[{client_addr}] Failed to set target TCP_USER_TIMEOUT: {}",
+            e
+        );
+    }
+    if let Err(e) = socket_options::apply_dscp(&target_stream, config.dscp) {
+        debug!("[{client_addr}] Failed to set target DSCP: {}", e);
+    }
+
+    // Anything that can fail before the success reply goes out is still
+    // reported to the client as a general failure
+    let setup = async {
+        let addr = target_stream.local_addr()?;
+        let prefetched = if config.prefetch_target {
+            prefetch_target(&target_stream, config.buffer_size).await?
+        } else {
+            Vec::new()
+        };
+        Ok::<_, io::Error>((addr, prefetched))
+    };
+    let (destination_addr, prefetched) = match setup.await {
+        Ok(setup) => setup,
+        Err(e) => {
+            debug!("[{client_addr}] Failed to prepare relay: {}", e);
+            let error_result = CommandResult::error(Reply::GENERAL_FAILURE);
+            error_result.send_reply(client_writer).await?;
+            return Ok(error_result);
+        }
+    };
 
-    let result = CommandResult::success(destination_addr.ip(), destination_port);
+    // The dialer may have picked another address than the one requested
+    let dialed_addr = target_stream.peer_addr().unwrap_or(target_addr);
+    if let Some(event_sink) = &config.event_sink {
+        event_sink.target_connected(
+            client_addr,
+            context.requested_domain.as_deref(),
+            dialed_addr,
+            context.negotiated_method,
+        );
+    }
 
+    let result = CommandResult::success(destination_addr.ip(), destination_addr.port());
     result.send_reply(client_writer).await?;
+    log_accepted(config, context, client_addr, Command::Connect, dialed_addr);
+    context.end_setup();
 
-    handle_data_transfer(_client_reader, client_writer, target_stream, tcp_nodelay).await?;
+    let close_reason = relay_after_reply(
+        client_reader,
+        client_writer,
+        target_stream,
+        &prefetched,
+        client_addr,
+        config,
+    )
+    .await?;
 
-    Ok(result)
+    Ok(result.with_close_reason(close_reason))
 }
 
-pub async fn handle_data_transfer<R, W>(
+// Once the success reply is on the wire no further SOCKS reply can be sent,
+// so a failure while setting up the relay only closes the connection.
+async fn relay_after_reply<R, W>(
     client_reader: &mut BufReader<R>,
     client_writer: &mut BufWriter<W>,
     target_stream: TcpStream,
-    tcp_nodelay: bool,
-) -> io::Result<()>
+    prefetched: &[u8],
+    client_addr: SocketAddr,
+    config: &ConnectionConfig,
+) -> io::Result<CloseReason>
 where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
-    if tcp_nodelay {
-        if let Err(e) = target_stream.set_nodelay(true) {
-            debug!("Failed to set TCP_NODELAY: {}", e);
+    if !prefetched.is_empty() {
+        debug!(
+            "[{client_addr}] Forwarding {} prefetched bytes from target",
+            prefetched.len()
+        );
+        let forwarded = async {
+            client_writer.write_all(prefetched).await?;
+            client_writer.flush().await
+        };
+        if let Err(e) = forwarded.await {
+            debug!(
+                "[{client_addr}] Relay setup failed after success reply: {}",
+                e
+            );
+            return Ok(CloseReason::RelaySetupFailed);
         }
     }
 
-    let (mut target_reader, mut target_writer) = target_stream.into_split();
+    handle_data_transfer(
+        client_reader,
+        client_writer,
+        target_stream,
+        config,
+        prefetched.len() as u64,
+    )
+    .await
+}
 
-    tokio::select! {
-        result = copy(&mut *client_reader, &mut target_writer) => {
-            if let Err(e) = result {
-                debug!("Client to target transfer failed: {}", e);
-                return Err(e);
+// With fast open the bytes the client pipelined behind its request go out in
+// the SYN. Without any there is nothing to gain, and with several addresses
+// racing attempts could deliver them more than once.
+fn fast_open_data(
+    pipelined: &[u8],
+    addrs: &[SocketAddr],
+    config: &ConnectionConfig,
+) -> Option<Arc<[u8]>> {
+    (cfg!(target_os = "linux") && config.tcp_fast_open && addrs.len() == 1 && !pipelined.is_empty())
+        .then(|| Arc::from(pipelined))
+}
+
+// What each dial attempt needs, owned since attempts run as their own tasks
+#[derive(Clone)]
+struct DialOptions {
+    early_data: Option<Arc<[u8]>>,
+    so_rcvbuf: Option<usize>,
+    so_sndbuf: Option<usize>,
+    target_limiter: Option<Arc<TargetLimiter>>,
+}
+
+async fn connect_target(
+    addrs: &[SocketAddr],
+    early_data: Option<Arc<[u8]>>,
+    config: &ConnectionConfig,
+) -> io::Result<(TcpStream, Option<TargetSlot>)> {
+    let options = DialOptions {
+        early_data,
+        so_rcvbuf: config.so_rcvbuf,
+        so_sndbuf: config.so_sndbuf,
+        target_limiter: config.target_limiter.clone(),
+    };
+    dialer::connect_dual_stack(
+        addrs,
+        config.fallback_delay,
+        config.connect_deadline,
+        move |addr| connect_one(addr, options.clone()),
+    )
+    .await
+}
+
+// Each attempt claims a slot for the address it dials, so a domain counts
+// against whichever of its addresses it ends up connected to
+async fn connect_one(
+    addr: SocketAddr,
+    options: DialOptions,
+) -> io::Result<(TcpStream, Option<TargetSlot>)> {
+    let slot = match &options.target_limiter {
+        Some(limiter) => match limiter.try_acquire(addr) {
+            Some(slot) => Some(slot),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("target {} at its connection limit", addr),
+                ));
             }
+        },
+        None => None,
+    };
+
+    let socket = socket_options::outbound_socket(addr, options.so_rcvbuf, options.so_sndbuf)?;
+    #[cfg(target_os = "linux")]
+    if let Some(early_data) = &options.early_data {
+        let stream = socket_options::connect_with_fast_open(socket, addr, early_data).await?;
+        return Ok((stream, slot));
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = &options.early_data;
+
+    Ok((socket.connect(addr).await?, slot))
+}
+
+// Waits up to `PREFETCH_WAIT` for the target to speak first (e.g. a banner)
+// and drains what has arrived by then, capped at `limit` bytes so the
+// read-ahead never buffers unbounded data. Quiet targets cost no buffer.
+async fn prefetch_target(target_stream: &TcpStream, limit: usize) -> io::Result<Vec<u8>> {
+    match timeout(PREFETCH_WAIT, target_stream.readable()).await {
+        Ok(readable) => readable?,
+        Err(_) => return Ok(Vec::new()),
+    }
+
+    let mut prefetched = vec![0u8; limit];
+    let mut filled = 0;
+
+    while filled < limit {
+        match target_stream.try_read(&mut prefetched[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+            Err(e) => return Err(e),
         }
-        result = copy(&mut target_reader, &mut *client_writer) => {
-            if let Err(e) = result {
-                debug!("Target to client transfer failed: {}", e);
-                return Err(e);
+    }
+
+    prefetched.truncate(filled);
+    Ok(prefetched)
+}
+
+pub async fn handle_data_transfer<R, W>(
+    client_reader: &mut BufReader<R>,
+    client_writer: &mut BufWriter<W>,
+    target_stream: TcpStream,
+    config: &ConnectionConfig,
+    bytes_already_relayed: u64,
+) -> io::Result<CloseReason>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (mut nodelay, mut switch_to) = config.nodelay_switch.states(config.tcp_nodelay);
+    if bytes_already_relayed > 0
+        && let Some(after) = switch_to.take()
+    {
+        // The prefetched banner already was the first response byte
+        nodelay = after;
+    }
+    if (nodelay || switch_to.is_some())
+        && let Err(e) = target_stream.set_nodelay(nodelay)
+    {
+        debug!("Failed to set TCP_NODELAY: {}", e);
+    }
+
+    // The halves are moved into the relay, so keep a handle on the socket to
+    // flip keepalive on from the idle hook
+    let keepalive_socket = match config.idle_keepalive {
+        Some(_) => SockRef::from(&target_stream)
+            .try_clone()
+            .inspect_err(|e| debug!("Failed to clone target socket for keepalive: {}", e))
+            .ok(),
+        None => None,
+    };
+
+    let (target_reader, target_writer) = target_stream.into_split();
+    let mut target_writer = TargetHalf(target_writer);
+    let target_reader = NodelaySwitchReader::new(target_reader, switch_to);
+
+    // Both directions draw from the same budget
+    let relayed = AtomicU64::new(bytes_already_relayed);
+    let quota = config.max_bytes_per_connection;
+    let mut client_reader = QuotaReader::new(&mut *client_reader, &relayed, quota);
+    let mut target_reader = QuotaReader::new(TargetHalf(target_reader), &relayed, quota);
+
+    let mut relay_options = RelayOptions::from(config);
+    if bytes_already_relayed > 0 {
+        // The prefetched banner already was the first byte
+        relay_options.first_byte_timeout = None;
+    }
+    let stats = relay_with_idle_hook(
+        &mut client_reader,
+        &mut *client_writer,
+        &mut target_reader,
+        &mut target_writer,
+        &relay_options,
+        |idle| {
+            let Some(socket) = &keepalive_socket else {
+                return;
+            };
+            let keepalive = config.idle_keepalive.filter(|_| idle);
+            match socket_options::set_idle_keepalive(SockRef::from(socket), keepalive) {
+                Ok(()) if idle => debug!("Relay idle, enabled target keepalive"),
+                Ok(()) => debug!("Relay active again, disabled target keepalive"),
+                Err(e) => debug!("Failed to toggle target keepalive: {}", e),
             }
+        },
+    )
+    .await;
+    debug!(
+        "Relay ended by {:?} after {} bytes up, {} bytes down",
+        stats.end, stats.client_to_target, stats.target_to_client
+    );
+
+    let close_reason = match (stats.end, stats.error) {
+        (RelayEnd::Idle, _) => Ok(CloseReason::IdleTimeout),
+        (RelayEnd::NoData, _) => Ok(CloseReason::NoData),
+        (RelayEnd::ClientToTarget, None) => Ok(CloseReason::ClientClosed),
+        (RelayEnd::TargetToClient, None) => Ok(CloseReason::TargetClosed),
+        (end, Some(e)) if is_quota_exceeded(&e) => {
+            debug!("Byte quota reached while relaying {:?}", end);
+            Ok(CloseReason::QuotaExceeded)
+        }
+        (end, Some(e)) if is_target_reset(&e) => {
+            debug!("Target reset connection while relaying {:?}", end);
+            Ok(CloseReason::TargetReset)
+        }
+        (end, Some(e)) => {
+            debug!("{:?} transfer failed: {}", end, e);
+            Err(e)
+        }
+    }?;
+
+    // The relay only flushes when idle or on EOF, push out what is still
+    // buffered and send the client a FIN rather than leaving it to drop.
+    // A reset that is passed on to the client must not be preceded by a FIN.
+    let send_fin = !(close_reason == CloseReason::TargetReset && config.abort_on_target_reset);
+    let finished = async {
+        client_writer.flush().await?;
+        if send_fin {
+            client_writer.shutdown().await?;
+        }
+        Ok::<_, io::Error>(())
+    };
+    if let Err(e) = finished.await {
+        debug!("Failed to close client side after relay: {}", e);
+    }
+
+    Ok(close_reason)
+}
+
+// Marker carried inside io::Error so a reset on the target socket can be told
+// apart from a reset on the client socket once both go through `copy`.
+#[derive(Debug)]
+struct TargetResetMarker;
+
+impl fmt::Display for TargetResetMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Target connection reset")
+    }
+}
+
+impl std::error::Error for TargetResetMarker {}
+
+fn is_target_reset(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<TargetResetMarker>())
+}
+
+fn mark_target_error(error: io::Error) -> io::Error {
+    if error.kind() == io::ErrorKind::ConnectionReset {
+        io::Error::new(io::ErrorKind::ConnectionReset, TargetResetMarker)
+    } else {
+        error
+    }
+}
+
+#[derive(Debug)]
+struct QuotaExceededMarker;
+
+impl fmt::Display for QuotaExceededMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Connection byte quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceededMarker {}
+
+fn is_quota_exceeded(error: &io::Error) -> bool {
+    error
+        .get_ref()
+        .is_some_and(|inner| inner.is::<QuotaExceededMarker>())
+}
+
+// Caps reads so the shared `relayed` total never goes past `quota`. Once the
+// budget is spent the next read fails with a QuotaExceededMarker error.
+struct QuotaReader<'a, T> {
+    inner: T,
+    relayed: &'a AtomicU64,
+    quota: Option<u64>,
+}
+
+impl<'a, T> QuotaReader<'a, T> {
+    fn new(inner: T, relayed: &'a AtomicU64, quota: Option<u64>) -> Self {
+        Self {
+            inner,
+            relayed,
+            quota,
         }
     }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for QuotaReader<'_, T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let Some(quota) = self.quota else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+
+        let remaining = quota.saturating_sub(self.relayed.load(Ordering::Relaxed));
+        if remaining == 0 {
+            return Poll::Ready(Err(io::Error::other(QuotaExceededMarker)));
+        }
 
-    Ok(())
+        let limit = buf.remaining().min(remaining as usize);
+        let mut limited = buf.take(limit);
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+
+        let n = limited.filled().len();
+        // SAFETY: `limited` only wrote into the unfilled region of `buf`
+        unsafe { buf.assume_init(n) };
+        buf.advance(n);
+        self.relayed.fetch_add(n as u64, Ordering::Relaxed);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Sets TCP_NODELAY to `switch_to` once the target sends its first byte
+struct NodelaySwitchReader<T> {
+    inner: T,
+    switch_to: Option<bool>,
+}
+
+impl<T> NodelaySwitchReader<T> {
+    fn new(inner: T, switch_to: Option<bool>) -> Self {
+        Self { inner, switch_to }
+    }
+}
+
+impl<T: AsyncRead + AsRef<TcpStream> + Unpin> AsyncRead for NodelaySwitchReader<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+
+        if buf.filled().len() > before
+            && let Some(nodelay) = self.switch_to.take()
+            && let Err(e) = self.inner.as_ref().set_nodelay(nodelay)
+        {
+            debug!("Failed to switch TCP_NODELAY to {}: {}", nodelay, e);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct TargetHalf<T>(T);
+
+impl<T: AsyncRead + Unpin> AsyncRead for TargetHalf<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_read(cx, buf)
+            .map_err(mark_target_error)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for TargetHalf<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0)
+            .poll_write(cx, buf)
+            .map_err(mark_target_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_flush(cx)
+            .map_err(mark_target_error)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0)
+            .poll_shutdown(cx)
+            .map_err(mark_target_error)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::connection::{AddressType, RESERVED, SOCKS5_VERSION, reply::Reply, send_reply};
+    use crate::connection::{AddressType, RESERVED, SOCKS5_VERSION, send_reply};
 
     use super::*;
-    use std::net::{Ipv4Addr, Ipv6Addr};
-    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use crate::config::ProxyConfig;
+    use clap::Parser;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use tokio::{
+        io::{AsyncReadExt, duplex},
+        net::TcpListener,
+    };
 
     #[tokio::test]
     async fn test_send_reply_ipv4() {
@@ -220,4 +753,396 @@ mod tests {
         client.read_exact(&mut response).await.unwrap();
         assert_eq!(&response[8..10], 65535u16.to_be_bytes());
     }
+
+    async fn connect_to_banner_server(banner: &'static [u8]) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(banner).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        });
+
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_target_reads_available_data() {
+        let stream = connect_to_banner_server(b"220 ready\r\n").await;
+        let prefetched = prefetch_target(&stream, 1024).await.unwrap();
+        assert_eq!(prefetched, b"220 ready\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_target_gives_up_on_quiet_target() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let _target = listener.accept().await.unwrap();
+
+        let start = tokio::time::Instant::now();
+        let prefetched = prefetch_target(&stream, 1024).await.unwrap();
+        assert!(prefetched.is_empty());
+        assert!(start.elapsed() >= PREFETCH_WAIT);
+    }
+
+    #[tokio::test]
+    async fn test_prefetch_target_is_capped() {
+        let stream = connect_to_banner_server(b"220 ready\r\n").await;
+        let prefetched = prefetch_target(&stream, 3).await.unwrap();
+        assert_eq!(prefetched, b"220");
+    }
+
+    struct BrokenPipeWriter;
+
+    impl AsyncWrite for BrokenPipeWriter {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_setup_failure_after_reply_closes_cleanly() {
+        let stream = connect_to_banner_server(b"220 ready\r\n").await;
+        let (client, _peer) = duplex(1024);
+        let mut reader = BufReader::new(client);
+        let mut writer = BufWriter::new(BrokenPipeWriter);
+        let config = ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]));
+
+        let close_reason = relay_after_reply(
+            &mut reader,
+            &mut writer,
+            stream,
+            b"220 ready\r\n",
+            "127.0.0.1:1".parse().unwrap(),
+            &config,
+        )
+        .await
+        .expect("Setup failure should not surface as an error");
+        assert_eq!(close_reason, CloseReason::RelaySetupFailed);
+    }
+
+    #[tokio::test]
+    async fn test_nodelay_switches_at_first_response_byte() {
+        let stream = connect_to_banner_server(b"220 ready\r\n").await;
+        let (nodelay, switch_to) = NodelaySwitch::UntilResponse.states(true);
+        stream.set_nodelay(nodelay).unwrap();
+
+        let (reader, _writer) = stream.into_split();
+        let mut reader = NodelaySwitchReader::new(reader, switch_to);
+        assert!(reader.inner.as_ref().nodelay().unwrap());
+
+        let mut buf = [0u8; 3];
+        reader.read_exact(&mut buf).await.unwrap();
+        assert!(!reader.inner.as_ref().nodelay().unwrap());
+        assert_eq!(reader.switch_to, None);
+    }
+
+    #[test]
+    fn test_nodelay_switch_states() {
+        assert_eq!(NodelaySwitch::Off.states(true), (true, None));
+        assert_eq!(NodelaySwitch::Off.states(false), (false, None));
+        assert_eq!(
+            NodelaySwitch::AfterResponse.states(true),
+            (false, Some(true))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_sees_eof_after_target_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket.write_all(b"bye").await.unwrap();
+        });
+        let target_stream = TcpStream::connect(addr).await.unwrap();
+
+        let (proxy_side, mut client) = duplex(1024);
+        let (reader, writer) = tokio::io::split(proxy_side);
+        let mut reader = BufReader::new(reader);
+        // Held for the whole test, so only an explicit shutdown can end the stream
+        let mut writer = BufWriter::new(writer);
+        let config = ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]));
+
+        let close_reason =
+            handle_data_transfer(&mut reader, &mut writer, target_stream, &config, 0)
+                .await
+                .unwrap();
+        assert_eq!(close_reason, CloseReason::TargetClosed);
+
+        let mut received = Vec::new();
+        tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            client.read_to_end(&mut received),
+        )
+        .await
+        .expect("client should see EOF once the relay ends")
+        .unwrap();
+        assert_eq!(received, b"bye");
+    }
+
+    #[tokio::test]
+    async fn test_instantly_closing_target_ends_relay_with_silent_client() {
+        for reset in [false, true] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                if reset {
+                    socket.set_linger(Some(std::time::Duration::ZERO)).unwrap();
+                }
+            });
+            let target_stream = TcpStream::connect(addr).await.unwrap();
+
+            // The client stays open and never sends, so only the target's
+            // close can end the relay
+            let (proxy_side, _client) = duplex(1024);
+            let (reader, writer) = tokio::io::split(proxy_side);
+            let mut reader = BufReader::new(reader);
+            let mut writer = BufWriter::new(writer);
+            let config = ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]));
+
+            let close_reason = tokio::time::timeout(
+                std::time::Duration::from_secs(1),
+                handle_data_transfer(&mut reader, &mut writer, target_stream, &config, 0),
+            )
+            .await
+            .expect("relay should end as soon as the target closes")
+            .unwrap();
+            let expected = if reset {
+                CloseReason::TargetReset
+            } else {
+                CloseReason::TargetClosed
+            };
+            assert_eq!(close_reason, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_enabled_once_relay_is_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let target = tokio::spawn(async move { listener.accept().await.unwrap().0 });
+        let target_stream = TcpStream::connect(addr).await.unwrap();
+        let mut target = target.await.unwrap();
+        let probe = SockRef::from(&target_stream).try_clone().unwrap();
+
+        let (proxy_side, mut client) = duplex(1024);
+        let (reader, writer) = tokio::io::split(proxy_side);
+        let config = ConnectionConfig {
+            idle_keepalive: Some(std::time::Duration::from_millis(100)),
+            ..ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+        };
+        let relay = tokio::spawn(async move {
+            let mut reader = BufReader::new(reader);
+            let mut writer = BufWriter::new(writer);
+            handle_data_transfer(&mut reader, &mut writer, target_stream, &config, 0).await
+        });
+
+        assert!(!probe.keepalive().unwrap());
+        tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+        assert!(probe.keepalive().unwrap());
+
+        // Traffic switches it back off
+        target.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert!(!probe.keepalive().unwrap());
+
+        drop(target);
+        relay.await.unwrap().unwrap();
+    }
+
+    fn connect_request(dest_addrs: Vec<IpAddr>, dest_port: u16) -> SocksRequest {
+        SocksRequest {
+            version: SOCKS5_VERSION,
+            command: Command::CONNECT,
+            reserved: RESERVED,
+            address_type: AddressType::DOMAIN_NAME,
+            dest_addr: dest_addrs
+                .first()
+                .copied()
+                .unwrap_or(Ipv4Addr::UNSPECIFIED.into()),
+            dest_addrs,
+            dest_port,
+            dest_domain: Some("dual-stack.test".to_string()),
+        }
+    }
+
+    // Binds the IPv6 loopback on `port` and fills its accept queue, so further
+    // connects there stay pending like a black-holed route
+    async fn stalled_ipv6_listener(port: u16) -> (socket2::Socket, Vec<TcpStream>) {
+        let addr = SocketAddr::from((Ipv6Addr::LOCALHOST, port));
+        let socket = socket2::Socket::new(
+            socket2::Domain::IPV6,
+            socket2::Type::STREAM,
+            Some(socket2::Protocol::TCP),
+        )
+        .unwrap();
+        socket.set_only_v6(true).unwrap();
+        socket.bind(&addr.into()).unwrap();
+        socket.listen(0).unwrap();
+
+        let mut queued = Vec::new();
+        while let Ok(Ok(stream)) = tokio::time::timeout(
+            std::time::Duration::from_millis(100),
+            TcpStream::connect(addr),
+        )
+        .await
+        {
+            queued.push(stream);
+        }
+        (socket, queued)
+    }
+
+    #[tokio::test]
+    async fn test_connect_falls_back_to_ipv4_after_fallback_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _stalled = stalled_ipv6_listener(port).await;
+        let target = tokio::spawn(async move { listener.accept().await.unwrap() });
+
+        let fallback_delay = std::time::Duration::from_millis(300);
+        let config = ConnectionConfig {
+            ipv6_available: true,
+            fallback_delay,
+            connect_deadline: std::time::Duration::from_secs(5),
+            ..ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+        };
+        let request = connect_request(
+            vec![Ipv6Addr::LOCALHOST.into(), Ipv4Addr::LOCALHOST.into()],
+            port,
+        );
+
+        let (proxy_side, mut client) = duplex(1024);
+        let start = tokio::time::Instant::now();
+        let proxy = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(proxy_side);
+            let mut reader = BufReader::new(reader);
+            let mut writer = BufWriter::new(writer);
+            let client_addr = "127.0.0.1:5000".parse().unwrap();
+            handle_command(
+                request,
+                client_addr,
+                &mut reader,
+                &mut writer,
+                &config,
+                &ConnectionContext::new(),
+            )
+            .await
+        });
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(reply[1], Reply::SUCCESS);
+        assert_eq!(reply[3], AddressType::IPV4);
+        assert!(elapsed >= fallback_delay, "{elapsed:?}");
+        assert!(elapsed < std::time::Duration::from_secs(2), "{elapsed:?}");
+
+        // The IPv4 attempt is the one that got through
+        let (_, peer) = target.await.unwrap();
+        assert!(peer.is_ipv4());
+        drop(client);
+        proxy.abort();
+    }
+
+    #[tokio::test]
+    async fn test_domain_without_usable_addresses_is_host_unreachable() {
+        let config = ConnectionConfig {
+            ipv6_available: false,
+            ..ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+        };
+        // Resolved to IPv6 only, which this host cannot reach
+        let request = connect_request(vec![Ipv6Addr::LOCALHOST.into()], 80);
+
+        let (proxy_side, mut client) = duplex(1024);
+        let (reader, writer) = tokio::io::split(proxy_side);
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(writer);
+        let result = handle_command(
+            request,
+            "127.0.0.1:5000".parse().unwrap(),
+            &mut reader,
+            &mut writer,
+            &config,
+            &ConnectionContext::new(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(result.reply_code(), Reply::HOST_UNREACHABLE);
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::HOST_UNREACHABLE);
+    }
+
+    #[tokio::test]
+    async fn test_target_limit_counts_the_dialed_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _target = tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let limiter = Arc::new(TargetLimiter::new(1));
+        let config = ConnectionConfig {
+            target_limiter: Some(limiter.clone()),
+            ..ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+        };
+        let refused = SocketAddr::from(([127, 0, 0, 2], port));
+        let listening = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+
+        // Run one CONNECT and keep its relay open, returning the reply code
+        let connect = |request: SocksRequest| {
+            let config = config.clone();
+            async move {
+                let (proxy_side, mut client) = duplex(1024);
+                tokio::spawn(async move {
+                    let (reader, writer) = tokio::io::split(proxy_side);
+                    let mut reader = BufReader::new(reader);
+                    let mut writer = BufWriter::new(writer);
+                    handle_command(
+                        request,
+                        "127.0.0.1:5000".parse().unwrap(),
+                        &mut reader,
+                        &mut writer,
+                        &config,
+                        &ConnectionContext::new(),
+                    )
+                    .await
+                });
+                let mut reply = [0u8; 10];
+                client.read_exact(&mut reply).await.unwrap();
+                (reply[1], client)
+            }
+        };
+
+        // The domain's first address refuses, the second one is dialed
+        let request = connect_request(vec![refused.ip(), listening.ip()], port);
+        let (reply, _relay) = connect(request).await;
+        assert_eq!(reply, Reply::SUCCESS);
+        assert_eq!(limiter.active(listening), 1);
+        assert_eq!(limiter.active(refused), 0);
+
+        let (reply, _client) = connect(connect_request(vec![listening.ip()], port)).await;
+        assert_eq!(reply, Reply::CONNECTION_NOT_ALLOWED);
+    }
 }
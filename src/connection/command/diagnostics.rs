@@ -0,0 +1,113 @@
+use std::{io, net::SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tracing::debug;
+
+use crate::config::ConnectionConfig;
+use crate::connection::{ERROR_ADDR, ERROR_PORT, command::CommandResult, request::SocksRequest};
+
+// Non-standard extension for embedders: after a success reply the proxy sends
+// a u16 length followed by `key=value` lines describing what it supports.
+// Only reachable when built with the `diagnostics` feature and enabled with
+// --diagnostics-command, since it lets anyone identify the proxy.
+pub async fn handle_command<R, W>(
+    client_request: SocksRequest,
+    client_addr: SocketAddr,
+    _client_reader: &mut BufReader<R>,
+    client_writer: &mut BufWriter<W>,
+    config: &ConnectionConfig,
+) -> io::Result<CommandResult>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    debug!(
+        "[{client_addr}] Handling diagnostics request: {:?}",
+        client_request
+    );
+
+    let result = CommandResult::success(ERROR_ADDR.into(), ERROR_PORT);
+    result.send_reply(client_writer).await?;
+
+    let capabilities = capabilities(config);
+    client_writer
+        .write_all(&(capabilities.len() as u16).to_be_bytes())
+        .await?;
+    client_writer.write_all(capabilities.as_bytes()).await?;
+    client_writer.flush().await?;
+
+    Ok(result)
+}
+
+fn capabilities(config: &ConnectionConfig) -> String {
+    let capabilities = config.capabilities();
+    let commands: Vec<&str> = capabilities
+        .commands
+        .iter()
+        .map(|command| command.name())
+        .collect();
+    let methods: Vec<String> = capabilities
+        .auth_methods
+        .iter()
+        .map(|method| format!("0x{:02X}", method))
+        .collect();
+
+    format!(
+        "version={}\ncommands={}\nauth_methods={}\ndns={}\nipv6_targets={}\n",
+        env!("CARGO_PKG_VERSION"),
+        commands.join(","),
+        methods.join(","),
+        !config.no_dns,
+        config.ipv6_available,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ProxyConfig;
+    use crate::connection::{AddressType, command::Command, reply::Reply};
+    use clap::Parser;
+    use std::net::{IpAddr, Ipv4Addr};
+    use tokio::io::AsyncReadExt;
+
+    #[tokio::test]
+    async fn test_diagnostics_reports_capabilities() {
+        let request = SocksRequest {
+            version: 0x05,
+            command: Command::DIAGNOSTICS,
+            reserved: 0x00,
+            address_type: AddressType::IPV4,
+            dest_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            dest_addrs: vec![IpAddr::V4(Ipv4Addr::UNSPECIFIED)],
+            dest_port: 0,
+            dest_domain: None,
+        };
+        let config = ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks", "--no-dns"]));
+        let (server, mut client) = tokio::io::duplex(1024);
+        let (reader, _) = tokio::io::duplex(1);
+        let mut reader = BufReader::new(reader);
+        let mut writer = BufWriter::new(server);
+
+        let result = handle_command(
+            request,
+            "127.0.0.1:1".parse().unwrap(),
+            &mut reader,
+            &mut writer,
+            &config,
+        )
+        .await
+        .unwrap();
+        assert!(result.is_success());
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::SUCCESS);
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut body = vec![0u8; len];
+        client.read_exact(&mut body).await.unwrap();
+        let body = String::from_utf8(body).unwrap();
+        assert!(body.starts_with(&format!("version={}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(body.contains("auth_methods=0x00\n"));
+        assert!(body.contains("dns=false\n"));
+    }
+}
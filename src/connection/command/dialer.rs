@@ -0,0 +1,276 @@
+use std::{future::Future, io, net::SocketAddr, time::Duration};
+use tokio::{task::JoinSet, time::timeout};
+use tracing::debug;
+
+/// Connects to the first reachable address, racing address families.
+///
+/// Addresses are tried alternating between families, starting with the family
+/// of the first one. A new attempt starts as soon as any attempt fails or the
+/// latest one has been pending for `fallback_delay`; the whole race is bounded
+/// by `deadline`.
+pub async fn connect_dual_stack<F, Fut, T>(
+    addrs: &[SocketAddr],
+    fallback_delay: Duration,
+    deadline: Duration,
+    connect: F,
+) -> io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    // Filtering upstream can leave nothing to dial; report it as an
+    // unreachable host rather than racing zero attempts
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no addresses left to connect to",
+        ));
+    }
+
+    let mut pending = interleave_families(addrs).into_iter();
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    let race = async {
+        loop {
+            if attempts.is_empty() {
+                match pending.next() {
+                    Some(addr) => {
+                        attempts.spawn(connect(addr));
+                    }
+                    None => {
+                        return Err(last_error.unwrap_or_else(|| {
+                            io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect")
+                        }));
+                    }
+                }
+            }
+
+            let finished = if pending.len() > 0 {
+                tokio::select! {
+                    Some(result) = attempts.join_next() => result,
+                    _ = tokio::time::sleep(fallback_delay) => {
+                        if let Some(addr) = pending.next() {
+                            debug!("Attempt still pending, racing {}", addr);
+                            attempts.spawn(connect(addr));
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                match attempts.join_next().await {
+                    Some(result) => result,
+                    None => continue,
+                }
+            };
+
+            match finished {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    debug!("Connect attempt failed: {}", e);
+                    last_error = Some(e);
+                }
+                Err(e) => last_error = Some(io::Error::other(e)),
+            }
+            // A failure frees its slot in the race right away, even while
+            // other attempts are still pending
+            if let Some(addr) = pending.next() {
+                debug!("Attempt failed, racing {}", addr);
+                attempts.spawn(connect(addr));
+            }
+        }
+    };
+
+    match timeout(deadline, race).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connect deadline of {:?} exceeded", deadline),
+        )),
+    }
+}
+
+// Reorders addresses to alternate families, keeping each family's own order
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .iter()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+
+    let mut ordered = Vec::with_capacity(addrs.len());
+    preferred.reverse();
+    other.reverse();
+    while let Some(addr) = preferred.pop() {
+        ordered.push(addr);
+        if let Some(addr) = other.pop() {
+            ordered.push(addr);
+        }
+    }
+    ordered.extend(other.into_iter().rev());
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{error::SocksError, reply::Reply};
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        time::Instant,
+    };
+
+    const BROKEN_V6: &str = "[2001:db8::1]:80";
+
+    // Connects v4 addresses to `target`; v6 attempts hang like a blackholed route
+    fn broken_v6(
+        target: SocketAddr,
+    ) -> impl Fn(SocketAddr) -> std::pin::Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>
+    {
+        move |addr| {
+            if addr.is_ipv6() {
+                Box::pin(std::future::pending())
+            } else {
+                Box::pin(TcpStream::connect(target))
+            }
+        }
+    }
+
+    #[test]
+    fn test_interleave_families() {
+        let addrs: Vec<SocketAddr> = ["[::1]:1", "[::2]:1", "[::3]:1", "10.0.0.1:1"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        let ordered = interleave_families(&addrs);
+        assert_eq!(ordered, vec![addrs[0], addrs[3], addrs[1], addrs[2]]);
+        assert!(interleave_families(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_v4_connects_after_fallback_delay() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let addrs = [BROKEN_V6.parse().unwrap(), target];
+
+        for delay_ms in [100, 300] {
+            let delay = Duration::from_millis(delay_ms);
+            let start = Instant::now();
+            let stream =
+                connect_dual_stack(&addrs, delay, Duration::from_secs(5), broken_v6(target))
+                    .await
+                    .unwrap();
+            let elapsed = start.elapsed();
+
+            assert_eq!(stream.peer_addr().unwrap(), target);
+            assert!(elapsed >= delay, "{elapsed:?}");
+            assert!(elapsed < delay + Duration::from_millis(200), "{elapsed:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_attempt_falls_back_immediately() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let addrs = ["[::1]:1".parse().unwrap(), target];
+
+        let start = Instant::now();
+        connect_dual_stack(
+            &addrs,
+            Duration::from_secs(5),
+            Duration::from_secs(5),
+            |addr| async move {
+                if addr.is_ipv6() {
+                    Err(io::Error::from(io::ErrorKind::ConnectionRefused))
+                } else {
+                    TcpStream::connect(addr).await
+                }
+            },
+        )
+        .await
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_failure_starts_next_attempt_while_others_pend() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap();
+        let refused: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let addrs = [BROKEN_V6.parse().unwrap(), refused, target];
+
+        let delay = Duration::from_millis(300);
+        let start = Instant::now();
+        let stream = connect_dual_stack(&addrs, delay, Duration::from_secs(5), move |addr| {
+            let connect = broken_v6(target);
+            async move {
+                if addr == refused {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+                }
+                connect(addr).await
+            }
+        })
+        .await
+        .unwrap();
+        let elapsed = start.elapsed();
+
+        // The IPv6 attempt is still hanging when the refusal comes in; the
+        // last address must not wait for another fallback delay
+        assert_eq!(stream.peer_addr().unwrap(), target);
+        assert!(
+            elapsed < delay * 2 - Duration::from_millis(100),
+            "{elapsed:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_deadline_bounds_the_attempt() {
+        let target: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        let addrs = [BROKEN_V6.parse().unwrap()];
+
+        let start = Instant::now();
+        let err = connect_dual_stack(
+            &addrs,
+            Duration::from_millis(50),
+            Duration::from_millis(150),
+            broken_v6(target),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_reports_last_error_when_all_fail() {
+        let addrs = ["127.0.0.1:1".parse().unwrap()];
+        let err = connect_dual_stack::<_, _, TcpStream>(
+            &addrs,
+            Duration::from_millis(50),
+            Duration::from_secs(1),
+            |_| async { Err(io::Error::from(io::ErrorKind::ConnectionRefused)) },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn test_empty_address_list_is_unreachable() {
+        let err = connect_dual_stack(
+            &[],
+            Duration::from_millis(50),
+            Duration::from_secs(1),
+            |addr| async move { TcpStream::connect(addr).await },
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        assert_eq!(
+            SocksError::ConnectionFailed(err.kind()).to_reply_code(),
+            Reply::HOST_UNREACHABLE
+        );
+    }
+}
@@ -1,13 +1,20 @@
 pub mod bind;
 pub mod connect;
+#[cfg(feature = "diagnostics")]
+pub mod diagnostics;
+pub mod dialer;
+pub mod relay;
 pub mod udp_associate;
 
 use std::{io, net::SocketAddr};
 use tokio::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
+use tracing::info;
 
+use crate::ConnectionContext;
+use crate::config::ConnectionConfig;
 use crate::connection::{
-    AddressType, ERROR_ADDR, ERROR_PORT, error::SocksError, reply::Reply, request::SocksRequest,
-    send_reply,
+    AddressType, ERROR_ADDR, ERROR_PORT, close_reason::CloseReason, error::SocksError,
+    reply::Reply, request::SocksRequest, send_reply,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -16,12 +23,24 @@ pub enum Command {
     Connect = 0x01,
     Bind = 0x02,
     UdpAssociate = 0x03,
+    #[cfg(feature = "diagnostics")]
+    Diagnostics = 0xF0,
 }
 
 impl Command {
     pub const CONNECT: u8 = Self::Connect as u8;
     pub const BIND: u8 = Self::Bind as u8;
     pub const UDP_ASSOCIATE: u8 = Self::UdpAssociate as u8;
+    #[cfg(feature = "diagnostics")]
+    pub const DIAGNOSTICS: u8 = Self::Diagnostics as u8;
+
+    // Commands this build actually serves; UDP ASSOCIATE is always refused
+    pub const SERVED: &[Command] = &[
+        Command::Connect,
+        Command::Bind,
+        #[cfg(feature = "diagnostics")]
+        Command::Diagnostics,
+    ];
 
     pub async fn execute<R, W>(
         &self,
@@ -29,7 +48,8 @@ impl Command {
         client_addr: SocketAddr,
         client_reader: &mut BufReader<R>,
         client_writer: &mut BufWriter<W>,
-        tcp_nodelay: bool,
+        config: &ConnectionConfig,
+        context: &ConnectionContext,
     ) -> io::Result<CommandResult>
     where
         R: AsyncRead + Unpin,
@@ -42,13 +62,21 @@ impl Command {
                     client_addr,
                     client_reader,
                     client_writer,
-                    tcp_nodelay,
+                    config,
+                    context,
                 )
                 .await
             }
             Command::Bind => {
-                bind::handle_command(client_request, client_addr, client_reader, client_writer)
-                    .await
+                bind::handle_command(
+                    client_request,
+                    client_addr,
+                    client_reader,
+                    client_writer,
+                    config,
+                    context,
+                )
+                .await
             }
             Command::UdpAssociate => {
                 udp_associate::handle_command(
@@ -59,6 +87,17 @@ impl Command {
                 )
                 .await
             }
+            #[cfg(feature = "diagnostics")]
+            Command::Diagnostics => {
+                diagnostics::handle_command(
+                    client_request,
+                    client_addr,
+                    client_reader,
+                    client_writer,
+                    config,
+                )
+                .await
+            }
         }
     }
 
@@ -67,24 +106,68 @@ impl Command {
             0x01 => Some(Command::Connect),
             0x02 => Some(Command::Bind),
             0x03 => Some(Command::UdpAssociate),
+            #[cfg(feature = "diagnostics")]
+            0xF0 => Some(Command::Diagnostics),
             _ => None,
         }
     }
 
+    // Disabled commands, and extension commands unless explicitly enabled,
+    // answer like unknown ones
+    pub fn is_enabled(&self, config: &ConnectionConfig) -> bool {
+        match self {
+            Command::Bind => config.enable_bind,
+            #[cfg(feature = "diagnostics")]
+            Command::Diagnostics => config.diagnostics_command,
+            _ => true,
+        }
+    }
+
     pub fn name(&self) -> &'static str {
         match self {
             Command::Connect => "CONNECT",
             Command::Bind => "BIND",
             Command::UdpAssociate => "UDP_ASSOCIATE",
+            #[cfg(feature = "diagnostics")]
+            Command::Diagnostics => "DIAGNOSTICS",
         }
     }
 }
 
+// Concise info-level line for an accepted command, enabled by --log-accepted
+pub(crate) fn log_accepted(
+    config: &ConnectionConfig,
+    context: &ConnectionContext,
+    client_addr: SocketAddr,
+    command: Command,
+    addr: SocketAddr,
+) {
+    if !config.log_accepted {
+        return;
+    }
+    let method = context
+        .negotiated_method
+        .map_or("unknown", |method| method.display_name());
+    let target = match &context.requested_domain {
+        Some(domain) => format!("{} ({})", domain, addr),
+        None => addr.to_string(),
+    };
+    info!(
+        "Accepted {} from {} to {} (method: {})",
+        command.name(),
+        client_addr,
+        target,
+        method
+    );
+}
+
 #[derive(Debug)]
 pub struct CommandResult {
     pub reply_code: u8,
     pub bind_addr: std::net::IpAddr,
-    pub bind_port: u16
+    pub bind_port: u16,
+    // Set by commands that relay data once the relay ends
+    pub close_reason: Option<CloseReason>,
 }
 
 impl CommandResult {
@@ -92,7 +175,8 @@ impl CommandResult {
         Self {
             reply_code: Reply::SUCCESS,
             bind_addr,
-            bind_port
+            bind_port,
+            close_reason: None,
         }
     }
 
@@ -101,6 +185,20 @@ impl CommandResult {
             reply_code,
             bind_addr: std::net::IpAddr::from(ERROR_ADDR),
             bind_port: ERROR_PORT,
+            close_reason: None,
+        }
+    }
+
+    pub fn with_close_reason(mut self, close_reason: CloseReason) -> Self {
+        self.close_reason = Some(close_reason);
+        self
+    }
+
+    pub fn close_reason(&self) -> CloseReason {
+        match self.close_reason {
+            Some(reason) => reason,
+            None if self.is_error() => CloseReason::RequestRejected(self.reply_code),
+            None => CloseReason::Completed,
         }
     }
 
@@ -191,6 +289,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_command_clone() {
         let cmd = Command::Connect;
         let cloned = cmd.clone();
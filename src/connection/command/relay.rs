@@ -0,0 +1,888 @@
+use std::{
+    future::{Future, poll_fn},
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, sleep},
+};
+use tracing::debug;
+
+use crate::buffer_pool::{BufferBudget, BufferPool, PooledBuffer};
+use crate::config::ConnectionConfig;
+
+// Chunks each direction may move per poll before the task yields back to the
+// runtime, so a busy relay doesn't hog its worker thread either
+const MAX_ROUNDS_PER_POLL: usize = 16;
+
+/// Smallest per-direction buffer a relay shrinks to under a buffer budget.
+pub const MIN_RELAY_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayEnd {
+    ClientToTarget,
+    TargetToClient,
+    // Neither direction moved data for the idle timeout
+    Idle,
+    // Neither side sent anything before the first-byte timeout
+    NoData,
+}
+
+#[derive(Debug, Clone)]
+pub struct RelayOptions {
+    pub buffer_size: usize,
+    pub idle_timeout: Option<Duration>,
+    // Unlike the idle timeout this never resets, it only covers the first byte
+    pub first_byte_timeout: Option<Duration>,
+    // Keep relaying the other direction after one side sends EOF, instead of
+    // ending the relay at the first EOF
+    pub half_close: bool,
+    // Idle time after which the relay's idle hook fires, see `relay_with_idle_hook`
+    pub idle_keepalive: Option<Duration>,
+    // Borrow the two chunk buffers from here instead of allocating them
+    pub buffer_pool: Option<Arc<BufferPool>>,
+    // Both chunk buffers are charged against this before the relay starts
+    pub buffer_budget: Option<Arc<BufferBudget>>,
+}
+
+impl Default for RelayOptions {
+    fn default() -> Self {
+        Self {
+            buffer_size: 8 * 1024,
+            idle_timeout: None,
+            first_byte_timeout: None,
+            half_close: false,
+            idle_keepalive: None,
+            buffer_pool: None,
+            buffer_budget: None,
+        }
+    }
+}
+
+impl From<&ConnectionConfig> for RelayOptions {
+    fn from(config: &ConnectionConfig) -> Self {
+        Self {
+            buffer_size: config.buffer_size,
+            idle_timeout: config.idle_timeout,
+            first_byte_timeout: config.first_byte_timeout,
+            half_close: config.half_close,
+            idle_keepalive: config.idle_keepalive,
+            buffer_pool: config.buffer_pool.clone(),
+            buffer_budget: config.buffer_budget.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct RelayStats {
+    // Direction that ended the relay: the first EOF, the failing side, or a timeout
+    pub end: RelayEnd,
+    pub client_to_target: u64,
+    pub target_to_client: u64,
+    pub error: Option<io::Error>,
+}
+
+impl RelayStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.client_to_target + self.target_to_client
+    }
+}
+
+/// Relays both directions until EOF, an error, or a timeout.
+///
+/// Each poll moves at most one chunk per direction in turn, so a saturated
+/// direction cannot starve the other. With `half_close` set, an EOF is passed
+/// on as a write shutdown and the relay waits for the other direction's EOF;
+/// a side that stops accepting writes likewise only ends its own direction.
+/// Readers only report EOF once their buffered bytes are consumed, so data a
+/// `BufReader` read ahead during the request is relayed before the close.
+pub async fn relay<CR, CW, TR, TW>(
+    client_reader: &mut CR,
+    client_writer: &mut CW,
+    target_reader: &mut TR,
+    target_writer: &mut TW,
+    options: &RelayOptions,
+) -> RelayStats
+where
+    CR: AsyncRead + Unpin + ?Sized,
+    CW: AsyncWrite + Unpin + ?Sized,
+    TR: AsyncRead + Unpin + ?Sized,
+    TW: AsyncWrite + Unpin + ?Sized,
+{
+    relay_with_idle_hook(
+        client_reader,
+        client_writer,
+        target_reader,
+        target_writer,
+        options,
+        |_| {},
+    )
+    .await
+}
+
+/// Same as [`relay`], but calls `on_idle(true)` once neither direction has
+/// moved data for `options.idle_keepalive`, and `on_idle(false)` when data
+/// flows again after that. Unlike the idle timeout this never ends the relay.
+///
+/// With a buffer budget, the relay first waits until it can charge at least
+/// [`MIN_RELAY_BUFFER`] per direction, and uses smaller buffers when the full
+/// size does not fit.
+pub async fn relay_with_idle_hook<CR, CW, TR, TW, F>(
+    client_reader: &mut CR,
+    client_writer: &mut CW,
+    target_reader: &mut TR,
+    target_writer: &mut TW,
+    options: &RelayOptions,
+    mut on_idle: F,
+) -> RelayStats
+where
+    CR: AsyncRead + Unpin + ?Sized,
+    CW: AsyncWrite + Unpin + ?Sized,
+    TR: AsyncRead + Unpin + ?Sized,
+    TW: AsyncWrite + Unpin + ?Sized,
+    F: FnMut(bool),
+{
+    let wanted = options.buffer_size.max(1);
+    // Held until the relay ends, so the bytes are credited back with the buffers
+    let charge = match &options.buffer_budget {
+        Some(budget) => Some(budget.charge(2 * wanted, 2 * MIN_RELAY_BUFFER).await),
+        None => None,
+    };
+    let buffer_size = charge.as_ref().map_or(wanted, |charge| charge.bytes() / 2);
+    let mut upstream = Pipe::new(options, buffer_size);
+    let mut downstream = Pipe::new(options, buffer_size);
+    let mut first_eof = None;
+    let mut idle = options
+        .idle_timeout
+        .map(|timeout| (timeout, Box::pin(sleep(timeout))));
+    let mut first_byte = options
+        .first_byte_timeout
+        .map(|timeout| Box::pin(sleep(timeout)));
+    let mut idle_hook = options
+        .idle_keepalive
+        .map(|threshold| (threshold, Box::pin(sleep(threshold))));
+    let mut idle_signalled = false;
+
+    poll_fn(|cx| {
+        let (end, error) = 'relay: {
+            for _ in 0..MAX_ROUNDS_PER_POLL {
+                let moved_before = upstream.transferred + downstream.transferred;
+
+                let up = upstream.poll_step(cx, &mut *client_reader, &mut *target_writer);
+                let mut progressed = matches!(up, Step::Progress);
+                match up {
+                    Step::Done(Ok(())) => {
+                        first_eof.get_or_insert(RelayEnd::ClientToTarget);
+                        if !options.half_close || downstream.finished {
+                            break 'relay (first_eof.unwrap(), None);
+                        }
+                    }
+                    // The target stopped reading but may still be sending
+                    Step::WriteFailed(e) if options.half_close && !downstream.finished => {
+                        debug!("Target stopped accepting data ({}), relaying its side", e);
+                        first_eof.get_or_insert(RelayEnd::ClientToTarget);
+                    }
+                    Step::Done(Err(e)) | Step::WriteFailed(e) => {
+                        break 'relay (RelayEnd::ClientToTarget, Some(e));
+                    }
+                    Step::Progress | Step::Blocked => {}
+                }
+
+                let down = downstream.poll_step(cx, &mut *target_reader, &mut *client_writer);
+                progressed |= matches!(down, Step::Progress);
+                match down {
+                    Step::Done(Ok(())) => {
+                        first_eof.get_or_insert(RelayEnd::TargetToClient);
+                        if !options.half_close || upstream.finished {
+                            break 'relay (first_eof.unwrap(), None);
+                        }
+                    }
+                    Step::WriteFailed(e) if options.half_close && !upstream.finished => {
+                        debug!("Client stopped accepting data ({}), relaying its side", e);
+                        first_eof.get_or_insert(RelayEnd::TargetToClient);
+                    }
+                    Step::Done(Err(e)) | Step::WriteFailed(e) => {
+                        break 'relay (RelayEnd::TargetToClient, Some(e));
+                    }
+                    Step::Progress | Step::Blocked => {}
+                }
+
+                if upstream.transferred + downstream.transferred != moved_before {
+                    first_byte = None;
+                    if let Some((timeout, timer)) = &mut idle {
+                        timer.as_mut().reset(Instant::now() + *timeout);
+                    }
+                    if let Some((threshold, timer)) = &mut idle_hook {
+                        timer.as_mut().reset(Instant::now() + *threshold);
+                    }
+                    if idle_signalled {
+                        idle_signalled = false;
+                        on_idle(false);
+                    }
+                }
+
+                if !progressed {
+                    if let Some((_, timer)) = &mut idle_hook
+                        && !idle_signalled
+                        && timer.as_mut().poll(cx).is_ready()
+                    {
+                        idle_signalled = true;
+                        on_idle(true);
+                    }
+                    if let Some(timer) = &mut first_byte
+                        && timer.as_mut().poll(cx).is_ready()
+                    {
+                        break 'relay (RelayEnd::NoData, None);
+                    }
+                    if let Some((_, timer)) = &mut idle
+                        && timer.as_mut().poll(cx).is_ready()
+                    {
+                        break 'relay (RelayEnd::Idle, None);
+                    }
+                    return Poll::Pending;
+                }
+            }
+
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        };
+
+        Poll::Ready(RelayStats {
+            end,
+            client_to_target: upstream.transferred,
+            target_to_client: downstream.transferred,
+            error,
+        })
+    })
+    .await
+}
+
+#[derive(Debug)]
+enum Step {
+    Progress,
+    Blocked,
+    Done(io::Result<()>),
+    // Only the writer failed, the reader may still have data for the other way
+    WriteFailed(io::Error),
+}
+
+struct Pipe {
+    buf: PooledBuffer,
+    pos: usize,
+    cap: usize,
+    transferred: u64,
+    read_done: bool,
+    need_flush: bool,
+    shutdown_on_eof: bool,
+    finished: bool,
+}
+
+impl Pipe {
+    fn new(options: &RelayOptions, buffer_size: usize) -> Self {
+        let buf = match &options.buffer_pool {
+            Some(pool) if pool.buffer_size() == buffer_size => pool.checkout(),
+            _ => PooledBuffer::unpooled(buffer_size),
+        };
+        Self {
+            buf,
+            pos: 0,
+            cap: 0,
+            transferred: 0,
+            read_done: false,
+            need_flush: false,
+            shutdown_on_eof: options.half_close,
+            finished: false,
+        }
+    }
+
+    fn poll_step<R, W>(&mut self, cx: &mut Context<'_>, reader: &mut R, writer: &mut W) -> Step
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.finished {
+            return Step::Blocked;
+        }
+        let step = self.step(cx, reader, writer);
+        if matches!(step, Step::Done(_) | Step::WriteFailed(_)) {
+            self.finished = true;
+        }
+        step
+    }
+
+    // Moves at most one chunk from reader to writer
+    fn step<R, W>(&mut self, cx: &mut Context<'_>, reader: &mut R, writer: &mut W) -> Step
+    where
+        R: AsyncRead + Unpin + ?Sized,
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        if self.pos == self.cap && !self.read_done {
+            let mut read_buf = ReadBuf::new(&mut self.buf);
+            match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) => {
+                    let n = read_buf.filled().len();
+                    if n == 0 {
+                        self.read_done = true;
+                    }
+                    self.pos = 0;
+                    self.cap = n;
+                }
+                Poll::Ready(Err(e)) => return Step::Done(Err(e)),
+                Poll::Pending => {
+                    // Nothing new to send, push out what the writer is holding
+                    if self.need_flush {
+                        match Pin::new(&mut *writer).poll_flush(cx) {
+                            Poll::Ready(Ok(())) => self.need_flush = false,
+                            Poll::Ready(Err(e)) => return Step::WriteFailed(e),
+                            Poll::Pending => {}
+                        }
+                    }
+                    return Step::Blocked;
+                }
+            }
+        }
+
+        while self.pos < self.cap {
+            match Pin::new(&mut *writer).poll_write(cx, &self.buf[self.pos..self.cap]) {
+                Poll::Ready(Ok(0)) => {
+                    return Step::WriteFailed(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "write zero bytes into writer",
+                    ));
+                }
+                Poll::Ready(Ok(n)) => {
+                    self.pos += n;
+                    self.transferred += n as u64;
+                    self.need_flush = true;
+                }
+                Poll::Ready(Err(e)) => return Step::WriteFailed(e),
+                Poll::Pending => return Step::Blocked,
+            }
+        }
+
+        if self.read_done {
+            // Shutdown flushes as well, so it stands in for the final flush
+            let done = if self.shutdown_on_eof {
+                Pin::new(&mut *writer).poll_shutdown(cx)
+            } else {
+                Pin::new(&mut *writer).poll_flush(cx)
+            };
+            return match done {
+                Poll::Ready(Err(e)) => Step::WriteFailed(e),
+                Poll::Ready(Ok(())) => Step::Done(Ok(())),
+                Poll::Pending => Step::Blocked,
+            };
+        }
+
+        Step::Progress
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, duplex, split},
+        time::timeout,
+    };
+
+    fn options(buffer_size: usize) -> RelayOptions {
+        RelayOptions {
+            buffer_size,
+            ..RelayOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_reports_client_eof() {
+        let (mut client, proxy_client) = duplex(1024);
+        let (proxy_target, mut target) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let stats = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options(64),
+        )
+        .await;
+        assert_eq!(stats.end, RelayEnd::ClientToTarget);
+        assert!(stats.error.is_none());
+        assert_eq!(stats.client_to_target, 5);
+        assert_eq!(stats.target_to_client, 0);
+
+        let mut buf = [0u8; 5];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_relay_drains_bufreader_before_client_eof() {
+        let (mut client, proxy_client) = duplex(1024);
+        let (proxy_target, mut target) = duplex(1024);
+        let (client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+
+        client.write_all(b"request|final burst").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        // Request parsing leaves the rest of the burst sitting in the BufReader
+        let mut client_reader = BufReader::new(client_reader);
+        let mut request = [0u8; 8];
+        client_reader.read_exact(&mut request).await.unwrap();
+        assert_eq!(client_reader.fill_buf().await.unwrap(), b"final burst");
+
+        let stats = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options(4),
+        )
+        .await;
+        assert_eq!(stats.end, RelayEnd::ClientToTarget);
+        assert_eq!(stats.client_to_target, 11);
+
+        let mut buf = [0u8; 11];
+        target.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"final burst");
+    }
+
+    #[tokio::test]
+    async fn test_relay_reports_target_eof() {
+        let (mut client, proxy_client) = duplex(1024);
+        let (proxy_target, mut target) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+
+        target.write_all(b"banner").await.unwrap();
+        target.shutdown().await.unwrap();
+
+        let stats = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options(4),
+        )
+        .await;
+        assert_eq!(stats.end, RelayEnd::TargetToClient);
+        assert!(stats.error.is_none());
+        assert_eq!(stats.target_to_client, 6);
+
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"banner");
+    }
+
+    #[tokio::test]
+    async fn test_relay_reports_target_error() {
+        let (_client, proxy_client) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let mut failing = tokio_test::io::Builder::new()
+            .read_error(io::Error::new(io::ErrorKind::ConnectionReset, "reset"))
+            .build();
+        let mut target_writer = tokio::io::sink();
+
+        let stats = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut failing,
+            &mut target_writer,
+            &options(64),
+        )
+        .await;
+        assert_eq!(stats.end, RelayEnd::TargetToClient);
+        assert_eq!(stats.error.unwrap().kind(), io::ErrorKind::ConnectionReset);
+    }
+
+    #[tokio::test]
+    async fn test_relay_half_close_keeps_other_direction_open() {
+        let (client, proxy_client) = duplex(1024);
+        let (proxy_target, target) = duplex(1024);
+
+        let relay_task = tokio::spawn(async move {
+            let (mut client_reader, mut client_writer) = split(proxy_client);
+            let (mut target_reader, mut target_writer) = split(proxy_target);
+            let options = RelayOptions {
+                half_close: true,
+                ..options(64)
+            };
+            relay(
+                &mut client_reader,
+                &mut client_writer,
+                &mut target_reader,
+                &mut target_writer,
+                &options,
+            )
+            .await
+        });
+
+        let (mut client_reader, mut client_writer) = split(client);
+        let (mut target_reader, mut target_writer) = split(target);
+
+        // Client finishes its request, the target sees EOF and only then answers
+        client_writer.write_all(b"request").await.unwrap();
+        client_writer.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        target_reader.read_to_end(&mut request).await.unwrap();
+        assert_eq!(request, b"request");
+        assert!(!relay_task.is_finished());
+
+        target_writer.write_all(b"response!").await.unwrap();
+        target_writer.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client_reader.read_to_end(&mut response).await.unwrap();
+        assert_eq!(response, b"response!");
+
+        let stats = relay_task.await.unwrap();
+        assert_eq!(stats.end, RelayEnd::ClientToTarget);
+        assert!(stats.error.is_none());
+        assert_eq!(stats.client_to_target, 7);
+        assert_eq!(stats.target_to_client, 9);
+        assert_eq!(stats.total_bytes(), 16);
+    }
+
+    // A target that shut down its read side: every write fails
+    struct ReadClosedTarget;
+
+    impl AsyncWrite for ReadClosedTarget {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            _buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_relay_half_close_survives_target_read_shutdown() {
+        let (client, proxy_client) = duplex(1024);
+        let (mut target, proxy_target) = duplex(1024);
+
+        let relay_task = tokio::spawn(async move {
+            let (mut client_reader, mut client_writer) = split(proxy_client);
+            let options = RelayOptions {
+                half_close: true,
+                ..options(64)
+            };
+            let mut target_reader = proxy_target;
+            relay(
+                &mut client_reader,
+                &mut client_writer,
+                &mut target_reader,
+                &mut ReadClosedTarget,
+                &options,
+            )
+            .await
+        });
+
+        let (mut client_reader, mut client_writer) = split(client);
+        client_writer.write_all(b"request").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!relay_task.is_finished());
+
+        // The target keeps sending after refusing the request
+        target.write_all(b"response!").await.unwrap();
+        target.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        timeout(
+            Duration::from_secs(1),
+            client_reader.read_to_end(&mut response),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response, b"response!");
+
+        let stats = relay_task.await.unwrap();
+        assert_eq!(stats.end, RelayEnd::ClientToTarget);
+        assert!(stats.error.is_none());
+        assert_eq!(stats.client_to_target, 0);
+        assert_eq!(stats.target_to_client, 9);
+    }
+
+    #[tokio::test]
+    async fn test_relay_idle_timeout() {
+        let (mut client, proxy_client) = duplex(1024);
+        let (proxy_target, mut target) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+        let options = RelayOptions {
+            idle_timeout: Some(Duration::from_millis(100)),
+            ..options(64)
+        };
+
+        let relay = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options,
+        );
+        tokio::pin!(relay);
+
+        // Traffic inside the window keeps the relay alive
+        for _ in 0..3 {
+            tokio::select! {
+                _ = &mut relay => panic!("relay ended while active"),
+                _ = sleep(Duration::from_millis(60)) => {}
+            }
+            client.write_all(b"x").await.unwrap();
+        }
+
+        let stats = timeout(Duration::from_secs(1), &mut relay)
+            .await
+            .expect("idle relay never ended");
+        assert_eq!(stats.end, RelayEnd::Idle);
+        assert!(stats.error.is_none());
+        assert_eq!(stats.client_to_target, 3);
+
+        let mut buf = [0u8; 3];
+        target.read_exact(&mut buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_relay_first_byte_timeout() {
+        let (_client, proxy_client) = duplex(1024);
+        let (proxy_target, _target) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+        let options = RelayOptions {
+            first_byte_timeout: Some(Duration::from_millis(100)),
+            ..options(64)
+        };
+
+        let start = Instant::now();
+        let stats = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options,
+        )
+        .await;
+        assert_eq!(stats.end, RelayEnd::NoData);
+        assert_eq!(stats.total_bytes(), 0);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_relay_first_byte_timeout_disarmed_by_traffic() {
+        let (mut client, proxy_client) = duplex(1024);
+        let (proxy_target, mut target) = duplex(1024);
+        let (mut client_reader, mut client_writer) = split(proxy_client);
+        let (mut target_reader, mut target_writer) = split(proxy_target);
+        let options = RelayOptions {
+            first_byte_timeout: Some(Duration::from_millis(50)),
+            ..options(64)
+        };
+
+        client.write_all(b"hi").await.unwrap();
+        let relay = relay(
+            &mut client_reader,
+            &mut client_writer,
+            &mut target_reader,
+            &mut target_writer,
+            &options,
+        );
+        tokio::pin!(relay);
+
+        // Silence well past the timeout no longer ends the relay
+        tokio::select! {
+            stats = &mut relay => panic!("relay ended early: {stats:?}"),
+            _ = sleep(Duration::from_millis(200)) => {}
+        }
+
+        let mut buf = [0u8; 2];
+        target.read_exact(&mut buf).await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!((&mut relay).await.end, RelayEnd::ClientToTarget);
+    }
+
+    // Pushes `total` bytes into `writer` and reads them back out of `reader`
+    // on the far side of the relay, returning the longest gap between reads
+    async fn pump<R, W>(mut reader: R, mut writer: W, total: usize) -> Duration
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let sender = tokio::spawn(async move {
+            let chunk = vec![0xA5u8; 4096];
+            let mut sent = 0;
+            while sent < total {
+                writer.write_all(&chunk).await.unwrap();
+                sent += chunk.len();
+            }
+            writer
+        });
+
+        let mut buf = vec![0u8; 4096];
+        let mut received = 0;
+        let mut last_progress = Instant::now();
+        let mut longest_gap = Duration::ZERO;
+        while received < total {
+            let n = reader.read(&mut buf).await.unwrap();
+            assert_ne!(n, 0, "relay stopped early");
+            received += n;
+            longest_gap = longest_gap.max(last_progress.elapsed());
+            last_progress = Instant::now();
+        }
+
+        // Keep the write half open until the other direction is done too
+        drop(sender.await.unwrap());
+        longest_gap
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_relay_full_duplex_progress_is_balanced() {
+        const TOTAL: usize = 16 * 1024 * 1024;
+
+        let (client, proxy_client) = duplex(64 * 1024);
+        let (proxy_target, target) = duplex(64 * 1024);
+
+        let relay = tokio::spawn(async move {
+            let (mut client_reader, mut client_writer) = split(proxy_client);
+            let (mut target_reader, mut target_writer) = split(proxy_target);
+            relay(
+                &mut client_reader,
+                &mut client_writer,
+                &mut target_reader,
+                &mut target_writer,
+                &options(16 * 1024),
+            )
+            .await
+        });
+
+        let (client_reader, client_writer) = split(client);
+        let (target_reader, target_writer) = split(target);
+        let upload = pump(target_reader, client_writer, TOTAL);
+        let download = pump(client_reader, target_writer, TOTAL);
+
+        let (upload_gap, download_gap) = timeout(Duration::from_secs(30), async {
+            tokio::join!(upload, download)
+        })
+        .await
+        .expect("relay stalled");
+
+        assert!(upload_gap < Duration::from_millis(500), "{upload_gap:?}");
+        assert!(
+            download_gap < Duration::from_millis(500),
+            "{download_gap:?}"
+        );
+        relay.abort();
+    }
+
+    #[tokio::test]
+    async fn test_relay_reuses_pooled_buffers_across_connections() {
+        let pool = Arc::new(BufferPool::new(64, 8));
+        let options = RelayOptions {
+            buffer_pool: Some(pool.clone()),
+            ..options(64)
+        };
+
+        for i in 0..100u32 {
+            let (mut client, proxy_client) = duplex(1024);
+            let (proxy_target, mut target) = duplex(1024);
+            let (mut client_reader, mut client_writer) = split(proxy_client);
+            let (mut target_reader, mut target_writer) = split(proxy_target);
+
+            let request = format!("request {}", i);
+            let response = format!("response {}", i);
+            client.write_all(request.as_bytes()).await.unwrap();
+            target.write_all(response.as_bytes()).await.unwrap();
+            target.shutdown().await.unwrap();
+
+            let stats = relay(
+                &mut client_reader,
+                &mut client_writer,
+                &mut target_reader,
+                &mut target_writer,
+                &options,
+            )
+            .await;
+            assert_eq!(stats.end, RelayEnd::TargetToClient);
+
+            let mut received = vec![0u8; request.len()];
+            target.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, request.as_bytes());
+            let mut received = vec![0u8; response.len()];
+            client.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, response.as_bytes());
+        }
+
+        // One buffer per direction, allocated once and reused for every relay
+        assert_eq!(pool.allocated(), 2);
+        assert_eq!(pool.idle(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_relays_stay_within_buffer_budget() {
+        // Room for two full relays, or more with shrunk buffers
+        let budget = Arc::new(BufferBudget::new(4 * 4096 + 2 * MIN_RELAY_BUFFER));
+        let options = RelayOptions {
+            buffer_budget: Some(budget.clone()),
+            ..options(4096)
+        };
+
+        let mut clients = Vec::new();
+        let mut targets = Vec::new();
+        let mut relays = Vec::new();
+        for _ in 0..8 {
+            let (client, proxy_client) = duplex(1024);
+            let (proxy_target, target) = duplex(1024);
+            let options = options.clone();
+            relays.push(tokio::spawn(async move {
+                let (mut client_reader, mut client_writer) = split(proxy_client);
+                let (mut target_reader, mut target_writer) = split(proxy_target);
+                relay(
+                    &mut client_reader,
+                    &mut client_writer,
+                    &mut target_reader,
+                    &mut target_writer,
+                    &options,
+                )
+                .await
+            }));
+            clients.push(client);
+            targets.push(target);
+        }
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(budget.in_use(), budget.limit());
+        assert_eq!(relays.iter().filter(|relay| relay.is_finished()).count(), 0);
+
+        // Closing clients lets the waiting relays in, never past the limit
+        for mut client in clients {
+            client.shutdown().await.unwrap();
+            assert!(budget.in_use() <= budget.limit());
+        }
+        for relay in relays {
+            let stats = timeout(Duration::from_secs(1), relay)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stats.end, RelayEnd::ClientToTarget);
+            assert!(budget.in_use() <= budget.limit());
+        }
+        assert_eq!(budget.in_use(), 0);
+    }
+}
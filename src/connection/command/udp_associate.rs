@@ -35,11 +35,13 @@ mod tests {
     fn create_test_request() -> SocksRequest {
         SocksRequest {
             version: 0x05,
-            command: Command::UDP_ASSOCIATE as u8,
+            command: Command::UDP_ASSOCIATE,
             reserved: 0x00,
             address_type: AddressType::IPV4,
             dest_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            dest_addrs: vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))],
             dest_port: 8080,
+            dest_domain: None,
         }
     }
 
@@ -116,7 +118,7 @@ mod tests {
     #[tokio::test]
     async fn test_udp_associate_command_enum_value() {
         let request = create_test_request();
-        assert_eq!(request.command, Command::UDP_ASSOCIATE as u8);
+        assert_eq!(request.command, Command::UDP_ASSOCIATE);
         assert_eq!(request.command, 0x03);
     }
 }
@@ -1,7 +1,72 @@
-use std::io;
+use std::{error::Error, fmt, io};
 
 use crate::connection::reply::Reply;
 
+/// Stable, machine-readable identifier for a [`SocksError`] variant.
+///
+/// The numeric values never change once released, so embedders can map them
+/// to their own (e.g. localized) messages instead of matching on text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    InvalidVersion = 1,
+    InvalidReservedByte = 2,
+    UnsupportedAddressType = 3,
+    UnsupportedCommand = 4,
+    EmptyDomainName = 5,
+    InvalidDomainNameEncoding = 6,
+    DnsResolutionFailed = 7,
+    NoAddressesResolved = 8,
+    ConnectionFailed = 9,
+    InvalidData = 10,
+    IoError = 11,
+    DomainNameTooLong = 12,
+    VersionMismatch = 13,
+    MalformedGreeting = 14,
+}
+
+impl ErrorCode {
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// Whether the error means the client broke the protocol itself, as
+    /// opposed to asking for something the server refuses.
+    pub fn is_protocol_violation(self) -> bool {
+        matches!(
+            self,
+            ErrorCode::InvalidVersion
+                | ErrorCode::InvalidReservedByte
+                | ErrorCode::VersionMismatch
+                | ErrorCode::MalformedGreeting
+        )
+    }
+
+    /// Code attached to an `io::Error` made by [`SocksError::to_io_error`].
+    pub fn of(error: &io::Error) -> Option<ErrorCode> {
+        error
+            .get_ref()
+            .and_then(|inner| inner.downcast_ref::<CodedError>())
+            .map(|coded| coded.code)
+    }
+}
+
+// Payload of the io::Errors built from a SocksError, carrying its code along
+// with the English message
+#[derive(Debug)]
+struct CodedError {
+    code: ErrorCode,
+    message: String,
+}
+
+impl fmt::Display for CodedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for CodedError {}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum SocksError {
     InvalidVersion(u8),
@@ -10,14 +75,39 @@ pub enum SocksError {
     UnsupportedCommand(u8),
     EmptyDomainName,
     InvalidDomainNameEncoding,
-    DnsResolutionFailed,
+    DnsResolutionFailed { domain: String, detail: String },
     NoAddressesResolved,
     ConnectionFailed(io::ErrorKind),
     InvalidData,
     IoError(io::ErrorKind),
+    // Longer than --max-domain-len, carries the length the client sent
+    DomainNameTooLong(u8),
+    // Request version differs from the one the handshake negotiated
+    VersionMismatch { negotiated: u8, requested: u8 },
+    // Greeting that parsed but is refused, carries what was wrong with it
+    MalformedGreeting(String),
 }
 
 impl SocksError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SocksError::InvalidVersion(_) => ErrorCode::InvalidVersion,
+            SocksError::InvalidReservedByte(_) => ErrorCode::InvalidReservedByte,
+            SocksError::UnsupportedAddressType(_) => ErrorCode::UnsupportedAddressType,
+            SocksError::UnsupportedCommand(_) => ErrorCode::UnsupportedCommand,
+            SocksError::EmptyDomainName => ErrorCode::EmptyDomainName,
+            SocksError::InvalidDomainNameEncoding => ErrorCode::InvalidDomainNameEncoding,
+            SocksError::DnsResolutionFailed { .. } => ErrorCode::DnsResolutionFailed,
+            SocksError::NoAddressesResolved => ErrorCode::NoAddressesResolved,
+            SocksError::ConnectionFailed(_) => ErrorCode::ConnectionFailed,
+            SocksError::InvalidData => ErrorCode::InvalidData,
+            SocksError::IoError(_) => ErrorCode::IoError,
+            SocksError::DomainNameTooLong(_) => ErrorCode::DomainNameTooLong,
+            SocksError::VersionMismatch { .. } => ErrorCode::VersionMismatch,
+            SocksError::MalformedGreeting(_) => ErrorCode::MalformedGreeting,
+        }
+    }
+
     pub fn to_reply_code(&self) -> u8 {
         match self {
             SocksError::InvalidVersion(_) => Reply::GENERAL_FAILURE,
@@ -26,7 +116,7 @@ impl SocksError {
             SocksError::UnsupportedCommand(_) => Reply::COMMAND_NOT_SUPPORTED,
             SocksError::EmptyDomainName => Reply::GENERAL_FAILURE,
             SocksError::InvalidDomainNameEncoding => Reply::GENERAL_FAILURE,
-            SocksError::DnsResolutionFailed => Reply::HOST_UNREACHABLE,
+            SocksError::DnsResolutionFailed { .. } => Reply::HOST_UNREACHABLE,
             SocksError::NoAddressesResolved => Reply::HOST_UNREACHABLE,
             SocksError::ConnectionFailed(kind) => match kind {
                 io::ErrorKind::ConnectionRefused => Reply::CONNECTION_REFUSED,
@@ -38,39 +128,76 @@ impl SocksError {
             },
             SocksError::InvalidData => Reply::GENERAL_FAILURE,
             SocksError::IoError(_) => Reply::GENERAL_FAILURE,
+            SocksError::DomainNameTooLong(_) => Reply::CONNECTION_NOT_ALLOWED,
+            SocksError::VersionMismatch { .. } => Reply::GENERAL_FAILURE,
+            SocksError::MalformedGreeting(_) => Reply::GENERAL_FAILURE,
         }
     }
 
+    // The English message stays the error's Display, `ErrorCode::of` recovers
+    // the code from the returned error
     pub fn to_io_error(&self) -> io::Error {
-        match self {
-            SocksError::InvalidVersion(v) => io::Error::new(
+        let (kind, message) = match self {
+            SocksError::InvalidVersion(v) => (
                 io::ErrorKind::InvalidData,
                 format!("Invalid SOCKS version: {}", v),
             ),
-            SocksError::InvalidReservedByte(b) => io::Error::new(
+            SocksError::InvalidReservedByte(b) => (
                 io::ErrorKind::InvalidData,
                 format!("Invalid reserved byte: {}", b),
             ),
-            SocksError::UnsupportedAddressType(t) => io::Error::new(
+            SocksError::UnsupportedAddressType(t) => (
                 io::ErrorKind::InvalidData,
                 format!("Unsupported address type: {}", t),
             ),
-            SocksError::UnsupportedCommand(c) => io::Error::new(
+            SocksError::UnsupportedCommand(c) => (
                 io::ErrorKind::InvalidData,
                 format!("Unsupported command: {}", c),
             ),
             SocksError::EmptyDomainName => {
-                io::Error::new(io::ErrorKind::InvalidData, "Empty domain name")
+                (io::ErrorKind::InvalidData, "Empty domain name".to_string())
             }
-            SocksError::InvalidDomainNameEncoding => {
-                io::Error::new(io::ErrorKind::InvalidData, "Invalid domain name encoding")
-            }
-            SocksError::DnsResolutionFailed => io::Error::other("DNS resolution failed"),
-            SocksError::NoAddressesResolved => io::Error::other("No addresses resolved for domain"),
-            SocksError::ConnectionFailed(kind) => io::Error::new(*kind, "Connection failed"),
-            SocksError::InvalidData => io::Error::new(io::ErrorKind::InvalidData, "Invalid data"),
-            SocksError::IoError(kind) => io::Error::new(*kind, "IO error"),
-        }
+            SocksError::InvalidDomainNameEncoding => (
+                io::ErrorKind::InvalidData,
+                "Invalid domain name encoding".to_string(),
+            ),
+            SocksError::DnsResolutionFailed { domain, detail } => (
+                io::ErrorKind::Other,
+                format!("DNS resolution failed for '{}': {}", domain, detail),
+            ),
+            SocksError::NoAddressesResolved => (
+                io::ErrorKind::Other,
+                "No addresses resolved for domain".to_string(),
+            ),
+            SocksError::ConnectionFailed(kind) => (*kind, "Connection failed".to_string()),
+            SocksError::InvalidData => (io::ErrorKind::InvalidData, "Invalid data".to_string()),
+            SocksError::IoError(kind) => (*kind, "IO error".to_string()),
+            SocksError::DomainNameTooLong(len) => (
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Domain name of {} bytes exceeds the configured maximum",
+                    len
+                ),
+            ),
+            SocksError::VersionMismatch {
+                negotiated,
+                requested,
+            } => (
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Request version {} does not match negotiated version {}",
+                    requested, negotiated
+                ),
+            ),
+            SocksError::MalformedGreeting(reason) => (io::ErrorKind::InvalidData, reason.clone()),
+        };
+        io::Error::new(
+            kind,
+            CodedError {
+                code: self.code(),
+                message,
+            },
+        )
     }
 }
 
@@ -79,6 +206,13 @@ mod tests {
     use super::*;
     use std::io;
 
+    fn dns_failure() -> SocksError {
+        SocksError::DnsResolutionFailed {
+            domain: "example.invalid".to_string(),
+            detail: "failed to lookup address information".to_string(),
+        }
+    }
+
     #[test]
     fn test_socks_error_clone() {
         let error = SocksError::InvalidVersion(4);
@@ -142,7 +276,7 @@ mod tests {
 
         #[test]
         fn test_dns_resolution_failed_to_reply_code() {
-            let error = SocksError::DnsResolutionFailed;
+            let error = dns_failure();
             assert_eq!(error.to_reply_code(), Reply::HOST_UNREACHABLE);
         }
 
@@ -198,6 +332,12 @@ mod tests {
             }
         }
 
+        #[test]
+        fn test_domain_name_too_long_to_reply_code() {
+            let error = SocksError::DomainNameTooLong(254);
+            assert_eq!(error.to_reply_code(), Reply::CONNECTION_NOT_ALLOWED);
+        }
+
         #[test]
         fn test_invalid_data_to_reply_code() {
             let error = SocksError::InvalidData;
@@ -272,10 +412,16 @@ mod tests {
 
         #[test]
         fn test_dns_resolution_failed_to_io_error() {
-            let error = SocksError::DnsResolutionFailed;
+            let error = dns_failure();
             let io_error = error.to_io_error();
             assert_eq!(io_error.kind(), io::ErrorKind::Other);
             assert!(io_error.to_string().contains("DNS resolution failed"));
+            assert!(io_error.to_string().contains("example.invalid"));
+            assert!(
+                io_error
+                    .to_string()
+                    .contains("failed to lookup address information")
+            );
         }
 
         #[test]
@@ -353,11 +499,16 @@ mod tests {
                 SocksError::UnsupportedCommand(0xFF),
                 SocksError::EmptyDomainName,
                 SocksError::InvalidDomainNameEncoding,
-                SocksError::DnsResolutionFailed,
+                dns_failure(),
                 SocksError::NoAddressesResolved,
                 SocksError::ConnectionFailed(io::ErrorKind::ConnectionRefused),
                 SocksError::InvalidData,
                 SocksError::IoError(io::ErrorKind::UnexpectedEof),
+                SocksError::DomainNameTooLong(254),
+                SocksError::VersionMismatch {
+                    negotiated: 5,
+                    requested: 4,
+                },
             ];
 
             // Each error should be unique
@@ -381,11 +532,16 @@ mod tests {
                 SocksError::UnsupportedCommand(0xFF),
                 SocksError::EmptyDomainName,
                 SocksError::InvalidDomainNameEncoding,
-                SocksError::DnsResolutionFailed,
+                dns_failure(),
                 SocksError::NoAddressesResolved,
                 SocksError::ConnectionFailed(io::ErrorKind::ConnectionRefused),
                 SocksError::InvalidData,
                 SocksError::IoError(io::ErrorKind::UnexpectedEof),
+                SocksError::DomainNameTooLong(254),
+                SocksError::VersionMismatch {
+                    negotiated: 5,
+                    requested: 4,
+                },
             ];
 
             for error in errors {
@@ -399,6 +555,51 @@ mod tests {
             }
         }
 
+        #[test]
+        fn test_error_codes_are_stable() {
+            let cases = [
+                (SocksError::InvalidVersion(4), 1),
+                (SocksError::InvalidReservedByte(0xFF), 2),
+                (SocksError::UnsupportedAddressType(0xFF), 3),
+                (SocksError::UnsupportedCommand(0xFF), 4),
+                (SocksError::EmptyDomainName, 5),
+                (SocksError::InvalidDomainNameEncoding, 6),
+                (dns_failure(), 7),
+                (SocksError::NoAddressesResolved, 8),
+                (
+                    SocksError::ConnectionFailed(io::ErrorKind::ConnectionRefused),
+                    9,
+                ),
+                (SocksError::InvalidData, 10),
+                (SocksError::IoError(io::ErrorKind::UnexpectedEof), 11),
+                (SocksError::DomainNameTooLong(254), 12),
+                (
+                    SocksError::VersionMismatch {
+                        negotiated: 5,
+                        requested: 4,
+                    },
+                    13,
+                ),
+                (
+                    SocksError::MalformedGreeting("no methods offered".to_string()),
+                    14,
+                ),
+            ];
+
+            for (error, code) in cases {
+                assert_eq!(error.code().as_u16(), code, "{:?}", error);
+                let io_error = error.to_io_error();
+                assert_eq!(ErrorCode::of(&io_error), Some(error.code()));
+            }
+        }
+
+        #[test]
+        fn test_error_code_absent_on_plain_io_errors() {
+            let io_error = io::Error::new(io::ErrorKind::InvalidData, "Invalid data");
+            assert_eq!(ErrorCode::of(&io_error), None);
+            assert_eq!(ErrorCode::of(&io::ErrorKind::BrokenPipe.into()), None);
+        }
+
         #[test]
         fn test_boundary_values() {
             // Test boundary values for numeric variants
@@ -457,10 +658,7 @@ mod tests {
                     SocksError::InvalidDomainNameEncoding,
                     vec!["Invalid", "domain", "name", "encoding"],
                 ),
-                (
-                    SocksError::DnsResolutionFailed,
-                    vec!["DNS", "resolution", "failed"],
-                ),
+                (dns_failure(), vec!["DNS", "resolution", "failed"]),
                 (
                     SocksError::NoAddressesResolved,
                     vec!["No", "addresses", "resolved"],
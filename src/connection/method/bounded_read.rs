@@ -0,0 +1,105 @@
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+
+// Generous for usernames, passwords and GSSAPI tokens, but keeps a
+// hostile length prefix from sizing our allocations
+pub const DEFAULT_MAX_FIELD_LEN: usize = 64 * 1024;
+
+/// Reads exactly `len` bytes, refusing before allocating if `len` exceeds `max_len`.
+pub async fn read_bounded<R>(
+    reader: &mut BufReader<R>,
+    len: usize,
+    max_len: usize,
+) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Field length {} exceeds limit of {} bytes", len, max_len),
+        ));
+    }
+
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Reads a field prefixed with a one-byte length (userpass UNAME/PASSWD).
+pub async fn read_u8_prefixed<R>(reader: &mut BufReader<R>, max_len: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u8().await? as usize;
+    read_bounded(reader, len, max_len).await
+}
+
+/// Reads a field prefixed with a big-endian two-byte length (GSSAPI tokens).
+pub async fn read_u16_prefixed<R>(reader: &mut BufReader<R>, max_len: usize) -> io::Result<Vec<u8>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = reader.read_u16().await? as usize;
+    read_bounded(reader, len, max_len).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_read_u8_prefixed() {
+        let data = [3, b'b', b'o', b'b', 0xAA];
+        let mut reader = BufReader::new(&data[..]);
+        let field = read_u8_prefixed(&mut reader, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap();
+        assert_eq!(field, b"bob");
+        assert_eq!(reader.read_u8().await.unwrap(), 0xAA);
+    }
+
+    #[tokio::test]
+    async fn test_read_u16_prefixed() {
+        let data = [0x00, 0x02, 0x60, 0x82];
+        let mut reader = BufReader::new(&data[..]);
+        let field = read_u16_prefixed(&mut reader, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap();
+        assert_eq!(field, [0x60, 0x82]);
+    }
+
+    #[tokio::test]
+    async fn test_over_limit_length_rejected_before_reading() {
+        // Claims 0xFFFF bytes but sends none: must fail on the limit, not on EOF
+        let data = [0xFF, 0xFF];
+        let mut reader = BufReader::new(&data[..]);
+        let err = read_u16_prefixed(&mut reader, 1024).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("exceeds limit"));
+
+        let data = [200];
+        let mut reader = BufReader::new(&data[..]);
+        let err = read_u8_prefixed(&mut reader, 155).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_length_at_limit_accepted() {
+        let mut data = vec![4];
+        data.extend_from_slice(b"abcd");
+        let mut reader = BufReader::new(&data[..]);
+        assert_eq!(read_u8_prefixed(&mut reader, 4).await.unwrap(), b"abcd");
+    }
+
+    #[tokio::test]
+    async fn test_truncated_field_is_eof() {
+        let data = [5, b'a'];
+        let mut reader = BufReader::new(&data[..]);
+        let err = read_u8_prefixed(&mut reader, DEFAULT_MAX_FIELD_LEN)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -3,6 +3,18 @@ use crate::connection::{
     method::{method::Method, method_handler::MethodHandler},
 };
 
+/// How the handshake reacts to a greeting that lists duplicate or unknown
+/// methods, or is followed by more method bytes than it declared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GreetingPolicy {
+    /// Log a warning and continue negotiating.
+    #[default]
+    Warn,
+    /// Refuse the greeting with NO ACCEPTABLE METHODS.
+    Reject,
+}
+
 #[derive(Debug, Clone)]
 pub struct ClientGreeting {
     pub version: u8,
@@ -22,7 +34,15 @@ impl ClientGreeting {
         self.methods.contains(&(method as u8))
     }
 
-    pub fn validate(&self) -> Result<(), String> {
+    // Drops repeated method codes, keeping the client's order of first mention
+    pub fn dedup_methods(&mut self) {
+        let mut seen = [false; 256];
+        self.methods
+            .retain(|&m| !std::mem::replace(&mut seen[m as usize], true));
+        self.nmethods = self.methods.len() as u8;
+    }
+
+    pub fn validate(&self, policy: GreetingPolicy) -> Result<(), String> {
         if self.version != SOCKS5_VERSION {
             return Err(format!("Invalid SOCKS version: {}", self.version));
         }
@@ -35,6 +55,6 @@ impl ClientGreeting {
             ));
         }
 
-        MethodHandler::validate_client_methods(&self.methods)
+        MethodHandler::validate_client_methods(&self.methods, policy)
     }
 }
@@ -1,51 +1,67 @@
-use std::{io, net::SocketAddr};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::SocketAddr,
+    time::Duration,
+};
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
 use tracing::{debug, error, warn};
 
 use crate::connection::{
-    SOCKS5_VERSION,
-    method::{client_greeting::ClientGreeting, method::Method},
+    SOCKS5_VERSION, SocksError,
+    method::{
+        bounded_read,
+        client_greeting::{ClientGreeting, GreetingPolicy},
+        method::Method,
+    },
 };
 
+pub const DEFAULT_METHOD_PRIORITY: [u8; 5] = [
+    Method::NO_AUTHENTICATION_REQUIRED,
+    Method::USERNAME_PASSWORD,
+    Method::GSSAPI,
+    Method::IANA_ASSIGNED,
+    Method::RESERVED_FOR_PRIVATE_METHODS,
+];
+
 pub struct MethodHandler;
 
 impl MethodHandler {
     pub fn negotiate(client_methods: &[u8], server_methods: &[u8]) -> Option<Method> {
+        Self::negotiate_with_priority(client_methods, server_methods, &DEFAULT_METHOD_PRIORITY)
+    }
+
+    // Picks the first method in `method_priority` that both sides support
+    pub fn negotiate_with_priority(
+        client_methods: &[u8],
+        server_methods: &[u8],
+        method_priority: &[u8],
+    ) -> Option<Method> {
         debug!(
-            "Negotiating methods - Client: {:?}, Server: {:?}",
-            client_methods, server_methods
+            "Negotiating methods - Client: {:?}, Server: {:?}, Priority: {:?}",
+            client_methods, server_methods, method_priority
         );
 
-        let method_priority = [
-            Method::NO_AUTHENTICATION_REQUIRED,
-            Method::USERNAME_PASSWORD,
-            Method::GSSAPI,
-            Method::IANA_ASSIGNED,
-            Method::RESERVED_FOR_PRIVATE_METHODS,
-        ];
-
-        for &method_code in method_priority
-            .iter()
-            .map(|m| *m as u8)
-            .collect::<Vec<_>>()
-            .iter()
-        {
-            if server_methods.contains(&method_code) && client_methods.contains(&method_code) {
-                if let Some(method) = Method::from_u8(method_code) {
-                    if method.is_implemented() {
-                        debug!(
-                            "Negotiated method: {} (0x{:02X})",
-                            method.display_name(),
-                            method_code
-                        );
-                        return Some(method);
-                    } else {
-                        warn!(
-                            "Method {} is not implemented, skipping",
-                            method.display_name()
-                        );
-                    }
+        for &method_code in method_priority {
+            if !server_methods.contains(&method_code) || !client_methods.contains(&method_code) {
+                continue;
+            }
+
+            if let Some(method) = Method::from_u8(method_code) {
+                if method.is_implemented() {
+                    debug!(
+                        "Negotiated method: {} (0x{:02X})",
+                        method.display_name(),
+                        method_code
+                    );
+                    return Some(method);
+                } else {
+                    warn!(
+                        "Method {} is not implemented, skipping",
+                        method.display_name()
+                    );
                 }
             }
         }
@@ -57,8 +73,11 @@ impl MethodHandler {
     pub async fn handle_client_methods<W>(
         client_methods: &[u8],
         server_methods: &[u8],
+        method_priority: &[u8],
         writer: &mut BufWriter<W>,
         client_addr: SocketAddr,
+        failure_jitter: Duration,
+        write_timeout: Option<Duration>,
     ) -> io::Result<Method>
     where
         W: AsyncWrite + Unpin,
@@ -68,7 +87,7 @@ impl MethodHandler {
             client_addr, client_methods
         );
 
-        match Self::negotiate(client_methods, server_methods) {
+        match Self::negotiate_with_priority(client_methods, server_methods, method_priority) {
             Some(method) => {
                 debug!(
                     "Selected method {} for client {}",
@@ -77,8 +96,7 @@ impl MethodHandler {
                 );
 
                 let response = [SOCKS5_VERSION, method as u8];
-                writer.write_all(&response).await?;
-                writer.flush().await?;
+                Self::write_reply(writer, &response, write_timeout).await?;
 
                 Self::authenticate_method(method, writer, client_addr).await?;
 
@@ -90,9 +108,9 @@ impl MethodHandler {
                     client_addr
                 );
 
+                Self::delay_failure_reply(failure_jitter).await;
                 let response = [SOCKS5_VERSION, Method::NO_ACCEPTABLE_METHODS];
-                writer.write_all(&response).await?;
-                writer.flush().await?;
+                Self::write_reply(writer, &response, write_timeout).await?;
 
                 Err(io::Error::new(
                     io::ErrorKind::InvalidData,
@@ -102,6 +120,50 @@ impl MethodHandler {
         }
     }
 
+    // Writes and flushes a handshake reply. A client that stops reading could
+    // otherwise hold the flush until the whole handshake timeout runs out
+    pub async fn write_reply<W>(
+        writer: &mut BufWriter<W>,
+        reply: &[u8],
+        write_timeout: Option<Duration>,
+    ) -> io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let write = async {
+            writer.write_all(reply).await?;
+            writer.flush().await
+        };
+        match write_timeout {
+            Some(limit) => tokio::time::timeout(limit, write).await.map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Client did not take the handshake reply within {:?}", limit),
+                )
+            })?,
+            None => write.await,
+        }
+    }
+
+    // Sleeps a random duration in [0, max] so failure replies don't leak
+    // how far negotiation/authentication got through their timing
+    pub async fn delay_failure_reply(max: Duration) {
+        let delay = Self::failure_jitter(max);
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    fn failure_jitter(max: Duration) -> Duration {
+        if max.is_zero() {
+            return Duration::ZERO;
+        }
+        // RandomState is freshly keyed per call, good enough for jitter
+        let random = RandomState::new().build_hasher().finish();
+        let max_nanos = max.as_nanos().min(u64::MAX as u128 - 1) as u64;
+        Duration::from_nanos(random % (max_nanos + 1))
+    }
+
     async fn authenticate_method<W>(
         method: Method,
         writer: &mut BufWriter<W>,
@@ -182,25 +244,14 @@ impl MethodHandler {
     {
         let version = reader.read_u8().await?;
         if version != SOCKS5_VERSION {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!(
-                    "Invalid SOCKS version: expected {}, got {}",
-                    SOCKS5_VERSION, version
-                ),
-            ));
+            return Err(SocksError::InvalidVersion(version).to_io_error());
         }
 
+        // A zero count is well-formed on the wire; `ClientGreeting::validate`
+        // refuses it so the client still gets a NO ACCEPTABLE METHODS reply
         let nmethods = reader.read_u8().await?;
-        if nmethods == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "No authentication methods provided",
-            ));
-        }
-
-        let mut methods = vec![0u8; nmethods as usize];
-        reader.read_exact(&mut methods).await?;
+        let methods =
+            bounded_read::read_bounded(reader, nmethods as usize, u8::MAX as usize).await?;
 
         Ok(ClientGreeting {
             version,
@@ -209,7 +260,7 @@ impl MethodHandler {
         })
     }
 
-    pub fn validate_client_methods(methods: &[u8]) -> Result<(), String> {
+    pub fn validate_client_methods(methods: &[u8], policy: GreetingPolicy) -> Result<(), String> {
         if methods.is_empty() {
             return Err("No authentication methods provided".to_string());
         }
@@ -222,11 +273,17 @@ impl MethodHandler {
         sorted_methods.sort_unstable();
         sorted_methods.dedup();
         if sorted_methods.len() != methods.len() {
+            if policy == GreetingPolicy::Reject {
+                return Err("Client provided duplicate authentication methods".to_string());
+            }
             warn!("Client provided duplicate authentication methods");
         }
 
         for &method in methods {
             if Method::from_u8(method).is_none() {
+                if policy == GreetingPolicy::Reject {
+                    return Err(format!("Unknown authentication method: 0x{:02X}", method));
+                }
                 warn!("Unknown authentication method: 0x{:02X}", method);
             }
         }
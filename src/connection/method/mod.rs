@@ -1,4 +1,6 @@
+pub mod bounded_read;
 pub mod client_greeting;
+#[allow(clippy::module_inception)]
 pub mod method;
 pub mod method_handler;
 
@@ -6,7 +8,11 @@ pub mod method_handler;
 mod tests {
     use crate::connection::{
         SOCKS5_VERSION,
-        method::{client_greeting::ClientGreeting, method::Method, method_handler::MethodHandler},
+        method::{
+            client_greeting::{ClientGreeting, GreetingPolicy},
+            method::Method,
+            method_handler::MethodHandler,
+        },
     };
 
     use tokio::io::{AsyncWriteExt, BufReader, duplex};
@@ -62,6 +68,38 @@ mod tests {
         assert_eq!(result, Some(Method::NoAuthenticationRequired));
     }
 
+    #[test]
+    fn test_method_negotiation_configured_priority() {
+        let client_methods = vec![0x00, 0x02];
+        let server_methods = vec![0x00, 0x02];
+
+        // Only methods listed in the priority are considered
+        let result =
+            MethodHandler::negotiate_with_priority(&client_methods, &server_methods, &[0x02]);
+        assert_eq!(result, None);
+
+        // Userpass is preferred but not implemented yet, so NoAuth is the fallback
+        let result =
+            MethodHandler::negotiate_with_priority(&client_methods, &server_methods, &[0x02, 0x00]);
+        assert_eq!(result, Some(Method::NoAuthenticationRequired));
+    }
+
+    #[test]
+    fn test_greeting_dedup_methods() {
+        let mut greeting = ClientGreeting {
+            version: SOCKS5_VERSION,
+            nmethods: 5,
+            methods: vec![0x02, 0x00, 0x02, 0x00, 0x01],
+        };
+        assert!(greeting.validate(GreetingPolicy::Reject).is_err());
+        assert!(greeting.validate(GreetingPolicy::Warn).is_ok());
+
+        greeting.dedup_methods();
+        assert_eq!(greeting.methods, vec![0x02, 0x00, 0x01]);
+        assert_eq!(greeting.nmethods, 3);
+        assert!(greeting.validate(GreetingPolicy::Reject).is_ok());
+    }
+
     #[tokio::test]
     async fn test_parse_client_greeting_valid() {
         let (mut client, server) = duplex(1024);
@@ -111,15 +149,14 @@ mod tests {
         client.flush().await.unwrap();
 
         let mut reader = BufReader::new(server);
-        let result = MethodHandler::parse_client_greeting(&mut reader).await;
+        let greeting = MethodHandler::parse_client_greeting(&mut reader)
+            .await
+            .unwrap();
+        assert_eq!(greeting.nmethods, 0);
+        assert!(greeting.methods.is_empty());
 
-        assert!(result.is_err());
-        assert!(
-            result
-                .unwrap_err()
-                .to_string()
-                .contains("No authentication methods")
-        );
+        let err = greeting.validate(GreetingPolicy::Warn).unwrap_err();
+        assert!(err.contains("No authentication methods"));
     }
 
     #[test]
@@ -130,7 +167,7 @@ mod tests {
             methods: vec![0x00, 0x02],
         };
 
-        assert!(greeting.validate().is_ok());
+        assert!(greeting.validate(GreetingPolicy::Warn).is_ok());
 
         let invalid_greeting = ClientGreeting {
             version: 0x04,
@@ -138,13 +175,32 @@ mod tests {
             methods: vec![0x00],
         };
 
-        assert!(invalid_greeting.validate().is_err());
+        assert!(invalid_greeting.validate(GreetingPolicy::Warn).is_err());
     }
 
     #[test]
     fn test_validate_client_methods() {
-        assert!(MethodHandler::validate_client_methods(&[0x00]).is_ok());
-        assert!(MethodHandler::validate_client_methods(&[0x00, 0x02]).is_ok());
-        assert!(MethodHandler::validate_client_methods(&[]).is_err());
+        let policy = GreetingPolicy::Warn;
+        assert!(MethodHandler::validate_client_methods(&[0x00], policy).is_ok());
+        assert!(MethodHandler::validate_client_methods(&[0x00, 0x02], policy).is_ok());
+        assert!(MethodHandler::validate_client_methods(&[], policy).is_err());
+    }
+
+    #[test]
+    fn test_validate_client_methods_duplicates() {
+        let methods = [0x00, 0x00, 0x02];
+        assert!(MethodHandler::validate_client_methods(&methods, GreetingPolicy::Warn).is_ok());
+
+        let result = MethodHandler::validate_client_methods(&methods, GreetingPolicy::Reject);
+        assert!(result.unwrap_err().contains("duplicate"));
+    }
+
+    #[test]
+    fn test_validate_client_methods_unknown() {
+        let methods = [0x00, 0x99];
+        assert!(MethodHandler::validate_client_methods(&methods, GreetingPolicy::Warn).is_ok());
+
+        let result = MethodHandler::validate_client_methods(&methods, GreetingPolicy::Reject);
+        assert!(result.unwrap_err().contains("0x99"));
     }
 }
@@ -1,38 +1,99 @@
 pub mod address_type;
+pub mod close_reason;
 pub mod command;
 pub mod error;
 pub mod method;
 pub mod reply;
 pub mod request;
+pub mod socket_options;
 
-use std::{io, net::SocketAddr};
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
-use tracing::debug;
+use tokio::sync::Semaphore;
+use tracing::{debug, warn};
 
+use crate::ConnectionContext;
+use crate::config::ConnectionConfig;
 use crate::connection::{
-    address_type::AddressType, error::SocksError, method::method_handler::MethodHandler,
+    address_type::AddressType,
+    error::SocksError,
+    method::{
+        client_greeting::{ClientGreeting, GreetingPolicy},
+        method::Method,
+        method_handler::MethodHandler,
+    },
 };
 
 pub const SOCKS5_VERSION: u8 = 0x05;
 pub const RESERVED: u8 = 0x00;
+// Version byte of the username/password and GSSAPI subnegotiations
+pub const SUBNEGOTIATION_VERSION: u8 = 0x01;
 // For errors prior to established connection (in which case command returns the host, port)
 // these are used for connection errors (i.e. dns failure in domain name translation)
 pub const ERROR_ADDR: [u8; 4] = [0, 0, 0, 0];
 pub const ERROR_PORT: u16 = 0;
 
+// Runs the handshake with the settings from `config`, recording the
+// negotiated method on the connection's context as well as returning it
+pub async fn perform_handshake_with_context<R, W>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    client_addr: SocketAddr,
+    config: &ConnectionConfig,
+    context: &mut ConnectionContext,
+) -> io::Result<Method>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let method = perform_handshake(reader, writer, client_addr, config).await?;
+    context.negotiated_method = Some(method);
+    // The greeting only parses with this version
+    context.negotiated_version = Some(SOCKS5_VERSION);
+    Ok(method)
+}
+
+// A pipelined request starts with the SOCKS version and an auth
+// subnegotiation with 0x01, anything else right behind the greeting means the
+// client sent more method bytes than it declared in `nmethods`
+fn trailing_greeting_bytes(greeting: &ClientGreeting, buffered: &[u8]) -> Option<String> {
+    let &next = buffered.first()?;
+    if next == SOCKS5_VERSION || next == SUBNEGOTIATION_VERSION {
+        return None;
+    }
+    Some(format!(
+        "Greeting declared {} method(s) but was followed by unexpected byte 0x{:02X}; \
+         the client likely sent more methods than declared",
+        greeting.nmethods, next
+    ))
+}
+
+// Negotiates a method with the settings from `config`. The offered methods
+// go to the event sink as soon as the greeting parses, before it is validated
+// or negotiated, and a reply the client does not take within
+// `handshake_write_timeout` fails the handshake.
 pub async fn perform_handshake<R, W>(
     reader: &mut BufReader<R>,
     writer: &mut BufWriter<W>,
     client_addr: SocketAddr,
-    server_methods: &[u8],
-) -> io::Result<()>
+    config: &ConnectionConfig,
+) -> io::Result<Method>
 where
     R: AsyncRead + Unpin,
     W: AsyncWrite + Unpin,
 {
+    let greeting_policy = config.greeting_policy;
+    let auth_failure_jitter = config.auth_failure_jitter;
+    let write_timeout = config.handshake_write_timeout;
+
     debug!("Performing handshake for client {}", client_addr);
 
-    let client_greeting = match MethodHandler::parse_client_greeting(reader).await {
+    let mut client_greeting = match MethodHandler::parse_client_greeting(reader).await {
         Ok(greeting) => greeting,
         Err(e) => {
             debug!(
@@ -47,30 +108,139 @@ where
         "Parsed client greeting from {}: version={}, methods={:?}",
         client_addr, client_greeting.version, client_greeting.methods
     );
+    if let Some(event_sink) = &config.event_sink {
+        event_sink.greeting_received(client_addr, &client_greeting.methods);
+    }
 
-    if let Err(validation_error) = client_greeting.validate() {
+    if let Err(validation_error) = client_greeting.validate(greeting_policy) {
         debug!(
             "Invalid client greeting from {}: {}",
             client_addr, validation_error
         );
-        return Err(io::Error::new(io::ErrorKind::InvalidData, validation_error));
+        MethodHandler::delay_failure_reply(auth_failure_jitter).await;
+        MethodHandler::write_reply(
+            writer,
+            &[SOCKS5_VERSION, Method::NO_ACCEPTABLE_METHODS],
+            write_timeout,
+        )
+        .await?;
+        return Err(SocksError::MalformedGreeting(validation_error).to_io_error());
     }
+    if let Some(trailing_error) = trailing_greeting_bytes(&client_greeting, reader.buffer()) {
+        if greeting_policy == GreetingPolicy::Reject {
+            debug!(
+                "Invalid client greeting from {}: {}",
+                client_addr, trailing_error
+            );
+            MethodHandler::delay_failure_reply(auth_failure_jitter).await;
+            MethodHandler::write_reply(
+                writer,
+                &[SOCKS5_VERSION, Method::NO_ACCEPTABLE_METHODS],
+                write_timeout,
+            )
+            .await?;
+            return Err(SocksError::MalformedGreeting(trailing_error).to_io_error());
+        }
+        warn!("Client {}: {}", client_addr, trailing_error);
+    }
+    // Duplicates only get this far when the policy tolerates them
+    client_greeting.dedup_methods();
 
-    let _selected_method = MethodHandler::handle_client_methods(
+    let selected_method = MethodHandler::handle_client_methods(
         &client_greeting.methods,
-        server_methods,
+        &config.supported_auth_methods,
+        &config.method_priority,
         writer,
         client_addr,
+        auth_failure_jitter,
+        write_timeout,
     )
     .await?;
 
     debug!("Completed handshake for client {}", client_addr);
-    Ok(())
+    Ok(selected_method)
 }
 
-async fn resolve_domain(domain: &str) -> io::Result<Vec<std::net::SocketAddr>> {
-    let addrs: Vec<_> = tokio::net::lookup_host((domain, 0)).await?.collect();
-    Ok(addrs)
+/// Retries for lookups that failed transiently. The delay before retry `n`
+/// (counting from 0) is `backoff * 2^n`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DnsRetry {
+    pub retries: u32,
+    pub backoff: Duration,
+}
+
+// Each lookup occupies a blocking pool thread until getaddrinfo returns, so
+// with `slots` set the lookup waits for a permit before taking one
+async fn resolve_domain(
+    domain: &str,
+    slots: Option<&Arc<Semaphore>>,
+    retry: DnsRetry,
+) -> io::Result<Vec<SocketAddr>> {
+    resolve_with_retry(domain, slots, retry, |domain| {
+        Ok((domain, 0).to_socket_addrs()?.collect())
+    })
+    .await
+}
+
+async fn resolve_with_retry<F>(
+    domain: &str,
+    slots: Option<&Arc<Semaphore>>,
+    retry: DnsRetry,
+    lookup: F,
+) -> io::Result<Vec<SocketAddr>>
+where
+    F: Fn(&str) -> io::Result<Vec<SocketAddr>> + Clone + Send + 'static,
+{
+    let mut attempt = 0;
+    loop {
+        // Taken per attempt so a backoff never holds a slot
+        let permit = match slots {
+            Some(slots) => Some(
+                slots
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(io::Error::other)?,
+            ),
+            None => None,
+        };
+        let lookup = lookup.clone();
+        let owned_domain = domain.to_string();
+        // The permit goes with the blocking thread: a caller that gives up
+        // on the lookup does not stop getaddrinfo, so the slot stays taken
+        // until the thread is actually free again
+        let result = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            lookup(&owned_domain)
+        })
+        .await
+        .map_err(io::Error::other)?;
+        match result {
+            Err(e) if attempt < retry.retries && is_transient_dns_error(&e) => {
+                let delay = retry.backoff.saturating_mul(2u32.saturating_pow(attempt));
+                debug!(
+                    "Lookup of {} failed ({}), retrying in {:?}",
+                    domain, e, delay
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// getaddrinfo errors carry no ErrorKind of their own, only gai_strerror's
+// text: EAI_AGAIN (timeouts, SERVFAIL) reads "Temporary failure in name
+// resolution", while NXDOMAIN is "Name or service not known" and not retried
+fn is_transient_dns_error(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || error
+        .to_string()
+        .to_ascii_lowercase()
+        .contains("temporary failure")
 }
 
 pub async fn send_reply<W>(
@@ -111,6 +281,7 @@ pub async fn send_error_reply<W>(writer: &mut BufWriter<W>, error_code: u8) -> i
 where
     W: AsyncWrite + Unpin,
 {
+    debug!("Sending error reply 0x{:02X}", error_code);
     send_reply(
         writer,
         error_code,
@@ -124,8 +295,29 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connection::method::method_handler::DEFAULT_METHOD_PRIORITY;
+    use clap::Parser;
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
     use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
 
+    fn handshake_config(
+        server_methods: &[u8],
+        greeting_policy: GreetingPolicy,
+        auth_failure_jitter: Duration,
+    ) -> ConnectionConfig {
+        let config = crate::config::ProxyConfig::parse_from(["rhoxy-socks"]);
+        ConnectionConfig {
+            supported_auth_methods: server_methods.to_vec(),
+            method_priority: DEFAULT_METHOD_PRIORITY.to_vec(),
+            greeting_policy,
+            auth_failure_jitter,
+            ..ConnectionConfig::from(&config)
+        }
+    }
+
     #[tokio::test]
     async fn test_perform_handshake_success() {
         let (mut client, server) = duplex(1024);
@@ -134,7 +326,7 @@ mod tests {
         client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
         client.flush().await.unwrap();
 
-        let (client_reader, mut client_writer) = tokio::io::split(client);
+        let (client_reader, _client_writer) = tokio::io::split(client);
         let (server_reader, server_writer) = tokio::io::split(server);
 
         let mut reader = BufReader::new(server_reader);
@@ -142,9 +334,14 @@ mod tests {
         let client_addr = "127.0.0.1:8080".parse().unwrap();
         let server_methods = vec![0x00]; // Support no-auth
 
-        let result =
-            perform_handshake(&mut reader, &mut writer, client_addr, &server_methods).await;
-        assert!(result.is_ok());
+        let result = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(&server_methods, GreetingPolicy::Warn, Duration::ZERO),
+        )
+        .await;
+        assert_eq!(result.unwrap(), Method::NoAuthenticationRequired);
 
         // Verify response
         let mut response = [0u8; 2];
@@ -153,6 +350,40 @@ mod tests {
         assert_eq!(response, [0x05, 0x00]); // SOCKS5, no-auth
     }
 
+    #[tokio::test]
+    async fn test_perform_handshake_returns_negotiated_method() {
+        let (mut client, server) = duplex(1024);
+        // Username/password listed first, but only no-auth is implemented
+        client.write_all(&[0x05, 0x02, 0x02, 0x00]).await.unwrap();
+
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+        let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let method = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(
+                &[
+                    Method::USERNAME_PASSWORD,
+                    Method::NO_AUTHENTICATION_REQUIRED,
+                ],
+                GreetingPolicy::Warn,
+                Duration::ZERO,
+            ),
+        )
+        .await
+        .unwrap();
+        assert_eq!(method, Method::NoAuthenticationRequired);
+
+        // The caller gets the method the client was told about
+        let mut response = [0u8; 2];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [SOCKS5_VERSION, method as u8]);
+    }
+
     #[tokio::test]
     async fn test_perform_handshake_no_acceptable_methods() {
         let (mut client, server) = duplex(1024);
@@ -161,7 +392,7 @@ mod tests {
         client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
         client.flush().await.unwrap();
 
-        let (client_reader, _) = tokio::io::split(client);
+        let (_client_reader, _) = tokio::io::split(client);
         let (server_reader, server_writer) = tokio::io::split(server);
 
         let mut reader = BufReader::new(server_reader);
@@ -169,8 +400,440 @@ mod tests {
         let client_addr = "127.0.0.1:8080".parse().unwrap();
         let server_methods = vec![0x00]; // Only support no-auth
 
-        let result =
-            perform_handshake(&mut reader, &mut writer, client_addr, &server_methods).await;
+        let result = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(&server_methods, GreetingPolicy::Warn, Duration::ZERO),
+        )
+        .await;
         assert!(result.is_err());
     }
+
+    #[derive(Clone, Default)]
+    struct LogCapture(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl LogCapture {
+        fn contents(&self) -> String {
+            String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_perform_handshake_duplicate_methods_warns() {
+        let capture = LogCapture::default();
+        let make_writer = {
+            let capture = capture.clone();
+            move || capture.clone()
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(make_writer)
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (mut client, server) = duplex(1024);
+
+        // Client lists no-auth twice
+        client.write_all(&[0x05, 0x02, 0x00, 0x00]).await.unwrap();
+        client.flush().await.unwrap();
+
+        let (client_reader, _client_writer) = tokio::io::split(client);
+        let (server_reader, server_writer) = tokio::io::split(server);
+
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+        let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let result = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(&[0x00], GreetingPolicy::Warn, Duration::ZERO),
+        )
+        .await;
+        assert!(result.is_ok());
+
+        let mut response = [0u8; 2];
+        let mut client_reader = BufReader::new(client_reader);
+        client_reader.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x05, 0x00]);
+
+        assert!(
+            capture
+                .contents()
+                .contains("duplicate authentication methods")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_perform_handshake_duplicate_methods_rejected() {
+        let (mut client, server) = duplex(1024);
+
+        client.write_all(&[0x05, 0x02, 0x00, 0x00]).await.unwrap();
+        client.flush().await.unwrap();
+
+        let (client_reader, _client_writer) = tokio::io::split(client);
+        let (server_reader, server_writer) = tokio::io::split(server);
+
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+        let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let result = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(&[0x00], GreetingPolicy::Reject, Duration::ZERO),
+        )
+        .await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        let mut response = [0u8; 2];
+        let mut client_reader = BufReader::new(client_reader);
+        client_reader.read_exact(&mut response).await.unwrap();
+        assert_eq!(response, [0x05, Method::NO_ACCEPTABLE_METHODS]);
+    }
+
+    #[tokio::test]
+    async fn test_perform_handshake_extra_greeting_bytes() {
+        for policy in [GreetingPolicy::Warn, GreetingPolicy::Reject] {
+            let capture = LogCapture::default();
+            let make_writer = {
+                let capture = capture.clone();
+                move || capture.clone()
+            };
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(make_writer)
+                .with_max_level(tracing::Level::WARN)
+                .with_ansi(false)
+                .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+
+            let (mut client, server) = duplex(1024);
+            // Declares one method but sends two, followed by a CONNECT request
+            client
+                .write_all(&[0x05, 0x01, 0x00, 0x02, 0x05, 0x01, 0x00, 0x01])
+                .await
+                .unwrap();
+
+            let (server_reader, server_writer) = tokio::io::split(server);
+            let mut reader = BufReader::new(server_reader);
+            let mut writer = BufWriter::new(server_writer);
+
+            let result = perform_handshake(
+                &mut reader,
+                &mut writer,
+                "127.0.0.1:8080".parse().unwrap(),
+                &handshake_config(&[0x00], policy, Duration::ZERO),
+            )
+            .await;
+
+            let mut response = [0u8; 2];
+            client.read_exact(&mut response).await.unwrap();
+            if policy == GreetingPolicy::Reject {
+                let err = result.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert!(err.to_string().contains("followed by unexpected byte 0x02"));
+                assert_eq!(response, [0x05, Method::NO_ACCEPTABLE_METHODS]);
+            } else {
+                assert!(result.is_ok());
+                assert_eq!(response, [0x05, 0x00]);
+                assert!(
+                    capture
+                        .contents()
+                        .contains("sent more methods than declared")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_pipelined_request_is_not_trailing_greeting_bytes() {
+        let greeting = ClientGreeting {
+            version: 0x05,
+            nmethods: 1,
+            methods: vec![0x00],
+        };
+        assert!(trailing_greeting_bytes(&greeting, &[]).is_none());
+        assert!(trailing_greeting_bytes(&greeting, &[0x05, 0x01, 0x00]).is_none());
+        assert!(trailing_greeting_bytes(&greeting, &[0x01, 0x04]).is_none());
+        assert!(trailing_greeting_bytes(&greeting, &[0x80]).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_auth_failure_reply_delayed_within_jitter_bound() {
+        let jitter = Duration::from_millis(100);
+        let mut saw_delay = false;
+
+        for _ in 0..5 {
+            let (mut client, server) = duplex(1024);
+            // Only GSSAPI offered, server only supports no-auth
+            client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
+
+            let (server_reader, server_writer) = tokio::io::split(server);
+            let mut reader = BufReader::new(server_reader);
+            let mut writer = BufWriter::new(server_writer);
+            let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+            let start = tokio::time::Instant::now();
+            let result = perform_handshake(
+                &mut reader,
+                &mut writer,
+                client_addr,
+                &handshake_config(&[0x00], GreetingPolicy::Warn, jitter),
+            )
+            .await;
+            let elapsed = start.elapsed();
+            assert!(result.is_err());
+            assert!(elapsed <= jitter + Duration::from_millis(50), "{elapsed:?}");
+            saw_delay |= elapsed >= Duration::from_millis(1);
+
+            let mut response = [0u8; 2];
+            client.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, [0x05, Method::NO_ACCEPTABLE_METHODS]);
+        }
+
+        assert!(saw_delay);
+    }
+
+    #[tokio::test]
+    async fn test_auth_success_reply_not_delayed() {
+        let (mut client, server) = duplex(1024);
+        client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+        let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let start = tokio::time::Instant::now();
+        let result = perform_handshake(
+            &mut reader,
+            &mut writer,
+            client_addr,
+            &handshake_config(&[0x00], GreetingPolicy::Warn, Duration::from_secs(10)),
+        )
+        .await;
+        assert!(result.is_ok());
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_handshake_reply_write_times_out_on_stalled_client() {
+        // A one-byte pipe the client never drains cannot take the 2-byte reply
+        let (mut client, server) = duplex(1);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+        let client_addr = "127.0.0.1:8080".parse().unwrap();
+
+        let greeting = tokio::spawn(async move {
+            client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+            client
+        });
+
+        let start = tokio::time::Instant::now();
+        let config = ConnectionConfig {
+            handshake_write_timeout: Some(Duration::from_secs(2)),
+            ..handshake_config(&[0x00], GreetingPolicy::Warn, Duration::ZERO)
+        };
+        let result = perform_handshake(&mut reader, &mut writer, client_addr, &config).await;
+
+        let err = result.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        drop(greeting.await.unwrap());
+    }
+
+    #[test]
+    fn test_queued_resolutions_leave_blocking_pool_free() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .max_blocking_threads(2)
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(async {
+            let slots = std::sync::Arc::new(Semaphore::new(1));
+            // Stands in for a lookup stuck on a slow DNS server
+            let stuck = slots.clone().acquire_owned().await.unwrap();
+
+            let lookups: Vec<_> = (0..50)
+                .map(|_| {
+                    let slots = slots.clone();
+                    tokio::spawn(async move {
+                        resolve_domain("localhost", Some(&slots), DnsRetry::default()).await
+                    })
+                })
+                .collect();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            // Waiting lookups hold no blocking threads, other blocking work still runs
+            let other =
+                tokio::time::timeout(Duration::from_secs(1), tokio::task::spawn_blocking(|| 42))
+                    .await
+                    .expect("blocking pool should not be starved by queued lookups")
+                    .unwrap();
+            assert_eq!(other, 42);
+            assert!(lookups.iter().all(|lookup| !lookup.is_finished()));
+
+            drop(stuck);
+            for lookup in lookups {
+                let addrs = lookup.await.unwrap().unwrap();
+                assert!(addrs.iter().all(|addr| addr.ip().is_loopback()));
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lookup_keeps_its_slot_until_the_thread_returns() {
+        let slots = Arc::new(Semaphore::new(1));
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let (done_tx, done_rx) = tokio::sync::oneshot::channel();
+        let done_tx = Arc::new(Mutex::new(Some(done_tx)));
+        // Stands in for getaddrinfo stuck on an unresponsive DNS server
+        let stalled = move |_domain: &str| {
+            let _ = release_rx.lock().unwrap().recv();
+            if let Some(done_tx) = done_tx.lock().unwrap().take() {
+                let _ = done_tx.send(());
+            }
+            Ok(Vec::new())
+        };
+
+        let lookup = resolve_with_retry("stalled.test", Some(&slots), DnsRetry::default(), stalled);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), lookup)
+                .await
+                .is_err()
+        );
+        // The caller is gone but the thread is still blocked
+        assert_eq!(slots.available_permits(), 0);
+
+        release_tx.send(()).unwrap();
+        done_rx.await.unwrap();
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while slots.available_permits() == 0 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    fn stub_resolver(
+        failures: Vec<io::Error>,
+    ) -> (
+        Arc<AtomicUsize>,
+        impl Fn(&str) -> io::Result<Vec<SocketAddr>> + Clone + Send + 'static,
+    ) {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let failures = Arc::new(Mutex::new(failures));
+        let counter = calls.clone();
+        let lookup = move |_domain: &str| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            match failures.lock().unwrap().pop() {
+                Some(e) => Err(e),
+                None => Ok(vec!["192.0.2.7:0".parse().unwrap()]),
+            }
+        };
+        (calls, lookup)
+    }
+
+    fn eai_again() -> io::Error {
+        io::Error::other(
+            "failed to lookup address information: Temporary failure in name resolution",
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_transient_dns_failure_is_retried() {
+        let (calls, lookup) = stub_resolver(vec![eai_again()]);
+        let retry = DnsRetry {
+            retries: 2,
+            backoff: Duration::from_millis(100),
+        };
+
+        let start = tokio::time::Instant::now();
+        let addrs = resolve_with_retry("flaky.test", None, retry, lookup)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec!["192.0.2.7:0".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dns_retries_are_bounded_and_skip_nxdomain() {
+        let retry = DnsRetry {
+            retries: 2,
+            backoff: Duration::from_millis(100),
+        };
+
+        let (calls, lookup) = stub_resolver(vec![eai_again(), eai_again(), eai_again()]);
+        let start = tokio::time::Instant::now();
+        assert!(
+            resolve_with_retry("down.test", None, retry, lookup)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        // 100ms, then 200ms
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+
+        let nxdomain =
+            io::Error::other("failed to lookup address information: Name or service not known");
+        let (calls, lookup) = stub_resolver(vec![nxdomain]);
+        assert!(
+            resolve_with_retry("missing.test", None, retry, lookup)
+                .await
+                .is_err()
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_zero_method_greeting_is_refused_consistently() {
+        let mut parse_reader = BufReader::new(&[0x05, 0x00][..]);
+        let greeting = MethodHandler::parse_client_greeting(&mut parse_reader)
+            .await
+            .unwrap();
+        let validation_error = greeting.validate(GreetingPolicy::Reject).unwrap_err();
+
+        for policy in [GreetingPolicy::Warn, GreetingPolicy::Reject] {
+            let (mut client, server) = duplex(1024);
+            client.write_all(&[0x05, 0x00]).await.unwrap();
+            let (server_reader, server_writer) = tokio::io::split(server);
+            let mut reader = BufReader::new(server_reader);
+            let mut writer = BufWriter::new(server_writer);
+
+            let err = perform_handshake(
+                &mut reader,
+                &mut writer,
+                "127.0.0.1:8080".parse().unwrap(),
+                &handshake_config(&[0x00], policy, Duration::ZERO),
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.to_string(), validation_error);
+
+            let mut reply = [0u8; 2];
+            client.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, [SOCKS5_VERSION, Method::NO_ACCEPTABLE_METHODS]);
+        }
+    }
 }
@@ -1,3 +1,8 @@
+use std::{io, net::IpAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::connection::{AddressType, SOCKS5_VERSION, SocksError};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Reply {
@@ -47,9 +52,104 @@ impl Reply {
     }
 }
 
+/// Address in a server reply's BND.ADDR field.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplyAddress {
+    Ip(IpAddr),
+    // Servers may answer with a name; it is returned as sent, never resolved
+    Domain(String),
+}
+
+/// A server's reply to a request, as read by a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SocksReply {
+    pub version: u8,
+    pub reply: u8,
+    pub reserved: u8,
+    pub address_type: u8,
+    pub bound_addr: ReplyAddress,
+    pub bound_port: u16,
+}
+
+impl SocksReply {
+    /// Reads `VER REP RSV ATYP BND.ADDR BND.PORT`, the client-side counterpart
+    /// of `SocksRequest::parse_request`. Unknown reply codes are kept as-is.
+    pub async fn parse<R>(reader: &mut R) -> io::Result<SocksReply>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut header = [0u8; 4];
+        reader
+            .read_exact(&mut header)
+            .await
+            .map_err(|e| SocksError::IoError(e.kind()).to_io_error())?;
+        let [version, reply, reserved, address_type] = header;
+        if version != SOCKS5_VERSION {
+            return Err(SocksError::InvalidVersion(version).to_io_error());
+        }
+
+        let bound_addr = Self::read_address(reader, address_type)
+            .await
+            .map_err(|socks_error| socks_error.to_io_error())?;
+        let bound_port = reader
+            .read_u16()
+            .await
+            .map_err(|e| SocksError::IoError(e.kind()).to_io_error())?;
+
+        Ok(SocksReply {
+            version,
+            reply,
+            reserved,
+            address_type,
+            bound_addr,
+            bound_port,
+        })
+    }
+
+    pub fn reply_code(&self) -> Option<Reply> {
+        Reply::from_u8(self.reply)
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.reply == Reply::SUCCESS
+    }
+
+    async fn read_address<R>(reader: &mut R, atyp: u8) -> Result<ReplyAddress, SocksError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let io_error = |e: io::Error| SocksError::IoError(e.kind());
+        match AddressType::from_u8(atyp) {
+            Some(AddressType::IPv4) => {
+                let mut addr = [0u8; 4];
+                reader.read_exact(&mut addr).await.map_err(io_error)?;
+                Ok(ReplyAddress::Ip(IpAddr::from(addr)))
+            }
+            Some(AddressType::IPv6) => {
+                let mut addr = [0u8; 16];
+                reader.read_exact(&mut addr).await.map_err(io_error)?;
+                Ok(ReplyAddress::Ip(IpAddr::from(addr)))
+            }
+            Some(AddressType::DomainName) => {
+                let len = reader.read_u8().await.map_err(io_error)? as usize;
+                if len == 0 {
+                    return Err(SocksError::EmptyDomainName);
+                }
+                let mut domain = vec![0u8; len];
+                reader.read_exact(&mut domain).await.map_err(io_error)?;
+                String::from_utf8(domain)
+                    .map(ReplyAddress::Domain)
+                    .map_err(|_| SocksError::InvalidDomainNameEncoding)
+            }
+            None => Err(SocksError::UnsupportedAddressType(atyp)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
 
     #[test]
     fn test_reply_constants() {
@@ -136,13 +236,14 @@ mod tests {
         let reply = Reply::Success;
         let debug_str = format!("{:?}", reply);
         assert_eq!(debug_str, "Success");
-        
+
         let reply = Reply::ConnectionRefused;
         let debug_str = format!("{:?}", reply);
         assert_eq!(debug_str, "ConnectionRefused");
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_reply_clone() {
         let original = Reply::NetworkUnreachable;
         let cloned = original.clone();
@@ -176,4 +277,59 @@ mod tests {
             assert_eq!(reply, converted);
         }
     }
+
+    #[tokio::test]
+    async fn test_parse_ipv4_reply() {
+        let data = [0x05, 0x00, 0x00, 0x01, 192, 168, 1, 10, 0x04, 0x38];
+        let reply = SocksReply::parse(&mut &data[..]).await.unwrap();
+        assert!(reply.is_success());
+        assert_eq!(reply.address_type, AddressType::IPV4);
+        assert_eq!(
+            reply.bound_addr,
+            ReplyAddress::Ip(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)))
+        );
+        assert_eq!(reply.bound_port, 1080);
+    }
+
+    #[tokio::test]
+    async fn test_parse_ipv6_reply() {
+        let mut data = vec![0x05, 0x04, 0x00, 0x04];
+        data.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        data.extend_from_slice(&443u16.to_be_bytes());
+        let reply = SocksReply::parse(&mut &data[..]).await.unwrap();
+        assert_eq!(reply.reply_code(), Some(Reply::HostUnreachable));
+        assert!(!reply.is_success());
+        assert_eq!(
+            reply.bound_addr,
+            ReplyAddress::Ip(IpAddr::V6(Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(reply.bound_port, 443);
+    }
+
+    #[tokio::test]
+    async fn test_parse_domain_reply() {
+        let mut data = vec![0x05, 0x00, 0x00, 0x03, 11];
+        data.extend_from_slice(b"example.com");
+        data.extend_from_slice(&80u16.to_be_bytes());
+        let reply = SocksReply::parse(&mut &data[..]).await.unwrap();
+        assert_eq!(reply.address_type, AddressType::DOMAIN_NAME);
+        assert_eq!(
+            reply.bound_addr,
+            ReplyAddress::Domain("example.com".to_string())
+        );
+        assert_eq!(reply.bound_port, 80);
+    }
+
+    #[tokio::test]
+    async fn test_parse_reply_rejects_malformed() {
+        let wrong_version = [0x04, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        assert!(SocksReply::parse(&mut &wrong_version[..]).await.is_err());
+
+        let bad_atyp = [0x05, 0x00, 0x00, 0x02, 0, 0];
+        assert!(SocksReply::parse(&mut &bad_atyp[..]).await.is_err());
+
+        let truncated = [0x05, 0x00, 0x00, 0x01, 127, 0];
+        let err = SocksReply::parse(&mut &truncated[..]).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
 }
@@ -1,10 +1,12 @@
 use std::{io, net::SocketAddr};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufReader, BufWriter};
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 
+use crate::ConnectionContext;
+use crate::config::ConnectionConfig;
 use crate::connection::{
-    AddressType, RESERVED, SOCKS5_VERSION, SocksError, command::Command,
-    command::connect::handle_data_transfer, reply::Reply, send_error_reply, send_socks_error_reply,
+    AddressType, RESERVED, SOCKS5_VERSION, SocksError, close_reason::CloseReason, command::Command,
+    reply::Reply, send_error_reply, send_socks_error_reply,
 };
 
 #[derive(Debug)]
@@ -14,7 +16,12 @@ pub struct SocksRequest {
     pub reserved: u8,
     pub address_type: u8,
     pub dest_addr: std::net::IpAddr,
+    // Every address a domain name resolved to, in resolver order and starting
+    // with `dest_addr`; just `dest_addr` for a literal address
+    pub dest_addrs: Vec<std::net::IpAddr>,
     pub dest_port: u16,
+    // Name `dest_addr` was resolved from, for domain name requests
+    pub dest_domain: Option<String>,
 }
 
 impl SocksRequest {
@@ -22,21 +29,35 @@ impl SocksRequest {
         reader: &mut BufReader<R>,
         writer: &mut BufWriter<W>,
         client_addr: SocketAddr,
-        tcp_nodelay: bool,
-    ) -> io::Result<()>
+        config: &ConnectionConfig,
+        context: &mut ConnectionContext,
+    ) -> io::Result<CloseReason>
     where
-        R: AsyncRead + Unpin,
-        W: AsyncWrite + Unpin,
+        R: AsyncRead + Unpin + Send,
+        W: AsyncWrite + Unpin + Send,
     {
         debug!("Handling request from {}", client_addr);
 
-        let client_request = SocksRequest::parse_request(reader, writer).await?;
+        let client_request = SocksRequest::parse_request(reader, writer, config, context).await?;
+        // The client is past the handshake, free its slot for the next one
+        context.end_handshake();
+
+        if let Some(interceptor) = &config.interceptor
+            && let Some(close_reason) = interceptor
+                .intercept(&client_request, client_addr, reader, writer)
+                .await?
+        {
+            debug!("Connection {} handled by interceptor", client_addr);
+            return Ok(close_reason);
+        }
         debug!(
             "Parsed client request from {}: {:?}",
             client_addr, client_request
         );
 
-        let command: Command = match Command::parse_command(client_request.command) {
+        let command = Command::parse_command(client_request.command)
+            .filter(|command| command.is_enabled(config));
+        let command: Command = match command {
             Some(cmd) => cmd,
             None => {
                 debug!(
@@ -54,24 +75,60 @@ impl SocksRequest {
             }
         };
 
+        context.target = Some(SocketAddr::new(
+            client_request.dest_addr,
+            client_request.dest_port,
+        ));
+        context.requested_domain = client_request.dest_domain.clone();
         let result = command
-            .execute(client_request, client_addr, reader, writer, tcp_nodelay)
+            .execute(client_request, client_addr, reader, writer, config, context)
             .await?;
+        context.reply_code = Some(result.reply_code());
         debug!("Command execution result for {}: {:?}", client_addr, result);
 
-        Ok(())
+        Ok(result.close_reason())
     }
 
-    // Public for testing, should find a better way
+    // Reads one request, resolving a domain name per `config`. A non-zero
+    // reserved byte only passes with `lenient_reserved`, and once the
+    // handshake has run the request must carry the version it settled on
     pub async fn parse_request<R, W>(
         reader: &mut BufReader<R>,
         writer: &mut BufWriter<W>,
+        config: &ConnectionConfig,
+        context: &ConnectionContext,
     ) -> io::Result<SocksRequest>
     where
         R: AsyncRead + Unpin,
         W: AsyncWrite + Unpin,
     {
         let version = SocksRequest::read_u8_with_err(reader, "Failed to read version").await?;
+        // Checked before the address is read, so a bad request never costs a lookup
+        if version != context.negotiated_version.unwrap_or(SOCKS5_VERSION) {
+            let socks_error = match context.negotiated_version {
+                Some(negotiated) => {
+                    error!(
+                        "Request version {} does not match negotiated version {}",
+                        version, negotiated
+                    );
+                    SocksError::VersionMismatch {
+                        negotiated,
+                        requested: version,
+                    }
+                }
+                None => {
+                    error!(
+                        "Invalid SOCKS version: expected {}, got {}",
+                        SOCKS5_VERSION, version
+                    );
+                    SocksError::InvalidVersion(version)
+                }
+            };
+            if let Err(write_err) = send_socks_error_reply(writer, &socks_error).await {
+                debug!("Failed to send version error reply: {}", write_err);
+            }
+            return Err(socks_error.to_io_error());
+        }
 
         let command = SocksRequest::read_u8_with_err(reader, "Failed to read command").await?;
 
@@ -81,8 +138,9 @@ impl SocksRequest {
         let address_type =
             SocksRequest::read_u8_with_err(reader, "Failed to read address type").await?;
 
-        let dest_addr = match AddressType::parse(reader, address_type).await {
-            Ok(addr) => addr,
+        let (dest_addrs, dest_domain) = match AddressType::parse(reader, address_type, config).await
+        {
+            Ok(target) => target,
             Err(socks_error) => {
                 error!("Failed to parse address: {:?}", socks_error);
                 if let Err(write_err) = send_socks_error_reply(writer, &socks_error).await {
@@ -97,20 +155,21 @@ impl SocksRequest {
             error!("Failed to read port: {}", e);
             err
         })?;
+        // The mapped address already replaced the lookup, the port goes with it
+        let dest_port = match dest_domain
+            .as_deref()
+            .and_then(|domain| config.host_map.as_deref()?.get(domain))
+        {
+            Some(target) => target.port(),
+            None => dest_port,
+        };
 
-        if version != SOCKS5_VERSION {
-            error!(
-                "Invalid SOCKS version: expected {}, got {}",
-                SOCKS5_VERSION, version
+        if reserved != RESERVED && config.lenient_reserved {
+            warn!(
+                "Accepting non-zero reserved byte 0x{:02X} from non-compliant client",
+                reserved
             );
-            let socks_error = SocksError::InvalidVersion(version);
-            if let Err(write_err) = send_socks_error_reply(writer, &socks_error).await {
-                debug!("Failed to send version error reply: {}", write_err);
-            }
-            return Err(socks_error.to_io_error());
-        }
-
-        if reserved != RESERVED {
+        } else if reserved != RESERVED {
             error!(
                 "Invalid reserved byte: expected {}, got {}",
                 RESERVED, reserved
@@ -127,8 +186,10 @@ impl SocksRequest {
             command,
             reserved,
             address_type,
-            dest_addr,
+            dest_addr: dest_addrs[0],
+            dest_addrs,
             dest_port,
+            dest_domain,
         })
     }
 
@@ -151,9 +212,28 @@ mod tests {
     };
 
     use super::*;
+    use crate::config::ProxyConfig;
+    use crate::connection::error::ErrorCode;
+    use clap::Parser;
     use std::net::{Ipv4Addr, Ipv6Addr};
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+    fn test_config() -> ConnectionConfig {
+        ConnectionConfig::from(&ProxyConfig::parse_from(["rhoxy-socks"]))
+    }
+
+    // Parses with the default config, as before the handshake has run
+    async fn parse<R, W>(
+        reader: &mut BufReader<R>,
+        writer: &mut BufWriter<W>,
+    ) -> io::Result<SocksRequest>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        SocksRequest::parse_request(reader, writer, &test_config(), &ConnectionContext::new()).await
+    }
+
     #[tokio::test]
     async fn test_parse_request_connect_ipv4() {
         let (mut client, server) = tokio::io::duplex(1024);
@@ -165,7 +245,7 @@ mod tests {
 
         let mut reader = BufReader::new(server);
         let mut writer = BufWriter::new(client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse valid request");
         assert_eq!(request.version, SOCKS5_VERSION);
@@ -189,7 +269,7 @@ mod tests {
 
         let mut reader = BufReader::new(server);
         let mut writer = BufWriter::new(client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
     }
@@ -232,7 +312,7 @@ mod tests {
 
         let mut reader = BufReader::new(server);
         let mut writer = BufWriter::new(client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse IPv6 request");
         assert_eq!(request.version, SOCKS5_VERSION);
@@ -259,7 +339,7 @@ mod tests {
 
         let mut reader = BufReader::new(server);
         let mut writer = BufWriter::new(client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_ok());
         let request = result.unwrap();
         assert_eq!(request.version, SOCKS5_VERSION);
@@ -268,6 +348,27 @@ mod tests {
         assert_eq!(request.dest_port, 80);
     }
 
+    #[tokio::test]
+    async fn test_parse_request_dns_failure_names_domain() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        // .invalid is guaranteed never to resolve
+        let domain = b"rhoxy-test.invalid";
+        let mut data = vec![0x05, 0x01, 0x00, 0x03];
+        data.push(domain.len() as u8);
+        data.extend_from_slice(domain);
+        data.extend_from_slice(&80u16.to_be_bytes());
+
+        client.write_all(&data).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut reader = BufReader::new(server);
+        let mut writer = BufWriter::new(client);
+        let err = parse(&mut reader, &mut writer).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("DNS resolution failed"), "{message}");
+        assert!(message.contains("rhoxy-test.invalid"), "{message}");
+    }
+
     #[tokio::test]
     async fn test_parse_request_domain_empty() {
         let (mut client, server) = tokio::io::duplex(1024);
@@ -283,7 +384,7 @@ mod tests {
 
         let mut reader = BufReader::new(server);
         let mut writer = BufWriter::new(client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert_eq!(err.kind(), io::ErrorKind::InvalidData);
@@ -307,7 +408,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert_eq!(err.kind(), io::ErrorKind::InvalidData);
@@ -331,7 +432,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -348,7 +449,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse BIND request");
         assert_eq!(request.command, Command::BIND);
@@ -370,7 +471,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse UDP_ASSOCIATE request");
         assert_eq!(request.command, Command::UDP_ASSOCIATE);
@@ -392,7 +493,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse request with invalid command");
         assert_eq!(request.command, 0xFF);
@@ -410,13 +511,122 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert_eq!(err.kind(), io::ErrorKind::InvalidData);
         assert!(err.to_string().contains("Invalid reserved byte"));
     }
 
+    #[tokio::test]
+    async fn test_parse_request_reserved_byte_policy() {
+        for lenient in [false, true] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            client
+                .write_all(&[0x05, 0x01, 0x7F, 0x01, 127, 0, 0, 1, 0, 80])
+                .await
+                .unwrap();
+
+            let mut reader = BufReader::new(server);
+            let (_, dummy_client) = tokio::io::duplex(1024);
+            let mut writer = BufWriter::new(dummy_client);
+            let config = ConnectionConfig {
+                lenient_reserved: lenient,
+                ..test_config()
+            };
+            let result = SocksRequest::parse_request(
+                &mut reader,
+                &mut writer,
+                &config,
+                &ConnectionContext::new(),
+            )
+            .await;
+
+            if lenient {
+                let request = result.expect("Lenient mode should accept the request");
+                assert_eq!(request.reserved, 0x7F);
+                assert_eq!(request.dest_port, 80);
+            } else {
+                assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_parse_request_oversized_domain_length_reads_no_further() {
+        // Claims a 255-byte name, then streams far more than any request holds
+        let mut data = vec![0x05, 0x01, 0x00, 0x03, 0xFF];
+        data.extend(std::iter::repeat_n(b'a', 64 * 1024));
+
+        let mut source = &data[..];
+        let mut reader = BufReader::with_capacity(16, &mut source);
+        let (_, dummy_client) = tokio::io::duplex(1024);
+        let mut writer = BufWriter::new(dummy_client);
+        let config = ConnectionConfig {
+            max_domain_len: 64,
+            ..test_config()
+        };
+        let err = SocksRequest::parse_request(
+            &mut reader,
+            &mut writer,
+            &config,
+            &ConnectionContext::new(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::DomainNameTooLong));
+
+        // Only the header and the length byte were consumed
+        let unconsumed = reader.buffer().len();
+        drop(reader);
+        assert_eq!(data.len() - source.len() - unconsumed, 5);
+    }
+
+    #[tokio::test]
+    async fn test_parse_request_version_mismatch() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        client
+            .write_all(&[0x04, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+
+        let mut reader = BufReader::new(server);
+        let (_, dummy_client) = tokio::io::duplex(1024);
+        let mut writer = BufWriter::new(dummy_client);
+        let mut context = ConnectionContext::new();
+        context.negotiated_version = Some(SOCKS5_VERSION);
+        let err = SocksRequest::parse_request(&mut reader, &mut writer, &test_config(), &context)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::VersionMismatch));
+        assert_eq!(
+            err.to_string(),
+            "Request version 4 does not match negotiated version 5"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parse_request_version_checked_before_rest_of_request() {
+        // Only the version byte arrives, the rest of the request never does
+        let (mut client, server) = tokio::io::duplex(1024);
+        client.write_all(&[0x04]).await.unwrap();
+
+        let mut reader = BufReader::new(server);
+        let (_, dummy_client) = tokio::io::duplex(1024);
+        let mut writer = BufWriter::new(dummy_client);
+        let mut context = ConnectionContext::new();
+        context.negotiated_version = Some(SOCKS5_VERSION);
+        let err = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            SocksRequest::parse_request(&mut reader, &mut writer, &test_config(), &context),
+        )
+        .await
+        .expect("version should be refused without waiting for the address")
+        .unwrap_err();
+        assert_eq!(ErrorCode::of(&err), Some(ErrorCode::VersionMismatch));
+    }
+
     #[tokio::test]
     async fn test_parse_request_invalid_version() {
         let (mut client, server) = tokio::io::duplex(1024);
@@ -429,7 +639,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         let err = result.unwrap_err();
         assert_eq!(err.kind(), io::ErrorKind::InvalidData);
@@ -448,7 +658,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse port 0");
         assert_eq!(request.dest_port, 0);
@@ -466,7 +676,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let request = SocksRequest::parse_request(&mut reader, &mut writer)
+        let request = parse(&mut reader, &mut writer)
             .await
             .expect("Should parse port 65535");
         assert_eq!(request.dest_port, 65535);
@@ -482,7 +692,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -497,7 +707,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -515,7 +725,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -532,7 +742,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -550,7 +760,7 @@ mod tests {
         let mut reader = BufReader::new(server);
         let (_, dummy_client) = tokio::io::duplex(1024);
         let mut writer = BufWriter::new(dummy_client);
-        let result = SocksRequest::parse_request(&mut reader, &mut writer).await;
+        let result = parse(&mut reader, &mut writer).await;
         assert!(result.is_err());
         assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
     }
@@ -0,0 +1,333 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::{io, time::Duration};
+#[cfg(target_os = "linux")]
+use tokio::io::{AsyncWriteExt, Interest};
+
+use socket2::{SockRef, TcpKeepalive};
+use std::net::SocketAddr;
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::debug;
+
+// Kernel socket buffer sizes (SO_RCVBUF / SO_SNDBUF); None keeps the OS default
+pub fn apply_buffer_sizes(
+    stream: &TcpStream,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+) -> io::Result<()> {
+    set_buffer_sizes(SockRef::from(stream), recv_buffer_size, send_buffer_size)
+}
+
+// An unconnected socket for dialing `addr`. The buffer sizes are set here
+// rather than after connect() because the SYN already fixes the window scale.
+pub fn outbound_socket(
+    addr: SocketAddr,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+) -> io::Result<TcpSocket> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Err(e) = set_buffer_sizes(SockRef::from(&socket), recv_buffer_size, send_buffer_size) {
+        debug!("Failed to set socket buffer sizes for {}: {}", addr, e);
+    }
+    Ok(socket)
+}
+
+fn set_buffer_sizes(
+    socket: SockRef<'_>,
+    recv_buffer_size: Option<usize>,
+    send_buffer_size: Option<usize>,
+) -> io::Result<()> {
+    if let Some(size) = recv_buffer_size {
+        socket.set_recv_buffer_size(size)?;
+    }
+    if let Some(size) = send_buffer_size {
+        socket.set_send_buffer_size(size)?;
+    }
+    Ok(())
+}
+
+// TCP_USER_TIMEOUT: how long sent data may stay unacknowledged before the
+// kernel aborts the connection. None keeps the OS default.
+pub fn apply_user_timeout(stream: &TcpStream, timeout: Option<Duration>) -> io::Result<()> {
+    let Some(timeout) = timeout else {
+        return Ok(());
+    };
+    #[cfg(target_os = "linux")]
+    return SockRef::from(stream).set_tcp_user_timeout(Some(timeout));
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (stream, timeout);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TCP_USER_TIMEOUT is only supported on Linux",
+        ))
+    }
+}
+
+// Turns keepalive probes on or off mid-connection. The kernel counts the
+// keepalive time from the last packet, so with `idle` set to how long the
+// connection has already been quiet the first probe goes out right away.
+pub fn set_idle_keepalive(socket: SockRef<'_>, idle: Option<Duration>) -> io::Result<()> {
+    match idle {
+        Some(idle) => socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(idle)),
+        None => socket.set_keepalive(false),
+    }
+}
+
+// Marks outgoing packets with `dscp` in the IPv4 TOS / IPv6 traffic class
+// byte, leaving the two ECN bits clear. None keeps the OS default.
+pub fn apply_dscp(stream: &TcpStream, dscp: Option<u8>) -> io::Result<()> {
+    let Some(dscp) = dscp else {
+        return Ok(());
+    };
+    let tos = u32::from(dscp) << 2;
+    let socket = SockRef::from(stream);
+    if stream.local_addr()?.is_ipv4() {
+        return socket.set_tos_v4(tos);
+    }
+    #[cfg(any(target_os = "linux", target_os = "macos", target_os = "freebsd"))]
+    return socket.set_tclass_v6(tos);
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+    {
+        let _ = (socket, tos);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "IPv6 traffic class is not supported on this platform",
+        ))
+    }
+}
+
+// Probes for a usable IPv6 route. Connecting a UDP socket only consults the
+// routing table, so no packet leaves the host.
+pub fn ipv6_available() -> bool {
+    std::net::UdpSocket::bind("[::]:0")
+        .and_then(|socket| socket.connect("[2001:db8::1]:9"))
+        .is_ok()
+}
+
+// Connects with TCP_FASTOPEN_CONNECT set and sends `early_data` first. With
+// a cached cookie the kernel completes connect() before the SYN even leaves
+// and puts the data inside it, so this only returns once the handshake is
+// confirmed; without a cookie it falls back to a regular handshake.
+#[cfg(target_os = "linux")]
+pub async fn connect_with_fast_open(
+    socket: TcpSocket,
+    addr: SocketAddr,
+    early_data: &[u8],
+) -> io::Result<TcpStream> {
+    if let Err(e) = enable_fast_open_connect(&socket) {
+        debug!(
+            "TCP Fast Open unavailable for {}, connecting normally: {}",
+            addr, e
+        );
+    }
+
+    let mut stream = socket.connect(addr).await?;
+    stream.write_all(early_data).await?;
+    wait_established(&stream).await?;
+    Ok(stream)
+}
+
+// Waits until the handshake finished, or reports why it failed. A socket
+// still in SYN_SENT has no peer address yet.
+#[cfg(target_os = "linux")]
+async fn wait_established(stream: &TcpStream) -> io::Result<()> {
+    loop {
+        // Drop the writable readiness left over from the write so the wait
+        // below only wakes on a state change
+        let _ = stream.try_io(Interest::WRITABLE, || {
+            Err::<(), _>(io::Error::from(io::ErrorKind::WouldBlock))
+        });
+        if let Some(e) = stream.take_error()? {
+            return Err(e);
+        }
+        match stream.peer_addr() {
+            Ok(_) => return Ok(()),
+            Err(e) if e.raw_os_error() == Some(libc::ENOTCONN) => {}
+            Err(e) => return Err(e),
+        }
+        stream.ready(Interest::WRITABLE).await?;
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn enable_fast_open_connect(socket: &TcpSocket) -> io::Result<()> {
+    let enable: libc::c_int = 1;
+    // SAFETY: the fd is owned by `socket` and the option value points to a live c_int
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_FASTOPEN_CONNECT,
+            &enable as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn connected_stream() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_apply_buffer_sizes() {
+        let (stream, _peer) = connected_stream().await;
+        apply_buffer_sizes(&stream, Some(256 * 1024), Some(128 * 1024)).unwrap();
+
+        // Linux doubles the requested value to account for bookkeeping overhead
+        let socket = SockRef::from(&stream);
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_apply_buffer_sizes_none_is_noop() {
+        let (stream, _peer) = connected_stream().await;
+        let socket = SockRef::from(&stream);
+        let before = (
+            socket.recv_buffer_size().unwrap(),
+            socket.send_buffer_size().unwrap(),
+        );
+
+        apply_buffer_sizes(&stream, None, None).unwrap();
+        assert_eq!(
+            before,
+            (
+                socket.recv_buffer_size().unwrap(),
+                socket.send_buffer_size().unwrap()
+            )
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_outbound_socket_sizes_buffers_before_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let socket = outbound_socket(addr, Some(256 * 1024), Some(128 * 1024)).unwrap();
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+
+        let stream = socket.connect(addr).await.unwrap();
+        assert!(SockRef::from(&stream).recv_buffer_size().unwrap() >= 256 * 1024);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_fast_open_connect_delivers_first_bytes() {
+        use tokio::io::AsyncReadExt;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // The second connect may reuse the cookie from the first
+        for _ in 0..2 {
+            let socket = outbound_socket(addr, None, None).unwrap();
+            let connect = connect_with_fast_open(socket, addr, b"syn data");
+            let (stream, accepted) = tokio::join!(connect, listener.accept());
+            stream.unwrap();
+
+            let (mut peer, _) = accepted.unwrap();
+            let mut buf = [0u8; 8];
+            peer.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"syn data");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_fast_open_with_cached_cookie_reports_refused_target() {
+        // A fast-open listener hands out a cookie for 127.0.0.1 (when the
+        // host allows server-side fast open), which later connects reuse
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let qlen: libc::c_int = 16;
+        // SAFETY: the fd is owned by `listener` and the option value points to a live c_int
+        unsafe {
+            libc::setsockopt(
+                listener.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_FASTOPEN,
+                &qlen as *const libc::c_int as *const libc::c_void,
+                std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+            );
+        }
+        let addr = listener.local_addr().unwrap();
+        for _ in 0..2 {
+            let socket = outbound_socket(addr, None, None).unwrap();
+            let connect = connect_with_fast_open(socket, addr, b"hello");
+            let (stream, accepted) = tokio::join!(connect, listener.accept());
+            stream.unwrap();
+            accepted.unwrap();
+        }
+
+        // Same host, nothing listening: the refusal must surface here rather
+        // than after the caller already reported success
+        let closed = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let socket = outbound_socket(closed, None, None).unwrap();
+        let err = connect_with_fast_open(socket, closed, b"hello")
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_apply_user_timeout() {
+        let (stream, _peer) = connected_stream().await;
+        let socket = SockRef::from(&stream);
+        let default = socket.tcp_user_timeout().unwrap();
+
+        apply_user_timeout(&stream, None).unwrap();
+        assert_eq!(socket.tcp_user_timeout().unwrap(), default);
+
+        apply_user_timeout(&stream, Some(Duration::from_millis(1500))).unwrap();
+        assert_eq!(
+            socket.tcp_user_timeout().unwrap(),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_apply_dscp() {
+        let (stream, _peer) = connected_stream().await;
+        let socket = SockRef::from(&stream);
+        let default = socket.tos_v4().unwrap();
+
+        apply_dscp(&stream, None).unwrap();
+        assert_eq!(socket.tos_v4().unwrap(), default);
+
+        // EF (46) is 0xB8 in the TOS byte
+        apply_dscp(&stream, Some(46)).unwrap();
+        assert_eq!(socket.tos_v4().unwrap(), 0xB8);
+
+        let listener = TcpListener::bind("[::1]:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        apply_dscp(&stream, Some(10)).unwrap();
+        assert_eq!(SockRef::from(&stream).tclass_v6().unwrap(), 10 << 2);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    sync::oneshot,
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, info, warn};
+
+use crate::config::HttpUrl;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Background registration with a discovery endpoint.
+///
+/// Sends `POST` on start, `PUT` every `interval` as a heartbeat and `DELETE`
+/// from [`Registration::deregister`]. Failures are logged and never stop the proxy.
+pub struct Registration {
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Registration {
+    pub fn spawn(url: HttpUrl, listen_addr: SocketAddr, interval: Duration) -> Self {
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let body = format!(
+            "{{\"address\":\"{}\",\"version\":\"{}\",\"protocol\":\"socks5\"}}",
+            listen_addr,
+            env!("CARGO_PKG_VERSION")
+        );
+
+        let task = tokio::spawn(async move {
+            match send(&url, "POST", &body).await {
+                Ok(()) => info!("Registered {} with {}", listen_addr, url),
+                Err(e) => warn!("Failed to register with {}: {}", url, e),
+            }
+
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        if let Err(e) = send(&url, "PUT", &body).await {
+                            warn!("Discovery heartbeat to {} failed: {}", url, e);
+                        }
+                    }
+                    _ = &mut stop_rx => break,
+                }
+            }
+
+            match send(&url, "DELETE", &body).await {
+                Ok(()) => info!("Deregistered {} from {}", listen_addr, url),
+                Err(e) => warn!("Failed to deregister from {}: {}", url, e),
+            }
+        });
+
+        Self { stop_tx, task }
+    }
+
+    pub async fn deregister(self) {
+        let _ = self.stop_tx.send(());
+        if let Err(e) = self.task.await {
+            debug!("Discovery task ended abnormally: {}", e);
+        }
+    }
+}
+
+// Minimal HTTP/1.1 request, one connection per call; any 2xx counts as success
+pub(crate) async fn send(url: &HttpUrl, method: &str, body: &str) -> io::Result<()> {
+    let exchange = async {
+        let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            url.path,
+            url.host,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut status_line = String::new();
+        BufReader::new(stream).read_line(&mut status_line).await?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse::<u16>().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Malformed HTTP response"))?;
+        if !(200..300).contains(&status) {
+            return Err(io::Error::other(format!("HTTP status {}", status)));
+        }
+        Ok(())
+    };
+
+    timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "HTTP request timed out"))?
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    // Records the method, path and body of every request and answers 204
+    pub(crate) async fn mock_discovery_server() -> (SocketAddr, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"}") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let request = String::from_utf8_lossy(&request);
+                let request_line = request.lines().next().unwrap_or_default();
+                let body = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", request_line, body));
+                let _ = socket
+                    .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_registration_lifecycle() {
+        let (addr, requests) = mock_discovery_server().await;
+        let url: HttpUrl = format!("http://{}/proxies", addr).parse().unwrap();
+        let listen_addr: SocketAddr = "192.0.2.1:1080".parse().unwrap();
+
+        let registration = Registration::spawn(url, listen_addr, Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(120)).await;
+        registration.deregister().await;
+
+        let requests = requests.lock().unwrap();
+        assert!(requests[0].starts_with("POST /proxies HTTP/1.1"));
+        assert!(requests[0].contains("\"address\":\"192.0.2.1:1080\""));
+        assert!(requests[1].starts_with("PUT /proxies HTTP/1.1"));
+        assert!(
+            requests
+                .last()
+                .unwrap()
+                .starts_with("DELETE /proxies HTTP/1.1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_reports_http_errors() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let _ = socket.read(&mut buf).await;
+            let _ = socket.write_all(b"HTTP/1.1 503 Unavailable\r\n\r\n").await;
+        });
+
+        let url: HttpUrl = format!("http://{}/", addr).parse().unwrap();
+        let err = send(&url, "POST", "{}").await.unwrap_err();
+        assert!(err.to_string().contains("503"));
+    }
+}
@@ -0,0 +1,38 @@
+use std::{io, net::SocketAddr};
+
+use tokio::{
+    io::copy,
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+// Built-in target for benchmarking the SOCKS path without an external server.
+// Binds immediately and echoes every connection back to itself in the background.
+pub async fn spawn_echo_target(addr: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    info!("Test echo target listening on {}", local_addr);
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer_addr)) => {
+                    tokio::spawn(echo(stream, peer_addr));
+                }
+                Err(e) => {
+                    debug!("Echo target failed to accept connection: {}", e);
+                }
+            }
+        }
+    });
+
+    Ok(local_addr)
+}
+
+async fn echo(mut stream: TcpStream, peer_addr: SocketAddr) {
+    let (mut reader, mut writer) = stream.split();
+    match copy(&mut reader, &mut writer).await {
+        Ok(bytes) => debug!("Echo target echoed {} bytes to {}", bytes, peer_addr),
+        Err(e) => debug!("Echo target error for {}: {}", peer_addr, e),
+    }
+}
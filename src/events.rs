@@ -0,0 +1,123 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use tracing::{debug, info, warn};
+
+use crate::connection::{close_reason::CloseReason, method::method::Method};
+
+pub trait EventSink: Send + Sync {
+    fn connection_closed(&self, client_addr: SocketAddr, reason: CloseReason);
+
+    // Every method code the client offered, unknown ones included, so probes
+    // for unsupported auth can be audited. Called before validation.
+    fn greeting_received(&self, _client_addr: SocketAddr, _methods: &[u8]) {}
+
+    // Address a CONNECT actually dialed, with the domain it was resolved from
+    // when the client sent one and the auth method the handshake settled on
+    fn target_connected(
+        &self,
+        _client_addr: SocketAddr,
+        _domain: Option<&str>,
+        _target: SocketAddr,
+        _method: Option<Method>,
+    ) {
+    }
+}
+
+impl fmt::Debug for dyn EventSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventSink")
+    }
+}
+
+// Default sink. Logs 1 in `sample_rate` completed connections at info level
+// (0 disables sampling) and every failed connection at warn level.
+#[derive(Debug, Default)]
+pub struct LogEventSink {
+    sample_rate: u64,
+    completed: AtomicU64,
+}
+
+impl LogEventSink {
+    pub fn new(sample_rate: u64) -> Self {
+        Self {
+            sample_rate,
+            completed: AtomicU64::new(0),
+        }
+    }
+
+    fn should_log(&self, reason: CloseReason) -> bool {
+        if reason.is_error() {
+            return true;
+        }
+        if self.sample_rate == 0 {
+            return false;
+        }
+        let count = self.completed.fetch_add(1, Ordering::Relaxed);
+        count.is_multiple_of(self.sample_rate)
+    }
+}
+
+impl EventSink for LogEventSink {
+    fn connection_closed(&self, client_addr: SocketAddr, reason: CloseReason) {
+        if !self.should_log(reason) {
+            debug!("Connection {} closed: {}", client_addr, reason);
+        } else if reason.is_error() {
+            warn!("Connection {} closed: {}", client_addr, reason);
+        } else {
+            info!("Connection {} closed: {}", client_addr, reason);
+        }
+    }
+
+    fn target_connected(
+        &self,
+        client_addr: SocketAddr,
+        domain: Option<&str>,
+        target: SocketAddr,
+        _method: Option<Method>,
+    ) {
+        match domain {
+            Some(domain) => debug!(
+                "Connection {} connected to {} ({})",
+                client_addr, domain, target
+            ),
+            None => debug!("Connection {} connected to {}", client_addr, target),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_sample_rate_one_logs_everything() {
+        let sink = LogEventSink::new(1);
+        for _ in 0..5 {
+            assert!(sink.should_log(CloseReason::ClientClosed));
+        }
+        assert!(sink.should_log(CloseReason::Error(io::ErrorKind::BrokenPipe)));
+    }
+
+    #[test]
+    fn test_sample_rate_zero_logs_only_errors() {
+        let sink = LogEventSink::new(0);
+        for _ in 0..5 {
+            assert!(!sink.should_log(CloseReason::TargetClosed));
+        }
+        assert!(sink.should_log(CloseReason::Error(io::ErrorKind::BrokenPipe)));
+    }
+
+    #[test]
+    fn test_sample_rate_n() {
+        let sink = LogEventSink::new(3);
+        let logged: Vec<bool> = (0..6)
+            .map(|_| sink.should_log(CloseReason::ClientClosed))
+            .collect();
+        assert_eq!(logged, vec![true, false, false, true, false, false]);
+    }
+}
@@ -0,0 +1,84 @@
+use std::{collections::HashMap, net::SocketAddr, str::FromStr};
+
+// One --map entry: requests for `domain` are sent to `target` instead
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostMapping {
+    pub domain: String,
+    pub target: SocketAddr,
+}
+
+impl FromStr for HostMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (domain, target) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Host mapping '{}' must look like DOMAIN=ADDR:PORT", s))?;
+        let domain = domain.trim();
+        if domain.is_empty() {
+            return Err(format!("Missing domain in host mapping '{}'", s));
+        }
+        let target = target
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid target address in host mapping '{}'", s))?;
+
+        Ok(Self {
+            domain: domain.to_ascii_lowercase(),
+            target,
+        })
+    }
+}
+
+// Domains the connect path dials at a fixed address without resolving them,
+// for tests and split-horizon setups. Names match case-insensitively.
+#[derive(Debug, Clone, Default)]
+pub struct HostMap(HashMap<String, SocketAddr>);
+
+impl HostMap {
+    pub fn new(mappings: &[HostMapping]) -> Self {
+        Self(
+            mappings
+                .iter()
+                .map(|mapping| (mapping.domain.clone(), mapping.target))
+                .collect(),
+        )
+    }
+
+    pub fn get(&self, domain: &str) -> Option<SocketAddr> {
+        self.0.get(&domain.to_ascii_lowercase()).copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_mapping_parse() {
+        let mapping: HostMapping = "Example.com=127.0.0.1:9000".parse().unwrap();
+        assert_eq!(mapping.domain, "example.com");
+        assert_eq!(mapping.target, "127.0.0.1:9000".parse().unwrap());
+
+        let v6: HostMapping = "v6.test=[::1]:80".parse().unwrap();
+        assert_eq!(v6.target, "[::1]:80".parse().unwrap());
+
+        assert!("example.com".parse::<HostMapping>().is_err());
+        assert!("=127.0.0.1:80".parse::<HostMapping>().is_err());
+        assert!("example.com=127.0.0.1".parse::<HostMapping>().is_err());
+    }
+
+    #[test]
+    fn test_host_map_lookup_ignores_case() {
+        let map = HostMap::new(&["example.com=127.0.0.1:9000".parse().unwrap()]);
+        assert_eq!(
+            map.get("EXAMPLE.com"),
+            Some("127.0.0.1:9000".parse().unwrap())
+        );
+        assert_eq!(map.get("other.com"), None);
+    }
+}
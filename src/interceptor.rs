@@ -0,0 +1,27 @@
+use std::{fmt, future::Future, io, net::SocketAddr, pin::Pin};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::connection::{close_reason::CloseReason, request::SocksRequest};
+
+pub type InterceptFuture<'a> =
+    Pin<Box<dyn Future<Output = io::Result<Option<CloseReason>>> + Send + 'a>>;
+
+// Lets embedders take over a connection once its request has been parsed.
+// Resolving to `Some(reason)` means the interceptor handled the connection
+// (including any SOCKS reply); `None` falls through to normal command execution.
+pub trait ConnectionInterceptor: Send + Sync {
+    fn intercept<'a>(
+        &'a self,
+        request: &'a SocksRequest,
+        client_addr: SocketAddr,
+        reader: &'a mut (dyn AsyncRead + Unpin + Send),
+        writer: &'a mut (dyn AsyncWrite + Unpin + Send),
+    ) -> InterceptFuture<'a>;
+}
+
+impl fmt::Debug for dyn ConnectionInterceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConnectionInterceptor")
+    }
+}
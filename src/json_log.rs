@@ -0,0 +1,170 @@
+use std::fmt;
+
+use serde_json::{Map, Value};
+use tracing::{
+    Event, Subscriber,
+    field::{Field, Visit},
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+        format::Writer,
+        time::{FormatTime, SystemTime},
+    },
+    registry::LookupSpan,
+};
+
+/// How log lines are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human-readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+// Collects an event's or span's fields into a JSON object
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// Stores span fields as a JSON object, so [`JsonFormat`] can embed them.
+#[derive(Debug, Default)]
+pub struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+}
+
+/// Writes each event as one JSON line with `timestamp`, `level`, `target`,
+/// the event's `fields` and the `spans` it happened in, outermost first.
+/// Meant to be paired with [`JsonFields`].
+#[derive(Debug, Default)]
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let extensions = span.extensions();
+                let mut object = extensions
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|formatted| serde_json::from_str(&formatted.fields).ok())
+                    .unwrap_or_else(Map::new);
+                object.insert("name".to_string(), span.name().into());
+                Value::Object(object)
+            })
+            .collect();
+
+        let meta = event.metadata();
+        let mut line = Map::new();
+        line.insert("timestamp".to_string(), timestamp.into());
+        line.insert("level".to_string(), meta.level().as_str().into());
+        line.insert("target".to_string(), meta.target().into());
+        line.insert("fields".to_string(), Value::Object(fields));
+        line.insert("spans".to_string(), Value::Array(spans));
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_lines_carry_event_and_span_fields() {
+        let capture = Capture::default();
+        let make_writer = {
+            let capture = capture.clone();
+            move || capture.clone()
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(make_writer)
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("connection", id = 7u64);
+            let _entered = span.enter();
+            tracing::warn!(bytes = 42u64, "relay \"ended\"");
+        });
+
+        let output = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let line: Value = serde_json::from_str(output.trim_end()).unwrap();
+        assert_eq!(line["level"], "WARN");
+        assert_eq!(line["target"], module_path!());
+        assert_eq!(line["fields"]["message"], "relay \"ended\"");
+        assert_eq!(line["fields"]["bytes"], 42);
+        assert_eq!(line["spans"][0]["name"], "connection");
+        assert_eq!(line["spans"][0]["id"], 7);
+        assert!(line["timestamp"].as_str().is_some_and(|t| !t.is_empty()));
+    }
+}
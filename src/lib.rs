@@ -1,20 +1,151 @@
+pub mod acl;
+pub mod buffer_pool;
+pub mod client_limits;
 pub mod config;
 pub mod connection;
+pub mod discovery;
+pub mod echo;
+pub mod events;
+pub mod host_map;
+pub mod interceptor;
+pub mod json_log;
+pub mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+#[cfg(target_os = "linux")]
+pub mod privileges;
+pub mod registry;
 pub mod server;
+pub mod syslog;
+pub mod target_limits;
+pub mod webhook;
 
 use std::io;
 use std::net::SocketAddr;
-use tokio::io::{BufReader, BufWriter};
-use tokio::net::TcpStream;
-use tokio::time::timeout;
-use tracing::debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpStream, tcp::OwnedWriteHalf};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::{Instant, timeout};
+use tracing::{Instrument, debug, info_span};
+
+use crate::client_limits::HandshakeSlot;
+use crate::connection::close_reason::CloseReason;
+use crate::connection::error::ErrorCode;
+use crate::connection::method::method::Method;
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
 
 pub async fn handle_connection(
     stream: TcpStream,
     client_addr: SocketAddr,
     config: config::ConnectionConfig,
-) -> io::Result<()> {
+) -> io::Result<CloseReason> {
+    handle_connection_with_context(stream, client_addr, config, &mut ConnectionContext::new()).await
+}
+
+// Per-connection state threaded through the handshake, request and command
+// stages; fields are filled in as each stage completes
+#[derive(Debug)]
+pub struct ConnectionContext {
+    pub id: u64,
+    // Held only until the client's request has been read, so the server can cap
+    // how many connections sit in the handshake phase independently of relays
+    pub handshake_permit: Option<OwnedSemaphorePermit>,
+    // Same, for the per-IP handshake limit
+    pub client_handshake: Option<HandshakeSlot>,
+    pub negotiated_method: Option<Method>,
+    // Protocol version the greeting settled on; the request must repeat it
+    pub negotiated_version: Option<u8>,
+    pub target: Option<SocketAddr>,
+    // Domain name the client asked for, when `target` was resolved from one
+    pub requested_domain: Option<String>,
+    pub reply_code: Option<u8>,
+    pub close_reason: Option<CloseReason>,
+    // Held from accept until the relay starts, so the server can bound the
+    // setup work in flight. Locked since commands only borrow the context.
+    setup_permit: std::sync::Mutex<Option<OwnedSemaphorePermit>>,
+}
+
+impl ConnectionContext {
+    pub fn new() -> Self {
+        Self {
+            id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            handshake_permit: None,
+            client_handshake: None,
+            negotiated_method: None,
+            negotiated_version: None,
+            target: None,
+            requested_domain: None,
+            reply_code: None,
+            close_reason: None,
+            setup_permit: std::sync::Mutex::new(None),
+        }
+    }
+
+    pub fn with_handshake_permit(mut self, permit: Option<OwnedSemaphorePermit>) -> Self {
+        self.handshake_permit = permit;
+        self
+    }
+
+    pub fn with_client_handshake(mut self, slot: Option<HandshakeSlot>) -> Self {
+        self.client_handshake = slot;
+        self
+    }
+
+    pub fn with_setup_permit(self, permit: Option<OwnedSemaphorePermit>) -> Self {
+        *self.setup_permit.lock().unwrap() = permit;
+        self
+    }
+
+    // Frees the accept slot once the connection is relaying
+    pub fn end_setup(&self) {
+        self.setup_permit.lock().unwrap().take();
+    }
+
+    // Frees the handshake slots once the request has been read
+    pub fn end_handshake(&mut self) {
+        self.handshake_permit = None;
+        self.client_handshake = None;
+    }
+}
+
+impl Default for ConnectionContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub async fn handle_connection_with_context(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    config: config::ConnectionConfig,
+    context: &mut ConnectionContext,
+) -> io::Result<CloseReason> {
+    // Every log line for this connection carries the id, so an error reply
+    // can be tied back to the client it was sent to
+    let span = info_span!("connection", id = context.id);
+    let metrics = config.metrics.clone();
+    let opened_at = Instant::now();
+    let result = run_connection(stream, client_addr, config, context)
+        .instrument(span)
+        .await;
+    metrics.record_connection_duration(opened_at.elapsed());
+    if let Ok(close_reason) = &result {
+        context.close_reason = Some(*close_reason);
+    }
+    result
+}
+
+async fn run_connection(
+    stream: TcpStream,
+    client_addr: SocketAddr,
+    config: config::ConnectionConfig,
+    context: &mut ConnectionContext,
+) -> io::Result<CloseReason> {
     debug!("Handling connection from {}", client_addr);
+    config.metrics.record_client(client_addr.ip());
 
     if config.tcp_nodelay {
         // fuck it, we enable nodelay on the client stream also
@@ -25,19 +156,90 @@ pub async fn handle_connection(
         }
     }
 
+    if let Err(e) =
+        connection::socket_options::apply_buffer_sizes(&stream, config.so_rcvbuf, config.so_sndbuf)
+    {
+        debug!(
+            "Failed to set socket buffer sizes for {}: {}",
+            client_addr, e
+        );
+    }
+
+    if let Err(e) = connection::socket_options::apply_user_timeout(&stream, config.tcp_user_timeout)
+    {
+        debug!("Failed to set TCP_USER_TIMEOUT for {}: {}", client_addr, e);
+    }
+
     // TODO: Apply keep-alive
     let (reader, writer) = stream.into_split();
     let mut reader = BufReader::with_capacity(config.buffer_size, reader);
     let mut writer = BufWriter::with_capacity(config.buffer_size, writer);
 
+    let serve = serve_connection(&mut reader, &mut writer, client_addr, &config, context);
+    let result = match config.max_connection_lifetime {
+        Some(lifetime) => tokio::select! {
+            result = serve => result,
+            _ = tokio::time::sleep(lifetime) => {
+                debug!(
+                    "Connection {} reached its maximum lifetime of {:?}",
+                    client_addr, lifetime
+                );
+                Ok(CloseReason::LifetimeExceeded)
+            }
+        },
+        None => serve.await,
+    };
+
+    // Best-effort flush on every exit path so bytes still sitting in the
+    // BufWriter (e.g. relayed data before an abrupt error) reach the client
+    if let Err(e) = writer.flush().await {
+        debug!("Failed to flush writer for {}: {}", client_addr, e);
+    }
+    let close_reason = match result {
+        Ok(close_reason) => close_reason,
+        Err(e) => {
+            // Only a broken version, reserved byte or greeting counts, not a
+            // well-formed request the server refuses
+            if config.reset_on_protocol_violation
+                && ErrorCode::of(&e).is_some_and(ErrorCode::is_protocol_violation)
+            {
+                debug!("Resetting client {} after protocol violation", client_addr);
+                abortive_close(writer, client_addr);
+            }
+            return Err(e);
+        }
+    };
+
+    if close_reason == CloseReason::TargetReset && config.abort_on_target_reset {
+        debug!("Propagating target reset to client {}", client_addr);
+        abortive_close(writer, client_addr);
+    }
+
+    Ok(close_reason)
+}
+
+fn abortive_close(writer: BufWriter<OwnedWriteHalf>, client_addr: SocketAddr) {
+    if let Err(e) = writer.get_ref().as_ref().set_linger(Some(Duration::ZERO)) {
+        debug!("Failed to set SO_LINGER for {}: {}", client_addr, e);
+    }
+    // Skip the write-half shutdown so the close goes out as RST, not FIN
+    writer.into_inner().forget();
+}
+
+async fn serve_connection<R, W>(
+    reader: &mut BufReader<R>,
+    writer: &mut BufWriter<W>,
+    client_addr: SocketAddr,
+    config: &config::ConnectionConfig,
+    context: &mut ConnectionContext,
+) -> io::Result<CloseReason>
+where
+    R: AsyncRead + Unpin + Send,
+    W: AsyncWrite + Unpin + Send,
+{
     match timeout(
         config.handshake_timeout,
-        connection::perform_handshake(
-            &mut reader,
-            &mut writer,
-            client_addr,
-            &config.supported_auth_methods,
-        ),
+        connection::perform_handshake_with_context(reader, writer, client_addr, config, context),
     )
     .await
     {
@@ -47,16 +249,17 @@ pub async fn handle_connection(
                 "Handshake timeout for {} after {:?}",
                 client_addr, config.handshake_timeout
             );
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "Handshake timeout"));
+            return Ok(CloseReason::HandshakeTimeout);
         }
-    }
-    match timeout(
+    };
+    let close_reason = match timeout(
         config.connection_timeout,
         connection::request::SocksRequest::handle_request(
-            &mut reader,
-            &mut writer,
+            reader,
+            writer,
             client_addr,
-            config.tcp_nodelay,
+            config,
+            context,
         ),
     )
     .await
@@ -67,12 +270,77 @@ pub async fn handle_connection(
                 "Connection {} timed out after {:?}",
                 client_addr, config.connection_timeout
             );
-            return Err(io::Error::new(
-                io::ErrorKind::TimedOut,
-                format!("Connection timed out after {:?}", config.connection_timeout),
-            ));
+            CloseReason::ConnectionTimeout
         }
+    };
+
+    Ok(close_reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConnectionConfig, ProxyConfig};
+    use clap::Parser;
+    use tokio::io::duplex;
+    use tokio::time::Instant;
+
+    fn config_with(args: &[&str]) -> ConnectionConfig {
+        ConnectionConfig::from(&ProxyConfig::parse_from(
+            std::iter::once("rhoxy-socks").chain(args.iter().copied()),
+        ))
+    }
+
+    // With paused time the runtime jumps straight to the next timer once every
+    // task is idle, so these run instantly and measure virtual time exactly
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_client_hits_handshake_timeout_exactly() {
+        let config = config_with(&["--handshake-timeout", "7"]);
+        let (server, _client) = duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+
+        let start = Instant::now();
+        let close_reason = serve_connection(
+            &mut reader,
+            &mut writer,
+            "127.0.0.1:1".parse().unwrap(),
+            &config,
+            &mut ConnectionContext::new(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(close_reason, CloseReason::HandshakeTimeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(7));
     }
 
-    Ok(())
+    #[tokio::test(start_paused = true)]
+    async fn test_partial_greeting_times_out_from_connection_start() {
+        let config = config_with(&["--handshake-timeout", "3"]);
+        let (server, mut client) = duplex(1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let mut reader = BufReader::new(server_reader);
+        let mut writer = BufWriter::new(server_writer);
+
+        let mut context = ConnectionContext::new();
+        let start = Instant::now();
+        let slow_client = async {
+            tokio::time::sleep(Duration::from_secs(2)).await;
+            client.write_all(&[0x05]).await.unwrap();
+            client
+        };
+        let serve = serve_connection(
+            &mut reader,
+            &mut writer,
+            "127.0.0.1:1".parse().unwrap(),
+            &config,
+            &mut context,
+        );
+        let (result, _client) = tokio::join!(serve, slow_client);
+
+        assert_eq!(result.unwrap(), CloseReason::HandshakeTimeout);
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
 }
@@ -1,8 +1,15 @@
 use std::io;
 use std::sync::Arc;
 use tracing::error;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
 
-use rhoxy_socks::{config::ProxyConfig, server::ProxyServer};
+use rhoxy_socks::{
+    config::ProxyConfig,
+    echo,
+    json_log::{JsonFields, JsonFormat, LogFormat},
+    server::ProxyServer,
+    syslog::{LogTarget, SyslogWriter},
+};
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
@@ -13,9 +20,29 @@ async fn main() -> io::Result<()> {
         std::process::exit(1);
     }
 
-    tracing_subscriber::fmt()
+    let (writer, to_syslog) = match config.log_target {
+        LogTarget::Stdout => (BoxMakeWriter::new(io::stdout), false),
+        LogTarget::Syslog => match SyslogWriter::connect(config.syslog_addr) {
+            Ok(writer) => (BoxMakeWriter::new(writer), true),
+            Err(e) => {
+                eprintln!("Failed to open syslog: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    // The syslog daemon stamps the time and colours make no sense there
+    let subscriber = tracing_subscriber::fmt()
         .with_max_level(config.tracing_level())
-        .init();
+        .with_writer(writer)
+        .with_ansi(!to_syslog && config.log_format == LogFormat::Text);
+    match config.log_format {
+        LogFormat::Text if to_syslog => subscriber.without_time().init(),
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .init(),
+    }
 
     config.display_summary();
 
@@ -27,6 +54,13 @@ async fn main() -> io::Result<()> {
         }
     };
 
+    if let Some(echo_addr) = config.test_echo_target
+        && let Err(e) = echo::spawn_echo_target(echo_addr).await
+    {
+        error!("Failed to start test echo target on {}: {}", echo_addr, e);
+        return Err(e);
+    }
+
     let mut server = ProxyServer::new(server_addr, Arc::new(config)).await?;
     server.run().await
 }
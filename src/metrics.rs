@@ -0,0 +1,215 @@
+use std::{
+    net::IpAddr,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Serialize;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    clients_ipv4: AtomicU64,
+    clients_ipv6: AtomicU64,
+    targets_ipv4: AtomicU64,
+    targets_ipv6: AtomicU64,
+    near_connection_limit: AtomicBool,
+    connection_durations: DurationHistogram,
+}
+
+// Upper bounds of the connection duration buckets, in milliseconds; anything
+// longer than the last one lands in a final unbounded bucket
+pub const DURATION_BUCKETS_MS: [u64; 9] = [
+    100, 500, 1_000, 5_000, 30_000, 60_000, 300_000, 1_800_000, 3_600_000,
+];
+
+#[derive(Debug, Default)]
+struct DurationHistogram {
+    // Per-bucket (non-cumulative) counts, the last entry is the unbounded bucket
+    buckets: [AtomicU64; DURATION_BUCKETS_MS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FamilyCounts {
+    pub ipv4: u64,
+    pub ipv6: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct MetricsSnapshot {
+    pub clients: FamilyCounts,
+    pub targets: FamilyCounts,
+    // Active connections are at or above the --connection-watermark
+    pub near_connection_limit: bool,
+    pub connection_durations: HistogramSnapshot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HistogramBucket {
+    // Inclusive upper bound in milliseconds, None for the unbounded bucket
+    pub le_ms: Option<u64>,
+    // Observations at or below `le_ms`, i.e. cumulative like Prometheus buckets
+    pub count: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct HistogramSnapshot {
+    pub buckets: Vec<HistogramBucket>,
+    pub count: u64,
+    pub sum_ms: u64,
+}
+
+impl HistogramSnapshot {
+    /// Upper bound in milliseconds of the bucket holding the `quantile`
+    /// (0.0..=1.0) observation, e.g. 0.5 for the median or 0.99 for the tail.
+    /// None when nothing was recorded or the observation is past the last bound.
+    pub fn quantile_bound_ms(&self, quantile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((quantile.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        self.buckets
+            .iter()
+            .find(|bucket| bucket.count >= rank)
+            .and_then(|bucket| bucket.le_ms)
+    }
+}
+
+impl Metrics {
+    pub fn record_client(&self, addr: IpAddr) {
+        Self::increment_family(&self.clients_ipv4, &self.clients_ipv6, addr);
+    }
+
+    pub fn record_target(&self, addr: IpAddr) {
+        Self::increment_family(&self.targets_ipv4, &self.targets_ipv6, addr);
+    }
+
+    pub fn set_near_connection_limit(&self, near: bool) {
+        self.near_connection_limit.store(near, Ordering::Relaxed);
+    }
+
+    /// Records how long a connection was open, from accept to close.
+    pub fn record_connection_duration(&self, duration: Duration) {
+        let millis = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+        let index = DURATION_BUCKETS_MS
+            .iter()
+            .position(|&bound| millis <= bound)
+            .unwrap_or(DURATION_BUCKETS_MS.len());
+        let histogram = &self.connection_durations;
+        histogram.buckets[index].fetch_add(1, Ordering::Relaxed);
+        histogram.sum_ms.fetch_add(millis, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            clients: FamilyCounts {
+                ipv4: self.clients_ipv4.load(Ordering::Relaxed),
+                ipv6: self.clients_ipv6.load(Ordering::Relaxed),
+            },
+            targets: FamilyCounts {
+                ipv4: self.targets_ipv4.load(Ordering::Relaxed),
+                ipv6: self.targets_ipv6.load(Ordering::Relaxed),
+            },
+            near_connection_limit: self.near_connection_limit.load(Ordering::Relaxed),
+            connection_durations: self.connection_durations.snapshot(),
+        }
+    }
+
+    fn increment_family(ipv4: &AtomicU64, ipv6: &AtomicU64, addr: IpAddr) {
+        let counter = match addr {
+            IpAddr::V4(_) => ipv4,
+            IpAddr::V6(_) => ipv6,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl DurationHistogram {
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(index, count)| {
+                cumulative += count.load(Ordering::Relaxed);
+                HistogramBucket {
+                    le_ms: DURATION_BUCKETS_MS.get(index).copied(),
+                    count: cumulative,
+                }
+            })
+            .collect();
+
+        HistogramSnapshot {
+            buckets,
+            count: cumulative,
+            sum_ms: self.sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_family_counters() {
+        let metrics = Metrics::default();
+        metrics.record_client(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        metrics.record_client(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        metrics.record_client(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        metrics.record_target(IpAddr::V6(Ipv6Addr::LOCALHOST));
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.clients, FamilyCounts { ipv4: 2, ipv6: 1 });
+        assert_eq!(snapshot.targets, FamilyCounts { ipv4: 0, ipv6: 1 });
+    }
+
+    #[test]
+    fn test_connection_duration_buckets() {
+        let metrics = Metrics::default();
+        for millis in [5, 100, 101, 700, 700, 4_000_000] {
+            metrics.record_connection_duration(Duration::from_millis(millis));
+        }
+
+        let histogram = metrics.snapshot().connection_durations;
+        assert_eq!(histogram.count, 6);
+        assert_eq!(histogram.sum_ms, 4_001_606);
+        assert_eq!(histogram.buckets.len(), DURATION_BUCKETS_MS.len() + 1);
+        assert_eq!(
+            histogram.buckets[0],
+            HistogramBucket {
+                le_ms: Some(100),
+                count: 2
+            }
+        );
+        assert_eq!(
+            histogram.buckets[1],
+            HistogramBucket {
+                le_ms: Some(500),
+                count: 3
+            }
+        );
+        assert_eq!(
+            histogram.buckets[2],
+            HistogramBucket {
+                le_ms: Some(1_000),
+                count: 5
+            }
+        );
+        assert_eq!(histogram.buckets[8].count, 5);
+        assert_eq!(
+            histogram.buckets[9],
+            HistogramBucket {
+                le_ms: None,
+                count: 6
+            }
+        );
+
+        assert_eq!(histogram.quantile_bound_ms(0.5), Some(500));
+        assert_eq!(histogram.quantile_bound_ms(0.8), Some(1_000));
+        assert_eq!(histogram.quantile_bound_ms(1.0), None);
+        assert_eq!(HistogramSnapshot::default().quantile_bound_ms(0.5), None);
+    }
+}
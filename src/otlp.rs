@@ -0,0 +1,255 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+use tokio::{sync::oneshot, task::JoinHandle};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::HttpUrl,
+    discovery,
+    metrics::{FamilyCounts, Metrics, MetricsSnapshot},
+};
+
+// OTLP's AGGREGATION_TEMPORALITY_CUMULATIVE; our counters never reset
+const CUMULATIVE: u8 = 2;
+
+/// Background push of [`Metrics`] to an OpenTelemetry collector.
+///
+/// POSTs an OTLP/HTTP JSON export request every `interval`, and once more
+/// from [`OtlpExporter::stop`] so the final values are not lost. Failures are
+/// logged and never stop the proxy.
+pub struct OtlpExporter {
+    stop_tx: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl OtlpExporter {
+    pub fn spawn(url: HttpUrl, metrics: Arc<Metrics>, interval: Duration) -> Self {
+        let (stop_tx, mut stop_rx) = oneshot::channel();
+        let start_nanos = unix_nanos(SystemTime::now());
+        info!("Exporting metrics to {} every {:?}", url, interval);
+
+        let task = tokio::spawn(async move {
+            loop {
+                let stopping = tokio::select! {
+                    _ = tokio::time::sleep(interval) => false,
+                    _ = &mut stop_rx => true,
+                };
+                let body = export_request(&metrics.snapshot(), start_nanos, SystemTime::now());
+                if let Err(e) = discovery::send(&url, "POST", &body.to_string()).await {
+                    warn!("Metrics export to {} failed: {}", url, e);
+                }
+                if stopping {
+                    break;
+                }
+            }
+        });
+
+        Self { stop_tx, task }
+    }
+
+    pub async fn stop(self) {
+        let _ = self.stop_tx.send(());
+        if let Err(e) = self.task.await {
+            debug!("Metrics export task ended abnormally: {}", e);
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| {
+        u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX)
+    })
+}
+
+// ExportMetricsServiceRequest in the protobuf JSON mapping, where 64-bit
+// integers are strings
+fn export_request(snapshot: &MetricsSnapshot, start_nanos: u64, now: SystemTime) -> Value {
+    let start = start_nanos.to_string();
+    let now = unix_nanos(now).to_string();
+
+    let family_sum = |name: &str, description: &str, counts: &FamilyCounts| {
+        let points: Vec<Value> = [("ipv4", counts.ipv4), ("ipv6", counts.ipv6)]
+            .into_iter()
+            .map(|(family, count)| {
+                json!({
+                    "attributes": [attribute("family", family)],
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "asInt": count.to_string(),
+                })
+            })
+            .collect();
+        json!({
+            "name": name,
+            "description": description,
+            "sum": {
+                "dataPoints": points,
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+            },
+        })
+    };
+
+    // OTLP wants per-bucket counts, the snapshot's are cumulative
+    let durations = &snapshot.connection_durations;
+    let mut previous = 0;
+    let bucket_counts: Vec<String> = durations
+        .buckets
+        .iter()
+        .map(|bucket| {
+            let count = bucket.count - previous;
+            previous = bucket.count;
+            count.to_string()
+        })
+        .collect();
+    let explicit_bounds: Vec<u64> = durations
+        .buckets
+        .iter()
+        .filter_map(|bucket| bucket.le_ms)
+        .collect();
+
+    let metrics = vec![
+        family_sum(
+            "rhoxy.clients",
+            "Client connections accepted, by address family",
+            &snapshot.clients,
+        ),
+        family_sum(
+            "rhoxy.targets",
+            "Target connections established, by address family",
+            &snapshot.targets,
+        ),
+        json!({
+            "name": "rhoxy.near_connection_limit",
+            "description": "1 while active connections are at or above the watermark",
+            "gauge": {
+                "dataPoints": [{
+                    "timeUnixNano": now,
+                    "asInt": u8::from(snapshot.near_connection_limit).to_string(),
+                }],
+            },
+        }),
+        json!({
+            "name": "rhoxy.connection.duration",
+            "description": "Time from accept to close",
+            "unit": "ms",
+            "histogram": {
+                "dataPoints": [{
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "count": durations.count.to_string(),
+                    "sum": durations.sum_ms as f64,
+                    "bucketCounts": bucket_counts,
+                    "explicitBounds": explicit_bounds,
+                }],
+                "aggregationTemporality": CUMULATIVE,
+            },
+        }),
+    ];
+
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", env!("CARGO_PKG_NAME")),
+                    attribute("service.version", env!("CARGO_PKG_VERSION")),
+                ],
+            },
+            "scopeMetrics": [{
+                "scope": {
+                    "name": env!("CARGO_PKG_NAME"),
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        sync::Mutex,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+
+    // Records the request line and body of every export and answers 200
+    async fn mock_collector() -> (SocketAddr, Arc<Mutex<Vec<(String, Value)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = requests.clone();
+        tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let mut socket = BufReader::new(socket);
+                let mut request_line = String::new();
+                let mut content_length = 0;
+                let mut line = String::new();
+                socket.read_line(&mut request_line).await.unwrap();
+                while socket.read_line(&mut line).await.unwrap() > 2 {
+                    if let Some(len) = line.strip_prefix("Content-Length: ") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0u8; content_length];
+                socket.read_exact(&mut body).await.unwrap();
+                recorded.lock().unwrap().push((
+                    request_line.trim_end().to_string(),
+                    serde_json::from_slice(&body).unwrap(),
+                ));
+                let _ = socket
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                    .await;
+            }
+        });
+
+        (addr, requests)
+    }
+
+    #[tokio::test]
+    async fn test_exporter_pushes_metrics() {
+        let (addr, requests) = mock_collector().await;
+        let url: HttpUrl = format!("http://{}/v1/metrics", addr).parse().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        metrics.record_client(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        metrics.record_connection_duration(Duration::from_millis(700));
+
+        let exporter = OtlpExporter::spawn(url, metrics.clone(), Duration::from_millis(50));
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        metrics.record_client(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        exporter.stop().await;
+
+        let requests = requests.lock().unwrap();
+        assert!(requests.len() >= 2);
+        assert_eq!(requests[0].0, "POST /v1/metrics HTTP/1.1");
+
+        // The push from stop() carries the latest values
+        let request = &requests.last().unwrap().1;
+        let metrics = &request["resourceMetrics"][0]["scopeMetrics"][0]["metrics"];
+        assert_eq!(metrics[0]["name"], "rhoxy.clients");
+        let ipv4 = &metrics[0]["sum"]["dataPoints"][0];
+        assert_eq!(ipv4["attributes"][0]["value"]["stringValue"], "ipv4");
+        assert_eq!(ipv4["asInt"], "2");
+
+        let histogram = &metrics[3]["histogram"]["dataPoints"][0];
+        assert_eq!(histogram["count"], "1");
+        assert_eq!(histogram["bucketCounts"][2], "1");
+        assert_eq!(histogram["explicitBounds"][2], 1_000);
+    }
+}
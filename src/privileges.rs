@@ -0,0 +1,137 @@
+use std::{ffi::CString, io};
+
+use tracing::{debug, info};
+
+/// Target identity to switch to once the listener has been bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrivilegeDrop {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+}
+
+impl PrivilegeDrop {
+    /// Resolves `user` and optional `group` (names or numeric ids).
+    /// Without a group, the user's primary group from the passwd database is used.
+    pub fn resolve(user: &str, group: Option<&str>) -> io::Result<Self> {
+        let (uid, primary_gid) = lookup_user(user)?;
+
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("No primary group known for user '{}', pass --group", user),
+                )
+            })?,
+        };
+
+        Ok(Self { uid, gid })
+    }
+
+    /// Switches the process to the resolved identity. Must be called after binding.
+    pub fn apply(&self) -> io::Result<()> {
+        // SAFETY: plain libc getters with no arguments
+        let (current_uid, current_gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        if current_uid == self.uid && current_gid == self.gid {
+            debug!("Already running as uid={} gid={}", self.uid, self.gid);
+            return Ok(());
+        }
+
+        // Order matters: supplementary groups and gid must go before uid,
+        // since we lose the right to change them once uid is dropped
+        // SAFETY: the pointer refers to a single live gid_t
+        if unsafe { libc::setgroups(1, &self.gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: setgid/setuid take plain integer ids
+        if unsafe { libc::setgid(self.gid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if unsafe { libc::setuid(self.uid) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        info!("Dropped privileges to uid={} gid={}", self.uid, self.gid);
+        Ok(())
+    }
+}
+
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    // getpw* are not reentrant, but this only runs once during startup
+    if let Ok(uid) = user.parse::<libc::uid_t>() {
+        // SAFETY: getpwuid returns null or a pointer to static storage
+        let entry = unsafe { libc::getpwuid(uid) };
+        let gid = (!entry.is_null()).then(|| unsafe { (*entry).pw_gid });
+        return Ok((uid, gid));
+    }
+
+    let name = CString::new(user)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid user name"))?;
+    // SAFETY: name is a valid NUL-terminated string
+    let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown user '{}'", user),
+        ));
+    }
+
+    // SAFETY: entry was checked for null above
+    Ok(unsafe { ((*entry).pw_uid, Some((*entry).pw_gid)) })
+}
+
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid group name"))?;
+    // SAFETY: name is a valid NUL-terminated string
+    let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if entry.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("Unknown group '{}'", group),
+        ));
+    }
+
+    // SAFETY: entry was checked for null above
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_numeric_ids() {
+        let drop = PrivilegeDrop::resolve("0", Some("0")).unwrap();
+        assert_eq!(drop, PrivilegeDrop { uid: 0, gid: 0 });
+    }
+
+    #[test]
+    fn test_resolve_root_by_name() {
+        let drop = PrivilegeDrop::resolve("root", None).unwrap();
+        assert_eq!(drop.uid, 0);
+        assert_eq!(drop.gid, 0);
+    }
+
+    #[test]
+    fn test_resolve_unknown_user() {
+        let err = PrivilegeDrop::resolve("rhoxy-no-such-user", None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_resolve_unknown_group() {
+        let err = PrivilegeDrop::resolve("0", Some("rhoxy-no-such-group")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_apply_to_current_identity_is_noop() {
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        assert!(PrivilegeDrop { uid, gid }.apply().is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use serde::Serialize;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub client_addr: SocketAddr,
+    pub age_ms: u64,
+}
+
+// Connections currently being served, keyed by connection id
+#[derive(Debug, Default)]
+pub struct ConnectionRegistry {
+    active: Mutex<BTreeMap<u64, (SocketAddr, Instant)>>,
+}
+
+impl ConnectionRegistry {
+    /// Tracks a connection until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, id: u64, client_addr: SocketAddr) -> RegistrationGuard {
+        self.active
+            .lock()
+            .unwrap()
+            .insert(id, (client_addr, Instant::now()));
+        RegistrationGuard {
+            registry: self.clone(),
+            id,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.active.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Up to `limit` connections in id order, skipping the first `offset`.
+    pub fn snapshot(&self, limit: usize, offset: usize) -> Vec<ConnectionInfo> {
+        let active = self.active.lock().unwrap();
+        active
+            .iter()
+            .skip(offset)
+            .take(limit)
+            .map(|(&id, &(client_addr, started))| ConnectionInfo {
+                id,
+                client_addr,
+                age_ms: started.elapsed().as_millis() as u64,
+            })
+            .collect()
+    }
+}
+
+pub struct RegistrationGuard {
+    registry: Arc<ConnectionRegistry>,
+    id: u64,
+}
+
+impl Drop for RegistrationGuard {
+    fn drop(&mut self) {
+        self.registry.active.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_snapshot_pagination() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let _guards: Vec<_> = (1..=5)
+            .map(|id| registry.register(id, addr(1000 + id as u16)))
+            .collect();
+
+        let ids = |page: Vec<ConnectionInfo>| page.iter().map(|c| c.id).collect::<Vec<_>>();
+        assert_eq!(ids(registry.snapshot(2, 0)), vec![1, 2]);
+        assert_eq!(ids(registry.snapshot(2, 2)), vec![3, 4]);
+        assert_eq!(ids(registry.snapshot(2, 4)), vec![5]);
+        assert!(registry.snapshot(2, 5).is_empty());
+        assert!(registry.snapshot(0, 0).is_empty());
+        assert_eq!(registry.snapshot(usize::MAX, 0).len(), 5);
+
+        let page = registry.snapshot(1, 1);
+        assert_eq!(page[0].client_addr, addr(1002));
+    }
+
+    #[test]
+    fn test_guard_drop_unregisters() {
+        let registry = Arc::new(ConnectionRegistry::default());
+        let first = registry.register(7, addr(1));
+        let _second = registry.register(9, addr(2));
+        assert_eq!(registry.len(), 2);
+
+        drop(first);
+        let page = registry.snapshot(10, 0);
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].id, 9);
+    }
+}
@@ -1,20 +1,43 @@
-use std::{io, sync::Arc};
+use std::{
+    fs::File,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use tokio::{net::TcpListener, signal, sync::broadcast};
+use tokio::{
+    net::TcpListener,
+    signal,
+    sync::{OwnedSemaphorePermit, Semaphore, broadcast},
+};
 use tracing::{debug, error, info, warn};
 
 use crate::{
-    config::{ConnectionConfig, ProxyConfig},
-    handle_connection,
+    ConnectionContext,
+    acl::{AcceptFilter, AllowAll, ClientAcl, unmap_ipv4},
+    client_limits::{ClientLimiter, HandshakeSlot},
+    config::{Capabilities, ConnectionConfig, ProxyConfig},
+    connection::{close_reason::CloseReason, command::connect::Ipv6TargetPolicy, socket_options},
+    discovery::Registration,
+    events::{EventSink, LogEventSink},
+    handle_connection_with_context,
+    interceptor::ConnectionInterceptor,
+    metrics::Metrics,
+    registry::{ConnectionInfo, ConnectionRegistry},
+    webhook::WebhookSink,
 };
 
 struct ConnectionGuard {
     counter: Arc<std::sync::atomic::AtomicUsize>,
+    watermark: Arc<ConnectionWatermark>,
 }
 
 impl ConnectionGuard {
-    fn new(counter: Arc<std::sync::atomic::AtomicUsize>) -> Self {
-        Self { counter }
+    fn new(
+        counter: Arc<std::sync::atomic::AtomicUsize>,
+        watermark: Arc<ConnectionWatermark>,
+    ) -> Self {
+        Self { counter, watermark }
     }
 }
 
@@ -24,15 +47,148 @@ impl Drop for ConnectionGuard {
             .counter
             .fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
         debug!("Connection finished (active: {})", prev_count - 1);
+        self.watermark.observe(prev_count - 1);
+    }
+}
+
+// At most one high-watermark warning per interval, however often the active
+// count bounces around the threshold
+const WATERMARK_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+// Early warning before `max_connections` starts rejecting clients
+struct ConnectionWatermark {
+    threshold: usize,
+    max_connections: usize,
+    above: std::sync::atomic::AtomicBool,
+    last_warning: Mutex<Option<std::time::Instant>>,
+    metrics: Arc<Metrics>,
+}
+
+impl ConnectionWatermark {
+    fn new(max_connections: usize, percent: u8, metrics: Arc<Metrics>) -> Self {
+        Self {
+            threshold: (max_connections * percent as usize).div_ceil(100).max(1),
+            max_connections,
+            above: std::sync::atomic::AtomicBool::new(false),
+            last_warning: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    // Returns whether a warning was logged
+    fn observe(&self, active: usize) -> bool {
+        let above = active >= self.threshold;
+        if self.above.swap(above, std::sync::atomic::Ordering::Relaxed) == above {
+            return false;
+        }
+        self.metrics.set_near_connection_limit(above);
+        if !above {
+            debug!("Active connections back below watermark ({})", active);
+            return false;
+        }
+
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|at| at.elapsed() < WATERMARK_WARNING_INTERVAL) {
+            return false;
+        }
+        *last_warning = Some(std::time::Instant::now());
+        warn!(
+            "Active connections at {}/{}, nearing the connection limit",
+            active, self.max_connections
+        );
+        true
+    }
+}
+
+// How long accepts stay paused after running out of file descriptors if no
+// connection closes in the meantime
+const FD_EXHAUSTION_PAUSE: Duration = Duration::from_secs(1);
+
+// A descriptor held in reserve so the server can still accept, and then
+// close, a connection while the process is at its open-file limit
+struct SpareFd(Mutex<Option<File>>);
+
+impl SpareFd {
+    fn reserve() -> Self {
+        Self(Mutex::new(Self::open()))
+    }
+
+    fn open() -> Option<File> {
+        #[cfg(unix)]
+        return File::open("/dev/null").ok();
+        #[cfg(not(unix))]
+        return None;
+    }
+
+    fn release(&self) -> bool {
+        self.0.lock().unwrap().take().is_some()
+    }
+
+    fn restore(&self) {
+        let mut spare = self.0.lock().unwrap();
+        if spare.is_none() {
+            *spare = Self::open();
+        }
     }
 }
 
+fn is_fd_exhaustion(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE));
+    #[cfg(not(target_os = "linux"))]
+    return false;
+}
+
+// The listening socket itself is gone or shut down; accepting again would fail
+// the same way forever
+fn is_listener_closed(e: &io::Error) -> bool {
+    #[cfg(target_os = "linux")]
+    return matches!(
+        e.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK)
+    );
+    #[cfg(not(target_os = "linux"))]
+    return e.kind() == io::ErrorKind::InvalidInput;
+}
+
 pub struct ProxyServer {
     listener: TcpListener,
     config: Arc<ProxyConfig>,
     connection_config: ConnectionConfig,
     active_connections: Arc<std::sync::atomic::AtomicUsize>,
     shutdown_tx: broadcast::Sender<()>,
+    event_sink: Arc<dyn EventSink>,
+    client_acl: ClientAcl,
+    // Embedder hook consulted after the configured ACL
+    accept_filter: Arc<dyn AcceptFilter>,
+    // Slots for connections still in the handshake/request phase
+    handshake_slots: Option<Arc<Semaphore>>,
+    // Connections accepted but not yet relaying, when --max-accepts-in-flight is set
+    accept_slots: Option<Arc<Semaphore>>,
+    client_limiter: Option<Arc<ClientLimiter>>,
+    registry: Arc<ConnectionRegistry>,
+    spare_fd: SpareFd,
+    watermark: Arc<ConnectionWatermark>,
+    started_at: std::time::Instant,
+}
+
+// Version and uptime of a running server, for health and admin endpoints of
+// embedders
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ServerInfo {
+    pub name: &'static str,
+    pub version: &'static str,
+    // "debug" or "release"
+    pub profile: &'static str,
+    #[serde(rename = "uptime_secs", serialize_with = "serialize_secs")]
+    pub uptime: Duration,
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
 }
 
 impl ProxyServer {
@@ -42,6 +198,16 @@ impl ProxyServer {
     ) -> io::Result<Self> {
         info!("Starting server on {}", server_addr);
 
+        // Resolve before binding so a bad --user fails without touching the port
+        #[cfg(target_os = "linux")]
+        let privilege_drop = match &config.user {
+            Some(user) => Some(crate::privileges::PrivilegeDrop::resolve(
+                user,
+                config.group.as_deref(),
+            )?),
+            None => None,
+        };
+
         let listener = match TcpListener::bind(&server_addr).await {
             Ok(listener) => {
                 info!("Server listening on {}", server_addr);
@@ -53,8 +219,52 @@ impl ProxyServer {
             }
         };
 
-        let connection_config = ConnectionConfig::from(config.as_ref());
+        #[cfg(target_os = "linux")]
+        if let Some(privilege_drop) = privilege_drop
+            && let Err(e) = privilege_drop.apply()
+        {
+            error!("Failed to drop privileges: {}", e);
+            return Err(e);
+        }
+
+        if config.buffer_size_clamped() {
+            warn!(
+                "Buffer size {}KB exceeds the maximum, clamping to {}KB",
+                config.buffer_size,
+                config.effective_buffer_size_kb()
+            );
+        }
+        let mut connection_config = ConnectionConfig::from(config.as_ref());
+        if config.ipv6_targets == Ipv6TargetPolicy::Auto && !socket_options::ipv6_available() {
+            warn!("No IPv6 route found, rejecting IPv6 targets");
+            connection_config.ipv6_available = false;
+        }
+        let client_acl = config.client_acl();
+        let mut event_sink: Arc<dyn EventSink> =
+            Arc::new(LogEventSink::new(config.access_log_sample_rate));
+        if let Some(url) = &config.connect_webhook {
+            event_sink = Arc::new(WebhookSink::spawn(
+                url.clone(),
+                event_sink,
+                config.max_webhooks_in_flight,
+            ));
+        }
+        connection_config.event_sink = Some(event_sink.clone());
+        let handshake_slots = config
+            .max_pending_handshakes
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let accept_slots = config
+            .max_accepts_in_flight
+            .map(|limit| Arc::new(Semaphore::new(limit)));
+        let client_limiter = config
+            .max_handshakes_per_ip
+            .map(|limit| Arc::new(ClientLimiter::new(limit)));
         let active_connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let watermark = Arc::new(ConnectionWatermark::new(
+            config.max_connections,
+            config.connection_watermark,
+            connection_config.metrics.clone(),
+        ));
         let (shutdown_tx, _) = broadcast::channel(1);
 
         Ok(Self {
@@ -63,9 +273,72 @@ impl ProxyServer {
             connection_config,
             active_connections,
             shutdown_tx,
+            event_sink,
+            client_acl,
+            accept_filter: Arc::new(AllowAll),
+            handshake_slots,
+            accept_slots,
+            client_limiter,
+            registry: Arc::new(ConnectionRegistry::default()),
+            spare_fd: SpareFd::reserve(),
+            watermark,
+            started_at: std::time::Instant::now(),
         })
     }
 
+    pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Commands and auth methods this server accepts.
+    pub fn capabilities(&self) -> Capabilities {
+        self.connection_config.capabilities()
+    }
+
+    /// Version and build of this binary, and how long the server has existed.
+    pub fn info(&self) -> ServerInfo {
+        ServerInfo {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            profile: if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+            uptime: self.started_at.elapsed(),
+        }
+    }
+
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.connection_config.metrics.clone()
+    }
+
+    /// Active connections in id order, `limit` at a time starting at `offset`.
+    pub fn connections_snapshot(&self, limit: usize, offset: usize) -> Vec<ConnectionInfo> {
+        self.registry.snapshot(limit, offset)
+    }
+
+    // `run` holds the server mutably, keep this handle to inspect it meanwhile
+    pub fn registry(&self) -> Arc<ConnectionRegistry> {
+        self.registry.clone()
+    }
+
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn EventSink>) -> Self {
+        self.connection_config.event_sink = Some(event_sink.clone());
+        self.event_sink = event_sink;
+        self
+    }
+
+    pub fn with_accept_filter(mut self, accept_filter: Arc<dyn AcceptFilter>) -> Self {
+        self.accept_filter = accept_filter;
+        self
+    }
+
+    pub fn with_interceptor(mut self, interceptor: Arc<dyn ConnectionInterceptor>) -> Self {
+        self.connection_config.interceptor = Some(interceptor);
+        self
+    }
+
     pub async fn run(&mut self) -> io::Result<()> {
         info!(
             "Ready to accept connections (max: {})",
@@ -73,10 +346,28 @@ impl ProxyServer {
         );
 
         let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let registration = match &self.config.register_url {
+            Some(url) => Some(Registration::spawn(
+                url.clone(),
+                self.listener.local_addr()?,
+                Duration::from_secs(self.config.register_interval),
+            )),
+            None => None,
+        };
 
-        tokio::select! {
+        #[cfg(feature = "otlp")]
+        let exporter = self.config.otlp_endpoint.clone().map(|url| {
+            crate::otlp::OtlpExporter::spawn(
+                url,
+                self.metrics(),
+                Duration::from_secs(self.config.otlp_interval),
+            )
+        });
+
+        let result = tokio::select! {
             result = self.accept_loop() => {
                 error!("Accept loop terminated unexpectedly: {:?}", result);
+                self.shutdown().await;
                 result
             }
             _ = self.wait_for_shutdown() => {
@@ -89,29 +380,148 @@ impl ProxyServer {
                 self.shutdown().await;
                 Ok(())
             }
+        };
+
+        if let Some(registration) = registration {
+            registration.deregister().await;
+        }
+        #[cfg(feature = "otlp")]
+        if let Some(exporter) = exporter {
+            exporter.stop().await;
         }
+        result
     }
 
     async fn accept_loop(&self) -> io::Result<()> {
         loop {
+            // Taken before accepting, so at the limit new clients wait in the
+            // listen backlog instead of costing setup work
+            let setup_permit = match &self.accept_slots {
+                Some(slots) => Some(
+                    slots
+                        .clone()
+                        .acquire_owned()
+                        .await
+                        .expect("accept slots are never closed"),
+                ),
+                None => None,
+            };
+
             let (socket, socket_addr) = match self.listener.accept().await {
                 Ok(result) => result,
+                Err(e) if is_fd_exhaustion(&e) => {
+                    warn!(
+                        "Out of file descriptors ({}), pausing accepts until connections close",
+                        e
+                    );
+                    self.shed_pending_connection().await;
+                    self.wait_for_fd_release().await;
+                    continue;
+                }
+                Err(e) if is_listener_closed(&e) => {
+                    error!("Listener closed, stopping accept loop: {}", e);
+                    return Err(e);
+                }
                 Err(e) => {
                     error!("Failed to accept connection: {}", e);
                     continue;
                 }
             };
 
+            let socket_addr = if self.config.unmap_ipv4_clients {
+                unmap_ipv4(socket_addr)
+            } else {
+                socket_addr
+            };
+
+            if !self.client_acl.permits(socket_addr.ip()) {
+                debug!("Client {} not permitted by ACL, dropping", socket_addr);
+                drop(socket);
+                continue;
+            }
+            if !self.accept_filter.accept(socket_addr) {
+                debug!("Client {} rejected by accept filter, dropping", socket_addr);
+                drop(socket);
+                continue;
+            }
+
+            // Checked before the global slots so one noisy IP can't hold those
+            let client_handshake = match &self.client_limiter {
+                Some(limiter) => match limiter.try_start_handshake(socket_addr.ip()) {
+                    Some(slot) => Some(slot),
+                    None => {
+                        debug!("Per-IP handshake limit reached, rejecting {}", socket_addr);
+                        drop(socket);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            let handshake_permit = match &self.handshake_slots {
+                Some(slots) => match slots.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        debug!("Handshake limit reached, rejecting {}", socket_addr);
+                        drop(socket);
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
             if self.should_reject_connection()? {
                 debug!("Connection limit reached, rejecting {}", socket_addr);
                 drop(socket);
                 continue;
             }
 
-            self.spawn_connection_handler(socket, socket_addr).await;
+            self.spawn_connection_handler(
+                socket,
+                socket_addr,
+                handshake_permit,
+                client_handshake,
+                setup_permit,
+            )
+            .await;
         }
     }
 
+    // Spends the spare fd to take the connection that could not be accepted
+    // and close it, so the client sees a clean EOF instead of hanging
+    async fn shed_pending_connection(&self) {
+        if !self.spare_fd.release() {
+            return;
+        }
+
+        if let Ok(Ok((socket, socket_addr))) =
+            tokio::time::timeout(Duration::from_millis(10), self.listener.accept()).await
+        {
+            debug!("No file descriptors available, closing {}", socket_addr);
+            drop(socket);
+        }
+
+        self.spare_fd.restore();
+    }
+
+    async fn wait_for_fd_release(&self) {
+        let active_at_exhaustion = self
+            .active_connections
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let start = tokio::time::Instant::now();
+
+        while self
+            .active_connections
+            .load(std::sync::atomic::Ordering::Relaxed)
+            >= active_at_exhaustion
+            && start.elapsed() < FD_EXHAUSTION_PAUSE
+        {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        info!("Resuming accepts after file descriptor exhaustion");
+    }
+
     fn should_reject_connection(&self) -> io::Result<bool> {
         let new_count = self
             .active_connections
@@ -124,6 +534,7 @@ impl ProxyServer {
             return Ok(true);
         }
 
+        self.watermark.observe(new_count);
         Ok(false)
     }
 
@@ -131,6 +542,9 @@ impl ProxyServer {
         &self,
         socket: tokio::net::TcpStream,
         socket_addr: std::net::SocketAddr,
+        handshake_permit: Option<OwnedSemaphorePermit>,
+        client_handshake: Option<HandshakeSlot>,
+        setup_permit: Option<OwnedSemaphorePermit>,
     ) {
         let active_count = self
             .active_connections
@@ -143,28 +557,46 @@ impl ProxyServer {
         let conn_config = self.connection_config.clone();
         let conn_counter = self.active_connections.clone();
         let mut shutdown_rx = self.shutdown_tx.subscribe();
+        let event_sink = self.event_sink.clone();
+        let watermark = self.watermark.clone();
+        let mut context = ConnectionContext::new()
+            .with_handshake_permit(handshake_permit)
+            .with_client_handshake(client_handshake)
+            .with_setup_permit(setup_permit);
+        let registration = self.registry.register(context.id, socket_addr);
 
         tokio::spawn(async move {
-            let _connection_guard = ConnectionGuard::new(conn_counter.clone());
+            let _connection_guard = ConnectionGuard::new(conn_counter.clone(), watermark);
+            let _registration = registration;
 
             let result = tokio::select! {
-                result = handle_connection(socket, socket_addr, conn_config.clone()) => {
+                result = handle_connection_with_context(
+                    socket,
+                    socket_addr,
+                    conn_config.clone(),
+                    &mut context,
+                ) => {
                     result
                 }
                 _ = shutdown_rx.recv() => {
                     debug!("Connection {} interrupted by shutdown", socket_addr);
-                    return;
+                    Ok(CloseReason::Shutdown)
                 }
             };
 
-            match result {
-                Ok(_) => {
+            let close_reason = match result {
+                Ok(close_reason) => {
                     debug!("Connection {} completed successfully", socket_addr);
+                    close_reason
                 }
                 Err(e) => {
-                    error!("Connection error for {}: {}", socket_addr, e);
+                    // The sink reports the close, this only keeps the detail
+                    debug!("Connection error for {}: {}", socket_addr, e);
+                    CloseReason::from(&e)
                 }
-            }
+            };
+
+            event_sink.connection_closed(socket_addr, close_reason);
         });
     }
 
@@ -235,3 +667,518 @@ impl ProxyServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connection::{command::Command, method::method::Method};
+    use clap::Parser;
+    use std::{net::SocketAddr, sync::Mutex, time::Duration};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    #[derive(Default)]
+    struct RecordingSink {
+        closed: Mutex<Vec<(SocketAddr, CloseReason)>>,
+        greetings: Mutex<Vec<(SocketAddr, Vec<u8>)>>,
+    }
+
+    impl EventSink for RecordingSink {
+        fn connection_closed(&self, client_addr: SocketAddr, reason: CloseReason) {
+            self.closed.lock().unwrap().push((client_addr, reason));
+        }
+
+        fn greeting_received(&self, client_addr: SocketAddr, methods: &[u8]) {
+            self.greetings
+                .lock()
+                .unwrap()
+                .push((client_addr, methods.to_vec()));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_reflect_disabled_bind() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let capabilities = server.capabilities();
+        assert!(capabilities.supports(Command::Connect));
+        assert!(capabilities.supports(Command::Bind));
+        assert!(!capabilities.supports(Command::UdpAssociate));
+        assert_eq!(
+            capabilities.auth_methods,
+            vec![Method::NO_AUTHENTICATION_REQUIRED]
+        );
+
+        let config = Arc::new(ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--enable-bind",
+            "false",
+        ]));
+        let server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        assert_eq!(server.capabilities().commands, vec![Command::Connect]);
+    }
+
+    #[tokio::test]
+    async fn test_info_reports_version_and_uptime() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let first = server.info();
+        assert_eq!(first.name, "rhoxy-socks");
+        assert_eq!(first.version, env!("CARGO_PKG_VERSION"));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = server.info();
+        assert!(second.uptime >= first.uptime + Duration::from_millis(20));
+
+        let json = serde_json::to_value(&second).unwrap();
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+        assert!(json["uptime_secs"].as_f64().unwrap() >= 0.02);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_reports_close_reason() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let sink = Arc::new(RecordingSink::default());
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap()
+            .with_event_sink(sink.clone());
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+
+        let server_handle = tokio::spawn(async move { server.run().await });
+
+        // Park a client mid-handshake so it is still active at shutdown
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client.write_all(&[0x05]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        shutdown_tx.send(()).unwrap();
+        server_handle.await.unwrap().unwrap();
+
+        let closed = sink.closed.lock().unwrap();
+        assert_eq!(closed.len(), 1);
+        assert_eq!(closed[0].0, client.local_addr().unwrap());
+        assert_eq!(closed[0].1, CloseReason::Shutdown);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_run_exits_when_listener_is_shut_down() {
+        use std::os::fd::AsRawFd;
+
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let listener_fd = server.listener.as_raw_fd();
+        let server_handle = tokio::spawn(async move { server.run().await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // SAFETY: the fd stays owned by the server's listener, this only
+        // shuts it down so accept() starts failing with EINVAL
+        assert_eq!(unsafe { libc::shutdown(listener_fd, libc::SHUT_RDWR) }, 0);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), server_handle)
+            .await
+            .expect("accept loop should stop instead of spinning")
+            .unwrap();
+        let err = result.unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
+
+    #[tokio::test]
+    async fn test_run_registers_and_deregisters() {
+        let (discovery_addr, requests) = crate::discovery::tests::mock_discovery_server().await;
+        let register_url = format!("http://{}/proxies", discovery_addr);
+        let (server_addr, shutdown_tx) = start_server(&["--register-url", &register_url]).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(requests.lock().unwrap().len(), 1);
+
+        shutdown_tx.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST /proxies HTTP/1.1"));
+        assert!(requests[0].contains(&format!("\"address\":\"{}\"", server_addr)));
+        assert!(requests[1].starts_with("DELETE /proxies HTTP/1.1"));
+    }
+
+    async fn start_server(args: &[&str]) -> (SocketAddr, broadcast::Sender<()>) {
+        let config = Arc::new(ProxyConfig::parse_from(
+            std::iter::once("rhoxy-socks").chain(args.iter().copied()),
+        ));
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+        tokio::spawn(async move { server.run().await });
+        (server_addr, shutdown_tx)
+    }
+
+    async fn greet(server_addr: SocketAddr) -> std::io::Result<Vec<u8>> {
+        let mut client = TcpStream::connect(server_addr).await?;
+        client.write_all(&[0x05, 0x01, 0x00]).await?;
+        let mut response = Vec::new();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            let mut buf = [0u8; 2];
+            while response.len() < 2 {
+                match client.read(&mut buf).await? {
+                    0 => break,
+                    n => response.extend_from_slice(&buf[..n]),
+                }
+            }
+            Ok::<_, std::io::Error>(())
+        })
+        .await??;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn test_client_allowlist_accepts_matching_source() {
+        let (server_addr, shutdown_tx) = start_server(&["--client-allow", "127.0.0.0/8"]).await;
+        assert_eq!(greet(server_addr).await.unwrap(), vec![0x05, 0x00]);
+        let _ = shutdown_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_client_allowlist_drops_other_sources() {
+        let (server_addr, shutdown_tx) = start_server(&["--client-allow", "10.0.0.0/8"]).await;
+        // Dropped before the handshake: either EOF or a reset, never a reply
+        let response = greet(server_addr).await.unwrap_or_default();
+        assert!(response.is_empty());
+        let _ = shutdown_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_client_denylist_drops_source() {
+        let (server_addr, shutdown_tx) = start_server(&[
+            "--client-allow",
+            "127.0.0.0/8",
+            "--client-deny",
+            "127.0.0.1",
+        ])
+        .await;
+        let response = greet(server_addr).await.unwrap_or_default();
+        assert!(response.is_empty());
+        let _ = shutdown_tx.send(());
+    }
+
+    async fn stalled_handshake(server_addr: SocketAddr, source: &str) -> TcpStream {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket
+            .bind(format!("{}:0", source).parse().unwrap())
+            .unwrap();
+        let mut client = socket.connect(server_addr).await.unwrap();
+        // Version byte only, the proxy keeps waiting for the method list
+        client.write_all(&[0x05]).await.unwrap();
+        client
+    }
+
+    #[tokio::test]
+    async fn test_max_handshakes_per_ip_drops_extra_stalled_handshakes() {
+        let (server_addr, shutdown_tx) = start_server(&["--max-handshakes-per-ip", "3"]).await;
+
+        let mut stalled = Vec::new();
+        for _ in 0..3 {
+            stalled.push(stalled_handshake(server_addr, "127.0.0.1").await);
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // A fourth handshake from the same IP is dropped without a reply
+        let mut extra = stalled_handshake(server_addr, "127.0.0.1").await;
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(2), extra.read(&mut buf))
+            .await
+            .unwrap();
+        assert!(matches!(read, Ok(0) | Err(_)));
+
+        // Another source IP still gets through
+        let mut other = stalled_handshake(server_addr, "127.0.0.2").await;
+        other.write_all(&[0x01, 0x00]).await.unwrap();
+        let mut reply = [0u8; 2];
+        other.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        // Finishing a handshake frees its slot for the same IP
+        stalled[0].write_all(&[0x01, 0x00]).await.unwrap();
+        stalled[0].read_exact(&mut reply).await.unwrap();
+        stalled[0]
+            .write_all(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 1])
+            .await
+            .unwrap();
+        stalled[0].read_exact(&mut [0u8; 10]).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut next = stalled_handshake(server_addr, "127.0.0.1").await;
+        next.write_all(&[0x01, 0x00]).await.unwrap();
+        next.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0x05, 0x00]);
+
+        let _ = shutdown_tx.send(());
+    }
+
+    // Blocklist that can change while the server runs, like one fed by a threat feed
+    #[derive(Default)]
+    struct DynamicBlocklist(std::sync::Mutex<Vec<std::net::IpAddr>>);
+
+    impl AcceptFilter for DynamicBlocklist {
+        fn accept(&self, client_addr: SocketAddr) -> bool {
+            !self.0.lock().unwrap().contains(&client_addr.ip())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_accept_filter_drops_rejected_source_before_handshake() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let blocklist = Arc::new(DynamicBlocklist::default());
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap()
+            .with_accept_filter(blocklist.clone());
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+        tokio::spawn(async move { server.run().await });
+
+        assert_eq!(greet(server_addr).await.unwrap(), vec![0x05, 0x00]);
+
+        blocklist
+            .0
+            .lock()
+            .unwrap()
+            .push("127.0.0.1".parse().unwrap());
+        let response = greet(server_addr).await.unwrap_or_default();
+        assert!(response.is_empty());
+        let _ = shutdown_tx.send(());
+    }
+
+    async fn open_relay(server_addr: SocketAddr, target_addr: SocketAddr) -> TcpStream {
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        let mut request = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 12];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply[..4], [0x05, 0x00, 0x05, 0x00]);
+        client
+    }
+
+    async fn assert_echoes(client: &mut TcpStream) {
+        client.write_all(b"ping").await.unwrap();
+        let mut buf = [0u8; 4];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"ping");
+    }
+
+    async fn is_closed_by_server(client: &mut TcpStream) -> bool {
+        let mut buf = [0u8; 1];
+        match tokio::time::timeout(Duration::from_millis(200), client.read(&mut buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => true,
+            Ok(Ok(_)) | Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshakes_leave_slots_for_relays() {
+        let target_addr = crate::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (server_addr, shutdown_tx) =
+            start_server(&["--max-connections", "4", "--max-pending-handshakes", "2"]).await;
+
+        let mut relay = open_relay(server_addr, target_addr).await;
+
+        // Connect without ever sending a greeting
+        let mut stalled = Vec::new();
+        for _ in 0..8 {
+            stalled.push(TcpStream::connect(server_addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut held = Vec::new();
+        for mut client in stalled {
+            if !is_closed_by_server(&mut client).await {
+                held.push(client);
+            }
+        }
+        assert_eq!(held.len(), 2);
+        assert_echoes(&mut relay).await;
+
+        // With one stalled client gone, both new relays fit in the pool
+        held.pop();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut second = open_relay(server_addr, target_addr).await;
+        let mut third = open_relay(server_addr, target_addr).await;
+        assert_echoes(&mut second).await;
+        assert_echoes(&mut third).await;
+
+        let _ = shutdown_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_accepts_in_flight_are_capped_until_relaying() {
+        let target_addr = crate::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let (server_addr, shutdown_tx) = start_server(&["--max-accepts-in-flight", "2"]).await;
+
+        // Stuck before the greeting, each holds an accept slot
+        let mut stalled = Vec::new();
+        for _ in 0..2 {
+            stalled.push(TcpStream::connect(server_addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let waiting = tokio::spawn(open_relay(server_addr, target_addr));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!waiting.is_finished());
+
+        // A freed slot lets the waiting client through
+        stalled.pop();
+        let mut relay = tokio::time::timeout(Duration::from_secs(2), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_echoes(&mut relay).await;
+
+        // Established relays hold no slot, so another client fits
+        let mut second = open_relay(server_addr, target_addr).await;
+        assert_echoes(&mut second).await;
+
+        let _ = shutdown_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_connections_snapshot_lists_active_clients() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+        assert!(server.connections_snapshot(10, 0).is_empty());
+        let registry = server.registry();
+        tokio::spawn(async move { server.run().await });
+
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            clients.push(TcpStream::connect(server_addr).await.unwrap());
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let all = registry.snapshot(10, 0);
+        assert_eq!(all.len(), 3);
+        let mut client_addrs: Vec<_> = clients.iter().map(|c| c.local_addr().unwrap()).collect();
+        let mut listed: Vec<_> = all.iter().map(|c| c.client_addr).collect();
+        client_addrs.sort();
+        listed.sort();
+        assert_eq!(listed, client_addrs);
+
+        let page: Vec<_> = registry.snapshot(2, 1).iter().map(|c| c.id).collect();
+        assert_eq!(page, vec![all[1].id, all[2].id]);
+
+        drop(clients);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(registry.is_empty());
+        let _ = shutdown_tx.send(());
+    }
+
+    #[test]
+    fn test_watermark_warning_is_rate_limited() {
+        let metrics = Arc::new(Metrics::default());
+        let watermark = ConnectionWatermark::new(10, 80, metrics.clone());
+
+        assert!(!watermark.observe(7));
+        assert!(!metrics.snapshot().near_connection_limit);
+
+        assert!(watermark.observe(8));
+        assert!(metrics.snapshot().near_connection_limit);
+        assert!(!watermark.observe(9));
+        assert!(!watermark.observe(10));
+
+        assert!(!watermark.observe(5));
+        assert!(!metrics.snapshot().near_connection_limit);
+
+        // Crossing again within the interval flips the gauge but stays quiet
+        assert!(!watermark.observe(8));
+        assert!(metrics.snapshot().near_connection_limit);
+    }
+
+    #[tokio::test]
+    async fn test_watermark_gauge_follows_active_connections() {
+        let config = Arc::new(ProxyConfig::parse_from([
+            "rhoxy-socks",
+            "--max-connections",
+            "4",
+            "--connection-watermark",
+            "50",
+        ]));
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap();
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+        let metrics = server.metrics();
+        tokio::spawn(async move { server.run().await });
+
+        let first = TcpStream::connect(server_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!metrics.snapshot().near_connection_limit);
+
+        let second = TcpStream::connect(server_addr).await.unwrap();
+        let third = TcpStream::connect(server_addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(metrics.snapshot().near_connection_limit);
+
+        drop((first, second, third));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!metrics.snapshot().near_connection_limit);
+        let _ = shutdown_tx.send(());
+    }
+
+    #[tokio::test]
+    async fn test_event_sink_sees_offered_methods() {
+        let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+        let sink = Arc::new(RecordingSink::default());
+        let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+            .await
+            .unwrap()
+            .with_event_sink(sink.clone());
+        let server_addr = server.listener.local_addr().unwrap();
+        let shutdown_tx = server.shutdown_tx.clone();
+        tokio::spawn(async move { server.run().await });
+
+        // GSSAPI plus an unassigned code, neither of which the server accepts
+        let mut client = TcpStream::connect(server_addr).await.unwrap();
+        client.write_all(&[0x05, 0x02, 0x01, 0x7A]).await.unwrap();
+        let mut reply = [0u8; 2];
+        tokio::time::timeout(Duration::from_secs(2), client.read_exact(&mut reply))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply, [0x05, 0xFF]);
+
+        let greetings = sink.greetings.lock().unwrap().clone();
+        assert_eq!(
+            greetings,
+            vec![(client.local_addr().unwrap(), vec![0x01, 0x7A])]
+        );
+        let _ = shutdown_tx.send(());
+    }
+}
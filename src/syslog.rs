@@ -0,0 +1,182 @@
+use std::{
+    io::{self, Write},
+    net::{SocketAddr, UdpSocket},
+    sync::Arc,
+};
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+// RFC 5424 facility 3, system daemons
+const FACILITY_DAEMON: u8 = 3;
+const APP_NAME: &str = env!("CARGO_PKG_NAME");
+#[cfg(unix)]
+const LOCAL_SYSLOG_SOCKET: &str = "/dev/log";
+
+/// Where log output goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogTarget {
+    /// Formatted lines on stdout.
+    #[default]
+    Stdout,
+    /// RFC 5424 messages to the local syslog socket, or --syslog-addr over UDP.
+    Syslog,
+}
+
+#[derive(Debug)]
+enum Transport {
+    Udp(UdpSocket),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixDatagram),
+}
+
+impl Transport {
+    fn send(&self, message: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(message).map(|_| ()),
+            #[cfg(unix)]
+            Transport::Unix(socket) => socket.send(message).map(|_| ()),
+        }
+    }
+}
+
+/// `MakeWriter` for the fmt subscriber that sends every event as one
+/// RFC 5424 datagram. Timestamp and hostname are left as NILVALUE for the
+/// syslog daemon to fill in.
+#[derive(Debug, Clone)]
+pub struct SyslogWriter {
+    transport: Arc<Transport>,
+}
+
+impl SyslogWriter {
+    /// Connects to `addr` over UDP, or to the local syslog socket when None.
+    pub fn connect(addr: Option<SocketAddr>) -> io::Result<Self> {
+        let transport = match addr {
+            Some(addr) => {
+                let bind_addr: SocketAddr = if addr.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                let socket = UdpSocket::bind(bind_addr)?;
+                socket.connect(addr)?;
+                Transport::Udp(socket)
+            }
+            #[cfg(unix)]
+            None => {
+                let socket = std::os::unix::net::UnixDatagram::unbound()?;
+                socket.connect(LOCAL_SYSLOG_SOCKET)?;
+                Transport::Unix(socket)
+            }
+            #[cfg(not(unix))]
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "No local syslog socket on this platform, set --syslog-addr",
+                ));
+            }
+        };
+        Ok(Self {
+            transport: Arc::new(transport),
+        })
+    }
+}
+
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogMessage<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogMessage::new(self, severity(&Level::INFO))
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogMessage::new(self, severity(meta.level()))
+    }
+}
+
+/// Buffers one formatted event and sends it when dropped.
+pub struct SyslogMessage<'a> {
+    writer: &'a SyslogWriter,
+    buf: Vec<u8>,
+}
+
+impl<'a> SyslogMessage<'a> {
+    fn new(writer: &'a SyslogWriter, severity: u8) -> Self {
+        let header = format!(
+            "<{}>1 - - {} {} - - ",
+            FACILITY_DAEMON * 8 + severity,
+            APP_NAME,
+            std::process::id()
+        );
+        Self {
+            writer,
+            buf: header.into_bytes(),
+        }
+    }
+}
+
+impl Write for SyslogMessage<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SyslogMessage<'_> {
+    fn drop(&mut self) {
+        while self.buf.last() == Some(&b'\n') {
+            self.buf.pop();
+        }
+        // Nowhere left to report a failed log write
+        let _ = self.writer.transport.send(&self.buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_events_are_sent_as_rfc5424_datagrams() {
+        let sink = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sink.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let writer = SyslogWriter::connect(Some(sink.local_addr().unwrap())).unwrap();
+
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer)
+            .with_ansi(false)
+            .without_time()
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::warn!("disk almost full");
+            tracing::info!("listening");
+        });
+
+        let mut buf = [0u8; 1024];
+        let n = sink.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]).into_owned();
+        let header = format!("<28>1 - - rhoxy-socks {} - - ", std::process::id());
+        assert!(message.starts_with(&header), "{message}");
+        assert!(message.ends_with("disk almost full"), "{message}");
+
+        let n = sink.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]).into_owned();
+        assert!(message.starts_with("<30>1 "), "{message}");
+        assert!(message.contains("listening"), "{message}");
+    }
+}
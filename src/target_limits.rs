@@ -0,0 +1,92 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+// Concurrent relays per target address, shared by all clients
+#[derive(Debug)]
+pub struct TargetLimiter {
+    limit: usize,
+    active: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+impl TargetLimiter {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Claims a slot for `target`, or None if it is already at the limit.
+    /// The slot is given back when the returned guard is dropped.
+    pub fn try_acquire(self: &Arc<Self>, target: SocketAddr) -> Option<TargetSlot> {
+        let mut active = self.active.lock().unwrap();
+        let count = active.entry(target).or_insert(0);
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(TargetSlot {
+            limiter: self.clone(),
+            target,
+        })
+    }
+
+    pub fn active(&self, target: SocketAddr) -> usize {
+        self.active
+            .lock()
+            .unwrap()
+            .get(&target)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+pub struct TargetSlot {
+    limiter: Arc<TargetLimiter>,
+    target: SocketAddr,
+}
+
+impl Drop for TargetSlot {
+    fn drop(&mut self) {
+        let mut active = self.limiter.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.target) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.target);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_limit_is_per_target() {
+        let limiter = Arc::new(TargetLimiter::new(2));
+        let first = limiter.try_acquire(addr(80)).unwrap();
+        let _second = limiter.try_acquire(addr(80)).unwrap();
+        assert!(limiter.try_acquire(addr(80)).is_none());
+        assert!(limiter.try_acquire(addr(443)).is_some());
+
+        drop(first);
+        assert_eq!(limiter.active(addr(80)), 1);
+        assert!(limiter.try_acquire(addr(80)).is_some());
+    }
+
+    #[test]
+    fn test_released_targets_are_forgotten() {
+        let limiter = Arc::new(TargetLimiter::new(1));
+        drop(limiter.try_acquire(addr(80)).unwrap());
+        assert_eq!(limiter.active(addr(80)), 0);
+        assert!(limiter.active.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{Value, json};
+use tokio::sync::{Semaphore, mpsc};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::HttpUrl,
+    connection::{close_reason::CloseReason, method::method::Method},
+    discovery,
+    events::EventSink,
+};
+
+// Established CONNECTs waiting for a free request slot; more are dropped
+const QUEUE_LEN: usize = 1024;
+
+/// Event sink that POSTs every established CONNECT to a webhook as JSON.
+///
+/// Delivery is fire-and-forget: events are queued for a background task that
+/// keeps at most `max_in_flight` requests open, and are dropped with a warning
+/// when the queue is full, so a slow webhook never holds up a relay. All events
+/// are also passed on to `inner`.
+pub struct WebhookSink {
+    inner: Arc<dyn EventSink>,
+    tx: mpsc::Sender<Value>,
+}
+
+impl WebhookSink {
+    pub fn spawn(url: HttpUrl, inner: Arc<dyn EventSink>, max_in_flight: usize) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Value>(QUEUE_LEN);
+        let slots = Arc::new(Semaphore::new(max_in_flight));
+        let url = Arc::new(url);
+        info!("Posting established CONNECTs to {}", url);
+
+        tokio::spawn(async move {
+            while let Some(event) = rx.recv().await {
+                let Ok(permit) = slots.clone().acquire_owned().await else {
+                    break;
+                };
+                let url = url.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = discovery::send(&url, "POST", &event.to_string()).await {
+                        warn!("Webhook {} failed: {}", url, e);
+                    }
+                    drop(permit);
+                });
+            }
+        });
+
+        Self { inner, tx }
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn connection_closed(&self, client_addr: SocketAddr, reason: CloseReason) {
+        self.inner.connection_closed(client_addr, reason);
+    }
+
+    fn greeting_received(&self, client_addr: SocketAddr, methods: &[u8]) {
+        self.inner.greeting_received(client_addr, methods);
+    }
+
+    fn target_connected(
+        &self,
+        client_addr: SocketAddr,
+        domain: Option<&str>,
+        target: SocketAddr,
+        method: Option<Method>,
+    ) {
+        self.inner
+            .target_connected(client_addr, domain, target, method);
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        let event = json!({
+            "client": client_addr.to_string(),
+            "target": target.to_string(),
+            "domain": domain,
+            "method": method.map(|method| method as u8),
+            "timestamp": timestamp,
+        });
+        if self.tx.try_send(event).is_err() {
+            warn!("Webhook queue full, dropping CONNECT from {}", client_addr);
+        } else {
+            debug!("Queued webhook for CONNECT from {}", client_addr);
+        }
+    }
+}
@@ -0,0 +1,88 @@
+// Lowers the process-wide open-file limit, so it lives in its own test binary
+#![cfg(target_os = "linux")]
+
+use clap::Parser;
+use rhoxy_socks::config::ProxyConfig;
+use rhoxy_socks::server::ProxyServer;
+use std::fs::File;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+fn set_soft_fd_limit(limit: libc::rlim_t) -> libc::rlim_t {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    unsafe {
+        assert_eq!(libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlim), 0);
+    }
+    let previous = rlim.rlim_cur;
+    rlim.rlim_cur = limit.min(rlim.rlim_max);
+    unsafe {
+        assert_eq!(libc::setrlimit(libc::RLIMIT_NOFILE, &rlim), 0);
+    }
+    previous
+}
+
+fn open_fd_count() -> usize {
+    std::fs::read_dir("/proc/self/fd").unwrap().count()
+}
+
+// Opens files until the process runs out of descriptors
+fn exhaust_fds() -> Vec<File> {
+    let mut hogs = Vec::new();
+    while let Ok(file) = File::open("/dev/null") {
+        hogs.push(file);
+    }
+    hogs
+}
+
+async fn greet(server_addr: SocketAddr) -> TcpStream {
+    let mut client = TcpStream::connect(server_addr).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    let mut reply = [0u8; 2];
+    timeout(Duration::from_secs(2), client.read_exact(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply, [0x05, 0x00]);
+    client
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_server_recovers_from_fd_exhaustion() {
+    let config = Arc::new(ProxyConfig::parse_from(["rhoxy-socks"]));
+    let mut server = ProxyServer::new("127.0.0.1:0".parse().unwrap(), config)
+        .await
+        .unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move { server.run().await });
+
+    let active = greet(server_addr).await;
+
+    let previous_limit = set_soft_fd_limit(open_fd_count() as libc::rlim_t + 16);
+    let mut hogs = exhaust_fds();
+    assert!(!hogs.is_empty());
+
+    // Free a single descriptor for the client; the server has none left
+    hogs.pop();
+    let mut shed = TcpStream::connect(server_addr).await.unwrap();
+    let mut buf = [0u8; 1];
+    let closed = timeout(Duration::from_secs(2), shed.read(&mut buf))
+        .await
+        .expect("excess connection left hanging");
+    assert!(matches!(closed, Ok(0) | Err(_)));
+    drop(shed);
+
+    // Once descriptors are freed the server accepts again
+    drop(active);
+    drop(hogs);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    greet(server_addr).await;
+
+    set_soft_fd_limit(previous_limit);
+}
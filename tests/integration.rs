@@ -1,9 +1,28 @@
 use rhoxy_socks::config::ConnectionConfig;
+use rhoxy_socks::connection::DnsRetry;
+use rhoxy_socks::connection::address_type::ResolveFamily;
+use rhoxy_socks::connection::close_reason::CloseReason;
+use rhoxy_socks::connection::command::bind::PortRange;
+use rhoxy_socks::connection::command::connect::NodelaySwitch;
+use rhoxy_socks::connection::method::client_greeting::GreetingPolicy;
 use rhoxy_socks::connection::method::method::Method;
-use rhoxy_socks::{connection::SOCKS5_VERSION, handle_connection};
-use std::net::Ipv6Addr;
+use rhoxy_socks::connection::method::method_handler::DEFAULT_METHOD_PRIORITY;
+use rhoxy_socks::connection::reply::Reply;
+use rhoxy_socks::connection::request::SocksRequest;
+use rhoxy_socks::events::EventSink;
+use rhoxy_socks::host_map::HostMap;
+use rhoxy_socks::interceptor::{ConnectionInterceptor, InterceptFuture};
+use rhoxy_socks::metrics::{FamilyCounts, Metrics};
+use rhoxy_socks::target_limits::TargetLimiter;
+use rhoxy_socks::webhook::WebhookSink;
+use rhoxy_socks::{
+    ConnectionContext, connection::SOCKS5_VERSION, handle_connection,
+    handle_connection_with_context,
+};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::task;
 use tokio::time::timeout;
@@ -12,13 +31,89 @@ fn default_test_config() -> ConnectionConfig {
     ConnectionConfig {
         buffer_size: 32 * 1024,
         tcp_nodelay: true,
+        nodelay_switch: NodelaySwitch::Off,
+        tcp_fast_open: false,
+        tcp_user_timeout: None,
+        fallback_delay: Duration::from_millis(250),
+        connect_deadline: Duration::from_secs(10),
         shutdown_timeout: std::time::Duration::from_secs(10),
+        max_connection_lifetime: None,
         connection_timeout: std::time::Duration::from_secs(30),
         supported_auth_methods: vec![Method::NO_AUTHENTICATION_REQUIRED],
         handshake_timeout: std::time::Duration::from_secs(30),
+        handshake_write_timeout: None,
+        greeting_policy: GreetingPolicy::Warn,
+        auth_failure_jitter: Duration::ZERO,
+        abort_on_target_reset: false,
+        reset_on_protocol_violation: false,
+        idle_timeout: None,
+        first_byte_timeout: None,
+        idle_keepalive: None,
+        half_close: false,
+        prefetch_target: false,
+        buffer_pool: None,
+        buffer_budget: None,
+        max_bytes_per_connection: None,
+        target_limiter: None,
+        allow_ports: vec![],
+        so_rcvbuf: None,
+        so_sndbuf: None,
+        dscp: None,
+        no_dns: false,
+        host_map: None,
+        dns_slots: None,
+        max_domain_len: 255,
+        dns_retry: DnsRetry::default(),
+        resolve_family: ResolveFamily::Any,
+        method_priority: DEFAULT_METHOD_PRIORITY.to_vec(),
+        bind_port_range: None,
+        bind_slots: None,
+        bind_accept_timeout: Duration::from_secs(30),
+        enable_bind: true,
+        lenient_reserved: false,
+        diagnostics_command: false,
+        log_accepted: false,
+        ipv6_available: true,
+        metrics: Arc::new(Metrics::default()),
+        interceptor: None,
+        event_sink: None,
     }
 }
 
+async fn socks_handshake(client: &mut TcpStream) {
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    client.flush().await.unwrap();
+    let mut response = [0u8; 2];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(response, [SOCKS5_VERSION, 0x00]);
+}
+
+async fn socks_connect(client: &mut TcpStream, target_addr: SocketAddr) -> [u8; 10] {
+    socks_handshake(client).await;
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x01];
+    request.extend_from_slice(&[127, 0, 0, 1]);
+    request.extend_from_slice(&target_addr.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    client.flush().await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply
+}
+
+async fn spawn_socks_server(
+    config: ConnectionConfig,
+) -> (SocketAddr, task::JoinHandle<std::io::Result<CloseReason>>) {
+    let socks_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_addr = socks_listener.local_addr().unwrap();
+    let handle = task::spawn(async move {
+        let (socket, client_addr) = socks_listener.accept().await.unwrap();
+        handle_connection(socket, client_addr, config).await
+    });
+    (socks_addr, handle)
+}
+
 #[tokio::test]
 async fn test_full_socks5_connect_ipv4() {
     // Spawn a mock target server (echo server on localhost:0)
@@ -602,3 +697,1475 @@ async fn test_zero_byte_transfer() {
     let _ = socks_handle.await;
     target_handle.await.unwrap();
 }
+
+#[tokio::test]
+async fn test_close_reason_client_closed() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (socket, _) = target_listener.accept().await.unwrap();
+        // Hold the target open until the test ends
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        drop(socket);
+    });
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    drop(client);
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ClientClosed);
+    target_handle.abort();
+}
+
+#[tokio::test]
+async fn test_close_reason_target_closed() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (socket, _) = target_listener.accept().await.unwrap();
+        drop(socket);
+    });
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::TargetClosed);
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_close_reason_request_rejected() {
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    // UDP ASSOCIATE is answered with COMMAND NOT SUPPORTED
+    client
+        .write_all(&[0x05, 0x03, 0x00, 0x01, 127, 0, 0, 1, 0, 53])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::COMMAND_NOT_SUPPORTED);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(
+        reason,
+        CloseReason::RequestRejected(Reply::COMMAND_NOT_SUPPORTED)
+    );
+}
+
+#[tokio::test]
+async fn test_close_reason_handshake_timeout() {
+    let config = ConnectionConfig {
+        handshake_timeout: Duration::from_millis(50),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let _client = TcpStream::connect(socks_addr).await.unwrap();
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::HandshakeTimeout);
+}
+
+#[tokio::test]
+async fn test_close_reason_connection_timeout() {
+    let config = ConnectionConfig {
+        connection_timeout: Duration::from_millis(50),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    // Never send the request
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ConnectionTimeout);
+}
+
+#[tokio::test]
+async fn test_close_reason_error() {
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    client.write_all(&[0x04, 0x01, 0x00]).await.unwrap();
+
+    let error = socks_handle.await.unwrap().unwrap_err();
+    assert_eq!(
+        CloseReason::from(&error),
+        CloseReason::Error(std::io::ErrorKind::InvalidData)
+    );
+}
+
+#[tokio::test]
+async fn test_connect_through_builtin_echo_target() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    for message in [&b"hello"[..], &b"benchmark payload"[..]] {
+        client.write_all(message).await.unwrap();
+        let mut buf = vec![0u8; message.len()];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, message);
+    }
+
+    drop(client);
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ClientClosed);
+}
+
+async fn spawn_resetting_target() -> (SocketAddr, task::JoinHandle<()>) {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let handle = task::spawn(async move {
+        let (socket, _) = target_listener.accept().await.unwrap();
+        // Zero linger turns the close into a RST
+        socket.set_linger(Some(Duration::ZERO)).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drop(socket);
+    });
+    (target_addr, handle)
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_abort_on_target_reset_propagates_reset() {
+    let (target_addr, target_handle) = spawn_resetting_target().await;
+
+    let config = ConnectionConfig {
+        abort_on_target_reset: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::TargetReset);
+    target_handle.await.unwrap();
+
+    let mut buf = [0u8; 1];
+    let result = timeout(Duration::from_secs(2), client.read(&mut buf))
+        .await
+        .unwrap();
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_target_reset_closes_gracefully_by_default() {
+    let (target_addr, target_handle) = spawn_resetting_target().await;
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::TargetReset);
+    target_handle.await.unwrap();
+
+    let mut buf = [0u8; 1];
+    let result = timeout(Duration::from_secs(2), client.read(&mut buf))
+        .await
+        .unwrap();
+    assert_eq!(result.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn test_pipelined_request_after_failed_auth_is_not_executed() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+
+    let config = ConnectionConfig {
+        supported_auth_methods: vec![Method::USERNAME_PASSWORD],
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+
+    // Greeting, username/password subnegotiation and CONNECT in one write
+    let mut pipelined = vec![0x05, 0x01, Method::USERNAME_PASSWORD];
+    pipelined.extend_from_slice(&[0x01, 4, b'u', b's', b'e', b'r', 4, b'p', b'a', b's', b's']);
+    pipelined.extend_from_slice(&[0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1]);
+    pipelined.extend_from_slice(&target_addr.port().to_be_bytes());
+    client.write_all(&pipelined).await.unwrap();
+
+    let mut reply = [0u8; 2];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply, [0x05, Method::NO_ACCEPTABLE_METHODS]);
+
+    let result = socks_handle.await.unwrap();
+    assert!(result.is_err());
+
+    // Nothing follows the failure reply; the unread request may turn the
+    // close into a reset
+    let mut rest = Vec::new();
+    match timeout(Duration::from_secs(2), client.read_to_end(&mut rest))
+        .await
+        .unwrap()
+    {
+        Ok(_) => assert!(rest.is_empty()),
+        Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset),
+    }
+    assert!(
+        timeout(Duration::from_millis(100), target_listener.accept())
+            .await
+            .is_err()
+    );
+}
+
+async fn send_bad_version_request(config: ConnectionConfig) -> (TcpStream, std::io::Error) {
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+    client
+        .write_all(&[0x04, 0x01, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+
+    let error = socks_handle.await.unwrap().unwrap_err();
+    (client, error)
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_reset_on_protocol_violation_aborts_connection() {
+    let config = ConnectionConfig {
+        reset_on_protocol_violation: true,
+        ..default_test_config()
+    };
+    let (mut client, error) = send_bad_version_request(config).await;
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut buf = Vec::new();
+    let result = timeout(Duration::from_secs(2), client.read_to_end(&mut buf))
+        .await
+        .unwrap();
+    assert_eq!(
+        result.unwrap_err().kind(),
+        std::io::ErrorKind::ConnectionReset
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_reset_on_protocol_violation_spares_refused_requests() {
+    let config = ConnectionConfig {
+        reset_on_protocol_violation: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+    // Well-formed, but with an address type the server does not support
+    client.write_all(&[0x05, 0x01, 0x00, 0x02]).await.unwrap();
+    let _ = socks_handle.await.unwrap();
+
+    let mut reply = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply[1], Reply::ADDRESS_TYPE_NOT_SUPPORTED);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_protocol_violation_closes_gracefully_by_default() {
+    let (mut client, error) = send_bad_version_request(default_test_config()).await;
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+    let mut reply = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(reply.len(), 10);
+    assert_eq!(reply[1], Reply::GENERAL_FAILURE);
+}
+
+#[tokio::test]
+async fn test_prefetch_target_forwards_banner() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        // Slower than the proxy is to reply, but within the prefetch wait
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let _ = socket.write_all(b"220 banner\r\n").await;
+    });
+
+    let config = ConnectionConfig {
+        prefetch_target: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&target_addr.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    // Without half-close the relay ends at this EOF, so the banner only
+    // reaches the client if it was read ahead of the reply
+    client.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received[1], Reply::SUCCESS);
+    assert_eq!(&received[10..], b"220 banner\r\n");
+
+    let _ = socks_handle.await;
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_address_family_metrics() {
+    let metrics = Arc::new(Metrics::default());
+    let config = ConnectionConfig {
+        metrics: metrics.clone(),
+        ..default_test_config()
+    };
+
+    for (bind_addr, atyp, addr_bytes) in [
+        ("127.0.0.1:0", 0x01, vec![127, 0, 0, 1]),
+        ("[::1]:0", 0x04, Ipv6Addr::LOCALHOST.octets().to_vec()),
+    ] {
+        let target_listener = TcpListener::bind(bind_addr).await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+        let target_handle = task::spawn(async move {
+            let _ = target_listener.accept().await.unwrap();
+        });
+
+        let socks_listener = TcpListener::bind(bind_addr).await.unwrap();
+        let socks_addr = socks_listener.local_addr().unwrap();
+        let conn_config = config.clone();
+        let socks_handle = task::spawn(async move {
+            let (socket, client_addr) = socks_listener.accept().await.unwrap();
+            handle_connection(socket, client_addr, conn_config).await
+        });
+
+        let mut client = TcpStream::connect(socks_addr).await.unwrap();
+        socks_handshake(&mut client).await;
+        let mut request = vec![0x05, 0x01, 0x00, atyp];
+        request.extend_from_slice(&addr_bytes);
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = vec![0u8; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::SUCCESS);
+
+        drop(client);
+        let _ = socks_handle.await.unwrap();
+        target_handle.await.unwrap();
+    }
+
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot.clients, FamilyCounts { ipv4: 1, ipv6: 1 });
+    assert_eq!(snapshot.targets, FamilyCounts { ipv4: 1, ipv6: 1 });
+}
+
+#[tokio::test]
+async fn test_connection_duration_histogram() {
+    let metrics = Arc::new(Metrics::default());
+    let config = ConnectionConfig {
+        metrics: metrics.clone(),
+        ..default_test_config()
+    };
+
+    // One connection closed straight away, one held open for ~300ms
+    for hold in [Duration::ZERO, Duration::from_millis(300)] {
+        let socks_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let socks_addr = socks_listener.local_addr().unwrap();
+        let conn_config = config.clone();
+        let socks_handle = task::spawn(async move {
+            let (socket, client_addr) = socks_listener.accept().await.unwrap();
+            handle_connection(socket, client_addr, conn_config).await
+        });
+
+        let client = TcpStream::connect(socks_addr).await.unwrap();
+        tokio::time::sleep(hold).await;
+        drop(client);
+        let _ = socks_handle.await.unwrap();
+    }
+
+    let histogram = metrics.snapshot().connection_durations;
+    assert_eq!(histogram.count, 2);
+    assert_eq!(histogram.buckets[0].le_ms, Some(100));
+    assert_eq!(histogram.buckets[0].count, 1);
+    assert_eq!(histogram.buckets[1].le_ms, Some(500));
+    assert_eq!(histogram.buckets[1].count, 2);
+    assert!(histogram.sum_ms >= 300);
+    assert_eq!(histogram.quantile_bound_ms(0.5), Some(100));
+    assert_eq!(histogram.quantile_bound_ms(0.99), Some(500));
+}
+
+#[tokio::test]
+async fn test_max_bytes_per_connection_truncates_relay() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let _ = socket.write_all(&[0xAB; 10 * 1024]).await;
+        let mut buf = [0u8; 1];
+        let _ = socket.read(&mut buf).await;
+    });
+
+    let config = ConnectionConfig {
+        max_bytes_per_connection: Some(4096),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received.len(), 4096);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::QuotaExceeded);
+    drop(client);
+    target_handle.await.unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_buffered_data_flushed_when_relay_ends_abruptly() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        // Wait until the proxy has flushed its CONNECT reply and is relaying
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // Data and RST back to back: the proxy reads the data, then
+        // immediately hits the reset without ever yielding to flush
+        socket.set_linger(Some(Duration::ZERO)).unwrap();
+        socket.write_all(b"last words").await.unwrap();
+        drop(socket);
+    });
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::TargetReset);
+    target_handle.await.unwrap();
+
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, b"last words");
+}
+
+#[tokio::test]
+async fn test_no_dns_rejects_domain_connect() {
+    let config = ConnectionConfig {
+        no_dns: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    let domain = b"localhost";
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&80u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::ADDRESS_TYPE_NOT_SUPPORTED);
+    assert!(socks_handle.await.unwrap().is_err());
+}
+
+async fn socks_connect_domain(client: &mut TcpStream, domain: &[u8], port: u16) -> [u8; 10] {
+    socks_handshake(client).await;
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, domain.len() as u8];
+    request.extend_from_slice(domain);
+    request.extend_from_slice(&port.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    reply
+}
+
+#[tokio::test]
+async fn test_host_map_redirects_mapped_domain() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let mapping = format!("Mapped.Test={}", target_addr).parse().unwrap();
+    let config = ConnectionConfig {
+        host_map: Some(Arc::new(HostMap::new(&[mapping]))),
+        ..default_test_config()
+    };
+    let (socks_addr, _socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+
+    // Neither the name nor the port needs to be real, the mapping decides both
+    let reply = socks_connect_domain(&mut client, b"mapped.test", 1).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_host_map_resolves_unmapped_domain() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let mapping = "mapped.test=127.0.0.1:1".parse().unwrap();
+    let config = ConnectionConfig {
+        host_map: Some(Arc::new(HostMap::new(&[mapping]))),
+        resolve_family: ResolveFamily::V4,
+        ..default_test_config()
+    };
+    let (socks_addr, _socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+
+    let reply = socks_connect_domain(&mut client, b"localhost", target_addr.port()).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+}
+
+#[tokio::test]
+async fn test_no_dns_allows_ip_connect() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let config = ConnectionConfig {
+        no_dns: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    drop(client);
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ClientClosed);
+}
+
+// Answers CONNECTs to one port itself and leaves everything else alone
+struct CannedConnect {
+    port: u16,
+}
+
+impl ConnectionInterceptor for CannedConnect {
+    fn intercept<'a>(
+        &'a self,
+        request: &'a SocksRequest,
+        _client_addr: SocketAddr,
+        _reader: &'a mut (dyn AsyncRead + Unpin + Send),
+        writer: &'a mut (dyn AsyncWrite + Unpin + Send),
+    ) -> InterceptFuture<'a> {
+        Box::pin(async move {
+            if request.dest_port != self.port {
+                return Ok(None);
+            }
+            writer
+                .write_all(&[0x05, Reply::SUCCESS, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await?;
+            writer.write_all(b"canned").await?;
+            writer.flush().await?;
+            Ok(Some(CloseReason::Completed))
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_interceptor_handles_connect() {
+    // Nothing listens on this port, so a real dial would be refused
+    let unused_port = {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap().port()
+    };
+    let config = ConnectionConfig {
+        interceptor: Some(Arc::new(CannedConnect { port: unused_port })),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let target_addr = SocketAddr::from(([127, 0, 0, 1], unused_port));
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let mut body = Vec::new();
+    client.read_to_end(&mut body).await.unwrap();
+    assert_eq!(body, b"canned");
+    assert_eq!(socks_handle.await.unwrap().unwrap(), CloseReason::Completed);
+}
+
+#[tokio::test]
+async fn test_interceptor_declines_to_default_dialer() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let config = ConnectionConfig {
+        interceptor: Some(Arc::new(CannedConnect { port: 0 })),
+        ..default_test_config()
+    };
+    let (socks_addr, _socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    client.write_all(b"echo").await.unwrap();
+    let mut buf = [0u8; 4];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"echo");
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_connect_with_tcp_fast_open() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let config = ConnectionConfig {
+        tcp_fast_open: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    client.write_all(b"first bytes").await.unwrap();
+    let mut buf = [0u8; 11];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"first bytes");
+
+    drop(client);
+    assert_eq!(
+        socks_handle.await.unwrap().unwrap(),
+        CloseReason::ClientClosed
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_tcp_fast_open_sends_pipelined_data_before_replying() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let closed_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let config = ConnectionConfig {
+        tcp_fast_open: true,
+        ..default_test_config()
+    };
+
+    // The first CONNECT may leave a cookie behind for the ones after it
+    for _ in 0..2 {
+        let (socks_addr, _socks_handle) = spawn_socks_server(config.clone()).await;
+        let mut client = TcpStream::connect(socks_addr).await.unwrap();
+        socks_handshake(&mut client).await;
+        let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        request.extend_from_slice(b"first bytes");
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::SUCCESS);
+        let mut buf = [0u8; 11];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"first bytes");
+    }
+
+    // A refused target must not be reported as connected
+    let (socks_addr, _socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+    let mut request = vec![0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    request.extend_from_slice(&closed_addr.port().to_be_bytes());
+    request.extend_from_slice(b"first bytes");
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::CONNECTION_REFUSED);
+}
+
+#[derive(Clone, Default)]
+struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_error_reply_log_carries_connection_id() {
+    let capture = LogCapture::default();
+    let make_writer = {
+        let capture = capture.clone();
+        move || capture.clone()
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(make_writer)
+        .with_max_level(tracing::Level::DEBUG)
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    // Unknown ATYP gets an error reply straight from request parsing
+    client
+        .write_all(&[0x05, 0x01, 0x00, 0x09, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::ADDRESS_TYPE_NOT_SUPPORTED);
+    assert!(socks_handle.await.unwrap().is_err());
+
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+    let reply_line = logs
+        .lines()
+        .find(|line| line.contains("Sending error reply 0x08"))
+        .expect("error reply was not logged");
+    let id_start = reply_line
+        .find("connection{id=")
+        .expect("error reply log has no connection id");
+    let id_field = &reply_line[id_start..reply_line[id_start..].find('}').unwrap() + id_start];
+
+    // The same id tags the rest of this connection's lines
+    let accept_line = logs
+        .lines()
+        .find(|line| line.contains("Handling connection from"))
+        .unwrap();
+    assert!(accept_line.contains(id_field), "{accept_line}");
+}
+
+#[tokio::test]
+async fn test_idle_relay_is_closed() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        let _ = socket.read(&mut buf).await;
+    });
+
+    let config = ConnectionConfig {
+        idle_timeout: Some(Duration::from_millis(200)),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let reason = timeout(Duration::from_secs(2), socks_handle)
+        .await
+        .expect("idle relay was not closed")
+        .unwrap()
+        .unwrap();
+    assert_eq!(reason, CloseReason::IdleTimeout);
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_half_close_relays_response_after_client_eof() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        socket.read_to_end(&mut request).await.unwrap();
+        socket.write_all(&request).await.unwrap();
+    });
+
+    let config = ConnectionConfig {
+        half_close: true,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    client.write_all(b"whole request").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    timeout(Duration::from_secs(2), client.read_to_end(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response, b"whole request");
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ClientClosed);
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_ipv6_target_rejected_when_unavailable() {
+    let config = ConnectionConfig {
+        ipv6_available: false,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    // A listening [::1] target would be reachable, so a reply proves it was never tried
+    let target_listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let mut request = vec![0x05, 0x01, 0x00, 0x04];
+    request.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    request.extend_from_slice(&target_listener.local_addr().unwrap().port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::NETWORK_UNREACHABLE);
+    assert!(
+        timeout(Duration::from_millis(100), target_listener.accept())
+            .await
+            .is_err()
+    );
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(
+        reason,
+        CloseReason::RequestRejected(Reply::NETWORK_UNREACHABLE)
+    );
+}
+
+#[tokio::test]
+async fn test_silent_relay_closed_at_first_byte_timeout() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let mut buf = [0u8; 1];
+        let _ = socket.read(&mut buf).await;
+    });
+
+    let config = ConnectionConfig {
+        first_byte_timeout: Some(Duration::from_millis(200)),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+    let connected = tokio::time::Instant::now();
+
+    let reason = timeout(Duration::from_secs(2), socks_handle)
+        .await
+        .expect("silent relay was not closed")
+        .unwrap()
+        .unwrap();
+    assert_eq!(reason, CloseReason::NoData);
+    let elapsed = connected.elapsed();
+    assert!(elapsed >= Duration::from_millis(150), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_active_connection_closed_at_max_lifetime() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let _ = tokio::io::copy(&mut reader, &mut writer).await;
+    });
+
+    let config = ConnectionConfig {
+        max_connection_lifetime: Some(Duration::from_millis(300)),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let start = tokio::time::Instant::now();
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    // Keep traffic flowing until the proxy cuts the connection
+    let mut buf = [0u8; 4];
+    loop {
+        if client.write_all(b"ping").await.is_err() {
+            break;
+        }
+        match timeout(Duration::from_secs(2), client.read_exact(&mut buf))
+            .await
+            .expect("connection outlived its lifetime")
+        {
+            Ok(_) => assert_eq!(&buf, b"ping"),
+            Err(_) => break,
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let elapsed = start.elapsed();
+
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::LifetimeExceeded);
+    assert!(elapsed >= Duration::from_millis(300), "{elapsed:?}");
+    assert!(elapsed < Duration::from_secs(1), "{elapsed:?}");
+    target_handle.await.unwrap();
+}
+
+#[tokio::test]
+async fn test_connection_context_populated_after_connect() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let _ = target_listener.accept().await.unwrap();
+    });
+
+    let socks_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_addr = socks_listener.local_addr().unwrap();
+    let socks_handle = task::spawn(async move {
+        let (socket, client_addr) = socks_listener.accept().await.unwrap();
+        let mut context = ConnectionContext::new();
+        let result = handle_connection_with_context(
+            socket,
+            client_addr,
+            default_test_config(),
+            &mut context,
+        )
+        .await;
+        (result, context)
+    });
+
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+    target_handle.await.unwrap();
+    drop(client);
+
+    let (result, context) = timeout(Duration::from_secs(5), socks_handle)
+        .await
+        .unwrap()
+        .unwrap();
+    let close_reason = result.unwrap();
+    assert_eq!(
+        context.negotiated_method,
+        Some(Method::NoAuthenticationRequired)
+    );
+    assert_eq!(context.target, Some(target_addr));
+    assert_eq!(context.reply_code, Some(Reply::SUCCESS));
+    assert_eq!(context.close_reason, Some(close_reason));
+    assert!(context.handshake_permit.is_none());
+}
+
+#[tokio::test]
+async fn test_max_connections_per_target_limits_extra_connect() {
+    const LIMIT: usize = 2;
+
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    task::spawn(async move {
+        let mut held = Vec::new();
+        while let Ok((socket, _)) = target_listener.accept().await {
+            held.push(socket);
+        }
+    });
+
+    let mut config = default_test_config();
+    let limiter = Arc::new(TargetLimiter::new(LIMIT));
+    config.target_limiter = Some(limiter.clone());
+    let socks_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let socks_addr = socks_listener.local_addr().unwrap();
+    task::spawn(async move {
+        while let Ok((socket, client_addr)) = socks_listener.accept().await {
+            let config = config.clone();
+            task::spawn(async move { handle_connection(socket, client_addr, config).await });
+        }
+    });
+
+    let mut relays = Vec::new();
+    for _ in 0..LIMIT {
+        let mut client = TcpStream::connect(socks_addr).await.unwrap();
+        let reply = socks_connect(&mut client, target_addr).await;
+        assert_eq!(reply[1], Reply::SUCCESS);
+        relays.push(client);
+    }
+
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::CONNECTION_NOT_ALLOWED);
+    assert_eq!(limiter.active(target_addr), LIMIT);
+
+    // Ending a relay frees its slot for the next client
+    drop(relays.pop());
+    timeout(Duration::from_secs(5), async {
+        while limiter.active(target_addr) == LIMIT {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+}
+
+#[tokio::test]
+async fn test_allow_ports_permits_listed_port() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move { target_listener.accept().await.is_ok() });
+
+    let port = target_addr.port();
+    let mut config = default_test_config();
+    config.allow_ports = vec![
+        "80".parse().unwrap(),
+        PortRange::new(port, port.saturating_add(10)).unwrap(),
+    ];
+    let (socks_addr, _handle) = spawn_socks_server(config).await;
+
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+    assert!(target_handle.await.unwrap());
+}
+
+#[tokio::test]
+async fn test_allow_ports_refuses_other_ports() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+
+    // Ephemeral listener ports are never 80 or 443
+    let mut config = default_test_config();
+    config.allow_ports = vec!["80".parse().unwrap(), "443".parse().unwrap()];
+    let (socks_addr, handle) = spawn_socks_server(config).await;
+
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::CONNECTION_NOT_ALLOWED);
+    handle.await.unwrap().unwrap();
+
+    // Refused before dialing, so the target never saw a connection
+    let accepted = timeout(Duration::from_millis(100), target_listener.accept()).await;
+    assert!(accepted.is_err());
+}
+
+#[tokio::test]
+async fn test_disabled_bind_is_not_supported() {
+    let mut config = default_test_config();
+    config.enable_bind = false;
+    let (socks_addr, handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    client
+        .write_all(&[0x05, 0x02, 0x00, 0x01, 127, 0, 0, 1, 0, 80])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::COMMAND_NOT_SUPPORTED);
+    assert!(handle.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_diagnostics_command_unreachable_by_default() {
+    let (socks_addr, handle) = spawn_socks_server(default_test_config()).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    client
+        .write_all(&[0x05, 0xF0, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::COMMAND_NOT_SUPPORTED);
+    assert!(handle.await.unwrap().is_err());
+}
+
+#[cfg(feature = "diagnostics")]
+#[tokio::test]
+async fn test_diagnostics_command_when_enabled() {
+    let mut config = default_test_config();
+    config.diagnostics_command = true;
+    let (socks_addr, handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    client
+        .write_all(&[0x05, 0xF0, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::SUCCESS);
+    let len = client.read_u16().await.unwrap() as usize;
+    let mut body = vec![0u8; len];
+    client.read_exact(&mut body).await.unwrap();
+    assert!(
+        String::from_utf8(body)
+            .unwrap()
+            .contains("commands=CONNECT,BIND")
+    );
+    assert_eq!(handle.await.unwrap().unwrap(), CloseReason::Completed);
+}
+
+#[tokio::test]
+async fn test_pipelined_burst_reaches_target_when_client_closes_immediately() {
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let (mut socket, _) = target_listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).await.unwrap();
+        received
+    });
+
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+    let payload: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+
+    // Greeting, request and payload in one write so most of it is read into
+    // the proxy's BufReader before the relay starts
+    let mut burst = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    burst.extend_from_slice(&target_addr.port().to_be_bytes());
+    burst.extend_from_slice(&payload);
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    client.write_all(&burst).await.unwrap();
+    client.shutdown().await.unwrap();
+
+    let mut replies = [0u8; 12];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[1], 0x00);
+    assert_eq!(replies[3], Reply::SUCCESS);
+
+    let received = timeout(Duration::from_secs(5), target_handle)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(received, payload);
+    assert_eq!(
+        socks_handle.await.unwrap().unwrap(),
+        CloseReason::ClientClosed
+    );
+}
+
+#[tokio::test]
+async fn test_early_data_after_request_is_echoed() {
+    let target_addr = rhoxy_socks::echo::spawn_echo_target("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let (socks_addr, socks_handle) = spawn_socks_server(default_test_config()).await;
+
+    // Handshake, request and early payload in a single write
+    let mut burst = vec![0x05, 0x01, 0x00, 0x05, 0x01, 0x00, 0x01, 127, 0, 0, 1];
+    burst.extend_from_slice(&target_addr.port().to_be_bytes());
+    burst.extend_from_slice(b"early payload");
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    client.write_all(&burst).await.unwrap();
+
+    let mut replies = [0u8; 12];
+    client.read_exact(&mut replies).await.unwrap();
+    assert_eq!(replies[1], 0x00);
+    assert_eq!(replies[3], Reply::SUCCESS);
+
+    let mut echoed = [0u8; 13];
+    timeout(Duration::from_secs(2), client.read_exact(&mut echoed))
+        .await
+        .expect("early payload should be relayed without further client writes")
+        .unwrap();
+    assert_eq!(&echoed, b"early payload");
+
+    drop(client);
+    let reason = socks_handle.await.unwrap().unwrap();
+    assert_eq!(reason, CloseReason::ClientClosed);
+}
+
+#[tokio::test]
+async fn test_log_accepted_emits_info_line_for_connect() {
+    for enabled in [false, true] {
+        let capture = LogCapture::default();
+        let make_writer = {
+            let capture = capture.clone();
+            move || capture.clone()
+        };
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(make_writer)
+            .with_max_level(tracing::Level::INFO)
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target_listener.local_addr().unwrap();
+        let target_handle = task::spawn(async move {
+            let _ = target_listener.accept().await.unwrap();
+        });
+
+        let mut config = default_test_config();
+        config.log_accepted = enabled;
+        let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+        let mut client = TcpStream::connect(socks_addr).await.unwrap();
+        let reply = socks_connect(&mut client, target_addr).await;
+        assert_eq!(reply[1], Reply::SUCCESS);
+        target_handle.await.unwrap();
+        drop(client);
+        let _ = socks_handle.await.unwrap();
+
+        let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+        let line = logs.lines().find(|line| line.contains("Accepted CONNECT"));
+        if enabled {
+            let line = line.expect("accepted CONNECT was not logged");
+            assert!(line.contains(" INFO "), "{line}");
+            assert!(line.contains(&format!("to {}", target_addr)), "{line}");
+            assert!(
+                line.contains("method: No Authentication Required"),
+                "{line}"
+            );
+        } else {
+            assert!(line.is_none(), "{logs}");
+        }
+    }
+}
+
+#[derive(Default)]
+struct TargetRecorder {
+    connected: std::sync::Mutex<Vec<(Option<String>, SocketAddr)>>,
+}
+
+impl EventSink for TargetRecorder {
+    fn connection_closed(&self, _client_addr: SocketAddr, _reason: CloseReason) {}
+
+    fn target_connected(
+        &self,
+        _client_addr: SocketAddr,
+        domain: Option<&str>,
+        target: SocketAddr,
+        _method: Option<Method>,
+    ) {
+        self.connected
+            .lock()
+            .unwrap()
+            .push((domain.map(str::to_string), target));
+    }
+}
+
+#[tokio::test]
+async fn test_domain_connect_reports_domain_and_dialed_address() {
+    let capture = LogCapture::default();
+    let make_writer = {
+        let capture = capture.clone();
+        move || capture.clone()
+    };
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(make_writer)
+        .with_max_level(tracing::Level::INFO)
+        .with_ansi(false)
+        .finish();
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    let target_handle = task::spawn(async move {
+        let _ = target_listener.accept().await.unwrap();
+    });
+
+    let recorder = Arc::new(TargetRecorder::default());
+    let config = ConnectionConfig {
+        resolve_family: ResolveFamily::V4,
+        log_accepted: true,
+        event_sink: Some(recorder.clone()),
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 9];
+    request.extend_from_slice(b"localhost");
+    request.extend_from_slice(&target_addr.port().to_be_bytes());
+    client.write_all(&request).await.unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::SUCCESS);
+    target_handle.await.unwrap();
+    drop(client);
+    let _ = socks_handle.await.unwrap();
+
+    assert_eq!(
+        *recorder.connected.lock().unwrap(),
+        vec![(Some("localhost".to_string()), target_addr)]
+    );
+    let logs = String::from_utf8(capture.0.lock().unwrap().clone()).unwrap();
+    let line = logs
+        .lines()
+        .find(|line| line.contains("Accepted CONNECT"))
+        .expect("accepted CONNECT was not logged");
+    assert!(
+        line.contains(&format!("to localhost ({})", target_addr)),
+        "{line}"
+    );
+}
+
+#[tokio::test]
+async fn test_family_filter_leaving_no_address_is_host_unreachable() {
+    let config = ConnectionConfig {
+        resolve_family: ResolveFamily::V4,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    // An IPv6 literal sent as a domain name resolves locally to one v6 address
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 3];
+    request.extend_from_slice(b"::1");
+    request.extend_from_slice(&80u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    timeout(Duration::from_secs(2), client.read_exact(&mut reply))
+        .await
+        .expect("no reply for a filtered-out resolution")
+        .unwrap();
+    assert_eq!(reply[1], Reply::HOST_UNREACHABLE);
+    drop(client);
+    let _ = socks_handle.await;
+}
+
+#[tokio::test]
+async fn test_domain_over_max_len_is_refused() {
+    let config = ConnectionConfig {
+        max_domain_len: 8,
+        ..default_test_config()
+    };
+    let (socks_addr, socks_handle) = spawn_socks_server(config).await;
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    socks_handshake(&mut client).await;
+
+    let mut request = vec![0x05, 0x01, 0x00, 0x03, 9];
+    request.extend_from_slice(b"localhost");
+    request.extend_from_slice(&80u16.to_be_bytes());
+    client.write_all(&request).await.unwrap();
+
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], Reply::CONNECTION_NOT_ALLOWED);
+    drop(client);
+    let _ = socks_handle.await;
+}
+
+// Answers every POST with 200 and forwards its JSON body
+async fn mock_webhook() -> (
+    SocketAddr,
+    tokio::sync::mpsc::UnboundedReceiver<serde_json::Value>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    task::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let mut socket = BufReader::new(socket);
+            let mut content_length = 0;
+            let mut line = String::new();
+            while socket.read_line(&mut line).await.unwrap() > 2 {
+                if let Some(len) = line.strip_prefix("Content-Length: ") {
+                    content_length = len.trim().parse().unwrap();
+                }
+                line.clear();
+            }
+            let mut body = vec![0u8; content_length];
+            socket.read_exact(&mut body).await.unwrap();
+            let _ = tx.send(serde_json::from_slice(&body).unwrap());
+            let _ = socket
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .await;
+        }
+    });
+    (addr, rx)
+}
+
+#[tokio::test]
+async fn test_connect_webhook_posts_established_connect() {
+    let (webhook_addr, mut posts) = mock_webhook().await;
+    let target_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target_addr = target_listener.local_addr().unwrap();
+    task::spawn(async move { target_listener.accept().await });
+
+    let recorder = Arc::new(TargetRecorder::default());
+    let url = format!("http://{}/hooks/connect", webhook_addr)
+        .parse()
+        .unwrap();
+    let mut config = default_test_config();
+    config.event_sink = Some(Arc::new(WebhookSink::spawn(url, recorder.clone(), 2)));
+    let (socks_addr, _handle) = spawn_socks_server(config).await;
+
+    let mut client = TcpStream::connect(socks_addr).await.unwrap();
+    let reply = socks_connect(&mut client, target_addr).await;
+    assert_eq!(reply[1], Reply::SUCCESS);
+
+    let payload = timeout(Duration::from_secs(5), posts.recv())
+        .await
+        .expect("webhook should be called")
+        .unwrap();
+    assert_eq!(payload["client"], client.local_addr().unwrap().to_string());
+    assert_eq!(payload["target"], target_addr.to_string());
+    assert_eq!(payload["domain"], serde_json::Value::Null);
+    assert_eq!(payload["method"], Method::NO_AUTHENTICATION_REQUIRED);
+    assert!(payload["timestamp"].as_u64().unwrap() > 0);
+
+    // The wrapped sink still sees the event
+    assert_eq!(
+        *recorder.connected.lock().unwrap(),
+        vec![(None, target_addr)]
+    );
+}